@@ -0,0 +1,84 @@
+#!/bin/bash
+#
+# Build rboot with the symbol table used to symbolize backtraces.
+#
+# The table is generated from a first link of rboot and embedded by a second one. It lives
+# after the code, so every function must keep its address: this is checked before the
+# binary is written.
+
+usage()
+{
+	cat << EOF
+Usage: ./build-bootloader.sh [--release] [cargo build arguments...]
+Where:
+	--release: Build rboot-release.bin instead of rboot-debug.bin.
+EOF
+
+	exit 1
+}
+
+profile=debug
+profile_flag=
+
+case "$1" in
+	--release)
+		profile=release
+		profile_flag=--release
+		shift
+		;;
+	-h|--help)
+		usage
+		;;
+esac
+
+target_dir="target/aarch64-thog-none/${profile}"
+elf="${target_dir}/rboot"
+first_elf="${target_dir}/rboot.nosymbols"
+
+function chkerr {
+	ret=$?
+	if [ ${ret} -ne 0 ]; then
+		echo $1
+		exit ${ret}
+	fi
+}
+
+function cargo_rboot {
+	command=$1
+	shift
+
+	cargo ${command} --bin rboot --target aarch64-thog-none.json \
+		-Z build-std=core,compiler_builtins ${profile_flag} "$@"
+}
+
+# Function symbols with their addresses and sizes, in address order.
+function list_functions {
+	rust-nm --defined-only --print-size --numeric-sort "$1" | grep -i ' t '
+}
+
+unset RBOOT_SYMBOLS_ELF
+cargo_rboot build "$@"
+chkerr "First rboot build failed"
+
+cp "${elf}" "${first_elf}"
+chkerr "Could not save the first rboot link"
+
+export RBOOT_SYMBOLS_ELF="${PWD}/${first_elf}"
+cargo_rboot build "$@"
+chkerr "rboot build with the symbol table failed"
+
+list_functions "${first_elf}" > "${first_elf}.functions"
+chkerr "Could not list the functions of the first rboot link"
+
+list_functions "${elf}" > "${elf}.functions"
+chkerr "Could not list the functions of rboot"
+
+if ! diff -u "${first_elf}.functions" "${elf}.functions"; then
+	echo "Embedding the symbol table moved functions, backtraces would be wrong"
+	exit 1
+fi
+
+cargo_rboot objcopy "$@" -- -O binary "rboot-${profile}.bin"
+chkerr "Could not write rboot-${profile}.bin"
+
+# vi: ts=8 sw=8 noexpandtab
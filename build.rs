@@ -6,17 +6,230 @@ use std::io::Write;
 use std::path::Path;
 use std::process::Command;
 
-fn main() {
-    let faucon_dir = env::var("FAUCON_DIR").unwrap();
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn read_c_str(data: &[u8], offset: usize) -> &str {
+    let end = data[offset..]
+        .iter()
+        .position(|&c| c == 0)
+        .map(|len| offset + len)
+        .unwrap_or_else(|| data.len());
+
+    std::str::from_utf8(&data[offset..end]).unwrap_or("")
+}
+
+/// Demangle a legacy Rust symbol name, dropping the hash.
+fn demangle(name: &str) -> String {
+    let mut mangled = match name.strip_prefix("_ZN") {
+        Some(mangled) => mangled,
+        None => return name.to_string(),
+    };
+
+    let mut segments = Vec::new();
+    while let Some(len_end) = mangled.find(|c: char| !c.is_ascii_digit()) {
+        if len_end == 0 {
+            break;
+        }
+
+        let len: usize = mangled[..len_end].parse().unwrap();
+        if mangled.len() < len_end + len {
+            return name.to_string();
+        }
+
+        segments.push(&mangled[len_end..len_end + len]);
+        mangled = &mangled[len_end + len..];
+    }
+
+    if let Some(last) = segments.last() {
+        if last.len() == 17
+            && last.starts_with('h')
+            && last[1..].chars().all(|c| c.is_ascii_hexdigit())
+        {
+            segments.pop();
+        }
+    }
+
+    let mut result = String::new();
+    for (index, segment) in segments.iter().enumerate() {
+        if index != 0 {
+            result.push_str("::");
+        }
+
+        let segment = segment
+            .strip_prefix('_')
+            .filter(|s| s.starts_with('$'))
+            .unwrap_or(segment);
+        result.push_str(
+            &segment
+                .replace("..", "::")
+                .replace("$LT$", "<")
+                .replace("$GT$", ">")
+                .replace("$RF$", "&")
+                .replace("$BP$", "*")
+                .replace("$LP$", "(")
+                .replace("$RP$", ")")
+                .replace("$C$", ",")
+                .replace("$SP$", "@")
+                .replace("$u20$", " ")
+                .replace("$u27$", "'")
+                .replace("$u5b$", "[")
+                .replace("$u5d$", "]")
+                .replace("$u7b$", "{")
+                .replace("$u7d$", "}")
+                .replace("$u7e$", "~"),
+        );
+    }
+
+    result
+}
+
+/// Extract the function symbols of an ELF64 image as (address, size, name), sorted by address.
+fn read_function_symbols(elf: &[u8]) -> Vec<(u64, u64, String)> {
+    assert!(&elf[0..4] == b"\x7fELF" && elf[4] == 2, "Not an ELF64 file");
+
+    let section_header_offset = read_u64(elf, 0x28) as usize;
+    let section_header_size = read_u16(elf, 0x3a) as usize;
+    let section_header_count = read_u16(elf, 0x3c) as usize;
+
+    let mut symbols = Vec::new();
+
+    for index in 0..section_header_count {
+        let header = section_header_offset + index * section_header_size;
+        if read_u32(elf, header + 4) != SHT_SYMTAB {
+            continue;
+        }
+
+        let symtab_offset = read_u64(elf, header + 24) as usize;
+        let symtab_size = read_u64(elf, header + 32) as usize;
+        let symtab_entry_size = read_u64(elf, header + 56) as usize;
+
+        let strtab_header =
+            section_header_offset + read_u32(elf, header + 40) as usize * section_header_size;
+        let strtab_offset = read_u64(elf, strtab_header + 24) as usize;
+
+        for symbol in (symtab_offset..symtab_offset + symtab_size).step_by(symtab_entry_size) {
+            let name_offset = read_u32(elf, symbol) as usize;
+            let info = elf[symbol + 4];
+            let value = read_u64(elf, symbol + 8);
+            let size = read_u64(elf, symbol + 16);
+
+            if info & 0xf != STT_FUNC || value == 0 && size == 0 {
+                continue;
+            }
+
+            let name = demangle(read_c_str(elf, strtab_offset + name_offset));
+            symbols.push((value, size, name));
+        }
+    }
+
+    symbols.sort_by_key(|symbol| symbol.0);
+    symbols.dedup_by_key(|symbol| symbol.0);
+
+    symbols
+}
+
+/// Serialize the symbols in the format expected by src/backtrace.rs.
+///
+/// Header: "RSYM", entry count (u32).
+/// Entries: address (u32), size (u32), name offset (u32), name length (u32).
+/// Followed by the names, offsets are relative to the end of the entries.
+fn build_symbol_table(symbols: &[(u64, u64, String)]) -> Vec<u8> {
+    let mut table = Vec::new();
+    let mut names = Vec::new();
+
+    table.extend_from_slice(b"RSYM");
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+
+    for (address, size, name) in symbols {
+        table.extend_from_slice(&(*address as u32).to_le_bytes());
+        table.extend_from_slice(&(*size as u32).to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+
+    table.extend_from_slice(&names);
+    table
+}
+
+/// Generate the embedded symbol table.
+///
+/// The symbols come from a previously linked rboot ELF given by RBOOT_SYMBOLS_ELF,
+/// build-bootloader.sh does both links and checks that no function moved.
+/// As the table lives in .rodata after the code, and the code only finds it through
+/// the linker script, relinking with it leaves .text untouched.
+fn generate_symbols(out_dir: &str) {
+    println!("cargo:rerun-if-env-changed=RBOOT_SYMBOLS_ELF");
+
+    let symbols = match env::var("RBOOT_SYMBOLS_ELF") {
+        Ok(elf_path) => {
+            println!("cargo:rerun-if-changed={}", elf_path);
+
+            let elf = std::fs::read(&elf_path).expect("Cannot read RBOOT_SYMBOLS_ELF");
+            read_function_symbols(&elf)
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let table = build_symbol_table(&symbols);
+
+    let table_path = Path::new(out_dir).join("symbols.bin");
+    File::create(&table_path)
+        .unwrap()
+        .write_all(&table)
+        .unwrap();
+
+    let mut f = File::create(Path::new(out_dir).join("symbols.rs")).unwrap();
+    f.write_all(format!("#[used] #[link_section = \".symbols\"] static SYMBOL_TABLE: [u8; {}] = *include_bytes!(concat!(env!(\"OUT_DIR\"), \"/symbols.bin\"));", table.len()).as_bytes()).unwrap();
+}
+
+/// Build the TSEC firmware with faucon and embed it.
+///
+/// Host builds running the library unit tests don't have it: without FAUCON_DIR,
+/// only building the rboot binary fails, with an explicit error.
+fn generate_falcon_firmware(out_dir: &str) {
+    println!("cargo:rerun-if-env-changed=FAUCON_DIR");
+
+    let dest_path = Path::new(out_dir).join("falcon_fw.rs");
+    let mut f = File::create(&dest_path).unwrap();
+
+    let faucon_dir = match env::var("FAUCON_DIR") {
+        Ok(faucon_dir) => faucon_dir,
+        Err(_) => {
+            if env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("aarch64") {
+                println!("cargo:warning=FAUCON_DIR isn't set, the TSEC firmware can't be built");
+            }
+
+            f.write_all(
+                b"compile_error!(\"FAUCON_DIR must point to the faucon sources to build rboot\");",
+            )
+            .unwrap();
+            return;
+        }
+    };
+
     println!("cargo:rerun-if-changed={}/faucon.asm", faucon_dir);
     println!("cargo:rerun-if-changed={}/faucon_fw.bin", faucon_dir);
 
     let faucon_meta = std::fs::metadata(format!("{}/faucon_fw.bin", faucon_dir)).unwrap();
 
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let dest_path = Path::new(&out_dir).join("falcon_fw.rs");
-    let mut f = File::create(&dest_path).unwrap();
-
     Command::new("make")
         .current_dir(faucon_dir)
         .output()
@@ -25,3 +238,30 @@ fn main() {
     f.write_all(b"use libtegra::tsec::Firmware;").unwrap();
     f.write_all(format!("static FALCON_FW: Firmware<u8, {}> = Firmware::new(*include_bytes!(concat!(env!(\"FAUCON_DIR\"),\"faucon_fw.bin\")));", faucon_meta.len()).as_bytes()).unwrap();
 }
+
+/// Give the translation granule selected by the cargo features to link.ld.
+///
+/// Sections with different permissions only need to be aligned on the pages the MMU maps.
+fn generate_linker_script(out_dir: &str) {
+    let granule_size = if env::var_os("CARGO_FEATURE_GRANULE_64K").is_some() {
+        0x10000
+    } else if env::var_os("CARGO_FEATURE_GRANULE_16K").is_some() {
+        0x4000
+    } else {
+        0x1000
+    };
+
+    let mut f = File::create(Path::new(out_dir).join("granule.ld")).unwrap();
+    writeln!(f, "__granule_size = {:#x};", granule_size).unwrap();
+
+    // link.ld includes it from there.
+    println!("cargo:rustc-link-search=native={}", out_dir);
+}
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    generate_linker_script(&out_dir);
+    generate_falcon_firmware(&out_dir);
+    generate_symbols(&out_dir);
+}
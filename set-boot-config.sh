@@ -0,0 +1,70 @@
+#!/bin/bash
+#
+# Write a boot configuration in a built rboot image, see src/boot_config.rs for its keys.
+
+usage()
+{
+	cat << EOF
+Usage: ./set-boot-config.sh <rboot_elf> <config_file> <output_bin>
+Where:
+	rboot_elf: rboot ELF, as built in target/aarch64-thog-none/<profile>/rboot.
+	config_file: key=value lines.
+	output_bin: Raw binary to write, as given to exec-payload.sh.
+EOF
+
+	exit 1
+}
+
+if [ $# -ne 3 ]; then
+	usage
+fi
+
+rboot_elf="$1"
+config_file="$2"
+output_bin="$3"
+
+# CONFIG_SIZE in src/boot_config.rs, the magic included.
+config_size=1024
+magic="RBOOTCFG"
+
+tmp_dir="/tmp/set-boot-config.$$"
+blob="${tmp_dir}/boot_config.bin"
+configured_elf="${tmp_dir}/rboot"
+
+function rm_tmp_dir {
+	rm -rf "${tmp_dir}"
+}
+
+trap rm_tmp_dir exit SIGHUP SIGINT SIGTERM
+
+function chkerr {
+	ret=$?
+	if [ ${ret} -ne 0 ]; then
+		echo $1
+		exit ${ret}
+	fi
+}
+
+mkdir -p "${tmp_dir}"
+chkerr "Could not create temporary dir"
+
+text_size=`stat -c %s "${config_file}"`
+chkerr "Could not read ${config_file}"
+
+if [ ${text_size} -ge $((config_size - ${#magic})) ]; then
+	echo "${config_file} doesn't fit in the $((config_size - ${#magic} - 1)) bytes available"
+	exit 1
+fi
+
+# The text ends at the first zero, pad the blob with them.
+{ printf "%s" "${magic}"; cat "${config_file}"; } > "${blob}"
+truncate -s ${config_size} "${blob}"
+chkerr "Could not build the boot config blob"
+
+rust-objcopy --update-section .boot_config="${blob}" "${rboot_elf}" "${configured_elf}"
+chkerr "Could not write the boot config to ${rboot_elf}"
+
+rust-objcopy -O binary "${configured_elf}" "${output_bin}"
+chkerr "Could not write ${output_bin}"
+
+# vi: ts=8 sw=8 noexpandtab
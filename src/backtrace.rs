@@ -0,0 +1,157 @@
+//! Frame pointer backtraces, symbolized with the table embedded by build.rs.
+
+use core::fmt::Write;
+use core::str;
+
+include!(concat!(env!("OUT_DIR"), "/symbols.rs"));
+
+extern "C" {
+    static _stack_bottom: u8;
+    static _stack_top: u8;
+    static __symbols_start__: u8;
+    static __symbols_end__: u8;
+}
+
+const SYMBOL_TABLE_MAGIC: &[u8] = b"RSYM";
+const SYMBOL_HEADER_SIZE: usize = 8;
+const SYMBOL_ENTRY_SIZE: usize = 16;
+
+const MAX_BACKTRACE_DEPTH: usize = 32;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// Get the embedded symbol table.
+///
+/// It is found through the linker script rather than SYMBOL_TABLE, so the code doesn't
+/// depend on the table size and stays identical between the two links of build-bootloader.sh.
+fn symbol_table() -> &'static [u8] {
+    unsafe {
+        let start = &__symbols_start__ as *const u8;
+        let end = &__symbols_end__ as *const u8;
+
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Find the function containing `address`, returning its name and the offset inside it.
+pub fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    let table = symbol_table();
+
+    if table.len() < SYMBOL_HEADER_SIZE || &table[0..4] != SYMBOL_TABLE_MAGIC {
+        return None;
+    }
+
+    let count = read_u32(table, 4) as usize;
+    let names_offset = SYMBOL_HEADER_SIZE + count * SYMBOL_ENTRY_SIZE;
+    let entry_address =
+        |index: usize| read_u32(table, SYMBOL_HEADER_SIZE + index * SYMBOL_ENTRY_SIZE) as u64;
+
+    // Symbols are relative to the image base as rboot relocates itself.
+    let image_base = crate::rt::_start as usize as u64;
+    let relative_address = address.checked_sub(image_base)?;
+
+    // Find the last symbol starting before the address.
+    let mut low = 0;
+    let mut high = count;
+    while low < high {
+        let middle = (low + high) / 2;
+
+        if entry_address(middle) <= relative_address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    if low == 0 {
+        return None;
+    }
+
+    let entry = SYMBOL_HEADER_SIZE + (low - 1) * SYMBOL_ENTRY_SIZE;
+    let start = read_u32(table, entry) as u64;
+    let size = read_u32(table, entry + 4) as u64;
+
+    if size != 0 && relative_address >= start + size {
+        return None;
+    }
+
+    let name_start = names_offset + read_u32(table, entry + 8) as usize;
+    let name_end = name_start + read_u32(table, entry + 12) as usize;
+    let name = str::from_utf8(table.get(name_start..name_end)?).ok()?;
+
+    Some((name, relative_address - start))
+}
+
+pub fn get_frame_pointer() -> u64 {
+    let frame_pointer: u64;
+    unsafe {
+        asm!("mov {fp}, x29", fp = out(reg) frame_pointer, options(nomem, nostack));
+    }
+
+    frame_pointer
+}
+
+fn is_valid_frame(frame_pointer: u64) -> bool {
+    let stack_bottom = unsafe { &_stack_bottom as *const _ as u64 };
+    let stack_top = unsafe { &_stack_top as *const _ as u64 };
+
+    frame_pointer % 16 == 0 && frame_pointer >= stack_bottom && frame_pointer + 16 <= stack_top
+}
+
+fn write_frame<W: Write>(writer: &mut W, depth: usize, address: u64) {
+    match symbolize(address) {
+        Some((name, offset)) => writeln!(
+            writer,
+            "  #{:<2} {:#018x} {}+{:#x}\r",
+            depth, address, name, offset
+        ),
+        None => writeln!(writer, "  #{:<2} {:#018x} ???\r", depth, address),
+    }
+    .ok();
+}
+
+/// Walk the x29 frame record chain starting at `frame_pointer`.
+///
+/// When given, `pc` is printed as the first frame.
+pub fn write_backtrace<W: Write>(writer: &mut W, pc: Option<u64>, frame_pointer: u64) {
+    writeln!(writer, "Backtrace:\r").ok();
+
+    let mut depth = 0;
+    if let Some(pc) = pc {
+        write_frame(writer, depth, pc);
+        depth += 1;
+    }
+
+    let mut frame_pointer = frame_pointer;
+    while depth < MAX_BACKTRACE_DEPTH && is_valid_frame(frame_pointer) {
+        // A frame record is the caller frame pointer followed by the return address.
+        let (next_frame_pointer, return_address) = unsafe {
+            (
+                *(frame_pointer as *const u64),
+                *((frame_pointer + 8) as *const u64),
+            )
+        };
+
+        if return_address == 0 {
+            break;
+        }
+
+        // Point at the call instruction rather than after it.
+        write_frame(writer, depth, return_address - 4);
+        depth += 1;
+
+        // The stack grows down, callers frames are always higher.
+        if next_frame_pointer <= frame_pointer {
+            break;
+        }
+
+        frame_pointer = next_frame_pointer;
+    }
+}
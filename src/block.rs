@@ -0,0 +1,81 @@
+//! Block devices, the storage interface partition tables and filesystems are built on.
+
+/// Size of the blocks of every device rboot knows of.
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The access goes past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a multiple of the block size.
+    BadBufferSize,
+    /// The device didn't answer in time.
+    Timeout,
+    /// The device reported an error.
+    Io,
+    /// The device can't be written.
+    ReadOnly,
+}
+
+/// A storage device addressed in blocks of [`BLOCK_SIZE`] bytes.
+pub trait BlockDevice {
+    fn block_count(&self) -> u64;
+
+    /// Read `buffer.len() / BLOCK_SIZE` blocks starting at block `lba`.
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Write `buffer.len() / BLOCK_SIZE` blocks starting at block `lba`.
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Error>;
+}
+
+/// Check that `buffer` holds whole blocks that are all on a device of `block_count` blocks.
+pub fn check_range(block_count: u64, lba: u64, buffer_size: usize) -> Result<(), Error> {
+    if buffer_size % BLOCK_SIZE != 0 {
+        return Err(Error::BadBufferSize);
+    }
+
+    let end = lba
+        .checked_add((buffer_size / BLOCK_SIZE) as u64)
+        .ok_or(Error::OutOfRange)?;
+
+    if end > block_count {
+        return Err(Error::OutOfRange);
+    }
+
+    Ok(())
+}
+
+/// Read `buffer.len()` bytes at byte `offset` of `device`, which don't have to be aligned.
+pub fn read_bytes(
+    device: &mut dyn BlockDevice,
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<(), Error> {
+    let mut block = [0; BLOCK_SIZE];
+    let mut offset = offset;
+    let mut done = 0;
+
+    while done < buffer.len() {
+        let lba = offset / BLOCK_SIZE as u64;
+        let block_offset = (offset % BLOCK_SIZE as u64) as usize;
+        let remaining = buffer.len() - done;
+
+        if block_offset == 0 && remaining >= BLOCK_SIZE {
+            // Whole blocks go straight to the buffer.
+            let size = remaining - remaining % BLOCK_SIZE;
+            device.read_blocks(lba, &mut buffer[done..done + size])?;
+
+            done += size;
+            offset += size as u64;
+        } else {
+            let size = core::cmp::min(BLOCK_SIZE - block_offset, remaining);
+            device.read_blocks(lba, &mut block)?;
+            buffer[done..done + size].copy_from_slice(&block[block_offset..block_offset + size]);
+
+            done += size;
+            offset += size as u64;
+        }
+    }
+
+    Ok(())
+}
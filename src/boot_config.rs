@@ -0,0 +1,190 @@
+//! Boot configuration embedded in the rboot image.
+//!
+//! The `.boot_config` section of rboot holds a [`ConfigBlob`]: a magic followed by `key=value`
+//! lines, padded with zeroes. It is empty in a fresh build, set-boot-config.sh fills it in a
+//! built image so the configuration changes without rebuilding. Lines starting with `#` are
+//! comments.
+//!
+//! Known keys:
+//! - `log`: log filter directives (see [`crate::log_filter`]).
+//! - `log_prompt_ms`: time given at boot to press a key on the UART and type log filter
+//!   directives, no prompt when absent or 0.
+//! - `initrd_config_path`: path at which the configuration is appended to the initramfs of
+//!   the kernels booted by rboot, not appended when absent.
+
+use core::str;
+
+pub const CONFIG_MAGIC: [u8; 8] = *b"RBOOTCFG";
+
+/// Size of the blob, magic included.
+pub const CONFIG_SIZE: usize = 1024;
+
+#[repr(C)]
+pub struct ConfigBlob {
+    magic: [u8; 8],
+    text: [u8; CONFIG_SIZE - 8],
+}
+
+impl ConfigBlob {
+    /// A blob without any key.
+    pub const EMPTY: Self = ConfigBlob {
+        magic: CONFIG_MAGIC,
+        text: [0; CONFIG_SIZE - 8],
+    };
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    BadMagic,
+    /// The text isn't valid UTF-8.
+    InvalidText,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BootConfig<'a> {
+    text: &'a str,
+}
+
+impl<'a> BootConfig<'a> {
+    /// A configuration without any key.
+    pub const fn empty() -> Self {
+        BootConfig { text: "" }
+    }
+
+    /// Parse the content of a [`ConfigBlob`], the text stops at the first zero.
+    pub fn parse(blob: &'a [u8]) -> Result<Self, ConfigError> {
+        if blob.len() < CONFIG_MAGIC.len() || blob[..CONFIG_MAGIC.len()] != CONFIG_MAGIC {
+            return Err(ConfigError::BadMagic);
+        }
+
+        let text = &blob[CONFIG_MAGIC.len()..];
+        let end = text.iter().position(|&c| c == 0).unwrap_or(text.len());
+
+        let text = str::from_utf8(&text[..end]).map_err(|_| ConfigError::InvalidText)?;
+
+        Ok(BootConfig { text })
+    }
+
+    /// The whole configuration text.
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    /// Value of `key`, the last line setting it wins.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        let mut value = None;
+
+        for line in self.text.lines() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(index) = line.find('=') {
+                if line[..index].trim() == key {
+                    value = Some(line[index + 1..].trim());
+                }
+            }
+        }
+
+        value
+    }
+
+    /// Value of `key` as a decimal or `0x` prefixed hexadecimal number.
+    pub fn get_u32(&self, key: &str) -> Option<u32> {
+        let value = self.get(key)?;
+
+        match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    }
+}
+
+impl<'a> Default for BootConfig<'a> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(text: &str) -> Vec<u8> {
+        let mut blob = vec![0; CONFIG_SIZE];
+        blob[..8].copy_from_slice(&CONFIG_MAGIC);
+        blob[8..8 + text.len()].copy_from_slice(text.as_bytes());
+        blob
+    }
+
+    #[test]
+    fn empty_blob_has_no_key() {
+        let blob = unsafe {
+            core::slice::from_raw_parts(
+                &ConfigBlob::EMPTY as *const ConfigBlob as *const u8,
+                CONFIG_SIZE,
+            )
+        };
+        let config = BootConfig::parse(blob).unwrap();
+
+        assert_eq!(core::mem::size_of::<ConfigBlob>(), CONFIG_SIZE);
+        assert_eq!(config.get("log"), None);
+    }
+
+    #[test]
+    fn keys_are_trimmed_and_comments_skipped() {
+        let blob = blob("# log=error\n  log = rboot::mmu=trace,info \r\nlog_prompt_ms=500\n");
+        let config = BootConfig::parse(&blob).unwrap();
+
+        assert_eq!(config.get("log"), Some("rboot::mmu=trace,info"));
+        assert_eq!(config.get_u32("log_prompt_ms"), Some(500));
+        assert_eq!(config.get("log_prompt"), None);
+        assert!(config.text().ends_with("log_prompt_ms=500\n"));
+    }
+
+    #[test]
+    fn last_value_wins() {
+        let blob = blob("log=warn\nlog=debug\n");
+
+        assert_eq!(BootConfig::parse(&blob).unwrap().get("log"), Some("debug"));
+    }
+
+    #[test]
+    fn numbers() {
+        let blob = blob("hex=0x1F4\nbad=12ms\n");
+        let config = BootConfig::parse(&blob).unwrap();
+
+        assert_eq!(config.get_u32("hex"), Some(500));
+        assert_eq!(config.get_u32("bad"), None);
+    }
+
+    #[test]
+    fn text_stops_at_the_first_zero() {
+        let mut blob = blob("log=info\n");
+        blob[20..24].copy_from_slice(b"x=1\n");
+
+        assert_eq!(BootConfig::parse(&blob).unwrap().get("x"), None);
+    }
+
+    #[test]
+    fn errors() {
+        let mut bad_magic = blob("log=info");
+        bad_magic[0] = b'X';
+        assert_eq!(
+            BootConfig::parse(&bad_magic).unwrap_err(),
+            ConfigError::BadMagic
+        );
+        assert_eq!(
+            BootConfig::parse(b"RBOOT").unwrap_err(),
+            ConfigError::BadMagic
+        );
+
+        let mut invalid = blob("log=");
+        invalid[12] = 0xFF;
+        assert_eq!(
+            BootConfig::parse(&invalid).unwrap_err(),
+            ConfigError::InvalidText
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Chainloading of ELF payloads built like rboot.
+
+use core::mem;
+use core::slice;
+
+use crate::elf::{self, ElfFile};
+use crate::linux;
+use crate::mmu::{self, MapError, MemoryPermission};
+use crate::tegra210::board::p2371_2180 as board;
+use crate::utils;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    Elf(elf::Error),
+    /// The image would overwrite the file it is loaded from.
+    Overlap,
+    /// The image isn't in a DRAM bank, at the given load address.
+    OutsideDram(u64),
+    /// The image would overwrite rboot or a carveout, starting at the given address.
+    ReservedMemory(u64),
+    /// Position independent images need a load address.
+    NoBase,
+    Map(MapError),
+}
+
+impl From<elf::Error> for Error {
+    fn from(error: elf::Error) -> Self {
+        Error::Elf(error)
+    }
+}
+
+impl From<MapError> for Error {
+    fn from(error: MapError) -> Self {
+        Error::Map(error)
+    }
+}
+
+/// An image copied in memory and ready to run.
+#[derive(Copy, Clone, Debug)]
+pub struct LoadedImage {
+    /// Address the first page of the image is loaded at.
+    pub start: u64,
+    pub size: u64,
+    /// Offset added to the addresses of the file.
+    pub bias: u64,
+    pub entry: u64,
+}
+
+/// Check that the image, from `start` to `end`, is in DRAM used neither by rboot nor by a
+/// carveout. The gaps between segments are mapped too, so they count.
+fn check_destination(start: u64, end: u64) -> Result<(), Error> {
+    let in_dram = board::DRAM_BANKS
+        .iter()
+        .any(|(bank_start, bank_size)| start >= *bank_start && end <= bank_start + bank_size);
+    if !in_dram {
+        return Err(Error::OutsideDram(start));
+    }
+
+    let (rboot_start, rboot_end) = linux::get_rboot_range();
+    let reserved = core::iter::once((rboot_start, rboot_end - rboot_start)).chain(
+        board::CARVEOUTS
+            .iter()
+            .map(|(_, address, size)| (*address, *size)),
+    );
+
+    for (address, size) in reserved {
+        if start < address + size && address < end {
+            return Err(Error::ReservedMemory(address));
+        }
+    }
+
+    Ok(())
+}
+
+/// Position independent images are loaded at `base` and relocated, other images at their
+/// link address. The mapping is identity, `base` has to point to DRAM.
+pub fn load(file: &ElfFile, base: Option<u64>) -> Result<LoadedImage, Error> {
+    let (link_start, link_end) = file.get_load_range()?;
+
+    let start = if file.is_position_independent() {
+        utils::align_down(base.ok_or(Error::NoBase)?, mmu::PAGE_SIZE)
+    } else {
+        link_start
+    };
+    let size = link_end - link_start;
+    let end = start.checked_add(size).ok_or(Error::OutsideDram(start))?;
+    let bias = start.wrapping_sub(link_start);
+
+    let source_start = file.data().as_ptr() as u64;
+    let source_end = source_start + file.data().len() as u64;
+    if start < source_end && source_start < end {
+        return Err(Error::Overlap);
+    }
+
+    check_destination(start, end)?;
+
+    // Writable while copying and relocating.
+    mmu::map_normal_page(start, start, size, MemoryPermission::RW)?;
+
+    let image = unsafe { slice::from_raw_parts_mut(start as *mut u8, size as usize) };
+    file.copy_segments(image)?;
+    if file.is_position_independent() {
+        file.relocate(image, bias)?;
+    }
+
+    mmu::clean_dcache_range(start, size);
+    mmu::invalidate_icache_all();
+
+    file.for_each_mapping(|address, size, permission| {
+        let address = address.wrapping_add(bias);
+
+        mmu::map_normal_page(address, address, size, permission)
+    })?;
+
+    Ok(LoadedImage {
+        start,
+        size,
+        bias,
+        entry: file.entry().wrapping_add(bias),
+    })
+}
+
+/// Call the entry point of a loaded image, returning its result.
+///
+/// # Safety
+///
+/// The image must be a valid program following the C calling convention.
+pub unsafe fn start(image: &LoadedImage) -> u64 {
+    let entry: extern "C" fn() -> u64 = mem::transmute(image.entry as usize);
+
+    entry()
+}
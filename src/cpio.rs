@@ -0,0 +1,159 @@
+//! Writer for newc cpio archives, the format of the Linux initramfs.
+//!
+//! The kernel unpacks concatenated archives in order, compressed or not, so entries can be
+//! appended to an existing initramfs as a new archive following it.
+
+use core::fmt::{self, Write};
+
+const NEWC_MAGIC: &str = "070701";
+const HEADER_SIZE: usize = 110;
+
+const TRAILER_NAME: &str = "TRAILER!!!";
+
+pub const MODE_DIRECTORY: u32 = 0o040_000;
+pub const MODE_FILE: u32 = 0o100_000;
+
+/// First inode number given to entries, far from the ones of usual archives.
+const FIRST_INODE: u32 = 0x7262_0000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The archive doesn't fit in the buffer.
+    NoSpace,
+    /// Names can't be empty, absolute or contain NUL.
+    BadName,
+}
+
+fn align4(value: usize) -> usize {
+    (value + 3) & !3
+}
+
+/// Writes the hexadecimal fields of a header.
+struct HeaderWriter<'a> {
+    header: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> Write for HeaderWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.length + s.len();
+        if end > self.header.len() {
+            return Err(fmt::Error);
+        }
+
+        self.header[self.length..end].copy_from_slice(s.as_bytes());
+        self.length = end;
+
+        Ok(())
+    }
+}
+
+/// Appends a newc archive after the data already at the start of a buffer.
+pub struct CpioWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+    next_inode: u32,
+}
+
+impl<'a> CpioWriter<'a> {
+    /// Start an archive after the `length` bytes of `buffer` already used.
+    pub fn new(buffer: &'a mut [u8], length: usize) -> Result<Self, Error> {
+        let start = align4(length);
+        if start > buffer.len() {
+            return Err(Error::NoSpace);
+        }
+
+        // Zeros between archives are skipped by the kernel.
+        for byte in buffer[length..start].iter_mut() {
+            *byte = 0;
+        }
+
+        Ok(CpioWriter {
+            buffer,
+            length: start,
+            next_inode: FIRST_INODE,
+        })
+    }
+
+    fn add_entry(&mut self, name: &str, mode: u32, data: &[u8]) -> Result<(), Error> {
+        if name.is_empty() || name.starts_with('/') || name.contains('\0') {
+            return Err(Error::BadName);
+        }
+
+        let name_end = self.length + HEADER_SIZE + name.len() + 1;
+        let data_start = align4(name_end);
+        let data_end = data_start + data.len();
+        let entry_end = align4(data_end);
+
+        if entry_end > self.buffer.len() {
+            return Err(Error::NoSpace);
+        }
+
+        let nlink = if mode & MODE_DIRECTORY != 0 { 2 } else { 1 };
+        let inode = if name == TRAILER_NAME {
+            0
+        } else {
+            self.next_inode += 1;
+            self.next_inode
+        };
+
+        let mut header = HeaderWriter {
+            header: &mut self.buffer[self.length..self.length + HEADER_SIZE],
+            length: 0,
+        };
+
+        // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor,
+        // rdevminor, namesize and check.
+        write!(
+            header,
+            "{}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            NEWC_MAGIC,
+            inode,
+            mode,
+            0,
+            0,
+            nlink,
+            0,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0
+        )
+        .map_err(|_| Error::NoSpace)?;
+
+        let name_start = self.length + HEADER_SIZE;
+        self.buffer[name_start..name_end - 1].copy_from_slice(name.as_bytes());
+        for byte in self.buffer[name_end - 1..data_start].iter_mut() {
+            *byte = 0;
+        }
+
+        self.buffer[data_start..data_end].copy_from_slice(data);
+        for byte in self.buffer[data_end..entry_end].iter_mut() {
+            *byte = 0;
+        }
+
+        self.length = entry_end;
+
+        Ok(())
+    }
+
+    /// Add a directory, its parent has to be in the archive already.
+    pub fn add_directory(&mut self, name: &str, permissions: u32) -> Result<(), Error> {
+        self.add_entry(name, MODE_DIRECTORY | (permissions & 0o7777), &[])
+    }
+
+    /// Add a regular file, its parent has to be in the archive already.
+    pub fn add_file(&mut self, name: &str, data: &[u8], permissions: u32) -> Result<(), Error> {
+        self.add_entry(name, MODE_FILE | (permissions & 0o7777), data)
+    }
+
+    /// End the archive, returning the size of the buffer used.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        self.add_entry(TRAILER_NAME, 0, &[])?;
+
+        Ok(self.length)
+    }
+}
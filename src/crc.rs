@@ -0,0 +1,91 @@
+//! Checksum helpers.
+
+/// CRC-32 (IEEE 802.3) reflected polynomial.
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+const fn make_crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            if value & 1 != 0 {
+                value = (value >> 1) ^ CRC32_POLYNOMIAL;
+            } else {
+                value >>= 1;
+            }
+
+            bit += 1;
+        }
+
+        table[index] = value;
+        index += 1;
+    }
+
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+/// Continue a CRC-32 computation, `crc` being the result of a previous call (0 to start).
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+
+    for byte in data {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+/// Compute the CRC-32 (as used by zlib, GPT and friends) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// CRC-16 (CCITT polynomial) as used by XMODEM and YMODEM.
+const CRC16_POLYNOMIAL: u16 = 0x1021;
+
+/// Compute the CRC-16/XMODEM of `data`.
+pub fn crc16_xmodem(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ CRC16_POLYNOMIAL;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECK_INPUT: &[u8] = b"123456789";
+
+    #[test]
+    fn crc32_check() {
+        assert_eq!(crc32(CHECK_INPUT), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+
+        let (start, end) = CHECK_INPUT.split_at(4);
+        assert_eq!(crc32_update(crc32(start), end), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn crc16_xmodem_check() {
+        assert_eq!(crc16_xmodem(CHECK_INPUT), 0x31C3);
+        assert_eq!(crc16_xmodem(&[]), 0);
+    }
+}
@@ -0,0 +1,898 @@
+//! ELF64 AArch64 files: header validation, segment layout and `R_AARCH64_RELATIVE`
+//! relocations. The bootloader loads them with its `chainload` module.
+//!
+//! The dynamic section definitions are shared with the self relocation code of `rt`.
+//!
+//! Only `core` is used, the module can be built and tested on the host.
+
+use core::convert::TryInto;
+use core::mem;
+use core::ptr;
+
+use crate::page_table::{MemoryPermission, PAGE_SIZE};
+use crate::utils;
+
+pub const DT_NULL: isize = 0;
+pub const DT_RELA: isize = 7;
+pub const DT_RELASZ: isize = 8;
+pub const DT_RELAENT: isize = 9;
+pub const DT_RELACOUNT: isize = 0x6ffffff9;
+pub const DT_REL: isize = 17;
+pub const DT_RELSZ: isize = 18;
+pub const DT_RELENT: isize = 19;
+pub const DT_RELCOUNT: isize = 0x6ffffffa;
+
+pub const R_AARCH64_NONE: usize = 0;
+pub const R_AARCH64_RELATIVE: usize = 0x403;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ElfDyn {
+    pub tag: isize,
+    pub val: usize,
+}
+
+#[repr(C)]
+pub struct ElfRel {
+    pub offset: usize,
+    pub info: usize,
+}
+
+#[repr(C)]
+pub struct ElfRela {
+    pub offset: usize,
+    pub info: usize,
+    pub addend: isize,
+}
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u32 = 1;
+const EM_AARCH64: u16 = 183;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct ElfHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_entry_size: u16,
+    program_header_count: u16,
+    section_header_entry_size: u16,
+    section_header_count: u16,
+    section_names_index: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    /// Mapping permission matching `p_flags`, the MMU can't map write or execute only pages.
+    pub fn permission(&self) -> MemoryPermission {
+        let readable = self.flags & PF_R != 0;
+        let writable = self.flags & PF_W != 0;
+        let executable = self.flags & PF_X != 0;
+
+        match (readable || writable, writable, executable) {
+            (_, true, true) => MemoryPermission::RWX,
+            (_, true, false) => MemoryPermission::RW,
+            (_, false, true) => MemoryPermission::RX,
+            (true, false, false) => MemoryPermission::R,
+            (false, false, false) => MemoryPermission::Invalid,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The file is smaller than the headers it describes.
+    Truncated,
+    BadMagic,
+    /// Not a little endian ELF64 file for AArch64.
+    UnsupportedFormat,
+    /// Neither an executable nor a position independent executable.
+    UnsupportedType(u16),
+    InvalidProgramHeader,
+    /// Loadable segments aren't sorted by address or overlap.
+    UnsortedSegments,
+    NoLoadableSegment,
+    InvalidDynamicSection,
+    UnsupportedRelocation(u32),
+}
+
+/// A validated ELF file.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < mem::size_of::<ElfHeader>() {
+            return Err(Error::Truncated);
+        }
+
+        let header = unsafe { ptr::read_unaligned(data.as_ptr() as *const ElfHeader) };
+
+        if header.ident[..4] != ELF_MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        if header.ident[4] != ELFCLASS64
+            || header.ident[5] != ELFDATA2LSB
+            || header.version != EV_CURRENT
+            || header.machine != EM_AARCH64
+        {
+            return Err(Error::UnsupportedFormat);
+        }
+
+        if header.elf_type != ET_EXEC && header.elf_type != ET_DYN {
+            return Err(Error::UnsupportedType(header.elf_type));
+        }
+
+        if header.program_header_entry_size as usize != mem::size_of::<ProgramHeader>() {
+            return Err(Error::InvalidProgramHeader);
+        }
+
+        let program_headers_size =
+            header.program_header_count as u64 * mem::size_of::<ProgramHeader>() as u64;
+        let program_headers_end = header
+            .program_header_offset
+            .checked_add(program_headers_size)
+            .ok_or(Error::Truncated)?;
+        if program_headers_end > data.len() as u64 {
+            return Err(Error::Truncated);
+        }
+
+        let file = ElfFile { data, header };
+
+        for program_header in file.program_headers() {
+            let file_end = program_header
+                .offset
+                .checked_add(program_header.file_size)
+                .ok_or(Error::InvalidProgramHeader)?;
+
+            if file_end > data.len() as u64 {
+                return Err(Error::Truncated);
+            }
+        }
+
+        // Pages are mapped whole, the end of the last one can't overflow either.
+        let mut previous_end = 0;
+        for segment in file.load_segments() {
+            let end = segment
+                .vaddr
+                .checked_add(segment.memory_size)
+                .filter(|end| end.checked_add(PAGE_SIZE - 1).is_some());
+
+            match end {
+                Some(end) if segment.file_size <= segment.memory_size => {
+                    if segment.vaddr < previous_end {
+                        return Err(Error::UnsortedSegments);
+                    }
+
+                    previous_end = end;
+                }
+                _ => return Err(Error::InvalidProgramHeader),
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Whether the image can be loaded anywhere, applying its relocations.
+    pub fn is_position_independent(&self) -> bool {
+        self.header.elf_type == ET_DYN
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let offset = self.header.program_header_offset as usize;
+
+        (0..self.header.program_header_count as usize).map(move |index| {
+            let address = data[offset + index * mem::size_of::<ProgramHeader>()..].as_ptr();
+
+            unsafe { ptr::read_unaligned(address as *const ProgramHeader) }
+        })
+    }
+
+    /// `PT_LOAD` segments, sorted by address.
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers()
+            .filter(|program_header| program_header.segment_type == PT_LOAD)
+    }
+
+    /// Page aligned range of virtual addresses covered by the loadable segments.
+    pub fn get_load_range(&self) -> Result<(u64, u64), Error> {
+        let start = self
+            .load_segments()
+            .map(|segment| segment.vaddr)
+            .next()
+            .ok_or(Error::NoLoadableSegment)?;
+        let end = self
+            .load_segments()
+            .map(|segment| segment.vaddr + segment.memory_size)
+            .last()
+            .ok_or(Error::NoLoadableSegment)?;
+
+        Ok((
+            utils::align_down(start, PAGE_SIZE),
+            utils::align_up(end, PAGE_SIZE),
+        ))
+    }
+
+    /// Copy the loadable segments to `image`, the memory of the whole load range, and clear
+    /// what isn't in the file.
+    pub fn copy_segments(&self, image: &mut [u8]) -> Result<(), Error> {
+        let (link_start, link_end) = self.get_load_range()?;
+        let image = &mut image[..(link_end - link_start) as usize];
+
+        for segment in self.load_segments() {
+            let start = (segment.vaddr - link_start) as usize;
+            let file_end = start + segment.file_size as usize;
+            let end = start + segment.memory_size as usize;
+            let source = &self.data[segment.offset as usize..][..segment.file_size as usize];
+
+            image[start..file_end].copy_from_slice(source);
+            for byte in &mut image[file_end..end] {
+                *byte = 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the `R_AARCH64_RELATIVE` relocations of the copied `image`, which runs `bias`
+    /// bytes after its link address.
+    pub fn relocate(&self, image: &mut [u8], bias: u64) -> Result<(), Error> {
+        let dynamic = self
+            .program_headers()
+            .find(|program_header| program_header.segment_type == PT_DYNAMIC);
+
+        match dynamic {
+            Some(dynamic) => apply_relocations(image, &dynamic, bias, self.get_load_range()?),
+            None => Ok(()),
+        }
+    }
+
+    /// Call `callback` with the page aligned ranges of the loadable segments and their
+    /// permissions, at link addresses.
+    ///
+    /// A page shared by two segments gets both permissions.
+    pub fn for_each_mapping<E, F>(&self, mut callback: F) -> Result<(), E>
+    where
+        F: FnMut(u64, u64, MemoryPermission) -> Result<(), E>,
+    {
+        let mut previous_segment: Option<(u64, MemoryPermission)> = None;
+
+        for segment in self.load_segments() {
+            let permission = segment.permission();
+            let mut segment_start = utils::align_down(segment.vaddr, PAGE_SIZE);
+            let segment_end = utils::align_up(segment.vaddr + segment.memory_size, PAGE_SIZE);
+            let mut last_page_permission = permission;
+
+            if let Some((previous_end, previous_permission)) = previous_segment {
+                if segment_start < previous_end {
+                    last_page_permission = merge_permissions(permission, previous_permission);
+                    callback(segment_start, PAGE_SIZE, last_page_permission)?;
+                    segment_start += PAGE_SIZE;
+                }
+            }
+
+            if segment_start < segment_end {
+                callback(segment_start, segment_end - segment_start, permission)?;
+                last_page_permission = permission;
+            }
+
+            previous_segment = Some((segment_end, last_page_permission));
+        }
+
+        Ok(())
+    }
+}
+
+fn merge_permissions(first: MemoryPermission, second: MemoryPermission) -> MemoryPermission {
+    let writable = first.is_writable() || second.is_writable();
+    let executable = first.is_executable() || second.is_executable();
+
+    match (writable, executable) {
+        (true, true) => MemoryPermission::RWX,
+        (true, false) => MemoryPermission::RW,
+        (false, true) => MemoryPermission::RX,
+        (false, false) => MemoryPermission::R,
+    }
+}
+
+/// Whether `size` bytes at the link address `address` are part of the image.
+fn is_in_image(address: u64, size: u64, link_range: (u64, u64)) -> bool {
+    let (start, end) = link_range;
+
+    address >= start && address <= end && size <= end - address
+}
+
+/// Read the `index`th 64 bits word of a table at the link address `address`.
+fn read_word(image: &[u8], link_start: u64, address: u64, index: usize) -> u64 {
+    let offset = (address - link_start) as usize + index * 8;
+
+    u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_word(image: &mut [u8], link_start: u64, address: u64, value: u64) {
+    let offset = (address - link_start) as usize;
+
+    image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn apply_relocations(
+    image: &mut [u8],
+    dynamic: &ProgramHeader,
+    bias: u64,
+    link_range: (u64, u64),
+) -> Result<(), Error> {
+    let (link_start, link_end) = link_range;
+    if (image.len() as u64) < link_end - link_start
+        || !is_in_image(dynamic.vaddr, dynamic.memory_size, link_range)
+    {
+        return Err(Error::InvalidDynamicSection);
+    }
+
+    let mut rela = None;
+    let mut rela_size = 0;
+    let mut rela_entry_size = mem::size_of::<ElfRela>() as u64;
+    let mut rel = None;
+    let mut rel_size = 0;
+    let mut rel_entry_size = mem::size_of::<ElfRel>() as u64;
+
+    let count = dynamic.memory_size as usize / mem::size_of::<ElfDyn>();
+    for index in 0..count {
+        let tag = read_word(image, link_start, dynamic.vaddr, index * 2) as isize;
+        let value = read_word(image, link_start, dynamic.vaddr, index * 2 + 1);
+
+        match tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => rela_size = value,
+            DT_RELAENT => rela_entry_size = value,
+            DT_REL => rel = Some(value),
+            DT_RELSZ => rel_size = value,
+            DT_RELENT => rel_entry_size = value,
+            _ => {}
+        }
+    }
+
+    if let Some(rela) = rela {
+        if rela_entry_size != mem::size_of::<ElfRela>() as u64
+            || !is_in_image(rela, rela_size, link_range)
+        {
+            return Err(Error::InvalidDynamicSection);
+        }
+
+        for index in 0..(rela_size / rela_entry_size) as usize {
+            let offset = read_word(image, link_start, rela, index * 3);
+            let info = read_word(image, link_start, rela, index * 3 + 1);
+            let addend = read_word(image, link_start, rela, index * 3 + 2);
+            if !is_in_image(offset, 8, link_range) {
+                return Err(Error::InvalidDynamicSection);
+            }
+
+            match info as usize & 0xffff_ffff {
+                R_AARCH64_NONE => {}
+                R_AARCH64_RELATIVE => {
+                    write_word(image, link_start, offset, bias.wrapping_add(addend))
+                }
+                relocation_type => {
+                    return Err(Error::UnsupportedRelocation(relocation_type as u32))
+                }
+            }
+        }
+    }
+
+    if let Some(rel) = rel {
+        if rel_entry_size != mem::size_of::<ElfRel>() as u64
+            || !is_in_image(rel, rel_size, link_range)
+        {
+            return Err(Error::InvalidDynamicSection);
+        }
+
+        for index in 0..(rel_size / rel_entry_size) as usize {
+            let offset = read_word(image, link_start, rel, index * 2);
+            let info = read_word(image, link_start, rel, index * 2 + 1);
+            if !is_in_image(offset, 8, link_range) {
+                return Err(Error::InvalidDynamicSection);
+            }
+
+            match info as usize & 0xffff_ffff {
+                R_AARCH64_NONE => {}
+                R_AARCH64_RELATIVE => {
+                    let value = read_word(image, link_start, offset, 0).wrapping_add(bias);
+                    write_word(image, link_start, offset, value);
+                }
+                relocation_type => {
+                    return Err(Error::UnsupportedRelocation(relocation_type as u32))
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_SIZE: usize = 64;
+    const PROGRAM_HEADER_SIZE: usize = 56;
+
+    struct Segment<'a> {
+        segment_type: u32,
+        flags: u32,
+        vaddr: u64,
+        data: &'a [u8],
+        memory_size: u64,
+    }
+
+    fn load(flags: u32, vaddr: u64, data: &[u8], memory_size: u64) -> Segment {
+        Segment {
+            segment_type: PT_LOAD,
+            flags,
+            vaddr,
+            data,
+            memory_size,
+        }
+    }
+
+    fn elf_file(elf_type: u16, entry: u64, segments: &[Segment]) -> Vec<u8> {
+        let mut file = vec![0; HEADER_SIZE];
+        file[..4].copy_from_slice(&ELF_MAGIC);
+        file[4] = ELFCLASS64;
+        file[5] = ELFDATA2LSB;
+        file[6] = EV_CURRENT as u8;
+        file[16..18].copy_from_slice(&elf_type.to_le_bytes());
+        file[18..20].copy_from_slice(&EM_AARCH64.to_le_bytes());
+        file[20..24].copy_from_slice(&EV_CURRENT.to_le_bytes());
+        file[24..32].copy_from_slice(&entry.to_le_bytes());
+        file[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+        file[52..54].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        file[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        file[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let mut offset = HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE;
+        for segment in segments {
+            let fields = [
+                offset as u64,
+                segment.vaddr,
+                segment.vaddr,
+                segment.data.len() as u64,
+                segment.memory_size,
+                PAGE_SIZE,
+            ];
+
+            file.extend_from_slice(&segment.segment_type.to_le_bytes());
+            file.extend_from_slice(&segment.flags.to_le_bytes());
+            for field in fields.iter() {
+                file.extend_from_slice(&field.to_le_bytes());
+            }
+
+            offset += segment.data.len();
+        }
+
+        for segment in segments {
+            file.extend_from_slice(segment.data);
+        }
+
+        file
+    }
+
+    /// Offset of a field of the `index`th program header.
+    fn program_header_field(index: usize, field: usize) -> usize {
+        HEADER_SIZE + index * PROGRAM_HEADER_SIZE + field
+    }
+
+    #[test]
+    fn parse_errors() {
+        let text = [0xAA; 0x10];
+        let data = [0xBB; 0x20];
+        let segments = [
+            load(PF_R | PF_X, 0x1000, &text, 0x10),
+            load(PF_R | PF_W, 0x2000, &data, 0x100),
+        ];
+        let file = elf_file(ET_EXEC, 0x1000, &segments);
+        let parse = |file: &[u8]| ElfFile::parse(file).err();
+        let patched = |offset: usize, bytes: &[u8]| {
+            let mut file = file.clone();
+            file[offset..offset + bytes.len()].copy_from_slice(bytes);
+            parse(&file)
+        };
+
+        assert_eq!(parse(&file), None);
+        assert_eq!(parse(&file[..HEADER_SIZE - 1]), Some(Error::Truncated));
+        assert_eq!(patched(0, b"\x7fELG"), Some(Error::BadMagic));
+        assert_eq!(patched(4, &[1]), Some(Error::UnsupportedFormat));
+        assert_eq!(patched(5, &[2]), Some(Error::UnsupportedFormat));
+        assert_eq!(
+            patched(18, &62u16.to_le_bytes()),
+            Some(Error::UnsupportedFormat)
+        );
+        assert_eq!(
+            patched(20, &2u32.to_le_bytes()),
+            Some(Error::UnsupportedFormat)
+        );
+        assert_eq!(
+            patched(16, &1u16.to_le_bytes()),
+            Some(Error::UnsupportedType(1))
+        );
+        assert_eq!(
+            patched(54, &32u16.to_le_bytes()),
+            Some(Error::InvalidProgramHeader)
+        );
+
+        // Program headers or segment data past the end of the file.
+        assert_eq!(patched(56, &100u16.to_le_bytes()), Some(Error::Truncated));
+        assert_eq!(parse(&file[..file.len() - 1]), Some(Error::Truncated));
+        assert_eq!(
+            patched(program_header_field(1, 8), &u64::MAX.to_le_bytes()),
+            Some(Error::InvalidProgramHeader)
+        );
+
+        // More file data than memory.
+        assert_eq!(
+            patched(program_header_field(1, 40), &0x1Fu64.to_le_bytes()),
+            Some(Error::InvalidProgramHeader)
+        );
+
+        // The last page of a segment would end past the address space.
+        let address = u64::MAX - PAGE_SIZE;
+        assert_eq!(
+            patched(program_header_field(1, 16), &address.to_le_bytes()),
+            Some(Error::InvalidProgramHeader)
+        );
+        assert_eq!(
+            patched(
+                program_header_field(1, 16),
+                &(address - 0x100).to_le_bytes()
+            ),
+            None
+        );
+
+        let segments = [
+            load(PF_R | PF_W, 0x2000, &data, 0x100),
+            load(PF_R | PF_X, 0x1000, &text, 0x10),
+        ];
+        let file = elf_file(ET_EXEC, 0x1000, &segments);
+        assert_eq!(parse(&file), Some(Error::UnsortedSegments));
+
+        let segments = [
+            load(PF_R | PF_X, 0x1000, &text, 0x1001),
+            load(PF_R | PF_W, 0x2000, &data, 0x100),
+        ];
+        let file = elf_file(ET_EXEC, 0x1000, &segments);
+        assert_eq!(parse(&file), Some(Error::UnsortedSegments));
+
+        // Segments sharing a page are fine.
+        let segments = [
+            load(PF_R | PF_X, 0x1000, &text, 0x10),
+            load(PF_R | PF_W, 0x1010, &data, 0x100),
+        ];
+        let file = elf_file(ET_EXEC, 0x1000, &segments);
+        assert_eq!(parse(&file), None);
+
+        let file = elf_file(ET_EXEC, 0x1000, &[]);
+        let file = ElfFile::parse(&file).unwrap();
+        assert_eq!(file.get_load_range(), Err(Error::NoLoadableSegment));
+    }
+
+    #[test]
+    fn copy_segments() {
+        let text = [0xAA; 0x10];
+        let data = [0xBB; 0x20];
+        let segments = [
+            load(PF_R | PF_X, 0x1_0010, &text, 0x10),
+            Segment {
+                segment_type: PT_DYNAMIC,
+                flags: PF_R,
+                vaddr: 0x1_0000,
+                data: &[],
+                memory_size: 0,
+            },
+            load(PF_R | PF_W, 0x1_2000, &data, 0x1100),
+        ];
+        let file = elf_file(ET_EXEC, 0x1_0010, &segments);
+        let file = ElfFile::parse(&file).unwrap();
+
+        assert!(!file.is_position_independent());
+        assert_eq!(file.entry(), 0x1_0010);
+        assert_eq!(file.load_segments().count(), 2);
+        let end = utils::align_up(0x1_3100, PAGE_SIZE);
+        assert_eq!(file.get_load_range(), Ok((0x1_0000, end)));
+
+        let mut image = vec![0xFF; (end - 0x1_0000) as usize];
+        file.copy_segments(&mut image).unwrap();
+
+        let mut expected = vec![0xFF; image.len()];
+        expected[0x10..0x20].copy_from_slice(&text);
+        expected[0x2000..0x2020].copy_from_slice(&data);
+        for byte in &mut expected[0x2020..0x3100] {
+            *byte = 0;
+        }
+        assert_eq!(image, expected);
+    }
+
+    const DYNAMIC_ADDRESS: u64 = 0x1000;
+    const RELA_ADDRESS: u64 = 0x1100;
+    const REL_ADDRESS: u64 = 0x1200;
+    const TARGETS_ADDRESS: u64 = 0x1300;
+    const R_AARCH64_ABS64: u64 = 257;
+    /// Size of the load range of `relocatable_file`, up to the end of the page of its data.
+    const IMAGE_SIZE: u64 = (DYNAMIC_ADDRESS + 0x400 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    /// A position independent file linked at 0, with its dynamic section and relocation
+    /// tables in a data segment at `DYNAMIC_ADDRESS`.
+    fn relocatable_file(dynamic: &[(isize, u64)], rela: &[u64], rel: &[u64]) -> Vec<u8> {
+        let mut data = vec![0; 0x320];
+        let mut write_words = |address: u64, words: &[u64]| {
+            for (index, word) in words.iter().enumerate() {
+                let offset = (address - DYNAMIC_ADDRESS) as usize + index * 8;
+                data[offset..offset + 8].copy_from_slice(&word.to_le_bytes());
+            }
+        };
+
+        let dynamic: Vec<u64> = dynamic
+            .iter()
+            .flat_map(|(tag, value)| vec![*tag as u64, *value])
+            .collect();
+        write_words(DYNAMIC_ADDRESS, &dynamic);
+        write_words(RELA_ADDRESS, rela);
+        write_words(REL_ADDRESS, rel);
+        write_words(TARGETS_ADDRESS, &[0, 0, 0x80, 0x90]);
+
+        let text = [0xAA; 0x100];
+        let segments = [
+            load(PF_R | PF_X, 0, &text, 0x100),
+            Segment {
+                segment_type: PT_DYNAMIC,
+                flags: PF_R | PF_W,
+                vaddr: DYNAMIC_ADDRESS,
+                data: &[],
+                memory_size: dynamic.len() as u64 * 8,
+            },
+            load(PF_R | PF_W, DYNAMIC_ADDRESS, &data, 0x400),
+        ];
+
+        elf_file(ET_DYN, 0x10, &segments)
+    }
+
+    fn relocate(file: &[u8], bias: u64) -> Result<Vec<u64>, Error> {
+        let file = ElfFile::parse(file).unwrap();
+        let mut image = vec![0; IMAGE_SIZE as usize];
+        file.copy_segments(&mut image).unwrap();
+        file.relocate(&mut image, bias)?;
+
+        let targets = image[TARGETS_ADDRESS as usize..][..32]
+            .chunks(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        Ok(targets)
+    }
+
+    fn default_dynamic(rela_size: u64, rel_size: u64) -> [(isize, u64); 7] {
+        [
+            (DT_RELA, RELA_ADDRESS),
+            (DT_RELASZ, rela_size),
+            (DT_RELAENT, 24),
+            (DT_REL, REL_ADDRESS),
+            (DT_RELSZ, rel_size),
+            (DT_RELENT, 16),
+            (DT_NULL, 0),
+        ]
+    }
+
+    #[test]
+    fn relocations() {
+        let rela = [
+            TARGETS_ADDRESS,
+            R_AARCH64_RELATIVE as u64,
+            0x40,
+            TARGETS_ADDRESS + 8,
+            R_AARCH64_NONE as u64,
+            0x50,
+        ];
+        // The symbol index in the upper bits of `info` is ignored.
+        let rel = [
+            TARGETS_ADDRESS + 16,
+            R_AARCH64_RELATIVE as u64,
+            TARGETS_ADDRESS + 24,
+            7 << 32 | R_AARCH64_RELATIVE as u64,
+        ];
+        let file = relocatable_file(&default_dynamic(48, 32), &rela, &rel);
+
+        assert!(ElfFile::parse(&file).unwrap().is_position_independent());
+        assert_eq!(
+            relocate(&file, 0x8000_0000),
+            Ok(vec![0x8000_0040, 0, 0x8000_0080, 0x8000_0090])
+        );
+
+        // Loaded below the link address.
+        assert_eq!(
+            relocate(&file, 0u64.wrapping_sub(0x40)),
+            Ok(vec![0, 0, 0x40, 0x50])
+        );
+
+        // Entries after DT_NULL are ignored.
+        let mut dynamic = default_dynamic(48, 32);
+        dynamic[3] = (DT_NULL, 0);
+        assert_eq!(
+            relocate(&relocatable_file(&dynamic, &rela, &rel), 0x1000),
+            Ok(vec![0x1040, 0, 0x80, 0x90])
+        );
+
+        let file = ElfFile::parse(&file).unwrap();
+        let mut image = vec![0; 0x1000];
+        assert_eq!(
+            file.relocate(&mut image, 0x1000),
+            Err(Error::InvalidDynamicSection)
+        );
+    }
+
+    #[test]
+    fn bad_relocations() {
+        let relative = R_AARCH64_RELATIVE as u64;
+        let dynamic = default_dynamic(24, 16);
+        let relocate_with = |dynamic: &[(isize, u64)], rela: &[u64], rel: &[u64]| {
+            relocate(&relocatable_file(dynamic, rela, rel), 0x1000).err()
+        };
+        let valid_rela = [TARGETS_ADDRESS, relative, 0];
+        let valid_rel = [TARGETS_ADDRESS, relative];
+
+        assert_eq!(relocate_with(&dynamic, &valid_rela, &valid_rel), None);
+        assert_eq!(
+            relocate_with(&dynamic, &[TARGETS_ADDRESS, R_AARCH64_ABS64, 0], &valid_rel),
+            Some(Error::UnsupportedRelocation(R_AARCH64_ABS64 as u32))
+        );
+        assert_eq!(
+            relocate_with(&dynamic, &valid_rela, &[TARGETS_ADDRESS, R_AARCH64_ABS64]),
+            Some(Error::UnsupportedRelocation(R_AARCH64_ABS64 as u32))
+        );
+
+        // Relocated words past the end of the image.
+        assert_eq!(
+            relocate_with(&dynamic, &[IMAGE_SIZE - 7, relative, 0], &valid_rel),
+            Some(Error::InvalidDynamicSection)
+        );
+        assert_eq!(
+            relocate_with(&dynamic, &valid_rela, &[IMAGE_SIZE, relative]),
+            Some(Error::InvalidDynamicSection)
+        );
+        assert_eq!(
+            relocate_with(&dynamic, &[IMAGE_SIZE - 8, relative, 0], &valid_rel),
+            None
+        );
+
+        // Tables past the end of the image or with unexpected entry sizes.
+        let mut bad_dynamic = dynamic;
+        bad_dynamic[0].1 = IMAGE_SIZE - 0x10;
+        assert_eq!(
+            relocate_with(&bad_dynamic, &valid_rela, &valid_rel),
+            Some(Error::InvalidDynamicSection)
+        );
+        let mut bad_dynamic = dynamic;
+        bad_dynamic[4].1 = IMAGE_SIZE - REL_ADDRESS + 16;
+        assert_eq!(
+            relocate_with(&bad_dynamic, &valid_rela, &valid_rel),
+            Some(Error::InvalidDynamicSection)
+        );
+        let mut bad_dynamic = dynamic;
+        bad_dynamic[2].1 = 16;
+        assert_eq!(
+            relocate_with(&bad_dynamic, &valid_rela, &valid_rel),
+            Some(Error::InvalidDynamicSection)
+        );
+        let mut bad_dynamic = dynamic;
+        bad_dynamic[5].1 = 24;
+        assert_eq!(
+            relocate_with(&bad_dynamic, &valid_rela, &valid_rel),
+            Some(Error::InvalidDynamicSection)
+        );
+
+        // The dynamic section itself is outside the image.
+        let mut file = relocatable_file(&dynamic, &valid_rela, &valid_rel);
+        let offset = program_header_field(1, 16);
+        file[offset..offset + 8].copy_from_slice(&IMAGE_SIZE.to_le_bytes());
+        assert_eq!(relocate(&file, 0x1000), Err(Error::InvalidDynamicSection));
+    }
+
+    #[test]
+    fn mappings() {
+        // In pages, so the segments share pages the same way with every granule.
+        let page = PAGE_SIZE;
+        let segments = [
+            load(PF_R | PF_X, page, &[], page * 3 / 2),
+            load(PF_R, page * 5 / 2, &[], page / 16),
+            load(PF_R | PF_W, page * 5 / 2 + page / 16, &[], 2 * page),
+            load(PF_R | PF_W, 8 * page, &[], page),
+        ];
+        let file = elf_file(ET_EXEC, page, &segments);
+        let file = ElfFile::parse(&file).unwrap();
+
+        let mut mappings = Vec::new();
+        let result: Result<(), ()> = file.for_each_mapping(|address, size, permission| {
+            mappings.push((address, size, permission));
+            Ok(())
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            mappings,
+            [
+                (page, 2 * page, MemoryPermission::RX),
+                (2 * page, page, MemoryPermission::RX),
+                (2 * page, page, MemoryPermission::RWX),
+                (3 * page, 2 * page, MemoryPermission::RW),
+                (8 * page, page, MemoryPermission::RW),
+            ]
+        );
+
+        // Errors stop the iteration.
+        let mut count = 0;
+        let result = file.for_each_mapping(|_, _, _| {
+            count += 1;
+            Err(count)
+        });
+        assert_eq!(result, Err(1));
+    }
+
+    #[test]
+    fn permissions() {
+        let permission = |flags| {
+            let segments = [load(flags, 0x1000, &[], 0x10)];
+            let file = elf_file(ET_EXEC, 0x1000, &segments);
+            let file = ElfFile::parse(&file).unwrap();
+            let segment = file.load_segments().next().unwrap();
+
+            segment.permission()
+        };
+
+        assert_eq!(permission(0), MemoryPermission::Invalid);
+        assert_eq!(permission(PF_R), MemoryPermission::R);
+        assert_eq!(permission(PF_W), MemoryPermission::RW);
+        assert_eq!(permission(PF_X), MemoryPermission::RX);
+        assert_eq!(permission(PF_R | PF_W), MemoryPermission::RW);
+        assert_eq!(permission(PF_R | PF_X), MemoryPermission::RX);
+        assert_eq!(permission(PF_W | PF_X), MemoryPermission::RWX);
+    }
+}
@@ -0,0 +1,715 @@
+//! ESR_ELx (Exception Syndrome Register) decoding.
+
+use core::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExceptionClass {
+    Unknown,
+    WfiWfe,
+    Cp15McrMrc,
+    Cp15McrrMrrc,
+    Cp14McrMrc,
+    Cp14LdcStc,
+    SimdFpAccess,
+    Cp10Mrc,
+    PointerAuthTrap,
+    Cp14Mrrc,
+    BranchTarget,
+    IllegalExecutionState,
+    Svc32,
+    Hvc32,
+    Smc32,
+    Svc64,
+    Hvc64,
+    Smc64,
+    MsrMrsTrap,
+    SveAccess,
+    EretTrap,
+    PointerAuthFailure,
+    ImplementationDefinedEl3,
+    InstructionAbortLowerEl,
+    InstructionAbortCurrentEl,
+    PcAlignment,
+    DataAbortLowerEl,
+    DataAbortCurrentEl,
+    SpAlignment,
+    FpException32,
+    FpException64,
+    SError,
+    BreakpointLowerEl,
+    BreakpointCurrentEl,
+    SoftwareStepLowerEl,
+    SoftwareStepCurrentEl,
+    WatchpointLowerEl,
+    WatchpointCurrentEl,
+    Bkpt32,
+    VectorCatch32,
+    Brk64,
+    Reserved(u8),
+}
+
+impl ExceptionClass {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => ExceptionClass::Unknown,
+            0x01 => ExceptionClass::WfiWfe,
+            0x03 => ExceptionClass::Cp15McrMrc,
+            0x04 => ExceptionClass::Cp15McrrMrrc,
+            0x05 => ExceptionClass::Cp14McrMrc,
+            0x06 => ExceptionClass::Cp14LdcStc,
+            0x07 => ExceptionClass::SimdFpAccess,
+            0x08 => ExceptionClass::Cp10Mrc,
+            0x09 => ExceptionClass::PointerAuthTrap,
+            0x0c => ExceptionClass::Cp14Mrrc,
+            0x0d => ExceptionClass::BranchTarget,
+            0x0e => ExceptionClass::IllegalExecutionState,
+            0x11 => ExceptionClass::Svc32,
+            0x12 => ExceptionClass::Hvc32,
+            0x13 => ExceptionClass::Smc32,
+            0x15 => ExceptionClass::Svc64,
+            0x16 => ExceptionClass::Hvc64,
+            0x17 => ExceptionClass::Smc64,
+            0x18 => ExceptionClass::MsrMrsTrap,
+            0x19 => ExceptionClass::SveAccess,
+            0x1a => ExceptionClass::EretTrap,
+            0x1c => ExceptionClass::PointerAuthFailure,
+            0x1f => ExceptionClass::ImplementationDefinedEl3,
+            0x20 => ExceptionClass::InstructionAbortLowerEl,
+            0x21 => ExceptionClass::InstructionAbortCurrentEl,
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 => ExceptionClass::DataAbortLowerEl,
+            0x25 => ExceptionClass::DataAbortCurrentEl,
+            0x26 => ExceptionClass::SpAlignment,
+            0x28 => ExceptionClass::FpException32,
+            0x2c => ExceptionClass::FpException64,
+            0x2f => ExceptionClass::SError,
+            0x30 => ExceptionClass::BreakpointLowerEl,
+            0x31 => ExceptionClass::BreakpointCurrentEl,
+            0x32 => ExceptionClass::SoftwareStepLowerEl,
+            0x33 => ExceptionClass::SoftwareStepCurrentEl,
+            0x34 => ExceptionClass::WatchpointLowerEl,
+            0x35 => ExceptionClass::WatchpointCurrentEl,
+            0x38 => ExceptionClass::Bkpt32,
+            0x3a => ExceptionClass::VectorCatch32,
+            0x3c => ExceptionClass::Brk64,
+            code => ExceptionClass::Reserved(code),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            ExceptionClass::Unknown => 0x00,
+            ExceptionClass::WfiWfe => 0x01,
+            ExceptionClass::Cp15McrMrc => 0x03,
+            ExceptionClass::Cp15McrrMrrc => 0x04,
+            ExceptionClass::Cp14McrMrc => 0x05,
+            ExceptionClass::Cp14LdcStc => 0x06,
+            ExceptionClass::SimdFpAccess => 0x07,
+            ExceptionClass::Cp10Mrc => 0x08,
+            ExceptionClass::PointerAuthTrap => 0x09,
+            ExceptionClass::Cp14Mrrc => 0x0c,
+            ExceptionClass::BranchTarget => 0x0d,
+            ExceptionClass::IllegalExecutionState => 0x0e,
+            ExceptionClass::Svc32 => 0x11,
+            ExceptionClass::Hvc32 => 0x12,
+            ExceptionClass::Smc32 => 0x13,
+            ExceptionClass::Svc64 => 0x15,
+            ExceptionClass::Hvc64 => 0x16,
+            ExceptionClass::Smc64 => 0x17,
+            ExceptionClass::MsrMrsTrap => 0x18,
+            ExceptionClass::SveAccess => 0x19,
+            ExceptionClass::EretTrap => 0x1a,
+            ExceptionClass::PointerAuthFailure => 0x1c,
+            ExceptionClass::ImplementationDefinedEl3 => 0x1f,
+            ExceptionClass::InstructionAbortLowerEl => 0x20,
+            ExceptionClass::InstructionAbortCurrentEl => 0x21,
+            ExceptionClass::PcAlignment => 0x22,
+            ExceptionClass::DataAbortLowerEl => 0x24,
+            ExceptionClass::DataAbortCurrentEl => 0x25,
+            ExceptionClass::SpAlignment => 0x26,
+            ExceptionClass::FpException32 => 0x28,
+            ExceptionClass::FpException64 => 0x2c,
+            ExceptionClass::SError => 0x2f,
+            ExceptionClass::BreakpointLowerEl => 0x30,
+            ExceptionClass::BreakpointCurrentEl => 0x31,
+            ExceptionClass::SoftwareStepLowerEl => 0x32,
+            ExceptionClass::SoftwareStepCurrentEl => 0x33,
+            ExceptionClass::WatchpointLowerEl => 0x34,
+            ExceptionClass::WatchpointCurrentEl => 0x35,
+            ExceptionClass::Bkpt32 => 0x38,
+            ExceptionClass::VectorCatch32 => 0x3a,
+            ExceptionClass::Brk64 => 0x3c,
+            ExceptionClass::Reserved(code) => code,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExceptionClass::Unknown => "Unknown reason",
+            ExceptionClass::WfiWfe => "Trapped WFI or WFE instruction",
+            ExceptionClass::Cp15McrMrc => "Trapped MCR or MRC access (coproc 0b1111)",
+            ExceptionClass::Cp15McrrMrrc => "Trapped MCRR or MRRC access (coproc 0b1111)",
+            ExceptionClass::Cp14McrMrc => "Trapped MCR or MRC access (coproc 0b1110)",
+            ExceptionClass::Cp14LdcStc => "Trapped LDC or STC access",
+            ExceptionClass::SimdFpAccess => "Trapped SVE, Advanced SIMD or floating-point access",
+            ExceptionClass::Cp10Mrc => "Trapped VMRS access",
+            ExceptionClass::PointerAuthTrap => "Trapped pointer authentication instruction",
+            ExceptionClass::Cp14Mrrc => "Trapped MRRC access (coproc 0b1110)",
+            ExceptionClass::BranchTarget => "Branch target exception",
+            ExceptionClass::IllegalExecutionState => "Illegal execution state",
+            ExceptionClass::Svc32 => "SVC instruction (AArch32)",
+            ExceptionClass::Hvc32 => "HVC instruction (AArch32)",
+            ExceptionClass::Smc32 => "SMC instruction (AArch32)",
+            ExceptionClass::Svc64 => "SVC instruction (AArch64)",
+            ExceptionClass::Hvc64 => "HVC instruction (AArch64)",
+            ExceptionClass::Smc64 => "SMC instruction (AArch64)",
+            ExceptionClass::MsrMrsTrap => "Trapped MSR, MRS or system instruction",
+            ExceptionClass::SveAccess => "Trapped SVE access",
+            ExceptionClass::EretTrap => "Trapped ERET, ERETAA or ERETAB instruction",
+            ExceptionClass::PointerAuthFailure => "Pointer authentication failure",
+            ExceptionClass::ImplementationDefinedEl3 => "Implementation defined exception to EL3",
+            ExceptionClass::InstructionAbortLowerEl => "Instruction abort from a lower EL",
+            ExceptionClass::InstructionAbortCurrentEl => "Instruction abort",
+            ExceptionClass::PcAlignment => "PC alignment exception",
+            ExceptionClass::DataAbortLowerEl => "Data abort from a lower EL",
+            ExceptionClass::DataAbortCurrentEl => "Data abort",
+            ExceptionClass::SpAlignment => "Stack alignment exception",
+            ExceptionClass::FpException32 => "Floating-point exception (AArch32)",
+            ExceptionClass::FpException64 => "Floating-point exception (AArch64)",
+            ExceptionClass::SError => "SError interrupt",
+            ExceptionClass::BreakpointLowerEl => "Breakpoint from a lower EL",
+            ExceptionClass::BreakpointCurrentEl => "Breakpoint",
+            ExceptionClass::SoftwareStepLowerEl => "Software step from a lower EL",
+            ExceptionClass::SoftwareStepCurrentEl => "Software step",
+            ExceptionClass::WatchpointLowerEl => "Watchpoint from a lower EL",
+            ExceptionClass::WatchpointCurrentEl => "Watchpoint",
+            ExceptionClass::Bkpt32 => "BKPT instruction (AArch32)",
+            ExceptionClass::VectorCatch32 => "Vector catch (AArch32)",
+            ExceptionClass::Brk64 => "BRK instruction (AArch64)",
+            ExceptionClass::Reserved(_) => "Reserved exception class",
+        }
+    }
+}
+
+/// Name of a 6-bit DFSC/IFSC fault status code.
+pub fn get_fault_status_name(fault_status: u8) -> &'static str {
+    match fault_status & 0x3f {
+        0b000000 => "Address size fault, level 0",
+        0b000001 => "Address size fault, level 1",
+        0b000010 => "Address size fault, level 2",
+        0b000011 => "Address size fault, level 3",
+        0b000100 => "Translation fault, level 0",
+        0b000101 => "Translation fault, level 1",
+        0b000110 => "Translation fault, level 2",
+        0b000111 => "Translation fault, level 3",
+        0b001001 => "Access flag fault, level 1",
+        0b001010 => "Access flag fault, level 2",
+        0b001011 => "Access flag fault, level 3",
+        0b001101 => "Permission fault, level 1",
+        0b001110 => "Permission fault, level 2",
+        0b001111 => "Permission fault, level 3",
+        0b010000 => "Synchronous external abort",
+        0b010001 => "Synchronous tag check fault",
+        0b010100 => "Synchronous external abort on translation table walk, level 0",
+        0b010101 => "Synchronous external abort on translation table walk, level 1",
+        0b010110 => "Synchronous external abort on translation table walk, level 2",
+        0b010111 => "Synchronous external abort on translation table walk, level 3",
+        0b011000 => "Synchronous parity or ECC error on memory access",
+        0b011100 => "Synchronous parity or ECC error on translation table walk, level 0",
+        0b011101 => "Synchronous parity or ECC error on translation table walk, level 1",
+        0b011110 => "Synchronous parity or ECC error on translation table walk, level 2",
+        0b011111 => "Synchronous parity or ECC error on translation table walk, level 3",
+        0b100001 => "Alignment fault",
+        0b100010 => "Debug exception",
+        0b110000 => "TLB conflict abort",
+        0b110001 => "Unsupported atomic hardware update fault",
+        0b110100 => "Implementation defined fault (Lockdown)",
+        0b110101 => "Implementation defined fault (Unsupported exclusive or atomic access)",
+        _ => "Unknown fault",
+    }
+}
+
+/// Name of the 6-bit DFSC of an SError interrupt.
+pub fn get_serror_fault_status_name(fault_status: u8) -> &'static str {
+    match fault_status & 0x3f {
+        0b000000 => "Uncategorized error",
+        0b010001 => "Asynchronous SError interrupt",
+        _ => "Reserved fault status",
+    }
+}
+
+/// Name of the AET (asynchronous error type) of an SError interrupt.
+pub fn get_serror_error_type_name(error_type: u8) -> &'static str {
+    match error_type & 0b111 {
+        0b000 => "Uncontainable (UC)",
+        0b001 => "Unrecoverable (UEU)",
+        0b010 => "Restartable (UEO)",
+        0b011 => "Recoverable (UER)",
+        0b110 => "Corrected (CE)",
+        _ => "Reserved error type",
+    }
+}
+
+/// ISS of instruction and data aborts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AbortSyndrome {
+    /// DFSC or IFSC.
+    pub fault_status: u8,
+    /// The abort was caused by a write (data aborts only).
+    pub write_not_read: bool,
+    /// The abort happened during a stage 2 walk for a stage 1 walk.
+    pub s1ptw: bool,
+    /// The abort came from a cache maintenance instruction (data aborts only).
+    pub cache_maintenance: bool,
+    /// External abort type is implementation defined.
+    pub external_abort: bool,
+    /// FAR is not valid.
+    pub far_not_valid: bool,
+    /// SAS, SSE, SRT, SF and AR are valid (data aborts only).
+    pub isv: bool,
+    /// Access size (0: byte, 1: halfword, 2: word, 3: doubleword).
+    pub sas: u8,
+    /// The access was sign extended.
+    pub sse: bool,
+    /// Register number of the load/store.
+    pub srt: u8,
+    /// The load/store was using a 64-bit register.
+    pub sixty_four: bool,
+    /// The access had acquire/release semantics.
+    pub acquire_release: bool,
+}
+
+impl AbortSyndrome {
+    pub fn from_iss(iss: u32, is_data_abort: bool) -> Self {
+        let bit = |index: u32| (iss >> index) & 1 != 0;
+        let isv = is_data_abort && bit(24);
+
+        AbortSyndrome {
+            fault_status: (iss & 0x3f) as u8,
+            write_not_read: is_data_abort && bit(6),
+            s1ptw: bit(7),
+            cache_maintenance: is_data_abort && bit(8),
+            external_abort: bit(9),
+            far_not_valid: bit(10),
+            isv,
+            sas: if isv { ((iss >> 22) & 0b11) as u8 } else { 0 },
+            sse: isv && bit(21),
+            srt: if isv { ((iss >> 16) & 0x1f) as u8 } else { 0 },
+            sixty_four: isv && bit(15),
+            acquire_release: isv && bit(14),
+        }
+    }
+
+    /// Access size in bytes, when known.
+    pub fn access_size(&self) -> Option<usize> {
+        if self.isv {
+            Some(1 << self.sas)
+        } else {
+            None
+        }
+    }
+}
+
+/// ISS of trapped MSR, MRS and system instructions.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SystemRegisterSyndrome {
+    pub op0: u8,
+    pub op1: u8,
+    pub op2: u8,
+    pub crn: u8,
+    pub crm: u8,
+    pub rt: u8,
+    /// MRS (read) when set, MSR (write) otherwise.
+    pub is_read: bool,
+}
+
+impl SystemRegisterSyndrome {
+    pub fn from_iss(iss: u32) -> Self {
+        SystemRegisterSyndrome {
+            op0: ((iss >> 20) & 0b11) as u8,
+            op2: ((iss >> 17) & 0b111) as u8,
+            op1: ((iss >> 14) & 0b111) as u8,
+            crn: ((iss >> 10) & 0xf) as u8,
+            rt: ((iss >> 5) & 0x1f) as u8,
+            crm: ((iss >> 1) & 0xf) as u8,
+            is_read: iss & 1 != 0,
+        }
+    }
+}
+
+/// ISS of trapped floating-point exceptions.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FpSyndrome {
+    /// The flags below are valid.
+    pub trapped_fault_valid: bool,
+    pub input_denormal: bool,
+    pub inexact: bool,
+    pub underflow: bool,
+    pub overflow: bool,
+    pub divide_by_zero: bool,
+    pub invalid_operation: bool,
+}
+
+impl FpSyndrome {
+    pub fn from_iss(iss: u32) -> Self {
+        let bit = |index: u32| (iss >> index) & 1 != 0;
+
+        FpSyndrome {
+            trapped_fault_valid: bit(23),
+            input_denormal: bit(7),
+            inexact: bit(4),
+            underflow: bit(3),
+            overflow: bit(2),
+            divide_by_zero: bit(1),
+            invalid_operation: bit(0),
+        }
+    }
+}
+
+/// ISS of SError interrupts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SErrorSyndrome {
+    /// The syndrome is implementation defined, the other fields are meaningless.
+    pub implementation_defined: bool,
+    /// The error was synchronized by an implicit error synchronization event.
+    pub iesb: bool,
+    /// Asynchronous error type.
+    pub aet: u8,
+    pub external_abort: bool,
+    /// DFSC, with the SError encoding (see [`get_serror_fault_status_name`]).
+    pub fault_status: u8,
+}
+
+/// DFSC of SError interrupts with an architected syndrome.
+const SERROR_ASYNCHRONOUS: u8 = 0b010001;
+
+impl SErrorSyndrome {
+    pub fn from_iss(iss: u32) -> Self {
+        SErrorSyndrome {
+            implementation_defined: (iss >> 24) & 1 != 0,
+            iesb: (iss >> 13) & 1 != 0,
+            aet: ((iss >> 10) & 0b111) as u8,
+            external_abort: (iss >> 9) & 1 != 0,
+            fault_status: (iss & 0x3f) as u8,
+        }
+    }
+
+    /// The asynchronous error type, only valid for asynchronous SError interrupts.
+    pub fn error_type(&self) -> Option<u8> {
+        if !self.implementation_defined && self.fault_status == SERROR_ASYNCHRONOUS {
+            Some(self.aet)
+        } else {
+            None
+        }
+    }
+}
+
+/// Class specific part of the syndrome.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Syndrome {
+    /// SVC, HVC and SMC immediate.
+    Call(u16),
+    /// BRK comment.
+    Breakpoint(u16),
+    SystemRegister(SystemRegisterSyndrome),
+    Abort(AbortSyndrome),
+    FloatingPoint(FpSyndrome),
+    SError(SErrorSyndrome),
+    /// WFI (false) or WFE (true).
+    WaitFor(bool),
+    /// Raw ISS of the classes without a dedicated decoding.
+    Other(u32),
+}
+
+/// A decoded ESR_ELx value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Esr(pub u64);
+
+impl Esr {
+    pub fn exception_class(self) -> ExceptionClass {
+        ExceptionClass::from_code(((self.0 >> 26) & 0x3f) as u8)
+    }
+
+    /// The trapped instruction was 32 bits wide.
+    pub fn is_32bit_instruction(self) -> bool {
+        (self.0 >> 25) & 1 != 0
+    }
+
+    pub fn iss(self) -> u32 {
+        (self.0 & 0x1ff_ffff) as u32
+    }
+
+    pub fn syndrome(self) -> Syndrome {
+        let iss = self.iss();
+
+        match self.exception_class() {
+            ExceptionClass::Svc32
+            | ExceptionClass::Hvc32
+            | ExceptionClass::Smc32
+            | ExceptionClass::Svc64
+            | ExceptionClass::Hvc64
+            | ExceptionClass::Smc64 => Syndrome::Call(iss as u16),
+            ExceptionClass::Brk64 | ExceptionClass::Bkpt32 => Syndrome::Breakpoint(iss as u16),
+            ExceptionClass::MsrMrsTrap => {
+                Syndrome::SystemRegister(SystemRegisterSyndrome::from_iss(iss))
+            }
+            ExceptionClass::InstructionAbortLowerEl | ExceptionClass::InstructionAbortCurrentEl => {
+                Syndrome::Abort(AbortSyndrome::from_iss(iss, false))
+            }
+            ExceptionClass::DataAbortLowerEl
+            | ExceptionClass::DataAbortCurrentEl
+            | ExceptionClass::WatchpointLowerEl
+            | ExceptionClass::WatchpointCurrentEl => {
+                Syndrome::Abort(AbortSyndrome::from_iss(iss, true))
+            }
+            ExceptionClass::FpException32 | ExceptionClass::FpException64 => {
+                Syndrome::FloatingPoint(FpSyndrome::from_iss(iss))
+            }
+            ExceptionClass::SError => Syndrome::SError(SErrorSyndrome::from_iss(iss)),
+            ExceptionClass::WfiWfe => Syndrome::WaitFor(iss & 1 != 0),
+            _ => Syndrome::Other(iss),
+        }
+    }
+}
+
+impl fmt::Display for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exception_class = self.exception_class();
+
+        write!(
+            f,
+            "{} (EC: {:#04x}, IL: {}, ISS: {:#09x})",
+            exception_class.name(),
+            exception_class.code(),
+            self.is_32bit_instruction() as u8,
+            self.iss()
+        )?;
+
+        match self.syndrome() {
+            Syndrome::Call(immediate) => write!(f, "\r\n  imm16: {:#06x}", immediate),
+            Syndrome::Breakpoint(comment) => write!(f, "\r\n  comment: {:#06x}", comment),
+            Syndrome::SystemRegister(sysreg) => write!(
+                f,
+                "\r\n  {} S{}_{}_C{}_C{}_{}, X{}",
+                if sysreg.is_read { "MRS" } else { "MSR" },
+                sysreg.op0,
+                sysreg.op1,
+                sysreg.crn,
+                sysreg.crm,
+                sysreg.op2,
+                sysreg.rt
+            ),
+            Syndrome::Abort(abort) => {
+                write!(
+                    f,
+                    "\r\n  {} (FSC: {:#04x})\r\n  WnR: {}, S1PTW: {}, CM: {}, EA: {}, FnV: {}",
+                    get_fault_status_name(abort.fault_status),
+                    abort.fault_status,
+                    abort.write_not_read as u8,
+                    abort.s1ptw as u8,
+                    abort.cache_maintenance as u8,
+                    abort.external_abort as u8,
+                    abort.far_not_valid as u8
+                )?;
+
+                if abort.isv {
+                    write!(
+                        f,
+                        "\r\n  SAS: {} bytes, SSE: {}, SRT: X{}, SF: {}, AR: {}",
+                        1 << abort.sas,
+                        abort.sse as u8,
+                        abort.srt,
+                        abort.sixty_four as u8,
+                        abort.acquire_release as u8
+                    )?;
+                }
+
+                Ok(())
+            }
+            Syndrome::FloatingPoint(fp) => write!(
+                f,
+                "\r\n  TFV: {}, IDF: {}, IXF: {}, UFF: {}, OFF: {}, DZF: {}, IOF: {}",
+                fp.trapped_fault_valid as u8,
+                fp.input_denormal as u8,
+                fp.inexact as u8,
+                fp.underflow as u8,
+                fp.overflow as u8,
+                fp.divide_by_zero as u8,
+                fp.invalid_operation as u8
+            ),
+            Syndrome::SError(serror) if serror.implementation_defined => {
+                write!(f, "\r\n  Implementation defined syndrome (IDS: 1)")
+            }
+            Syndrome::SError(serror) => {
+                write!(
+                    f,
+                    "\r\n  {} (DFSC: {:#04x})\r\n  IESB: {}, EA: {}",
+                    get_serror_fault_status_name(serror.fault_status),
+                    serror.fault_status,
+                    serror.iesb as u8,
+                    serror.external_abort as u8
+                )?;
+
+                if let Some(error_type) = serror.error_type() {
+                    write!(
+                        f,
+                        "\r\n  {} (AET: {:#05b})",
+                        get_serror_error_type_name(error_type),
+                        error_type
+                    )?;
+                }
+
+                Ok(())
+            }
+            Syndrome::WaitFor(is_wfe) => {
+                write!(f, "\r\n  {}", if is_wfe { "WFE" } else { "WFI" })
+            }
+            Syndrome::Other(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn esr(exception_class: u8, iss: u32) -> Esr {
+        Esr((exception_class as u64) << 26 | 1 << 25 | iss as u64)
+    }
+
+    #[test]
+    fn exception_class_codes_round_trip() {
+        for code in 0..0x40 {
+            assert_eq!(ExceptionClass::from_code(code).code(), code);
+        }
+
+        assert_eq!(
+            ExceptionClass::from_code(0x02),
+            ExceptionClass::Reserved(0x02)
+        );
+    }
+
+    #[test]
+    fn data_abort() {
+        // str w5, [x0] on an unmapped page.
+        let iss = 1 << 24 | 0b10 << 22 | 5 << 16 | 1 << 6 | 0b000111;
+        let esr = esr(0x25, iss);
+
+        assert_eq!(esr.exception_class(), ExceptionClass::DataAbortCurrentEl);
+        assert!(esr.is_32bit_instruction());
+
+        let abort = match esr.syndrome() {
+            Syndrome::Abort(abort) => abort,
+            syndrome => panic!("{:?}", syndrome),
+        };
+        assert_eq!(abort.fault_status, 0b000111);
+        assert!(abort.write_not_read && abort.isv && !abort.sixty_four);
+        assert_eq!(abort.srt, 5);
+        assert_eq!(abort.access_size(), Some(4));
+
+        let text = format!("{}", esr);
+        assert!(text.starts_with("Data abort (EC: 0x25, IL: 1, ISS: 0x1850047)"));
+        assert!(text.contains("Translation fault, level 3 (FSC: 0x07)"));
+        assert!(text.contains("SAS: 4 bytes, SSE: 0, SRT: X5"));
+    }
+
+    #[test]
+    fn instruction_abort_ignores_data_abort_fields() {
+        let abort = match esr(0x21, 1 << 24 | 1 << 6 | 0b001111).syndrome() {
+            Syndrome::Abort(abort) => abort,
+            syndrome => panic!("{:?}", syndrome),
+        };
+
+        assert!(!abort.isv && !abort.write_not_read);
+        assert_eq!(abort.access_size(), None);
+        assert_eq!(
+            get_fault_status_name(abort.fault_status),
+            "Permission fault, level 3"
+        );
+    }
+
+    #[test]
+    fn watchpoint() {
+        let esr = esr(0x35, 1 << 6 | 0b100010);
+
+        assert_eq!(esr.exception_class(), ExceptionClass::WatchpointCurrentEl);
+        assert!(format!("{}", esr).contains("Debug exception (FSC: 0x22)\r\n  WnR: 1"));
+    }
+
+    #[test]
+    fn serror_uses_its_own_fault_status_encoding() {
+        let esr = esr(0x2f, 0);
+
+        match esr.syndrome() {
+            Syndrome::SError(serror) => assert_eq!(serror.error_type(), None),
+            syndrome => panic!("{:?}", syndrome),
+        }
+
+        let text = format!("{}", esr);
+        assert!(text.contains("Uncategorized error (DFSC: 0x00)"));
+        assert!(!text.contains("Address size fault"));
+        assert!(!text.contains("AET"));
+    }
+
+    #[test]
+    fn serror_error_type() {
+        let esr = esr(0x2f, 1 << 13 | 0b011 << 10 | 1 << 9 | 0b010001);
+
+        let serror = match esr.syndrome() {
+            Syndrome::SError(serror) => serror,
+            syndrome => panic!("{:?}", syndrome),
+        };
+        assert!(serror.iesb && serror.external_abort);
+        assert_eq!(serror.error_type(), Some(0b011));
+
+        let text = format!("{}", esr);
+        assert!(text.contains("Asynchronous SError interrupt (DFSC: 0x11)\r\n  IESB: 1, EA: 1"));
+        assert!(text.contains("Recoverable (UER) (AET: 0b011)"));
+
+        assert_eq!(get_serror_error_type_name(0b000), "Uncontainable (UC)");
+        assert_eq!(get_serror_error_type_name(0b110), "Corrected (CE)");
+        assert_eq!(get_serror_error_type_name(0b100), "Reserved error type");
+    }
+
+    #[test]
+    fn implementation_defined_serror() {
+        let esr = esr(0x2f, 1 << 24 | 0b010001);
+
+        match esr.syndrome() {
+            Syndrome::SError(serror) => assert_eq!(serror.error_type(), None),
+            syndrome => panic!("{:?}", syndrome),
+        }
+
+        let text = format!("{}", esr);
+        assert!(text.ends_with("Implementation defined syndrome (IDS: 1)"));
+    }
+
+    #[test]
+    fn trapped_system_register_access() {
+        // mrs x2, ctr_el0
+        let iss = 3 << 20 | 1 << 17 | 3 << 14 | 2 << 5 | 1;
+        let esr = esr(0x18, iss);
+
+        assert_eq!(
+            esr.syndrome(),
+            Syndrome::SystemRegister(SystemRegisterSyndrome {
+                op0: 3,
+                op1: 3,
+                op2: 1,
+                crn: 0,
+                crm: 0,
+                rt: 2,
+                is_read: true,
+            })
+        );
+        assert!(format!("{}", esr).ends_with("MRS S3_3_C0_C0_1, X2"));
+    }
+
+    #[test]
+    fn calls_and_breakpoints() {
+        assert_eq!(esr(0x15, 0x42).syndrome(), Syndrome::Call(0x42));
+        assert_eq!(esr(0x3c, 0xf000).syndrome(), Syndrome::Breakpoint(0xf000));
+        assert_eq!(esr(0x01, 1).syndrome(), Syndrome::WaitFor(true));
+        assert_eq!(esr(0x00, 0x123).syndrome(), Syndrome::Other(0x123));
+    }
+}
@@ -1,8 +1,12 @@
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use libtegra::uart::Uart;
 
+use crate::backtrace;
+use crate::esr::{Esr, ExceptionClass};
 use crate::rt;
+use crate::tegra210::gic;
 use crate::utils;
 
 global_asm!(
@@ -30,10 +34,14 @@ global_asm!(
         .align 9
         /* Current EL with SPx */
         vector_entry _current_elx_sync
-        vector_not_handled
-        vector_not_handled
+        vector_entry _current_elx_irq
+        vector_entry _current_elx_fiq
         vector_not_handled
 
+        /*
+         * Payloads are entered at the current EL, nothing runs at a lower EL
+         * while these vectors are installed: such exceptions are only reported.
+         */
         .align 9
         /* Lower EL exception to Current EL (AArch64) */
         vector_not_handled
@@ -54,8 +62,8 @@ pub fn set_vbar(vbar: u64) {
     unsafe {
         match utils::get_current_el() {
             1 => asm!("msr vbar_el1, {vbar}", vbar = in(reg) vbar, options(nostack)),
-            1 => asm!("msr vbar_el2, {vbar}", vbar = in(reg) vbar, options(nostack)),
-            1 => asm!("msr vbar_el3, {vbar}", vbar = in(reg) vbar, options(nostack)),
+            2 => asm!("msr vbar_el2, {vbar}", vbar = in(reg) vbar, options(nostack)),
+            3 => asm!("msr vbar_el3, {vbar}", vbar = in(reg) vbar, options(nostack)),
             _ => unimplemented!(),
         }
     }
@@ -168,8 +176,8 @@ global_asm!(
         b.eq   1f
 
     3:
-        msr    elr_el2, x0
-        msr    spsr_el2, x1
+        msr    elr_el3, x0
+        msr    spsr_el3, x1
         b 0f
     2:
         msr    elr_el2, x0
@@ -219,14 +227,125 @@ unsafe extern "C" fn _current_elx_sync() -> ! {
     )
 }
 
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn _current_elx_irq() -> ! {
+    asm!(
+        "
+        __save_generic_registers
+        __save_el_registers
+        mov x0, sp
+        bl current_elx_irq
+        __restore_el_registers
+        __restore_generic_registers
+        eret
+        ",
+        options(noreturn),
+    )
+}
+
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn _current_elx_fiq() -> ! {
+    asm!(
+        "
+        __save_generic_registers
+        __save_el_registers
+        mov x0, sp
+        bl current_elx_fiq
+        __restore_el_registers
+        __restore_generic_registers
+        eret
+        ",
+        options(noreturn),
+    )
+}
+
+/// Registers saved on exception entry, restored by `eret` on return.
 #[repr(C)]
-struct ExceptionInfo {
-    far_duplicate: u64,
-    far: u64,
-    pc: u64,
-    cpsr: u64,
-    esr: u64,
-    x: [u64; 31],
+pub struct ExceptionInfo {
+    pub far_duplicate: u64,
+    pub far: u64,
+    pub pc: u64,
+    pub cpsr: u64,
+    pub esr: u64,
+    pub x: [u64; 31],
+}
+
+impl ExceptionInfo {
+    /// Resume execution after the faulting instruction.
+    pub fn skip_instruction(&mut self) {
+        self.pc += 4;
+    }
+}
+
+/// Synchronous exception handler.
+///
+/// Returns true when the exception was dealt with, execution then resumes
+/// at `exception.pc` with the (possibly modified) saved registers.
+pub type ExceptionHandler = fn(exception: &mut ExceptionInfo) -> bool;
+
+static mut EXCEPTION_HANDLERS: [Option<ExceptionHandler>; 64] = [None; 64];
+
+/// Register a handler for synchronous exceptions of the given class, returning the previous one.
+pub fn register_handler(
+    class: ExceptionClass,
+    handler: Option<ExceptionHandler>,
+) -> Option<ExceptionHandler> {
+    unsafe {
+        let previous_handler = EXCEPTION_HANDLERS[class.code() as usize];
+        EXCEPTION_HANDLERS[class.code() as usize] = handler;
+
+        previous_handler
+    }
+}
+
+fn call_handler(exception: &mut ExceptionInfo) -> bool {
+    let class = Esr(exception.esr).exception_class();
+
+    match unsafe { EXCEPTION_HANDLERS[class.code() as usize] } {
+        Some(handler) => handler(exception),
+        None => false,
+    }
+}
+
+static PROBE_FAULTED: AtomicBool = AtomicBool::new(false);
+
+fn probe_abort_handler(exception: &mut ExceptionInfo) -> bool {
+    PROBE_FAULTED.store(true, Ordering::SeqCst);
+    exception.skip_instruction();
+
+    true
+}
+
+/// Read a 32-bit register, returning None if the access raised a synchronous data abort.
+///
+/// NOTE: Asynchronous aborts (SError) and bus hangs are not caught.
+pub fn probe_read32(address: u64) -> Option<u32> {
+    let previous_handler = register_handler(
+        ExceptionClass::DataAbortCurrentEl,
+        Some(probe_abort_handler),
+    );
+    PROBE_FAULTED.store(false, Ordering::SeqCst);
+
+    let value: u32;
+    unsafe {
+        asm!(
+            "ldr {value:w}, [{address}]",
+            value = out(reg) value,
+            address = in(reg) address,
+            options(nostack)
+        );
+    }
+
+    let faulted = PROBE_FAULTED.load(Ordering::SeqCst);
+    register_handler(ExceptionClass::DataAbortCurrentEl, previous_handler);
+
+    if faulted {
+        None
+    } else {
+        Some(value)
+    }
 }
 
 unsafe fn dump_exception(exception: &mut ExceptionInfo) {
@@ -237,6 +356,7 @@ unsafe fn dump_exception(exception: &mut ExceptionInfo) {
     writeln!(&mut uart_a, "PC:\t{:20x}\t", exception.pc).ok();
     writeln!(&mut uart_a, "CPSR:\t{:20x}\t", exception.cpsr).ok();
     writeln!(&mut uart_a, "ESR:\t{:20x}\r", exception.esr).ok();
+    writeln!(&mut uart_a, "{}\r", Esr(exception.esr)).ok();
 
     for (index, value) in exception.x.iter_mut().enumerate() {
         write!(&mut uart_a, "X{}:\t{:20x}\t", index, *value).ok();
@@ -245,6 +365,9 @@ unsafe fn dump_exception(exception: &mut ExceptionInfo) {
             writeln!(&mut uart_a, "\r").ok();
         }
     }
+
+    writeln!(&mut uart_a, "\r").ok();
+    backtrace::write_backtrace(&mut uart_a, Some(exception.pc), exception.x[29]);
 }
 
 #[no_mangle]
@@ -254,13 +377,7 @@ unsafe extern "C" fn unhandled_vector(exception: &mut ExceptionInfo) {
     writeln!(
         &mut uart_a,
         "Unhandled vector ({})\r",
-        get_exception_type_elx(exception.esr)
-    )
-    .ok();
-    writeln!(
-        &mut uart_a,
-        "Instruction Fault name: {}\r",
-        get_instruction_fault_name(exception.esr)
+        Esr(exception.esr).exception_class().name()
     )
     .ok();
 
@@ -269,65 +386,31 @@ unsafe extern "C" fn unhandled_vector(exception: &mut ExceptionInfo) {
     rt::reboot_to_rcm();
 }
 
-pub fn get_exception_type_elx(esr: u64) -> &'static str {
-    let exception_class = esr >> 26;
-
-    match exception_class {
-        0x18 => "Configurable trap",
-        0x22 => "PC alignment exception",
-        0x25 => "Data abort",
-        0x26 => "Stack alignment exception",
-        0x2f => "Serror",
-        0x30 => "Debug exception",
-        _ => "Unknown exception",
-    }
-}
-
-pub fn get_instruction_fault_name(esr: u64) -> &'static str {
-    let exception_class = esr & 0x1f;
-
-    match exception_class {
-        0b000000 => "Address size fault in TTBR0 or TTBR1",
-        0b000101 => "Translation fault, 1st level",
-        0b000110 => "Translation fault, 2nd level",
-        0b000111 => "Translation fault, 3rd level",
-        0b001001 => "Access flag fault, 1st level",
-        0b001010 => "Access flag fault, 2nd level",
-        0b001011 => "Access flag fault, 3rd level",
-        0b001101 => "Permission fault, 1st level",
-        0b001110 => "Permission fault, 2nd level",
-        0b001111 => "Permission fault, 3rd level",
-        0b010000 => "Synchronous external abort",
-        0b011000 => "Synchronous parity error on memory access",
-        0b010101 => "Synchronous external abort on translation table walk, 1st level",
-        0b010110 => "Synchronous external abort on translation table walk, 2nd level",
-        0b010111 => "Synchronous external abort on translation table walk, 3rd level",
-        0b011101 => {
-            "Synchronous parity error on memory access on translation table walk, 1st level"
-        }
-        0b011110 => {
-            "Synchronous parity error on memory access on translation table walk, 2nd level"
-        }
-        0b011111 => {
-            "Synchronous parity error on memory access on translation table walk, 3rd level"
-        }
-        0b100001 => "Alignment fault",
-        0b100010 => "Debug event",
-        _ => "Unknown instruction fault",
-    }
-}
-
 #[no_mangle]
 unsafe extern "C" fn current_elx_sync(exception: &mut ExceptionInfo) {
+    if call_handler(exception) {
+        return;
+    }
+
     let mut uart_a = Uart::A;
     writeln!(&mut uart_a, "\r").ok();
     writeln!(
         &mut uart_a,
         "Sync ELX Exception ({})\r",
-        get_exception_type_elx(exception.esr)
+        Esr(exception.esr).exception_class().name()
     )
     .ok();
     dump_exception(exception);
 
     rt::reboot_to_rcm();
 }
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_irq(_exception: &mut ExceptionInfo) {
+    gic::handle_irq();
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_fiq(_exception: &mut ExceptionInfo) {
+    gic::handle_fiq();
+}
@@ -0,0 +1,1850 @@
+//! Read-only ext4 filesystems, also reading ext2 and ext3 ones.
+//!
+//! Files are mapped through extent trees or the indirect blocks of older filesystems. Hashed
+//! directories are searched through their index, a linear scan being used when the index can't
+//! be used or misses, which also covers hash collisions spanning leaves. Symbolic links are
+//! followed in paths. Checksums aren't checked and the journal isn't replayed.
+//!
+//! Only `core` and the block device API are used, the module can be built and tested on the
+//! host.
+
+use core::cmp;
+use core::fmt;
+use core::str;
+
+use crate::block::{self, BlockDevice, BLOCK_SIZE};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const SUPERBLOCK_MAGIC: u16 = 0xEF53;
+
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+const GOOD_OLD_INODE_SIZE: u32 = 128;
+const GOOD_OLD_DESC_SIZE: u32 = 32;
+const MIN_DESC_SIZE_64BIT: u32 = 64;
+
+const COMPAT_DIR_INDEX: u32 = 0x20;
+const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+
+const INCOMPAT_COMPRESSION: u32 = 0x1;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_DIRDATA: u32 = 0x1000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+/// Features changing the layout in ways this module doesn't know of.
+const INCOMPAT_UNSUPPORTED: u32 = INCOMPAT_COMPRESSION | INCOMPAT_JOURNAL_DEV | INCOMPAT_DIRDATA;
+
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+
+/// Set in the superblock flags when directory hashes use unsigned chars.
+const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+const ROOT_INODE: u32 = 2;
+/// Part of the inodes read, up to the high bits of the size.
+const INODE_SIZE_USED: usize = 128;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+
+const INODE_FLAG_ENCRYPT: u32 = 0x800;
+const INODE_FLAG_INDEX: u32 = 0x1000;
+const INODE_FLAG_EXTENTS: u32 = 0x8_0000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+const INODE_BLOCK_SIZE: usize = 60;
+const DIRECT_BLOCKS: u64 = 12;
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+const EXTENT_ENTRY_SIZE: usize = 12;
+const MAX_EXTENT_DEPTH: u16 = 5;
+/// Extents longer than this are uninitialized, reading as zeros.
+const MAX_INITIALIZED_EXTENT_LENGTH: u16 = 32768;
+
+const DIR_ENTRY_HEADER_SIZE: u64 = 8;
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Offset of the hash tree information in the first block of hashed directories, after the
+/// "." and ".." entries.
+const DX_ROOT_INFO_OFFSET: u64 = 24;
+const DX_ENTRY_SIZE: u64 = 8;
+const DX_HASH_LEGACY: u8 = 0;
+const DX_HASH_HALF_MD4: u8 = 1;
+const DX_HASH_TEA: u8 = 2;
+const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+const DX_HASH_TEA_UNSIGNED: u8 = 5;
+const DX_DEFAULT_SEED: [u32; 4] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+
+/// Longest path handled, symbolic link targets included.
+pub const MAX_PATH_LENGTH: usize = 256;
+const MAX_SYMLINKS: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    Block(block::Error),
+    /// The superblock doesn't describe an ext2, ext3 or ext4 filesystem.
+    NotExt4,
+    /// These incompatible features aren't supported.
+    UnsupportedFeatures(u32),
+    /// The inode number is invalid.
+    BadInode(u32),
+    /// The data of the inode is inline or encrypted.
+    UnsupportedInode(u32),
+    BadExtentTree(u32),
+    BadDirectory(u32),
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    PathTooLong,
+    TooManySymlinks,
+    /// The file is bigger than the buffer it's read to.
+    BufferTooSmall(u64),
+}
+
+impl From<block::Error> for Error {
+    fn from(error: block::Error) -> Self {
+        Error::Block(error)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn is_power_of(value: u32, base: u32) -> bool {
+    let mut value = value;
+    while value > 1 && value % base == 0 {
+        value /= base;
+    }
+
+    value == 1
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FileType {
+    /// The directory entries don't record the type.
+    Unknown,
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+impl FileType {
+    fn from_mode(mode: u16) -> Self {
+        match mode & MODE_TYPE_MASK {
+            MODE_FILE => FileType::File,
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+
+    fn from_dir_entry(file_type: u8) -> Self {
+        match file_type {
+            0 => FileType::Unknown,
+            1 => FileType::File,
+            2 => FileType::Directory,
+            7 => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Inode {
+    number: u32,
+    mode: u16,
+    flags: u32,
+    size: u64,
+    /// Extent tree root, block pointers or fast symbolic link target.
+    block: [u8; INODE_BLOCK_SIZE],
+}
+
+impl Inode {
+    fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+}
+
+/// A run of `length` blocks starting at block `logical` of a file, stored from block
+/// `physical` or a hole when `None`.
+#[derive(Copy, Clone, Debug)]
+struct Mapping {
+    logical: u64,
+    length: u64,
+    physical: Option<u64>,
+}
+
+impl Mapping {
+    fn contains(&self, logical: u64) -> bool {
+        logical >= self.logical && logical - self.logical < self.length
+    }
+
+    fn hole(logical: u64, length: u64) -> Self {
+        Mapping {
+            logical,
+            length,
+            physical: None,
+        }
+    }
+}
+
+/// A file opened for reading.
+#[derive(Copy, Clone, Debug)]
+pub struct File {
+    inode: Inode,
+    position: u64,
+    /// Last mapping used, sequential reads seldom need another one.
+    mapping: Option<Mapping>,
+}
+
+impl File {
+    pub fn size(&self) -> u64 {
+        self.inode.size
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Move to `position`, reads past the end returning nothing.
+    pub fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Dir {
+    inode: Inode,
+}
+
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    pub inode: u32,
+    pub file_type: FileType,
+    name: [u8; MAX_NAME_LENGTH],
+    name_length: usize,
+}
+
+impl DirEntry {
+    /// The name, which is usually but not necessarily UTF-8.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_length]
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DirEntry")
+            .field("inode", &self.inode)
+            .field("file_type", &self.file_type)
+            .field(
+                "name",
+                &str::from_utf8(self.name()).unwrap_or("<invalid UTF-8>"),
+            )
+            .finish()
+    }
+}
+
+pub struct FileSystem<'a> {
+    device: &'a mut dyn BlockDevice,
+    block_size: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    desc_size: u64,
+    feature_compat: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+    first_meta_bg: u64,
+    backup_groups: [u32; 2],
+    hash_seed: [u32; 4],
+    unsigned_hash: bool,
+    cached_lba: Option<u64>,
+    cache: [u8; BLOCK_SIZE],
+}
+
+impl<'a> FileSystem<'a> {
+    /// Mount the filesystem starting at the first block of `device`.
+    pub fn new(device: &'a mut dyn BlockDevice) -> Result<Self, Error> {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        block::read_bytes(device, SUPERBLOCK_OFFSET, &mut superblock)?;
+
+        if read_u16(&superblock, 56) != SUPERBLOCK_MAGIC {
+            return Err(Error::NotExt4);
+        }
+
+        let log_block_size = read_u32(&superblock, 24);
+        let blocks_per_group = read_u32(&superblock, 32);
+        let inodes_per_group = read_u32(&superblock, 40);
+        let revision = read_u32(&superblock, 76);
+        let feature_compat = read_u32(&superblock, 92);
+        let feature_incompat = read_u32(&superblock, 96);
+        let feature_ro_compat = read_u32(&superblock, 100);
+
+        if log_block_size > MAX_LOG_BLOCK_SIZE || blocks_per_group == 0 || inodes_per_group == 0 {
+            return Err(Error::NotExt4);
+        }
+
+        let block_size = 1024u32 << log_block_size;
+
+        // Revision 0 filesystems have none of the fields past the revision.
+        let (inode_size, feature_compat, feature_incompat, feature_ro_compat) = if revision == 0 {
+            (GOOD_OLD_INODE_SIZE, 0, 0, 0)
+        } else {
+            (
+                u32::from(read_u16(&superblock, 88)),
+                feature_compat,
+                feature_incompat,
+                feature_ro_compat,
+            )
+        };
+
+        if !inode_size.is_power_of_two()
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || inode_size > block_size
+        {
+            return Err(Error::NotExt4);
+        }
+
+        if feature_incompat & INCOMPAT_UNSUPPORTED != 0 {
+            return Err(Error::UnsupportedFeatures(
+                feature_incompat & INCOMPAT_UNSUPPORTED,
+            ));
+        }
+
+        if feature_incompat & INCOMPAT_RECOVER != 0 {
+            warn!("The ext4 journal needs recovery, recent changes may be missing");
+        }
+
+        let desc_size = if feature_incompat & INCOMPAT_64BIT != 0 {
+            let desc_size = u32::from(read_u16(&superblock, 254));
+            if desc_size < MIN_DESC_SIZE_64BIT || desc_size > block_size {
+                return Err(Error::NotExt4);
+            }
+
+            desc_size
+        } else {
+            GOOD_OLD_DESC_SIZE
+        };
+
+        let mut hash_seed = [0; 4];
+        for (index, word) in hash_seed.iter_mut().enumerate() {
+            *word = read_u32(&superblock, 236 + index * 4);
+        }
+
+        Ok(FileSystem {
+            device,
+            block_size: u64::from(block_size),
+            first_data_block: u64::from(read_u32(&superblock, 20)),
+            blocks_per_group: u64::from(blocks_per_group),
+            inodes_count: read_u32(&superblock, 0),
+            inodes_per_group,
+            inode_size: u64::from(inode_size),
+            desc_size: u64::from(desc_size),
+            feature_compat,
+            feature_incompat,
+            feature_ro_compat,
+            first_meta_bg: u64::from(read_u32(&superblock, 260)),
+            backup_groups: [read_u32(&superblock, 588), read_u32(&superblock, 592)],
+            hash_seed,
+            unsigned_hash: read_u32(&superblock, 352) & FLAGS_UNSIGNED_HASH != 0,
+            cached_lba: None,
+            cache: [0; BLOCK_SIZE],
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Read `buffer.len()` bytes at byte `offset` of the device, through the block cache.
+    fn read_bytes(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut offset = offset;
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let lba = offset / BLOCK_SIZE as u64;
+            let block_offset = (offset % BLOCK_SIZE as u64) as usize;
+            let remaining = buffer.len() - done;
+
+            let size = if block_offset == 0 && remaining >= BLOCK_SIZE {
+                // Whole blocks go straight to the buffer.
+                let size = remaining - remaining % BLOCK_SIZE;
+                self.device
+                    .read_blocks(lba, &mut buffer[done..done + size])?;
+
+                size
+            } else {
+                if self.cached_lba != Some(lba) {
+                    self.cached_lba = None;
+                    self.device.read_blocks(lba, &mut self.cache)?;
+                    self.cached_lba = Some(lba);
+                }
+
+                let size = cmp::min(BLOCK_SIZE - block_offset, remaining);
+                buffer[done..done + size]
+                    .copy_from_slice(&self.cache[block_offset..block_offset + size]);
+
+                size
+            };
+
+            done += size;
+            offset += size as u64;
+        }
+
+        Ok(())
+    }
+
+    fn read_block_u32(&mut self, offset: u64) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        self.read_bytes(offset, &mut bytes)?;
+
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn has_superblock(&self, group: u64) -> bool {
+        if group == 0 {
+            return true;
+        }
+
+        if self.feature_compat & COMPAT_SPARSE_SUPER2 != 0 {
+            return self
+                .backup_groups
+                .iter()
+                .any(|backup| u64::from(*backup) == group);
+        }
+
+        if self.feature_ro_compat & RO_COMPAT_SPARSE_SUPER == 0 || group == 1 {
+            return true;
+        }
+
+        group <= u64::from(u32::MAX)
+            && (is_power_of(group as u32, 3)
+                || is_power_of(group as u32, 5)
+                || is_power_of(group as u32, 7))
+    }
+
+    /// Block holding the `index`th block of group descriptors.
+    fn descriptor_block(&self, index: u64) -> u64 {
+        if self.feature_incompat & INCOMPAT_META_BG == 0 || index < self.first_meta_bg {
+            return self.first_data_block + 1 + index;
+        }
+
+        // With meta block groups, each block of descriptors is at the start of the first group
+        // it describes, after the superblock backup.
+        let group = index * (self.block_size / self.desc_size);
+        let first_block = self.first_data_block + group * self.blocks_per_group;
+
+        if self.has_superblock(group) {
+            first_block + 1
+        } else {
+            first_block
+        }
+    }
+
+    fn read_inode(&mut self, number: u32) -> Result<Inode, Error> {
+        if number == 0 || number > self.inodes_count {
+            return Err(Error::BadInode(number));
+        }
+
+        let group = u64::from((number - 1) / self.inodes_per_group);
+        let index = u64::from((number - 1) % self.inodes_per_group);
+
+        let descriptors_per_block = self.block_size / self.desc_size;
+        let descriptor = self.descriptor_block(group / descriptors_per_block) * self.block_size
+            + (group % descriptors_per_block) * self.desc_size;
+
+        let mut inode_table = u64::from(self.read_block_u32(descriptor + 8)?);
+        if self.desc_size >= u64::from(MIN_DESC_SIZE_64BIT) {
+            inode_table |= u64::from(self.read_block_u32(descriptor + 0x28)?) << 32;
+        }
+
+        let mut bytes = [0; INODE_SIZE_USED];
+        self.read_bytes(
+            inode_table * self.block_size + index * self.inode_size,
+            &mut bytes,
+        )?;
+
+        let mut block = [0; INODE_BLOCK_SIZE];
+        block.copy_from_slice(&bytes[40..40 + INODE_BLOCK_SIZE]);
+
+        Ok(Inode {
+            number,
+            mode: read_u16(&bytes, 0),
+            flags: read_u32(&bytes, 32),
+            size: u64::from(read_u32(&bytes, 4)) | u64::from(read_u32(&bytes, 108)) << 32,
+            block,
+        })
+    }
+
+    /// The run of blocks of `inode` holding block `logical`, `cache` keeping the last one.
+    fn map_block(
+        &mut self,
+        inode: &Inode,
+        cache: &mut Option<Mapping>,
+        logical: u64,
+    ) -> Result<Mapping, Error> {
+        if let Some(mapping) = cache {
+            if mapping.contains(logical) {
+                return Ok(*mapping);
+            }
+        }
+
+        if inode.flags & (INODE_FLAG_INLINE_DATA | INODE_FLAG_ENCRYPT) != 0 {
+            return Err(Error::UnsupportedInode(inode.number));
+        }
+
+        let mapping = if logical > u64::from(u32::MAX) {
+            Mapping::hole(logical, 1)
+        } else if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.map_extent(inode, logical as u32)?
+        } else {
+            self.map_indirect(inode, logical)?
+        };
+
+        *cache = Some(mapping);
+
+        Ok(mapping)
+    }
+
+    /// Read the extent tree entry at `offset` of the node in `node`, or in the inode.
+    fn read_extent_entry(
+        &mut self,
+        inode: &Inode,
+        node: Option<u64>,
+        offset: usize,
+    ) -> Result<[u8; EXTENT_ENTRY_SIZE], Error> {
+        let mut entry = [0; EXTENT_ENTRY_SIZE];
+
+        match node {
+            None if offset + EXTENT_ENTRY_SIZE <= INODE_BLOCK_SIZE => {
+                entry.copy_from_slice(&inode.block[offset..offset + EXTENT_ENTRY_SIZE])
+            }
+            Some(node) if (offset + EXTENT_ENTRY_SIZE) as u64 <= self.block_size => {
+                self.read_bytes(node * self.block_size + offset as u64, &mut entry)?
+            }
+            _ => return Err(Error::BadExtentTree(inode.number)),
+        }
+
+        Ok(entry)
+    }
+
+    fn map_extent(&mut self, inode: &Inode, logical: u32) -> Result<Mapping, Error> {
+        // End of the range covered by the node, bounding the holes after its last entry.
+        let mut node_end = u64::from(u32::MAX) + 1;
+        let mut node = None;
+        let mut expected_depth = None;
+
+        loop {
+            let header = self.read_extent_entry(inode, node, 0)?;
+            let entry_count = usize::from(read_u16(&header, 2));
+            let depth = read_u16(&header, 6);
+
+            if read_u16(&header, 0) != EXTENT_MAGIC
+                || depth > MAX_EXTENT_DEPTH
+                || expected_depth.map_or(false, |expected| expected != depth)
+            {
+                return Err(Error::BadExtentTree(inode.number));
+            }
+
+            // Entries are sorted, take the last one starting at or before the block.
+            let mut found = None;
+            let mut next_start = node_end;
+            for index in 0..entry_count {
+                let entry = self.read_extent_entry(inode, node, (index + 1) * EXTENT_ENTRY_SIZE)?;
+
+                if read_u32(&entry, 0) > logical {
+                    next_start = u64::from(read_u32(&entry, 0));
+                    break;
+                }
+
+                found = Some(entry);
+            }
+
+            let logical = u64::from(logical);
+            let entry = match found {
+                Some(entry) => entry,
+                None => return Ok(Mapping::hole(logical, next_start - logical)),
+            };
+
+            if depth == 0 {
+                let first = u64::from(read_u32(&entry, 0));
+                let raw_length = read_u16(&entry, 4);
+                let start = u64::from(read_u16(&entry, 6)) << 32 | u64::from(read_u32(&entry, 8));
+
+                let (length, initialized) = if raw_length > MAX_INITIALIZED_EXTENT_LENGTH {
+                    (raw_length - MAX_INITIALIZED_EXTENT_LENGTH, false)
+                } else {
+                    (raw_length, true)
+                };
+                let length = u64::from(length);
+
+                if logical - first >= length {
+                    return Ok(Mapping::hole(logical, next_start - logical));
+                }
+
+                return Ok(Mapping {
+                    logical: first,
+                    length,
+                    physical: if initialized { Some(start) } else { None },
+                });
+            }
+
+            node = Some(u64::from(read_u16(&entry, 8)) << 32 | u64::from(read_u32(&entry, 4)));
+            node_end = next_start;
+            expected_depth = Some(depth - 1);
+        }
+    }
+
+    fn map_indirect(&mut self, inode: &Inode, logical: u64) -> Result<Mapping, Error> {
+        if logical < DIRECT_BLOCKS {
+            let block = read_u32(&inode.block, logical as usize * 4);
+
+            return Ok(Mapping {
+                logical,
+                length: 1,
+                physical: if block == 0 {
+                    None
+                } else {
+                    Some(u64::from(block))
+                },
+            });
+        }
+
+        // The single, double and triple indirect blocks follow the direct ones.
+        let pointers_per_block = self.block_size / 4;
+        let mut index = logical - DIRECT_BLOCKS;
+        let mut level_size = 1;
+        let mut slot = DIRECT_BLOCKS as usize;
+        while index >= pointers_per_block * level_size {
+            index -= pointers_per_block * level_size;
+            level_size *= pointers_per_block;
+            slot += 1;
+
+            if slot > DIRECT_BLOCKS as usize + 2 {
+                return Ok(Mapping::hole(logical, 1));
+            }
+        }
+
+        let mut block = u64::from(read_u32(&inode.block, slot * 4));
+        loop {
+            if block == 0 {
+                return Ok(Mapping::hole(logical, 1));
+            }
+
+            let pointer = index / level_size;
+            let offset = block * self.block_size + pointer * 4;
+            index %= level_size;
+
+            if level_size > 1 {
+                block = u64::from(self.read_block_u32(offset)?);
+                level_size /= pointers_per_block;
+                continue;
+            }
+
+            let first = u64::from(self.read_block_u32(offset)?);
+            if first == 0 {
+                return Ok(Mapping::hole(logical, 1));
+            }
+
+            // Take the following blocks along while they are contiguous.
+            let mut length = 1;
+            while pointer + length < pointers_per_block
+                && u64::from(self.read_block_u32(offset + length * 4)?) == first + length
+            {
+                length += 1;
+            }
+
+            return Ok(Mapping {
+                logical,
+                length,
+                physical: Some(first),
+            });
+        }
+    }
+
+    /// Read from the data of `inode` at `position`, up to its end.
+    fn read_data(
+        &mut self,
+        inode: &Inode,
+        mapping: &mut Option<Mapping>,
+        position: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        let length = cmp::min(buffer.len() as u64, inode.size.saturating_sub(position)) as usize;
+        let mut position = position;
+        let mut done = 0;
+
+        while done < length {
+            let logical = position / self.block_size;
+            let block_offset = position % self.block_size;
+            let run = self.map_block(inode, mapping, logical)?;
+
+            let run_size = (run.logical + run.length - logical) * self.block_size - block_offset;
+            let size = cmp::min(run_size, (length - done) as u64) as usize;
+
+            match run.physical {
+                Some(physical) => {
+                    let offset =
+                        (physical + logical - run.logical) * self.block_size + block_offset;
+                    self.read_bytes(offset, &mut buffer[done..done + size])?;
+                }
+                None => {
+                    for byte in buffer[done..done + size].iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+
+            done += size;
+            position += size as u64;
+        }
+
+        Ok(done)
+    }
+
+    pub fn root_dir(&mut self) -> Result<Dir, Error> {
+        Ok(Dir {
+            inode: self.read_inode(ROOT_INODE)?,
+        })
+    }
+
+    /// The entries of `dir`, "." and ".." included.
+    pub fn read_dir<'f>(&'f mut self, dir: &Dir) -> DirEntries<'f, 'a> {
+        let end = dir.inode.size;
+
+        self.dir_entries(&dir.inode, 0, end)
+    }
+
+    fn dir_entries<'f>(&'f mut self, dir: &Inode, start: u64, end: u64) -> DirEntries<'f, 'a> {
+        DirEntries {
+            file_system: self,
+            dir: *dir,
+            mapping: None,
+            position: start,
+            end,
+            done: false,
+        }
+    }
+
+    fn find_linear(
+        &mut self,
+        dir: &Inode,
+        start: u64,
+        end: u64,
+        name: &[u8],
+    ) -> Result<Option<u32>, Error> {
+        for entry in self.dir_entries(dir, start, end) {
+            let entry = entry?;
+
+            if entry.name() == name {
+                return Ok(Some(entry.inode));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Hash of `name` in hashed directories, `None` if the hash function isn't supported.
+    fn name_hash(&self, version: u8, name: &[u8]) -> Option<u32> {
+        let version = if version <= DX_HASH_TEA && self.unsigned_hash {
+            version + DX_HASH_LEGACY_UNSIGNED
+        } else {
+            version
+        };
+
+        dx_hash(version, name, &self.hash_seed)
+    }
+
+    /// Look `name` up through the index of the hashed directory `dir`.
+    fn find_hashed(&mut self, dir: &Inode, name: &[u8]) -> Result<Option<u32>, Error> {
+        let mut mapping = None;
+        let root = self.dir_block_offset(dir, &mut mapping, 0)?;
+
+        let mut info = [0; 8];
+        self.read_bytes(root + DX_ROOT_INFO_OFFSET, &mut info)?;
+
+        let hash_version = info[4];
+        let info_length = u64::from(info[5]);
+        let levels = info[6];
+        let max_levels = if self.feature_incompat & INCOMPAT_LARGEDIR != 0 {
+            2
+        } else {
+            1
+        };
+
+        if read_u32(&info, 0) != 0 || levels > max_levels {
+            return Ok(None);
+        }
+
+        let hash = match self.name_hash(hash_version, name) {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+
+        let mut node = root + DX_ROOT_INFO_OFFSET + info_length;
+        let mut node_size = self.block_size - DX_ROOT_INFO_OFFSET - info_length;
+        let mut level = 0;
+
+        loop {
+            // The first entry holds the limit and count of entries instead of a hash.
+            let mut count_limit = [0; DX_ENTRY_SIZE as usize];
+            self.read_bytes(node, &mut count_limit)?;
+
+            let limit = u64::from(read_u16(&count_limit, 0));
+            let count = u64::from(read_u16(&count_limit, 2));
+            if count == 0 || count > limit || limit * DX_ENTRY_SIZE > node_size {
+                return Ok(None);
+            }
+
+            let mut block = u64::from(read_u32(&count_limit, 4));
+            for index in 1..count {
+                let mut entry = [0; DX_ENTRY_SIZE as usize];
+                self.read_bytes(node + index * DX_ENTRY_SIZE, &mut entry)?;
+
+                if read_u32(&entry, 0) > hash {
+                    break;
+                }
+
+                block = u64::from(read_u32(&entry, 4));
+            }
+
+            if level == levels {
+                let start = block * self.block_size;
+
+                return self.find_linear(dir, start, start + self.block_size, name);
+            }
+
+            // Index nodes start with an empty entry covering the block.
+            node = self.dir_block_offset(dir, &mut mapping, block)? + DIR_ENTRY_HEADER_SIZE;
+            node_size = self.block_size - DIR_ENTRY_HEADER_SIZE;
+            level += 1;
+        }
+    }
+
+    /// Byte offset on the device of block `logical` of `dir`.
+    fn dir_block_offset(
+        &mut self,
+        dir: &Inode,
+        mapping: &mut Option<Mapping>,
+        logical: u64,
+    ) -> Result<u64, Error> {
+        let run = self.map_block(dir, mapping, logical)?;
+
+        match run.physical {
+            Some(physical) => Ok((physical + logical - run.logical) * self.block_size),
+            None => Err(Error::BadDirectory(dir.number)),
+        }
+    }
+
+    fn find(&mut self, dir: &Inode, name: &[u8]) -> Result<u32, Error> {
+        if dir.flags & INODE_FLAG_INDEX != 0 && self.feature_compat & COMPAT_DIR_INDEX != 0 {
+            if let Ok(Some(inode)) = self.find_hashed(dir, name) {
+                return Ok(inode);
+            }
+        }
+
+        self.find_linear(dir, 0, dir.size, name)?
+            .ok_or(Error::NotFound)
+    }
+
+    /// Follow `path` from the root directory, symbolic links included.
+    fn lookup(&mut self, path: &str) -> Result<Inode, Error> {
+        let mut buffer = [0; MAX_PATH_LENGTH];
+        if path.len() > buffer.len() {
+            return Err(Error::PathTooLong);
+        }
+        buffer[..path.len()].copy_from_slice(path.as_bytes());
+
+        let root = self.read_inode(ROOT_INODE)?;
+        let mut current = root;
+        let mut start = 0;
+        let mut end = path.len();
+        let mut symlinks = 0;
+
+        loop {
+            while start < end && buffer[start] == b'/' {
+                start += 1;
+            }
+
+            if start == end {
+                return Ok(current);
+            }
+
+            let component_end = buffer[start..end]
+                .iter()
+                .position(|byte| *byte == b'/')
+                .map_or(end, |index| start + index);
+            let component = &buffer[start..component_end];
+            start = component_end;
+
+            if current.file_type() != FileType::Directory {
+                return Err(Error::NotADirectory);
+            }
+
+            let dir = current;
+            let inode = self.find(&dir, component)?;
+            current = self.read_inode(inode)?;
+
+            if current.file_type() != FileType::Symlink {
+                continue;
+            }
+
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(Error::TooManySymlinks);
+            }
+
+            // Replace the link by its target in the path left.
+            let target_length = current.size as usize;
+            let rest_length = end - start;
+            if current.size > MAX_PATH_LENGTH as u64
+                || target_length + rest_length > MAX_PATH_LENGTH
+            {
+                return Err(Error::PathTooLong);
+            }
+
+            buffer.copy_within(start..end, MAX_PATH_LENGTH - rest_length);
+
+            // Short targets are stored in the inode instead of the block pointers.
+            if target_length < INODE_BLOCK_SIZE {
+                buffer[..target_length].copy_from_slice(&current.block[..target_length]);
+            } else {
+                let mut mapping = None;
+                self.read_data(&current, &mut mapping, 0, &mut buffer[..target_length])?;
+            }
+
+            buffer.copy_within(MAX_PATH_LENGTH - rest_length.., target_length);
+            start = 0;
+            end = target_length + rest_length;
+
+            current = if target_length > 0 && buffer[0] == b'/' {
+                root
+            } else {
+                dir
+            };
+        }
+    }
+
+    pub fn open_dir(&mut self, path: &str) -> Result<Dir, Error> {
+        let inode = self.lookup(path)?;
+        if inode.file_type() != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        Ok(Dir { inode })
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<File, Error> {
+        let inode = self.lookup(path)?;
+        if inode.file_type() == FileType::Directory {
+            return Err(Error::IsADirectory);
+        }
+
+        Ok(File {
+            inode,
+            position: 0,
+            mapping: None,
+        })
+    }
+
+    /// Read from the position of `file`, returning the size read which is only smaller than
+    /// `buffer` at the end of the file.
+    pub fn read(&mut self, file: &mut File, buffer: &mut [u8]) -> Result<usize, Error> {
+        let size = self.read_data(&file.inode, &mut file.mapping, file.position, buffer)?;
+        file.position += size as u64;
+
+        Ok(size)
+    }
+
+    /// Read the whole file at `path` to `buffer`, returning its size.
+    pub fn read_file(&mut self, path: &str, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut file = self.open(path)?;
+
+        if file.size() > buffer.len() as u64 {
+            return Err(Error::BufferTooSmall(file.size()));
+        }
+
+        self.read(&mut file, buffer)
+    }
+}
+
+/// Iterator over the entries of a directory, between two byte offsets of its data.
+pub struct DirEntries<'f, 'a> {
+    file_system: &'f mut FileSystem<'a>,
+    dir: Inode,
+    mapping: Option<Mapping>,
+    position: u64,
+    end: u64,
+    done: bool,
+}
+
+impl<'f, 'a> DirEntries<'f, 'a> {
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, Error> {
+        let file_system = &mut *self.file_system;
+        let block_size = file_system.block_size;
+
+        while self.position < self.end {
+            let logical = self.position / block_size;
+            let block_offset = self.position % block_size;
+
+            let run = file_system.map_block(&self.dir, &mut self.mapping, logical)?;
+            let physical = match run.physical {
+                Some(physical) => physical + logical - run.logical,
+                None => {
+                    self.position = (logical + 1) * block_size;
+                    continue;
+                }
+            };
+
+            let offset = physical * block_size + block_offset;
+            let mut header = [0; DIR_ENTRY_HEADER_SIZE as usize];
+            file_system.read_bytes(offset, &mut header)?;
+
+            let inode = read_u32(&header, 0);
+            let record_length = match u64::from(read_u16(&header, 4)) {
+                // Records of 64KB blocks don't fit in 16 bits.
+                0 | 0xFFFF if block_size == 0x1_0000 => block_size,
+                length => length,
+            };
+            let (name_length, file_type) = if file_system.feature_incompat & INCOMPAT_FILETYPE != 0
+            {
+                (usize::from(header[6]), FileType::from_dir_entry(header[7]))
+            } else {
+                (usize::from(read_u16(&header, 6)), FileType::Unknown)
+            };
+
+            if record_length < DIR_ENTRY_HEADER_SIZE + name_length as u64
+                || record_length % 4 != 0
+                || block_offset + record_length > block_size
+                || name_length > MAX_NAME_LENGTH
+            {
+                return Err(Error::BadDirectory(self.dir.number));
+            }
+
+            self.position += record_length;
+
+            // Unused entries, also used for the index of hashed directories and checksums.
+            if inode == 0 {
+                continue;
+            }
+
+            let mut entry = DirEntry {
+                inode,
+                file_type,
+                name: [0; MAX_NAME_LENGTH],
+                name_length,
+            };
+            file_system.read_bytes(
+                offset + DIR_ENTRY_HEADER_SIZE,
+                &mut entry.name[..name_length],
+            )?;
+
+            return Ok(Some(entry));
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'f, 'a> Iterator for DirEntries<'f, 'a> {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Hash of `name` with the function `version` of hashed directories, `None` if it isn't
+/// supported.
+fn dx_hash(version: u8, name: &[u8], seed: &[u32; 4]) -> Option<u32> {
+    let mut state = if *seed == [0; 4] {
+        DX_DEFAULT_SEED
+    } else {
+        *seed
+    };
+
+    let hash = match version {
+        DX_HASH_LEGACY | DX_HASH_LEGACY_UNSIGNED => legacy_hash(name, version == DX_HASH_LEGACY),
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            for (index, chunk) in name.chunks(32).enumerate() {
+                let mut input = [0; 8];
+                let remaining = name.len() - index * 32;
+                hash_buffer(chunk, remaining, version == DX_HASH_HALF_MD4, &mut input);
+                half_md4_transform(&mut state, &input);
+            }
+
+            state[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            for (index, chunk) in name.chunks(16).enumerate() {
+                let mut input = [0; 4];
+                let remaining = name.len() - index * 16;
+                hash_buffer(chunk, remaining, version == DX_HASH_TEA, &mut input);
+                tea_transform(&mut state, &input);
+            }
+
+            state[0]
+        }
+        _ => return None,
+    };
+
+    // The last hash value is reserved as an end marker.
+    let hash = hash & !1;
+    Some(if hash == 0xFFFF_FFFE {
+        0xFFFF_FFFC
+    } else {
+        hash
+    })
+}
+
+/// Pack a chunk of name to the input of the hash functions, the padding depending on the
+/// length of the name left.
+fn hash_buffer(chunk: &[u8], remaining: usize, signed: bool, buffer: &mut [u32]) {
+    let length = remaining as u32;
+    let mut pad = length | length << 8;
+    pad |= pad << 16;
+
+    let mut value = pad;
+    let mut words = 0;
+    for (index, byte) in chunk.iter().enumerate() {
+        let c = if signed {
+            *byte as i8 as u32
+        } else {
+            u32::from(*byte)
+        };
+
+        value = c.wrapping_add(value << 8);
+        if index % 4 == 3 {
+            buffer[words] = value;
+            words += 1;
+            value = pad;
+        }
+    }
+
+    if words < buffer.len() {
+        buffer[words] = value;
+        words += 1;
+    }
+
+    for word in buffer[words..].iter_mut() {
+        *word = pad;
+    }
+}
+
+fn legacy_hash(name: &[u8], signed: bool) -> u32 {
+    let mut hash0: u32 = 0x12A3_FE2D;
+    let mut hash1: u32 = 0x37AB_E8F9;
+
+    for byte in name {
+        let c = if signed {
+            i32::from(*byte as i8)
+        } else {
+            i32::from(*byte)
+        };
+
+        let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7_152_373) as u32);
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7FFF_FFFF);
+        }
+
+        hash1 = hash0;
+        hash0 = hash;
+    }
+
+    hash0 << 1
+}
+
+fn md4_f(x: u32, y: u32, z: u32) -> u32 {
+    z ^ (x & (y ^ z))
+}
+
+fn md4_g(x: u32, y: u32, z: u32) -> u32 {
+    (x & y).wrapping_add((x ^ y) & z)
+}
+
+fn md4_h(x: u32, y: u32, z: u32) -> u32 {
+    x ^ y ^ z
+}
+
+fn half_md4_transform(state: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0x5A82_7999;
+    const K3: u32 = 0x6ED9_EBA1;
+
+    let [mut a, mut b, mut c, mut d] = *state;
+
+    macro_rules! round {
+        ($f:expr, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a
+                .wrapping_add($f($b, $c, $d))
+                .wrapping_add($x)
+                .rotate_left($s);
+        };
+    }
+
+    round!(md4_f, a, b, c, d, input[0], 3);
+    round!(md4_f, d, a, b, c, input[1], 7);
+    round!(md4_f, c, d, a, b, input[2], 11);
+    round!(md4_f, b, c, d, a, input[3], 19);
+    round!(md4_f, a, b, c, d, input[4], 3);
+    round!(md4_f, d, a, b, c, input[5], 7);
+    round!(md4_f, c, d, a, b, input[6], 11);
+    round!(md4_f, b, c, d, a, input[7], 19);
+
+    round!(md4_g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(md4_g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(md4_g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(md4_g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(md4_g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(md4_g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(md4_g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(md4_g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(md4_h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(md4_h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(md4_h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(md4_h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(md4_h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(md4_h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(md4_h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(md4_h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+}
+
+fn tea_transform(state: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9E37_79B9;
+
+    let mut sum: u32 = 0;
+    let mut b0 = state[0];
+    let mut b1 = state[1];
+    let [a, b, c, d] = *input;
+
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+
+    state[0] = state[0].wrapping_add(b0);
+    state[1] = state[1].wrapping_add(b1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MemoryDevice};
+
+    const BLOCK: usize = 1024;
+    const INODE_TABLE: usize = 4;
+    const INODE_SIZE: usize = 256;
+
+    const SEED: [u32; 4] = [0x3322_1100, 0x7766_5544, 0xBBAA_9988, 0xFFEE_DDCC];
+
+    const BOOT: u32 = 12;
+    const KERNEL: u32 = 16;
+    const OLD: u32 = 17;
+    const DTB: u32 = 19;
+    const EMPTY: u32 = 20;
+    const CURRENT: u32 = 21;
+
+    /// A 512KB filesystem with 1KB blocks, a single group and extents, for the trees and
+    /// directories mke2fs doesn't write.
+    struct Image {
+        data: Vec<u8>,
+    }
+
+    impl Image {
+        fn new() -> Self {
+            let mut image = Image {
+                data: vec![0; 512 * BLOCK],
+            };
+
+            let superblock = &mut image.data[1024..2048];
+            superblock[0..4].copy_from_slice(&64u32.to_le_bytes());
+            superblock[4..8].copy_from_slice(&512u32.to_le_bytes());
+            superblock[20..24].copy_from_slice(&1u32.to_le_bytes());
+            superblock[32..36].copy_from_slice(&8192u32.to_le_bytes());
+            superblock[40..44].copy_from_slice(&64u32.to_le_bytes());
+            superblock[56..58].copy_from_slice(&SUPERBLOCK_MAGIC.to_le_bytes());
+            superblock[76..80].copy_from_slice(&1u32.to_le_bytes());
+            superblock[88..90].copy_from_slice(&(INODE_SIZE as u16).to_le_bytes());
+            superblock[92..96].copy_from_slice(&COMPAT_DIR_INDEX.to_le_bytes());
+            // Extents, which don't change the layout of the filesystem.
+            superblock[96..100].copy_from_slice(&(INCOMPAT_FILETYPE | 0x40).to_le_bytes());
+            for (index, word) in SEED.iter().enumerate() {
+                superblock[236 + index * 4..240 + index * 4].copy_from_slice(&word.to_le_bytes());
+            }
+
+            let descriptor = &mut image.block(2)[..GOOD_OLD_DESC_SIZE as usize];
+            descriptor[8..12].copy_from_slice(&(INODE_TABLE as u32).to_le_bytes());
+
+            image
+        }
+
+        fn block(&mut self, block: u64) -> &mut [u8] {
+            let offset = block as usize * BLOCK;
+
+            &mut self.data[offset..offset + BLOCK]
+        }
+
+        fn inode(&mut self, number: u32) -> &mut [u8] {
+            let offset = INODE_TABLE * BLOCK + (number as usize - 1) * INODE_SIZE;
+
+            &mut self.data[offset..offset + INODE_SIZE]
+        }
+
+        fn write_inode(&mut self, number: u32, mode: u16, flags: u32, size: u64, block: &[u8]) {
+            let inode = self.inode(number);
+            inode[0..2].copy_from_slice(&mode.to_le_bytes());
+            inode[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+            inode[32..36].copy_from_slice(&flags.to_le_bytes());
+            inode[40..40 + block.len()].copy_from_slice(block);
+            inode[108..112].copy_from_slice(&((size >> 32) as u32).to_le_bytes());
+        }
+
+        /// Write an inode whose data is the single extent of `data`, starting at `block`.
+        fn write_extent_inode(&mut self, number: u32, mode: u16, data: &[u8], block: u64) {
+            let blocks = (data.len() + BLOCK - 1) / BLOCK;
+            for (index, chunk) in data.chunks(BLOCK).enumerate() {
+                self.block(block + index as u64)[..chunk.len()].copy_from_slice(chunk);
+            }
+
+            let root = extent_node(0, 4, &[leaf(0, blocks as u16, block)]);
+            self.write_inode(number, mode, INODE_FLAG_EXTENTS, data.len() as u64, &root);
+        }
+
+        fn set_flags(&mut self, flags: u32) {
+            self.data[1024 + 352..1024 + 356].copy_from_slice(&flags.to_le_bytes());
+        }
+    }
+
+    fn extent_node(depth: u16, max: u16, entries: &[[u8; 12]]) -> Vec<u8> {
+        let mut node = vec![0; 12];
+        node[0..2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        node[2..4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        node[4..6].copy_from_slice(&max.to_le_bytes());
+        node[6..8].copy_from_slice(&depth.to_le_bytes());
+        for entry in entries {
+            node.extend_from_slice(entry);
+        }
+        node
+    }
+
+    fn leaf(first: u32, length: u16, start: u64) -> [u8; 12] {
+        let mut entry = [0; 12];
+        entry[0..4].copy_from_slice(&first.to_le_bytes());
+        entry[4..6].copy_from_slice(&length.to_le_bytes());
+        entry[6..8].copy_from_slice(&((start >> 32) as u16).to_le_bytes());
+        entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+        entry
+    }
+
+    fn index(first: u32, node: u64) -> [u8; 12] {
+        let mut entry = [0; 12];
+        entry[0..4].copy_from_slice(&first.to_le_bytes());
+        entry[4..8].copy_from_slice(&(node as u32).to_le_bytes());
+        entry[8..10].copy_from_slice(&((node >> 32) as u16).to_le_bytes());
+        entry
+    }
+
+    /// A directory block, the last entry spanning the rest of it.
+    fn dir_block(entries: &[(u32, u8, &[u8])]) -> Vec<u8> {
+        let mut block = Vec::new();
+        for (position, (inode, file_type, name)) in entries.iter().enumerate() {
+            let record_length = if position + 1 == entries.len() {
+                BLOCK - block.len()
+            } else {
+                (8 + name.len() + 3) & !3
+            };
+
+            let start = block.len();
+            block.resize(start + record_length, 0);
+            block[start..start + 4].copy_from_slice(&inode.to_le_bytes());
+            block[start + 4..start + 6].copy_from_slice(&(record_length as u16).to_le_bytes());
+            block[start + 6] = name.len() as u8;
+            block[start + 7] = *file_type;
+            block[start + 8..start + 8 + name.len()].copy_from_slice(name);
+        }
+
+        assert_eq!(block.len(), BLOCK);
+        block
+    }
+
+    /// The kernel, mapped by a tree of depth 2 with holes and an uninitialized extent.
+    fn write_kernel(image: &mut Image) -> Vec<u8> {
+        let size = 12 * BLOCK - 100;
+        let data = test_support::pattern(size, 1);
+        let mut expected = data.clone();
+
+        // Blocks 3, 4, 7, 8 and 9 read as zeros.
+        for (first, length, start) in [(0, 3, 100), (5, 2, 110), (10, 2, 130)].iter() {
+            for block in 0..*length {
+                let offset = (first + block) * BLOCK;
+                let end = cmp::min(offset + BLOCK, size);
+                image.block(start + block as u64)[..end - offset]
+                    .copy_from_slice(&data[offset..end]);
+            }
+        }
+        for (start, end) in [(3, 5), (7, 10)].iter() {
+            for byte in expected[start * BLOCK..end * BLOCK].iter_mut() {
+                *byte = 0;
+            }
+        }
+        image.block(120).copy_from_slice(&[0xAA; BLOCK]);
+
+        let first_leaf = extent_node(0, 84, &[leaf(0, 3, 100), leaf(5, 2, 110)]);
+        image.block(51)[..first_leaf.len()].copy_from_slice(&first_leaf);
+        let second_leaf = extent_node(
+            0,
+            84,
+            &[
+                leaf(8, 2 + MAX_INITIALIZED_EXTENT_LENGTH, 120),
+                leaf(10, 2, 130),
+            ],
+        );
+        image.block(52)[..second_leaf.len()].copy_from_slice(&second_leaf);
+        let node = extent_node(1, 84, &[index(0, 51), index(8, 52)]);
+        image.block(50)[..node.len()].copy_from_slice(&node);
+
+        let root = extent_node(2, 4, &[index(0, 50)]);
+        image.write_inode(
+            KERNEL,
+            MODE_FILE | 0o644,
+            INODE_FLAG_EXTENTS,
+            size as u64,
+            &root,
+        );
+
+        expected
+    }
+
+    /// A file of an ext2 filesystem, through direct and single indirect blocks.
+    fn write_old_file(image: &mut Image) -> Vec<u8> {
+        let data = test_support::pattern(14 * BLOCK, 2);
+        let blocks = [
+            200, 201, 202, 203, 204, 205, 210, 211, 212, 213, 214, 215, 231, 232,
+        ];
+
+        let mut pointers = [0; INODE_BLOCK_SIZE];
+        for (index, block) in blocks.iter().enumerate() {
+            image
+                .block(*block)
+                .copy_from_slice(&data[index * BLOCK..(index + 1) * BLOCK]);
+
+            let pointer = (*block as u32).to_le_bytes();
+            if index < DIRECT_BLOCKS as usize {
+                pointers[index * 4..index * 4 + 4].copy_from_slice(&pointer);
+            } else {
+                let offset = (index - DIRECT_BLOCKS as usize) * 4;
+                image.block(230)[offset..offset + 4].copy_from_slice(&pointer);
+            }
+        }
+        pointers[48..52].copy_from_slice(&230u32.to_le_bytes());
+        image.write_inode(OLD, MODE_FILE | 0o644, 0, data.len() as u64, &pointers);
+
+        data
+    }
+
+    /// The hashed boot directory, `decoy` being in the leaf its hash doesn't lead to.
+    fn write_boot(image: &mut Image, version: u8, decoy: u32) {
+        let kernel_hash = dx_hash(version, b"Image", &SEED).unwrap();
+
+        let names: Vec<Vec<u8>> = (0..20)
+            .map(|index| format!("file{:02}", index).into_bytes())
+            .collect();
+        let mut leaves: [Vec<(u32, u8, &[u8])>; 2] = [Vec::new(), Vec::new()];
+        // Found by a linear search before the entry the index leads to.
+        leaves[0].push((decoy, 1, b"Image"));
+        leaves[1].push((KERNEL, 1, b"Image"));
+        for name in names.iter() {
+            let leaf = (dx_hash(version, name, &SEED).unwrap() >= kernel_hash) as usize;
+            leaves[leaf].push((EMPTY, 1, name));
+        }
+        leaves[1].push((DTB, 1, b"tegra210-p2371-2180.dtb"));
+        leaves[1].push((CURRENT, 7, b"current"));
+
+        let mut root = dir_block(&[(BOOT, 2, b"."), (2, 2, b"..")]);
+        root[24 + 4] = version;
+        root[24 + 5] = 8;
+        // Limit and count, then the blocks of the hashes from 0 and from the kernel's.
+        root[32..34].copy_from_slice(&124u16.to_le_bytes());
+        root[34..36].copy_from_slice(&2u16.to_le_bytes());
+        root[36..40].copy_from_slice(&1u32.to_le_bytes());
+        root[40..44].copy_from_slice(&kernel_hash.to_le_bytes());
+        root[44..48].copy_from_slice(&2u32.to_le_bytes());
+
+        image.block(33).copy_from_slice(&root);
+        image.block(34).copy_from_slice(&dir_block(&leaves[0]));
+        image.block(35).copy_from_slice(&dir_block(&leaves[1]));
+
+        let extents = extent_node(0, 4, &[leaf(0, 3, 33)]);
+        image.write_inode(
+            BOOT,
+            MODE_DIRECTORY | 0o755,
+            INODE_FLAG_EXTENTS | INODE_FLAG_INDEX,
+            3 * BLOCK as u64,
+            &extents,
+        );
+    }
+
+    fn tree() -> (Image, Vec<u8>, Vec<u8>) {
+        let mut image = Image::new();
+
+        let root = dir_block(&[
+            (2, 2, b"."),
+            (2, 2, b".."),
+            (BOOT, 2, b"boot"),
+            (OLD, 1, b"old"),
+        ]);
+        image.write_extent_inode(2, MODE_DIRECTORY | 0o755, &root, 32);
+        write_boot(&mut image, DX_HASH_HALF_MD4, EMPTY);
+
+        let kernel = write_kernel(&mut image);
+        let old = write_old_file(&mut image);
+        image.write_extent_inode(DTB, MODE_FILE | 0o644, b"dtb", 140);
+        image.write_inode(
+            EMPTY,
+            MODE_FILE | 0o644,
+            INODE_FLAG_EXTENTS,
+            0,
+            &extent_node(0, 4, &[]),
+        );
+
+        image.write_inode(CURRENT, MODE_SYMLINK | 0o777, 0, 7, b"../boot");
+
+        (image, kernel, old)
+    }
+
+    #[test]
+    fn hash_functions() {
+        let vectors: [(&[u8], [u32; 3]); 5] = [
+            (b"", [0x2547_FC5A, 0xEFCD_AB88, 0x6745_2300]),
+            (b"Image", [0x3D45_57C0, 0x8471_A692, 0x0A70_358E]),
+            (
+                b"a-name-of-exactly-thirty-two-byt",
+                [0xDB4F_F7B8, 0xDD9C_EBB4, 0x6B8F_969E],
+            ),
+            (
+                b"a name longer than thirty two bytes, over two chunks",
+                [0xF307_85DA, 0xF4B5_3C0E, 0xDB4E_B2C0],
+            ),
+            ("café".as_bytes(), [0x96CA_5A2C, 0xFB9C_5E5C, 0x1058_42EA]),
+        ];
+
+        // The default seed is used when the superblock has none.
+        for (name, hashes) in vectors.iter() {
+            for (version, hash) in hashes.iter().enumerate() {
+                assert_eq!(dx_hash(version as u8, name, &[0; 4]), Some(*hash));
+                assert_eq!(dx_hash(version as u8, name, &DX_DEFAULT_SEED), Some(*hash));
+            }
+        }
+
+        // Only bytes above 0x7F hash differently unsigned.
+        assert_eq!(
+            dx_hash(DX_HASH_LEGACY_UNSIGNED, b"Image", &[0; 4]),
+            Some(0x3D45_57C0)
+        );
+        let cafe = "café".as_bytes();
+        assert_eq!(
+            dx_hash(DX_HASH_LEGACY_UNSIGNED, cafe, &[0; 4]),
+            Some(0x6DDE_4230)
+        );
+        assert_eq!(
+            dx_hash(DX_HASH_HALF_MD4_UNSIGNED, cafe, &[0; 4]),
+            Some(0x9D72_AED6)
+        );
+        assert_eq!(
+            dx_hash(DX_HASH_TEA_UNSIGNED, cafe, &[0; 4]),
+            Some(0x6621_F032)
+        );
+
+        assert_eq!(
+            dx_hash(DX_HASH_HALF_MD4, b"Image", &SEED),
+            Some(0x4B74_5CC6)
+        );
+        assert_eq!(dx_hash(DX_HASH_HALF_MD4, cafe, &SEED), Some(0x5DE1_249A));
+        assert_eq!(
+            dx_hash(DX_HASH_HALF_MD4_UNSIGNED, cafe, &SEED),
+            Some(0xDAEC_8672)
+        );
+        assert_eq!(dx_hash(DX_HASH_TEA, b"Image", &SEED), Some(0x567F_7746));
+        assert_eq!(dx_hash(DX_HASH_TEA, cafe, &SEED), Some(0x6435_16DC));
+        assert_eq!(
+            dx_hash(DX_HASH_TEA_UNSIGNED, cafe, &SEED),
+            Some(0x6AAF_4150)
+        );
+
+        // The legacy hash isn't seeded.
+        assert_eq!(dx_hash(DX_HASH_LEGACY, b"Image", &SEED), Some(0x3D45_57C0));
+        assert_eq!(dx_hash(6, b"Image", &SEED), None);
+    }
+
+    #[test]
+    fn extent_trees() {
+        let (image, kernel, old) = tree();
+        let mut device = MemoryDevice(image.data);
+        let mut file_system = FileSystem::new(&mut device).unwrap();
+        assert_eq!(file_system.block_size(), 1024);
+
+        let mut buffer = vec![0xFF; 0x8000];
+        let size = file_system.read_file("boot/Image", &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &kernel[..]);
+
+        let size = file_system.read_file("old", &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &old[..]);
+
+        let size = file_system
+            .read_file("boot/tegra210-p2371-2180.dtb", &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer[..size], b"dtb");
+        assert_eq!(file_system.read_file("boot/file00", &mut buffer), Ok(0));
+
+        // Across holes and leaves, backwards.
+        let mut file = file_system.open("boot/Image").unwrap();
+        let mut buffer = [0; 1500];
+        for position in [6500, 2000, 9000, 0, 11000].iter() {
+            file.seek(*position);
+            let size = file_system.read(&mut file, &mut buffer).unwrap();
+            let end = cmp::min(*position as usize + buffer.len(), kernel.len());
+
+            assert_eq!(&buffer[..size], &kernel[*position as usize..end]);
+        }
+
+        assert_eq!(
+            file_system.read_file("boot/Image", &mut buffer),
+            Err(Error::BufferTooSmall(kernel.len() as u64))
+        );
+    }
+
+    #[test]
+    fn bad_extent_trees() {
+        let check = |edit: &dyn Fn(&mut Image)| {
+            let (mut image, _, _) = tree();
+            edit(&mut image);
+
+            let mut device = MemoryDevice(image.data);
+            let mut file_system = FileSystem::new(&mut device).unwrap();
+            let mut buffer = vec![0; 0x8000];
+
+            file_system.read_file("boot/Image", &mut buffer)
+        };
+
+        assert_eq!(check(&|_| {}), Ok(12 * BLOCK - 100));
+        assert_eq!(
+            check(&|image| image.block(52)[0] = 0),
+            Err(Error::BadExtentTree(KERNEL))
+        );
+        // A leaf where an index node is expected.
+        assert_eq!(
+            check(&|image| image.block(50)[6] = 0),
+            Err(Error::BadExtentTree(KERNEL))
+        );
+        assert_eq!(
+            check(&|image| image.block(51)[6] = 1),
+            Err(Error::BadExtentTree(KERNEL))
+        );
+        // More entries than fit in the inode or a block.
+        assert_eq!(
+            check(&|image| image.inode(KERNEL)[42] = 5),
+            Err(Error::BadExtentTree(KERNEL))
+        );
+        assert_eq!(
+            check(&|image| image.block(51)[2] = 90),
+            Err(Error::BadExtentTree(KERNEL))
+        );
+        assert_eq!(
+            check(&|image| image.inode(KERNEL)[32..36]
+                .copy_from_slice(&(INODE_FLAG_EXTENTS | INODE_FLAG_INLINE_DATA).to_le_bytes())),
+            Err(Error::UnsupportedInode(KERNEL))
+        );
+    }
+
+    #[test]
+    fn hashed_directories() {
+        for (version, unsigned) in [(DX_HASH_HALF_MD4, false), (DX_HASH_TEA, true)].iter() {
+            let (mut image, kernel, _) = tree();
+            if *unsigned {
+                image.set_flags(FLAGS_UNSIGNED_HASH);
+                write_boot(&mut image, *version, EMPTY);
+            }
+
+            let mut device = MemoryDevice(image.data);
+            let mut file_system = FileSystem::new(&mut device).unwrap();
+            let mut buffer = vec![0; 0x8000];
+
+            // The index leads to the second leaf.
+            let size = file_system.read_file("boot/Image", &mut buffer).unwrap();
+            assert_eq!(&buffer[..size], &kernel[..]);
+
+            let boot = file_system.open_dir("boot").unwrap();
+            let entries: Vec<DirEntry> = file_system.read_dir(&boot).map(Result::unwrap).collect();
+            assert_eq!(entries.len(), 26);
+            assert_eq!(entries[2].name(), b"Image");
+            assert_eq!(entries[2].inode, EMPTY);
+            assert_eq!(entries[3].file_type, FileType::File);
+
+            for index in 0..20 {
+                let path = format!("boot/file{:02}", index);
+                assert_eq!(file_system.read_file(&path, &mut buffer), Ok(0));
+            }
+            assert_eq!(
+                file_system.open("boot/missing").err(),
+                Some(Error::NotFound)
+            );
+        }
+
+        // Names in the wrong leaf and unknown hash functions fall back to a linear search.
+        for version in [DX_HASH_LEGACY, 6].iter() {
+            let (mut image, _, _) = tree();
+            write_boot(&mut image, DX_HASH_HALF_MD4, DTB);
+            image.block(33)[24 + 4] = *version;
+
+            let mut device = MemoryDevice(image.data);
+            let mut file_system = FileSystem::new(&mut device).unwrap();
+            let mut buffer = vec![0; 0x8000];
+
+            let size = file_system.read_file("boot/Image", &mut buffer).unwrap();
+            assert_eq!(&buffer[..size], b"dtb");
+            assert!(file_system.open("boot/file19").is_ok());
+        }
+    }
+
+    #[test]
+    fn mke2fs_image() {
+        let mut device = MemoryDevice(test_support::image("ext4"));
+        let mut file_system = FileSystem::new(&mut device).unwrap();
+        assert_eq!(file_system.block_size(), 1024);
+
+        // Fragmented over an extent tree of depth 1.
+        let kernel = test_support::pattern(12 * BLOCK - 100, 1);
+        let mut buffer = vec![0xFF; 0xC000];
+        let size = file_system.read_file("boot/Image", &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &kernel[..]);
+
+        let mut sparse = vec![0; 41160];
+        sparse[..1500].copy_from_slice(&test_support::pattern(1500, 4));
+        sparse[10240..12240].copy_from_slice(&test_support::pattern(2000, 5));
+        sparse[41060..].copy_from_slice(&test_support::pattern(100, 6));
+        let size = file_system.read_file("sparse", &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &sparse[..]);
+
+        let size = file_system
+            .read_file("boot/extlinux/extlinux.conf", &mut buffer)
+            .unwrap();
+        assert_eq!(
+            &buffer[..size],
+            &b"DEFAULT primary\nLABEL primary\n  LINUX /boot/Image\n"[..]
+        );
+
+        // Indexed by e2fsck, with the default half MD4 hash.
+        let boot = file_system.open_dir("boot").unwrap();
+        assert_ne!(boot.inode.flags & INODE_FLAG_INDEX, 0);
+        // ".", "..", the files, the fillers left, the kernel, dtb, extlinux and current.
+        assert_eq!(file_system.read_dir(&boot).count(), 2 + 60 + 5 + 4);
+        for index in 0..60 {
+            let path = format!("boot/file{:02}", index);
+            assert_eq!(file_system.read_file(&path, &mut buffer), Ok(0));
+        }
+        assert_eq!(
+            file_system.open("boot/filler1").err(),
+            Some(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn symlinks() {
+        let mut device = MemoryDevice(test_support::image("ext4"));
+        let mut file_system = FileSystem::new(&mut device).unwrap();
+        let dtb = test_support::pattern(3000, 3);
+        let mut buffer = vec![0; 0x8000];
+
+        // Fast, relative to the directory of the link.
+        let size = file_system.read_file("/vmlinuz", &mut buffer).unwrap();
+        assert_eq!(size, 12 * BLOCK - 100);
+        // Slow, absolute and through more links.
+        let size = file_system.read_file("dtb", &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &dtb[..]);
+        let size = file_system
+            .read_file("boot/current/current/tegra210-p2371-2180.dtb", &mut buffer)
+            .unwrap();
+        assert_eq!(size, dtb.len());
+
+        let dir = file_system.open_dir("boot/current").unwrap();
+        assert_eq!(file_system.read_dir(&dir).count(), 71);
+
+        assert_eq!(file_system.open("loop").err(), Some(Error::TooManySymlinks));
+        assert_eq!(file_system.open("long").err(), Some(Error::NotFound));
+        assert_eq!(
+            file_system.open("long/and/more").err(),
+            Some(Error::PathTooLong)
+        );
+        assert_eq!(
+            file_system.open("vmlinuz/x").err(),
+            Some(Error::NotADirectory)
+        );
+        assert_eq!(
+            file_system.open("boot/current").err(),
+            Some(Error::IsADirectory)
+        );
+    }
+
+    #[test]
+    fn bad_superblocks() {
+        let check = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut data = test_support::image("ext4");
+            edit(&mut data);
+
+            let mut device = MemoryDevice(data);
+            FileSystem::new(&mut device).err()
+        };
+
+        assert_eq!(check(&|_| {}), None);
+        assert_eq!(check(&|data| data[1024 + 56] = 0), Some(Error::NotExt4));
+        assert_eq!(check(&|data| data[1024 + 24] = 7), Some(Error::NotExt4));
+        assert_eq!(check(&|data| data[1024 + 88] = 200), Some(Error::NotExt4));
+        assert_eq!(
+            check(&|data| data[1024 + 96] |= INCOMPAT_COMPRESSION as u8),
+            Some(Error::UnsupportedFeatures(INCOMPAT_COMPRESSION))
+        );
+    }
+}
@@ -0,0 +1,649 @@
+//! Parser for the extlinux.conf boot menus of syslinux, as read by cboot and U-Boot.
+//!
+//! The configuration is validated once by [`Config::parse`], entries are then read from the
+//! text again when needed so nothing is copied. Keywords are case-insensitive, unknown ones are
+//! ignored with a warning.
+//!
+//! Only `core` is used, the module can be built and tested on the host.
+
+use core::fmt;
+use core::str;
+
+/// Where cboot and U-Boot look for the configuration, on the root filesystem.
+pub const CONFIG_PATH: &str = "/boot/extlinux/extlinux.conf";
+/// Directory relative paths of the configuration start from.
+pub const CONFIG_DIRECTORY: &str = "/boot/extlinux";
+
+/// Variable of `APPEND` replaced by the command line of the bootloader, as cboot does.
+const CBOOTARGS_VARIABLE: &str = "${cbootargs}";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Keyword {
+    Label,
+    MenuLabel,
+    MenuDefault,
+    MenuTitle,
+    Linux,
+    Initrd,
+    Fdt,
+    FdtOverlays,
+    Append,
+    Default,
+    Timeout,
+}
+
+const KEYWORDS: [(&str, Keyword); 13] = [
+    ("LABEL", Keyword::Label),
+    ("MENU LABEL", Keyword::MenuLabel),
+    ("MENU DEFAULT", Keyword::MenuDefault),
+    ("MENU TITLE", Keyword::MenuTitle),
+    ("LINUX", Keyword::Linux),
+    ("KERNEL", Keyword::Linux),
+    ("INITRD", Keyword::Initrd),
+    ("FDT", Keyword::Fdt),
+    ("DEVICETREE", Keyword::Fdt),
+    ("FDTOVERLAYS", Keyword::FdtOverlays),
+    ("APPEND", Keyword::Append),
+    ("DEFAULT", Keyword::Default),
+    ("TIMEOUT", Keyword::Timeout),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// The keyword needs an argument.
+    MissingArgument(&'static str),
+    /// The keyword only applies to a `LABEL`.
+    OutsideLabel(&'static str),
+    /// The keyword was already given for this label.
+    DuplicateKeyword(&'static str),
+    DuplicateLabel,
+    /// The label has no `LINUX`.
+    MissingKernel,
+    InvalidTimeout,
+    /// `DEFAULT` names no label.
+    UnknownDefault,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Error {
+    /// Line of the error, from 1.
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+
+        match self.kind {
+            ErrorKind::MissingArgument(keyword) => write!(f, "{} needs an argument", keyword),
+            ErrorKind::OutsideLabel(keyword) => write!(f, "{} outside of a LABEL", keyword),
+            ErrorKind::DuplicateKeyword(keyword) => write!(f, "{} given twice", keyword),
+            ErrorKind::DuplicateLabel => write!(f, "label defined twice"),
+            ErrorKind::MissingKernel => write!(f, "label without LINUX"),
+            ErrorKind::InvalidTimeout => write!(f, "invalid TIMEOUT"),
+            ErrorKind::UnknownDefault => write!(f, "DEFAULT names no label"),
+        }
+    }
+}
+
+/// Split the first word of `text` from the rest.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+
+    match text.find(char::is_whitespace) {
+        Some(index) => (&text[..index], text[index..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+/// The argument following `keyword` at the start of `line`, if it's there.
+fn strip_keyword<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let mut rest = line;
+
+    for word in keyword.split(' ') {
+        let (first, remaining) = split_word(rest);
+        if !first.eq_ignore_ascii_case(word) {
+            return None;
+        }
+
+        rest = remaining;
+    }
+
+    Some(rest)
+}
+
+/// A line as a keyword and its argument, `Ok(None)` for blank lines and comments and
+/// `Err(None)` for unknown keywords.
+fn parse_line(line: &str) -> Result<Option<(Keyword, &str)>, Option<ErrorKind>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let (name, keyword, argument) = KEYWORDS
+        .iter()
+        .find_map(|(name, keyword)| {
+            strip_keyword(line, name).map(|argument| (*name, *keyword, argument))
+        })
+        .ok_or(None)?;
+
+    let needs_argument = !matches!(keyword, Keyword::MenuDefault | Keyword::Append);
+
+    if needs_argument && argument.is_empty() {
+        return Err(Some(ErrorKind::MissingArgument(name)));
+    }
+
+    Ok(Some((keyword, argument)))
+}
+
+/// The command line given by `APPEND`, syslinux taking a lone "-" as no command line at all.
+fn append_argument(argument: &str) -> &str {
+    if argument == "-" {
+        ""
+    } else {
+        argument
+    }
+}
+
+fn keyword_name(keyword: Keyword) -> &'static str {
+    KEYWORDS
+        .iter()
+        .find(|(_, other)| *other == keyword)
+        .map_or("", |(name, _)| *name)
+}
+
+/// A `LABEL` and its settings.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Entry<'a> {
+    pub label: &'a str,
+    pub menu_label: Option<&'a str>,
+    pub linux: Option<&'a str>,
+    pub initrd: Option<&'a str>,
+    /// Device tree, the bootloader providing one when missing as in the stock L4T entries.
+    pub fdt: Option<&'a str>,
+    fdt_overlays: Option<&'a str>,
+    /// Kernel command line, which replaces the one of the device tree.
+    pub append: Option<&'a str>,
+    /// The entry has `MENU DEFAULT`.
+    pub is_menu_default: bool,
+    /// Line of the `LABEL`, from 1.
+    pub line: usize,
+}
+
+impl<'a> Entry<'a> {
+    /// The name to show in menus.
+    pub fn title(&self) -> &'a str {
+        self.menu_label.unwrap_or(self.label)
+    }
+
+    /// Paths of the device tree overlays to apply.
+    pub fn fdt_overlays(&self) -> impl Iterator<Item = &'a str> {
+        self.fdt_overlays.unwrap_or("").split_whitespace()
+    }
+
+    fn set(&mut self, keyword: Keyword, argument: &'a str) {
+        match keyword {
+            Keyword::MenuLabel => self.menu_label = Some(argument),
+            Keyword::MenuDefault => self.is_menu_default = true,
+            Keyword::Linux => self.linux = Some(argument),
+            Keyword::Initrd => self.initrd = Some(argument),
+            Keyword::Fdt => self.fdt = Some(argument),
+            Keyword::FdtOverlays => self.fdt_overlays = Some(argument),
+            Keyword::Append => self.append = Some(append_argument(argument)),
+            Keyword::Label | Keyword::MenuTitle | Keyword::Default | Keyword::Timeout => {}
+        }
+    }
+}
+
+/// Iterator over the entries of a [`Config`].
+pub struct Entries<'a> {
+    lines: core::iter::Enumerate<str::Lines<'a>>,
+    pending: Option<Entry<'a>>,
+    /// `APPEND` given outside of the labels, used by those without one.
+    default_append: Option<&'a str>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        let mut finished = None;
+
+        while let Some((index, line)) = self.lines.next() {
+            let (keyword, argument) = match parse_line(line) {
+                Ok(Some(directive)) => directive,
+                _ => continue,
+            };
+
+            if keyword == Keyword::Label {
+                let entry = Entry {
+                    label: argument,
+                    line: index + 1,
+                    ..Entry::default()
+                };
+
+                finished = self.pending.replace(entry);
+                if finished.is_some() {
+                    break;
+                }
+            } else if let Some(entry) = self.pending.as_mut() {
+                entry.set(keyword, argument);
+            }
+        }
+
+        let mut entry = finished.or_else(|| self.pending.take())?;
+        if entry.append.is_none() {
+            entry.append = self.default_append;
+        }
+
+        Some(entry)
+    }
+}
+
+/// A validated configuration.
+#[derive(Copy, Clone, Debug)]
+pub struct Config<'a> {
+    text: &'a str,
+    pub default: Option<&'a str>,
+    pub menu_title: Option<&'a str>,
+    /// Time to wait for a choice in tenths of a second, 0 waiting forever and `None` booting
+    /// the default entry right away.
+    pub timeout: Option<u32>,
+    append: Option<&'a str>,
+}
+
+impl<'a> Config<'a> {
+    pub fn parse(text: &'a str) -> Result<Self, Error> {
+        let mut config = Config {
+            text,
+            default: None,
+            menu_title: None,
+            timeout: None,
+            append: None,
+        };
+
+        let mut default_line = 0;
+        let mut in_label = false;
+        // Keywords given for the current label, as bits indexed by `Keyword`.
+        let mut seen = 0u32;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |kind| Error {
+                line: line_number,
+                kind,
+            };
+
+            let (keyword, argument) = match parse_line(line) {
+                Ok(Some(directive)) => directive,
+                Ok(None) => continue,
+                Err(Some(kind)) => return Err(error(kind)),
+                Err(None) => {
+                    warn!(
+                        "extlinux.conf line {}: unknown keyword ignored",
+                        line_number
+                    );
+                    continue;
+                }
+            };
+
+            match keyword {
+                Keyword::Label => {
+                    in_label = true;
+                    seen = 0;
+                }
+                Keyword::MenuTitle => config.menu_title = Some(argument),
+                Keyword::Default => {
+                    config.default = Some(argument);
+                    default_line = line_number;
+                }
+                Keyword::Timeout => {
+                    config.timeout = Some(
+                        argument
+                            .parse()
+                            .map_err(|_| error(ErrorKind::InvalidTimeout))?,
+                    );
+                }
+                Keyword::Append if !in_label => config.append = Some(append_argument(argument)),
+                _ => {
+                    let name = keyword_name(keyword);
+                    if !in_label {
+                        return Err(error(ErrorKind::OutsideLabel(name)));
+                    }
+
+                    let bit = 1 << keyword as u32;
+                    if seen & bit != 0 {
+                        return Err(error(ErrorKind::DuplicateKeyword(name)));
+                    }
+                    seen |= bit;
+                }
+            }
+        }
+
+        for (index, entry) in config.entries().enumerate() {
+            if entry.linux.is_none() {
+                return Err(Error {
+                    line: entry.line,
+                    kind: ErrorKind::MissingKernel,
+                });
+            }
+
+            if config
+                .entries()
+                .take(index)
+                .any(|other| other.label == entry.label)
+            {
+                return Err(Error {
+                    line: entry.line,
+                    kind: ErrorKind::DuplicateLabel,
+                });
+            }
+        }
+
+        if let Some(default) = config.default {
+            if config.find(default).is_none() {
+                return Err(Error {
+                    line: default_line,
+                    kind: ErrorKind::UnknownDefault,
+                });
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            lines: self.text.lines().enumerate(),
+            pending: None,
+            default_append: self.append,
+        }
+    }
+
+    pub fn find(&self, label: &str) -> Option<Entry<'a>> {
+        self.entries().find(|entry| entry.label == label)
+    }
+
+    /// The entry named by `DEFAULT`, else the one with `MENU DEFAULT`, else the first one.
+    pub fn default_entry(&self) -> Option<Entry<'a>> {
+        match self.default {
+            Some(label) => self.find(label),
+            None => self
+                .entries()
+                .find(|entry| entry.is_menu_default)
+                .or_else(|| self.entries().next()),
+        }
+    }
+}
+
+/// Copy `append` to `buffer` with `${cbootargs}` replaced by `cbootargs`, `None` if it doesn't
+/// fit.
+pub fn expand_append<'b>(append: &str, cbootargs: &str, buffer: &'b mut [u8]) -> Option<&'b str> {
+    let mut length = 0;
+
+    for (index, part) in append.split(CBOOTARGS_VARIABLE).enumerate() {
+        for piece in &[if index > 0 { cbootargs } else { "" }, part] {
+            let end = length + piece.len();
+            buffer
+                .get_mut(length..end)?
+                .copy_from_slice(piece.as_bytes());
+            length = end;
+        }
+    }
+
+    str::from_utf8(&buffer[..length]).ok().map(str::trim)
+}
+
+/// `path` if it's absolute, else `path` in `directory` written to `buffer`.
+pub fn resolve_path<'b>(directory: &str, path: &'b str, buffer: &'b mut [u8]) -> Option<&'b str> {
+    if path.starts_with('/') {
+        return Some(path);
+    }
+
+    let directory = directory.trim_end_matches('/');
+    let length = directory.len() + 1 + path.len();
+    let destination = buffer.get_mut(..length)?;
+
+    destination[..directory.len()].copy_from_slice(directory.as_bytes());
+    destination[directory.len()] = b'/';
+    destination[directory.len() + 1..].copy_from_slice(path.as_bytes());
+
+    str::from_utf8(destination).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+TIMEOUT 30
+DEFAULT backup
+MENU TITLE L4T boot options
+append root=/dev/mmcblk0p1 ${cbootargs}
+
+LABEL primary
+      MENU LABEL primary kernel
+      LINUX /boot/Image
+      INITRD /boot/initrd
+      FDT tegra210-p3448-0000-p3449-0000-b00.dtb
+      FDTOVERLAYS /boot/a.dtbo  b.dtbo
+
+# Old kernel.
+label backup
+      kernel /boot/Image.old
+      APPEND -
+      MENU DEFAULT
+      IPAPPEND 2
+
+LABEL quiet
+      LINUX Image
+      APPEND ${cbootargs} quiet
+";
+
+    fn parse_error(text: &str) -> Option<Error> {
+        Config::parse(text).err()
+    }
+
+    #[test]
+    fn entries() {
+        let config = Config::parse(SAMPLE).unwrap();
+
+        assert_eq!(config.timeout, Some(30));
+        assert_eq!(config.default, Some("backup"));
+        assert_eq!(config.menu_title, Some("L4T boot options"));
+
+        let entries: Vec<Entry> = config.entries().collect();
+        assert_eq!(entries.len(), 3);
+
+        let primary = entries[0];
+        assert_eq!(primary.label, "primary");
+        assert_eq!(primary.title(), "primary kernel");
+        assert_eq!(primary.linux, Some("/boot/Image"));
+        assert_eq!(primary.initrd, Some("/boot/initrd"));
+        assert_eq!(primary.fdt, Some("tegra210-p3448-0000-p3449-0000-b00.dtb"));
+        assert_eq!(
+            primary.fdt_overlays().collect::<Vec<_>>(),
+            ["/boot/a.dtbo", "b.dtbo"]
+        );
+        // Labels without APPEND use the global one.
+        assert_eq!(primary.append, Some("root=/dev/mmcblk0p1 ${cbootargs}"));
+        assert!(!primary.is_menu_default);
+        assert_eq!(primary.line, 6);
+
+        let backup = entries[1];
+        assert_eq!(backup.title(), "backup");
+        assert_eq!(backup.linux, Some("/boot/Image.old"));
+        assert_eq!(backup.initrd, None);
+        assert_eq!(backup.fdt, None);
+        assert_eq!(backup.fdt_overlays().count(), 0);
+        assert_eq!(backup.append, Some(""));
+        assert!(backup.is_menu_default);
+        assert_eq!(backup.line, 14);
+
+        assert_eq!(entries[2].append, Some("${cbootargs} quiet"));
+        assert_eq!(config.find("quiet"), Some(entries[2]));
+        assert_eq!(config.find("Quiet"), None);
+    }
+
+    #[test]
+    fn global_append() {
+        let config = Config::parse("LABEL a\nLINUX /Image\n").unwrap();
+        assert_eq!(config.find("a").unwrap().append, None);
+
+        // Once in a label, APPEND belongs to it.
+        let text = "APPEND x\nLABEL a\nLINUX /Image\nAPPEND y\nLABEL b\nLINUX /Image\n";
+        let config = Config::parse(text).unwrap();
+        assert_eq!(config.find("a").unwrap().append, Some("y"));
+        assert_eq!(config.find("b").unwrap().append, Some("x"));
+
+        let config = Config::parse("APPEND -\nLABEL a\nLINUX /Image\n").unwrap();
+        assert_eq!(config.find("a").unwrap().append, Some(""));
+
+        // An empty APPEND of a label still replaces the global one.
+        let config = Config::parse("APPEND x\nLABEL a\nLINUX /Image\nAPPEND\n").unwrap();
+        assert_eq!(config.find("a").unwrap().append, Some(""));
+    }
+
+    #[test]
+    fn default_entry() {
+        let config = Config::parse(SAMPLE).unwrap();
+        assert_eq!(config.default_entry().unwrap().label, "backup");
+
+        // DEFAULT wins over MENU DEFAULT.
+        let text = SAMPLE.replace("DEFAULT backup", "DEFAULT quiet");
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(config.default_entry().unwrap().label, "quiet");
+
+        let text = SAMPLE.replace("DEFAULT backup", "");
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(config.default_entry().unwrap().label, "backup");
+
+        let text = text.replace("MENU DEFAULT", "");
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(config.default_entry().unwrap().label, "primary");
+
+        let config = Config::parse("TIMEOUT 0\n").unwrap();
+        assert_eq!(config.timeout, Some(0));
+        assert_eq!(config.default_entry(), None);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |line, kind| Some(Error { line, kind });
+
+        assert_eq!(
+            parse_error("LABEL a\n\n  LINUX\n"),
+            error(3, ErrorKind::MissingArgument("LINUX"))
+        );
+        assert_eq!(
+            parse_error("LABEL a\nkernel \n"),
+            error(2, ErrorKind::MissingArgument("KERNEL"))
+        );
+        assert_eq!(
+            parse_error("# Comment\nLINUX /Image\n"),
+            error(2, ErrorKind::OutsideLabel("LINUX"))
+        );
+        assert_eq!(
+            parse_error("MENU DEFAULT\nLABEL a\nLINUX /Image\n"),
+            error(1, ErrorKind::OutsideLabel("MENU DEFAULT"))
+        );
+        assert_eq!(
+            parse_error("LABEL a\nLINUX /Image\nKERNEL /Image.old\n"),
+            error(3, ErrorKind::DuplicateKeyword("LINUX"))
+        );
+        assert_eq!(
+            parse_error("LABEL a\nLINUX /Image\nLABEL b\nLINUX /Image\nLABEL a\nLINUX /Image\n"),
+            error(5, ErrorKind::DuplicateLabel)
+        );
+        assert_eq!(
+            parse_error("LABEL a\nLINUX /Image\nLABEL b\nINITRD /initrd\n"),
+            error(3, ErrorKind::MissingKernel)
+        );
+        assert_eq!(
+            parse_error("\nTIMEOUT 1.5\n"),
+            error(2, ErrorKind::InvalidTimeout)
+        );
+        assert_eq!(
+            parse_error("LABEL a\nLINUX /Image\nDEFAULT b\n"),
+            error(3, ErrorKind::UnknownDefault)
+        );
+
+        // Unknown keywords are only warned about.
+        assert!(parse_error("SAY hello\nLABEL a\nLINUX /Image\nMENU HIDE\n").is_none());
+
+        let error = Error {
+            line: 4,
+            kind: ErrorKind::OutsideLabel("INITRD"),
+        };
+        assert_eq!(error.to_string(), "line 4: INITRD outside of a LABEL");
+    }
+
+    #[test]
+    fn expand() {
+        let mut buffer = [0; 64];
+
+        assert_eq!(
+            expand_append(
+                "root=/dev/sda ${cbootargs} quiet",
+                "console=ttyS0",
+                &mut buffer
+            ),
+            Some("root=/dev/sda console=ttyS0 quiet")
+        );
+        assert_eq!(
+            expand_append("${cbootargs}${cbootargs}", "ab", &mut buffer),
+            Some("abab")
+        );
+        // Surrounding spaces left by an empty replacement are trimmed.
+        assert_eq!(
+            expand_append(" ${cbootargs} quiet", "", &mut buffer),
+            Some("quiet")
+        );
+        assert_eq!(expand_append("", "unused", &mut buffer), Some(""));
+
+        let mut small = [0; 8];
+        assert_eq!(expand_append("12345678", "", &mut small), Some("12345678"));
+        assert_eq!(expand_append("123456789", "", &mut small), None);
+        assert_eq!(
+            expand_append("1234${cbootargs}", "5678", &mut small),
+            Some("12345678")
+        );
+        assert_eq!(expand_append("1234${cbootargs}", "56789", &mut small), None);
+        assert_eq!(
+            expand_append("${cbootargs}1", "1234567", &mut small),
+            Some("12345671")
+        );
+        assert_eq!(expand_append("${cbootargs}12", "1234567", &mut small), None);
+    }
+
+    #[test]
+    fn paths() {
+        let mut buffer = [0; 32];
+
+        assert_eq!(
+            resolve_path(CONFIG_DIRECTORY, "/boot/Image", &mut buffer),
+            Some("/boot/Image")
+        );
+        assert_eq!(
+            resolve_path(CONFIG_DIRECTORY, "Image", &mut buffer),
+            Some("/boot/extlinux/Image")
+        );
+        assert_eq!(
+            resolve_path("/boot/", "dtb/a.dtb", &mut buffer),
+            Some("/boot/dtb/a.dtb")
+        );
+
+        // Absolute paths don't need the buffer.
+        let mut small = [0; 11];
+        assert_eq!(
+            resolve_path("/boot", "/Image.long", &mut small),
+            Some("/Image.long")
+        );
+        assert_eq!(
+            resolve_path("/boot", "Image", &mut small),
+            Some("/boot/Image")
+        );
+        assert_eq!(resolve_path("/boot", "Image1", &mut small), None);
+    }
+}
@@ -0,0 +1,1427 @@
+//! Read-only FAT12, FAT16 and FAT32 filesystems, with VFAT long names.
+//!
+//! Names are matched case-insensitively. Short names are decoded as ASCII, bytes of the OEM
+//! code page beyond it being replaced.
+//!
+//! The last block read is cached, which serves most FAT lookups while following a cluster
+//! chain. Files and directories also remember their place in the chain, sequential reads don't
+//! walk it again from the start, and clusters that follow each other on disk are read at once.
+//!
+//! Only `core` and the block device API are used, the module can be built and tested on the
+//! host.
+
+use core::char;
+use core::cmp;
+use core::fmt;
+
+use crate::block::{self, BlockDevice, BLOCK_SIZE};
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+pub const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+pub const ATTRIBUTE_HIDDEN: u8 = 0x02;
+pub const ATTRIBUTE_SYSTEM: u8 = 0x04;
+pub const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+pub const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+pub const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+/// Long name entries have all of read-only, hidden, system and volume ID set.
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+const ATTRIBUTE_LONG_NAME_MASK: u8 = 0x3F;
+
+const END_OF_DIRECTORY: u8 = 0x00;
+const DELETED_ENTRY: u8 = 0xE5;
+
+/// Flags of the reserved byte set by Windows for short names in lowercase.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+const LAST_LONG_ENTRY: u8 = 0x40;
+const MAX_LONG_ENTRIES: usize = 20;
+const LONG_NAME_PART_LENGTH: usize = 13;
+/// Offsets of the UTF-16 units in a long name entry.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_PART_LENGTH] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LENGTH: usize = 255;
+/// 8 characters, the dot and 3 characters.
+const MAX_SHORT_NAME_LENGTH: usize = 12;
+
+/// Cluster counts deciding the FAT type, from the specification.
+const MAX_FAT12_CLUSTERS: u32 = 4084;
+const MAX_FAT16_CLUSTERS: u32 = 65524;
+
+const FIRST_CLUSTER: u32 = 2;
+/// FAT32 entries only use 28 bits.
+const FAT32_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+
+/// Set in the FAT32 extended flags when only one FAT is in use.
+const FAT32_NO_MIRRORING: u16 = 0x80;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    Block(block::Error),
+    /// The boot sector doesn't describe a FAT filesystem.
+    NotFat,
+    /// A cluster chain goes to this invalid cluster, or loops.
+    BadCluster(u32),
+    /// The cluster chain of a file is shorter than its size.
+    TruncatedFile,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// The file is bigger than the buffer it's read to.
+    BufferTooSmall(u32),
+}
+
+impl From<block::Error> for Error {
+    fn from(error: block::Error) -> Self {
+        Error::Block(error)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// Position in a cluster chain, `cluster` being the `index`th one.
+#[derive(Copy, Clone, Debug)]
+struct Cursor {
+    first_cluster: u32,
+    cluster: u32,
+    index: u32,
+}
+
+impl Cursor {
+    fn new(first_cluster: u32) -> Self {
+        Cursor {
+            first_cluster,
+            cluster: first_cluster,
+            index: 0,
+        }
+    }
+}
+
+/// A directory, cluster 0 being the fixed root directory of FAT12 and FAT16.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Dir {
+    first_cluster: u32,
+}
+
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    pub attributes: u8,
+    pub size: u32,
+    first_cluster: u32,
+    long_name: [u16; MAX_LONG_ENTRIES * LONG_NAME_PART_LENGTH],
+    long_name_length: usize,
+    short_name: [u16; MAX_SHORT_NAME_LENGTH],
+    short_name_length: usize,
+}
+
+impl DirEntry {
+    fn new() -> Self {
+        DirEntry {
+            attributes: 0,
+            size: 0,
+            first_cluster: 0,
+            long_name: [0; MAX_LONG_ENTRIES * LONG_NAME_PART_LENGTH],
+            long_name_length: 0,
+            short_name: [0; MAX_SHORT_NAME_LENGTH],
+            short_name_length: 0,
+        }
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    /// The long name if there is one, else the short name, invalid UTF-16 being replaced.
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        if self.long_name_length > 0 {
+            decode_name(&self.long_name[..self.long_name_length])
+        } else {
+            decode_name(&self.short_name[..self.short_name_length])
+        }
+    }
+
+    pub fn short_name(&self) -> impl Iterator<Item = char> + '_ {
+        decode_name(&self.short_name[..self.short_name_length])
+    }
+
+    /// Compare the long or the short name ignoring case.
+    pub fn has_name(&self, name: &str) -> bool {
+        let matches = |units: &[u16]| {
+            decode_name(units)
+                .flat_map(char::to_lowercase)
+                .eq(name.chars().flat_map(char::to_lowercase))
+        };
+
+        (self.long_name_length > 0 && matches(&self.long_name[..self.long_name_length]))
+            || matches(&self.short_name[..self.short_name_length])
+    }
+
+    /// Add an entry of a long name, returning the ordinal expected next and the checksum, or
+    /// `None` if the name is broken.
+    fn add_long_name_part(&mut self, raw: &[u8], state: Option<(u8, u8)>) -> Option<(u8, u8)> {
+        let ordinal = raw[0] & !LAST_LONG_ENTRY;
+        let checksum = raw[13];
+
+        if ordinal == 0 || ordinal as usize > MAX_LONG_ENTRIES || read_u16(raw, 26) != 0 {
+            return None;
+        }
+
+        // The entries are stored from the last part of the name to the first one.
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+            self.long_name_length = ordinal as usize * LONG_NAME_PART_LENGTH;
+        } else if state != Some((ordinal, checksum)) {
+            return None;
+        }
+
+        let start = (ordinal as usize - 1) * LONG_NAME_PART_LENGTH;
+        for (index, offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            self.long_name[start + index] = read_u16(raw, *offset);
+        }
+
+        Some((ordinal - 1, checksum))
+    }
+
+    /// Finish the long name, which ends at the first NUL if it doesn't fill its entries, or
+    /// drop it if it's invalid.
+    fn finish_long_name(&mut self) {
+        if let Some(length) = self.long_name[..self.long_name_length]
+            .iter()
+            .position(|unit| *unit == 0)
+        {
+            self.long_name_length = length;
+        }
+
+        if self.long_name_length > MAX_NAME_LENGTH {
+            self.long_name_length = 0;
+        }
+    }
+
+    fn set_short_name(&mut self, raw: &[u8]) {
+        let flags = raw[12];
+        let base = trim_short_name(&raw[0..8]);
+        let extension = trim_short_name(&raw[8..11]);
+
+        self.short_name_length = 0;
+        self.push_short_name(base, flags & LOWERCASE_BASE != 0);
+
+        if !extension.is_empty() {
+            self.short_name[self.short_name_length] = u16::from(b'.');
+            self.short_name_length += 1;
+            self.push_short_name(extension, flags & LOWERCASE_EXTENSION != 0);
+        }
+    }
+
+    fn push_short_name(&mut self, bytes: &[u8], lowercase: bool) {
+        for byte in bytes {
+            let c = if !byte.is_ascii() || byte.is_ascii_control() {
+                char::REPLACEMENT_CHARACTER
+            } else if lowercase {
+                byte.to_ascii_lowercase() as char
+            } else {
+                *byte as char
+            };
+
+            self.short_name[self.short_name_length] = c as u16;
+            self.short_name_length += 1;
+        }
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DirEntry {{ name: \"")?;
+        for c in self.name() {
+            write!(f, "{}", c)?;
+        }
+
+        write!(
+            f,
+            "\", attributes: {:#x}, size: {}, first_cluster: {} }}",
+            self.attributes, self.size, self.first_cluster
+        )
+    }
+}
+
+fn decode_name(units: &[u16]) -> impl Iterator<Item = char> + '_ {
+    char::decode_utf16(units.iter().copied()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+}
+
+fn trim_short_name(bytes: &[u8]) -> &[u8] {
+    let length = bytes
+        .iter()
+        .rposition(|byte| *byte != b' ')
+        .map_or(0, |index| index + 1);
+
+    &bytes[..length]
+}
+
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+/// What path lookups keep of an entry, [`DirEntry`] being big for the stack.
+#[derive(Copy, Clone, Debug)]
+struct Node {
+    is_directory: bool,
+    size: u32,
+    first_cluster: u32,
+}
+
+/// A file opened for reading.
+#[derive(Copy, Clone, Debug)]
+pub struct File {
+    size: u32,
+    position: u32,
+    cursor: Cursor,
+}
+
+impl File {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    /// Move to `position`, reads past the end returning nothing.
+    pub fn seek(&mut self, position: u32) {
+        self.position = position;
+    }
+}
+
+pub struct FileSystem<'a> {
+    device: &'a mut dyn BlockDevice,
+    fat_type: FatType,
+    cluster_size: u32,
+    cluster_count: u32,
+    /// Byte offsets on the device.
+    fat_offset: u64,
+    root_offset: u64,
+    data_offset: u64,
+    /// Entries of the fixed root directory of FAT12 and FAT16.
+    root_entry_count: u32,
+    /// First cluster of the root directory of FAT32.
+    root_cluster: u32,
+    cached_lba: Option<u64>,
+    cache: [u8; BLOCK_SIZE],
+}
+
+impl<'a> FileSystem<'a> {
+    /// Mount the filesystem starting at the first block of `device`.
+    pub fn new(device: &'a mut dyn BlockDevice) -> Result<Self, Error> {
+        let mut boot_sector = [0; BLOCK_SIZE];
+        device.read_blocks(0, &mut boot_sector)?;
+
+        if boot_sector[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2] != BOOT_SIGNATURE
+            || (boot_sector[0] != 0xEB && boot_sector[0] != 0xE9)
+        {
+            return Err(Error::NotFat);
+        }
+
+        let bytes_per_sector = u32::from(read_u16(&boot_sector, 11));
+        let sectors_per_cluster = u32::from(boot_sector[13]);
+        let reserved_sectors = u32::from(read_u16(&boot_sector, 14));
+        let fat_count = u32::from(boot_sector[16]);
+        let root_entry_count = u32::from(read_u16(&boot_sector, 17));
+        let total_sectors = match read_u16(&boot_sector, 19) {
+            0 => read_u32(&boot_sector, 32),
+            count => u32::from(count),
+        };
+        let fat_size = match read_u16(&boot_sector, 22) {
+            0 => read_u32(&boot_sector, 36),
+            size => u32::from(size),
+        };
+
+        if !bytes_per_sector.is_power_of_two()
+            || bytes_per_sector < BLOCK_SIZE as u32
+            || bytes_per_sector > 4096
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_size == 0
+        {
+            return Err(Error::NotFat);
+        }
+
+        let root_sectors =
+            (root_entry_count * DIR_ENTRY_SIZE as u32 + bytes_per_sector - 1) / bytes_per_sector;
+        let data_sector = u64::from(reserved_sectors)
+            + u64::from(fat_count) * u64::from(fat_size)
+            + u64::from(root_sectors);
+        let data_sectors = u64::from(total_sectors)
+            .checked_sub(data_sector)
+            .ok_or(Error::NotFat)?;
+        let cluster_count = (data_sectors / u64::from(sectors_per_cluster)) as u32;
+
+        let bytes_per_sector = u64::from(bytes_per_sector);
+        if u64::from(total_sectors) * bytes_per_sector > device.block_count() * BLOCK_SIZE as u64 {
+            return Err(Error::NotFat);
+        }
+
+        let fat_type = if cluster_count <= MAX_FAT12_CLUSTERS {
+            FatType::Fat12
+        } else if cluster_count <= MAX_FAT16_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        let mut active_fat = 0;
+        let mut root_cluster = 0;
+        if fat_type == FatType::Fat32 {
+            if root_entry_count != 0 {
+                return Err(Error::NotFat);
+            }
+
+            let extended_flags = read_u16(&boot_sector, 40);
+            if extended_flags & FAT32_NO_MIRRORING != 0 {
+                active_fat = u32::from(extended_flags & 0xF);
+                if active_fat >= fat_count {
+                    return Err(Error::NotFat);
+                }
+            }
+
+            root_cluster = read_u32(&boot_sector, 44) & FAT32_ENTRY_MASK;
+        } else if root_entry_count == 0 {
+            return Err(Error::NotFat);
+        }
+
+        let fat_sector = u64::from(reserved_sectors) + u64::from(active_fat) * u64::from(fat_size);
+        let root_sector = u64::from(reserved_sectors) + u64::from(fat_count) * u64::from(fat_size);
+
+        let file_system = FileSystem {
+            device,
+            fat_type,
+            cluster_size: (bytes_per_sector as u32) * sectors_per_cluster,
+            cluster_count,
+            fat_offset: fat_sector * bytes_per_sector,
+            root_offset: root_sector * bytes_per_sector,
+            data_offset: data_sector * bytes_per_sector,
+            root_entry_count,
+            root_cluster,
+            cached_lba: None,
+            cache: [0; BLOCK_SIZE],
+        };
+
+        if fat_type == FatType::Fat32 {
+            file_system.check_cluster(root_cluster)?;
+        }
+
+        Ok(file_system)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.cluster_size
+    }
+
+    /// Read `buffer.len()` bytes at byte `offset` of the device, through the block cache.
+    fn read_bytes(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut offset = offset;
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let lba = offset / BLOCK_SIZE as u64;
+            let block_offset = (offset % BLOCK_SIZE as u64) as usize;
+            let remaining = buffer.len() - done;
+
+            let size = if block_offset == 0 && remaining >= BLOCK_SIZE {
+                // Whole blocks go straight to the buffer.
+                let size = remaining - remaining % BLOCK_SIZE;
+                self.device
+                    .read_blocks(lba, &mut buffer[done..done + size])?;
+
+                size
+            } else {
+                if self.cached_lba != Some(lba) {
+                    self.cached_lba = None;
+                    self.device.read_blocks(lba, &mut self.cache)?;
+                    self.cached_lba = Some(lba);
+                }
+
+                let size = cmp::min(BLOCK_SIZE - block_offset, remaining);
+                buffer[done..done + size]
+                    .copy_from_slice(&self.cache[block_offset..block_offset + size]);
+
+                size
+            };
+
+            done += size;
+            offset += size as u64;
+        }
+
+        Ok(())
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), Error> {
+        if cluster < FIRST_CLUSTER || cluster - FIRST_CLUSTER >= self.cluster_count {
+            return Err(Error::BadCluster(cluster));
+        }
+
+        Ok(())
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> Result<u64, Error> {
+        self.check_cluster(cluster)?;
+
+        Ok(self.data_offset + u64::from(cluster - FIRST_CLUSTER) * u64::from(self.cluster_size))
+    }
+
+    /// The cluster following `cluster` in its chain, `None` at the end.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, Error> {
+        self.check_cluster(cluster)?;
+
+        let entry = match self.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read_bytes(
+                    self.fat_offset + u64::from(cluster + cluster / 2),
+                    &mut bytes,
+                )?;
+
+                // Entries are packed as 12 bits, the odd ones in the high bits.
+                let value = u32::from(u16::from_le_bytes(bytes));
+                if cluster % 2 == 0 {
+                    value & 0xFFF
+                } else {
+                    value >> 4
+                }
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.read_bytes(self.fat_offset + u64::from(cluster) * 2, &mut bytes)?;
+
+                u32::from(u16::from_le_bytes(bytes))
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.read_bytes(self.fat_offset + u64::from(cluster) * 4, &mut bytes)?;
+
+                u32::from_le_bytes(bytes) & FAT32_ENTRY_MASK
+            }
+        };
+
+        if entry >= self.fat_type.end_of_chain() {
+            return Ok(None);
+        }
+
+        self.check_cluster(entry)?;
+
+        Ok(Some(entry))
+    }
+
+    /// Move `cursor` to the `index`th cluster of its chain, returning false past the end.
+    fn seek(&mut self, cursor: &mut Cursor, index: u32) -> Result<bool, Error> {
+        // No chain can be longer than the filesystem, this also catches loops.
+        if index >= self.cluster_count {
+            return Err(Error::BadCluster(cursor.cluster));
+        }
+
+        if index < cursor.index {
+            *cursor = Cursor::new(cursor.first_cluster);
+        }
+
+        while cursor.index < index {
+            match self.next_cluster(cursor.cluster)? {
+                Some(cluster) => {
+                    cursor.cluster = cluster;
+                    cursor.index += 1;
+                }
+                None => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub fn root_dir(&self) -> Dir {
+        Dir {
+            first_cluster: self.root_cluster,
+        }
+    }
+
+    /// The directory starting at `cluster`, 0 being the root one in ".." entries.
+    fn dir_at(&self, cluster: u32) -> Dir {
+        if cluster == 0 {
+            self.root_dir()
+        } else {
+            Dir {
+                first_cluster: cluster,
+            }
+        }
+    }
+
+    /// Read the raw entry `index` of `dir`, returning false past the end.
+    fn read_raw_entry(
+        &mut self,
+        dir: Dir,
+        cursor: &mut Cursor,
+        index: u32,
+        raw: &mut [u8; DIR_ENTRY_SIZE],
+    ) -> Result<bool, Error> {
+        let offset = if dir.first_cluster == 0 {
+            if index >= self.root_entry_count {
+                return Ok(false);
+            }
+
+            self.root_offset + (index as usize * DIR_ENTRY_SIZE) as u64
+        } else {
+            let position = u64::from(index) * DIR_ENTRY_SIZE as u64;
+            let cluster_size = u64::from(self.cluster_size);
+
+            if !self.seek(cursor, (position / cluster_size) as u32)? {
+                return Ok(false);
+            }
+
+            self.cluster_offset(cursor.cluster)? + position % cluster_size
+        };
+
+        self.read_bytes(offset, raw)?;
+
+        Ok(true)
+    }
+
+    /// The entries of `dir`, deleted entries and volume labels being skipped.
+    pub fn read_dir<'f>(&'f mut self, dir: Dir) -> DirEntries<'f, 'a> {
+        DirEntries {
+            file_system: self,
+            dir,
+            cursor: Cursor::new(dir.first_cluster),
+            index: 0,
+            done: false,
+        }
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> Result<Node, Error> {
+        for entry in self.read_dir(dir) {
+            let entry = entry?;
+
+            if entry.has_name(name) {
+                return Ok(Node {
+                    is_directory: entry.is_directory(),
+                    size: entry.size,
+                    first_cluster: entry.first_cluster,
+                });
+            }
+        }
+
+        Err(Error::NotFound)
+    }
+
+    /// Follow `path` from the root directory, `None` being the root directory itself.
+    fn lookup(&mut self, path: &str) -> Result<Option<Node>, Error> {
+        let mut current = None;
+
+        for component in path.split('/') {
+            if component.is_empty() || component == "." {
+                continue;
+            }
+
+            let dir = match current {
+                None if component == ".." => continue,
+                None => self.root_dir(),
+                Some(Node {
+                    is_directory: true,
+                    first_cluster,
+                    ..
+                }) => self.dir_at(first_cluster),
+                Some(_) => return Err(Error::NotADirectory),
+            };
+
+            let entry = self.find(dir, component)?;
+
+            // ".." entries going back to the root directory.
+            current = if entry.is_directory && self.dir_at(entry.first_cluster) == self.root_dir() {
+                None
+            } else {
+                Some(entry)
+            };
+        }
+
+        Ok(current)
+    }
+
+    pub fn open_dir(&mut self, path: &str) -> Result<Dir, Error> {
+        match self.lookup(path)? {
+            None => Ok(self.root_dir()),
+            Some(entry) if entry.is_directory => Ok(self.dir_at(entry.first_cluster)),
+            Some(_) => Err(Error::NotADirectory),
+        }
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<File, Error> {
+        let entry = match self.lookup(path)? {
+            Some(entry) if !entry.is_directory => entry,
+            _ => return Err(Error::IsADirectory),
+        };
+
+        if entry.size != 0 {
+            self.check_cluster(entry.first_cluster)?;
+        }
+
+        Ok(File {
+            size: entry.size,
+            position: 0,
+            cursor: Cursor::new(entry.first_cluster),
+        })
+    }
+
+    /// Read from the position of `file`, returning the size read which is only smaller than
+    /// `buffer` at the end of the file.
+    pub fn read(&mut self, file: &mut File, buffer: &mut [u8]) -> Result<usize, Error> {
+        let cluster_size = u64::from(self.cluster_size);
+        let length = cmp::min(
+            buffer.len() as u64,
+            u64::from(file.size.saturating_sub(file.position)),
+        ) as usize;
+        let mut done = 0;
+
+        while done < length {
+            let position = u64::from(file.position);
+            if !self.seek(&mut file.cursor, (position / cluster_size) as u32)? {
+                return Err(Error::TruncatedFile);
+            }
+
+            let offset = self.cluster_offset(file.cursor.cluster)? + position % cluster_size;
+            let mut size = cluster_size - position % cluster_size;
+
+            // Take the clusters that follow on disk along.
+            while size < (length - done) as u64 {
+                match self.next_cluster(file.cursor.cluster)? {
+                    Some(cluster) if cluster == file.cursor.cluster + 1 => {
+                        file.cursor.cluster = cluster;
+                        file.cursor.index += 1;
+                        size += cluster_size;
+                    }
+                    _ => break,
+                }
+            }
+
+            let size = cmp::min(size, (length - done) as u64) as usize;
+            self.read_bytes(offset, &mut buffer[done..done + size])?;
+
+            done += size;
+            file.position += size as u32;
+        }
+
+        Ok(done)
+    }
+
+    /// Read the whole file at `path` to `buffer`, returning its size.
+    pub fn read_file(&mut self, path: &str, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut file = self.open(path)?;
+
+        if file.size as usize > buffer.len() {
+            return Err(Error::BufferTooSmall(file.size));
+        }
+
+        self.read(&mut file, buffer)
+    }
+}
+
+/// Iterator over the entries of a [`Dir`].
+pub struct DirEntries<'f, 'a> {
+    file_system: &'f mut FileSystem<'a>,
+    dir: Dir,
+    cursor: Cursor,
+    index: u32,
+    done: bool,
+}
+
+impl<'f, 'a> DirEntries<'f, 'a> {
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, Error> {
+        let mut entry = DirEntry::new();
+        let mut raw = [0; DIR_ENTRY_SIZE];
+
+        // Ordinal of the next long name entry expected, and checksum of its short name.
+        let mut long_name = None;
+
+        loop {
+            if !self
+                .file_system
+                .read_raw_entry(self.dir, &mut self.cursor, self.index, &mut raw)?
+            {
+                return Ok(None);
+            }
+
+            self.index += 1;
+
+            match raw[0] {
+                END_OF_DIRECTORY => return Ok(None),
+                DELETED_ENTRY => {
+                    long_name = None;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let attributes = raw[11];
+            if attributes & ATTRIBUTE_LONG_NAME_MASK == ATTRIBUTE_LONG_NAME {
+                long_name = entry.add_long_name_part(&raw, long_name);
+                continue;
+            }
+
+            if attributes & ATTRIBUTE_VOLUME_ID != 0 {
+                long_name = None;
+                continue;
+            }
+
+            // Long names left over by systems unaware of them don't match their short name.
+            if long_name == Some((0, short_name_checksum(&raw[0..11]))) {
+                entry.finish_long_name();
+            } else {
+                entry.long_name_length = 0;
+            }
+            entry.set_short_name(&raw);
+
+            let mut first_cluster = u32::from(read_u16(&raw, 26));
+            if self.file_system.fat_type == FatType::Fat32 {
+                first_cluster |= u32::from(read_u16(&raw, 20)) << 16;
+            }
+
+            entry.attributes = attributes;
+            entry.size = read_u32(&raw, 28);
+            entry.first_cluster = first_cluster;
+
+            return Ok(Some(entry));
+        }
+    }
+}
+
+impl<'f, 'a> Iterator for DirEntries<'f, 'a> {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MemoryDevice};
+
+    const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+    /// Builds filesystem images, placing clusters where the tests want them, for the damaged
+    /// filesystems and entries no tool writes.
+    struct Image {
+        data: Vec<u8>,
+        fat_type: FatType,
+        fat_offsets: Vec<usize>,
+        root_offset: usize,
+        data_offset: usize,
+        cluster_count: u32,
+    }
+
+    impl Image {
+        fn new(fat_type: FatType) -> Self {
+            // Cluster counts well inside the range of each type, 512 bytes clusters.
+            let (cluster_count, reserved_sectors, root_entry_count): (u32, u32, u32) =
+                match fat_type {
+                    FatType::Fat12 => (2000, 1, 64),
+                    FatType::Fat16 => (5000, 1, 64),
+                    FatType::Fat32 => (66000, 32, 0),
+                };
+            let fat_bytes = match fat_type {
+                FatType::Fat12 => (cluster_count + 2) * 3 / 2 + 1,
+                FatType::Fat16 => (cluster_count + 2) * 2,
+                FatType::Fat32 => (cluster_count + 2) * 4,
+            };
+            let fat_size = (fat_bytes + 511) / 512;
+            let root_sectors = root_entry_count * 32 / 512;
+            let data_sector = reserved_sectors + 2 * fat_size + root_sectors;
+            let total_sectors = data_sector + cluster_count;
+
+            let mut data = vec![0; total_sectors as usize * 512];
+            data[0] = 0xEB;
+            data[11..13].copy_from_slice(&512u16.to_le_bytes());
+            data[13] = 1;
+            data[14..16].copy_from_slice(&(reserved_sectors as u16).to_le_bytes());
+            data[16] = 2;
+            data[17..19].copy_from_slice(&(root_entry_count as u16).to_le_bytes());
+            if total_sectors < 0x1_0000 {
+                data[19..21].copy_from_slice(&(total_sectors as u16).to_le_bytes());
+            } else {
+                data[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+            }
+            if fat_type == FatType::Fat32 {
+                data[36..40].copy_from_slice(&fat_size.to_le_bytes());
+                data[44..48].copy_from_slice(&2u32.to_le_bytes());
+            } else {
+                data[22..24].copy_from_slice(&(fat_size as u16).to_le_bytes());
+            }
+            data[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2].copy_from_slice(&BOOT_SIGNATURE);
+
+            let fat_offset = reserved_sectors as usize * 512;
+            let mut image = Image {
+                data,
+                fat_type,
+                fat_offsets: vec![fat_offset, fat_offset + fat_size as usize * 512],
+                root_offset: (reserved_sectors + 2 * fat_size) as usize * 512,
+                data_offset: data_sector as usize * 512,
+                cluster_count,
+            };
+
+            // The media descriptor and end of chain markers of the reserved entries.
+            image.set_fat(0, 0x0FFF_FFF8);
+            image.set_fat(1, END_OF_CHAIN);
+            if fat_type == FatType::Fat32 {
+                image.set_fat(2, END_OF_CHAIN);
+            }
+
+            image
+        }
+
+        fn set_fat(&mut self, cluster: u32, value: u32) {
+            for fat_offset in self.fat_offsets.clone() {
+                match self.fat_type {
+                    FatType::Fat12 => {
+                        let offset = fat_offset + (cluster + cluster / 2) as usize;
+                        let mut packed =
+                            u16::from_le_bytes([self.data[offset], self.data[offset + 1]]);
+                        let value = (value & 0xFFF) as u16;
+
+                        packed = if cluster % 2 == 0 {
+                            (packed & 0xF000) | value
+                        } else {
+                            (packed & 0x000F) | (value << 4)
+                        };
+                        self.data[offset..offset + 2].copy_from_slice(&packed.to_le_bytes());
+                    }
+                    FatType::Fat16 => {
+                        let offset = fat_offset + cluster as usize * 2;
+                        self.data[offset..offset + 2]
+                            .copy_from_slice(&(value as u16).to_le_bytes());
+                    }
+                    FatType::Fat32 => {
+                        let offset = fat_offset + cluster as usize * 4;
+                        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        fn cluster(&mut self, cluster: u32) -> &mut [u8] {
+            let offset = self.data_offset + (cluster - 2) as usize * 512;
+
+            &mut self.data[offset..offset + 512]
+        }
+
+        /// Write `data` over the chain of `clusters`.
+        fn write_chain(&mut self, clusters: &[u32], data: &[u8]) {
+            for (index, cluster) in clusters.iter().enumerate() {
+                let next = clusters.get(index + 1).copied().unwrap_or(END_OF_CHAIN);
+                self.set_fat(*cluster, next);
+
+                let chunk = data.chunks(512).nth(index).unwrap_or(&[]);
+                self.cluster(*cluster)[..chunk.len()].copy_from_slice(chunk);
+            }
+        }
+
+        /// Write the entries of a directory, `None` being the root directory.
+        fn write_dir(&mut self, clusters: Option<&[u32]>, entries: &[[u8; 32]]) {
+            let data: Vec<u8> = entries.iter().flatten().copied().collect();
+
+            match clusters {
+                Some(clusters) => self.write_chain(clusters, &data),
+                None if self.fat_type == FatType::Fat32 => self.write_chain(&[2], &data),
+                None => self.data[self.root_offset..self.root_offset + data.len()]
+                    .copy_from_slice(&data),
+            }
+        }
+    }
+
+    fn checksum(short_name: &[u8; 11]) -> u8 {
+        let mut sum = 0u8;
+        for byte in short_name {
+            sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*byte);
+        }
+        sum
+    }
+
+    fn short_entry(name: &[u8; 11], attributes: u8, first_cluster: u32, size: u32) -> [u8; 32] {
+        let mut entry = [0; 32];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    /// The long name entries of `name`, in their order on disk.
+    fn long_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        if units.len() % 13 != 0 {
+            units.push(0);
+        }
+        while units.len() % 13 != 0 {
+            units.push(0xFFFF);
+        }
+
+        let count = units.len() / 13;
+        (0..count)
+            .rev()
+            .map(|index| {
+                let mut entry = [0; 32];
+                entry[0] = (index + 1) as u8;
+                if index + 1 == count {
+                    entry[0] |= LAST_LONG_ENTRY;
+                }
+                entry[11] = ATTRIBUTE_LONG_NAME;
+                entry[13] = checksum(short_name);
+                for (unit, offset) in units[index * 13..].iter().zip(LONG_NAME_OFFSETS.iter()) {
+                    entry[*offset..*offset + 2].copy_from_slice(&unit.to_le_bytes());
+                }
+                entry
+            })
+            .collect()
+    }
+
+    fn with_long_name(name: &str, short: [u8; 32]) -> Vec<[u8; 32]> {
+        let mut short_name = [0; 11];
+        short_name.copy_from_slice(&short[..11]);
+
+        let mut entries = long_entries(name, &short_name);
+        entries.push(short);
+        entries
+    }
+
+    fn names(file_system: &mut FileSystem, path: &str) -> Vec<String> {
+        let dir = file_system.open_dir(path).unwrap();
+
+        test_support::names(file_system.read_dir(dir).map(Result::unwrap), |entry| {
+            entry.name().collect()
+        })
+    }
+
+    /// The image made by `make-images.sh`.
+    fn image(fat_type: FatType) -> Vec<u8> {
+        test_support::image(match fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        })
+    }
+
+    /// The kernel of the images, in `boot/Image-5.10.0`.
+    fn kernel() -> Vec<u8> {
+        test_support::pattern(5 * 512 + 100, 1)
+    }
+
+    /// A tree with a fragmented file in a subdirectory, in each FAT type.
+    fn tree(fat_type: FatType) -> (Image, Vec<u8>) {
+        let mut image = Image::new(fat_type);
+        let kernel = kernel();
+
+        // Out of order, some clusters following each other.
+        let high = image.cluster_count - 10;
+        let kernel_clusters = [10, 11, 13, high, 12, 14];
+        image.write_chain(&kernel_clusters, &kernel);
+
+        let boot = 20;
+        let mut boot_entries = vec![
+            short_entry(b".          ", ATTRIBUTE_DIRECTORY, boot, 0),
+            short_entry(b"..         ", ATTRIBUTE_DIRECTORY, 0, 0),
+        ];
+        boot_entries.extend(with_long_name(
+            "Image-5.10.0",
+            short_entry(b"IMAGE-~1   ", ATTRIBUTE_ARCHIVE, 10, kernel.len() as u32),
+        ));
+        image.write_dir(Some(&[boot]), &boot_entries);
+
+        let mut root = vec![short_entry(b"RBOOT      ", ATTRIBUTE_VOLUME_ID, 0, 0)];
+        root.extend(with_long_name(
+            "boot",
+            short_entry(b"BOOT       ", ATTRIBUTE_DIRECTORY, boot, 0),
+        ));
+        root.push(short_entry(b"EMPTY   TXT", ATTRIBUTE_ARCHIVE, 0, 0));
+        image.write_dir(None, &root);
+
+        (image, kernel)
+    }
+
+    #[test]
+    fn fat_types_and_cluster_chains() {
+        let kernel = kernel();
+
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32].iter() {
+            let mut device = MemoryDevice(image(*fat_type));
+            let mut file_system = FileSystem::new(&mut device).unwrap();
+
+            assert_eq!(file_system.fat_type(), *fat_type);
+            assert_eq!(file_system.cluster_size(), 512);
+            assert_eq!(
+                names(&mut file_system, "/"),
+                [
+                    "boot",
+                    "many",
+                    "EMPTY.TXT",
+                    "Twenty-six characters.txt",
+                    "A long name over three entries, ünicode.tar.gz",
+                ]
+            );
+            // Without the file deleted to fragment the kernel.
+            assert_eq!(
+                names(&mut file_system, "boot"),
+                [".", "..", "Image-5.10.0", "extlinux.conf"]
+            );
+
+            let mut buffer = vec![0; 0x2000];
+            let size = file_system
+                .read_file("/BOOT/image-5.10.0", &mut buffer)
+                .unwrap();
+            assert_eq!(&buffer[..size], &kernel[..], "{:?}", fat_type);
+
+            // Through the short name, "." and "..".
+            let size = file_system
+                .read_file("boot/./../boot/IMAGE-~1.0", &mut buffer)
+                .unwrap();
+            assert_eq!(size, kernel.len());
+            assert_eq!(file_system.open_dir("boot/.."), Ok(file_system.root_dir()));
+
+            assert_eq!(file_system.read_file("empty.txt", &mut buffer), Ok(0));
+            assert!(file_system.open("twenty-six CHARACTERS.TXT").is_ok());
+            assert!(file_system
+                .open("a long name over three entries, Ünicode.tar.gz")
+                .is_ok());
+
+            // A directory over several clusters.
+            assert_eq!(names(&mut file_system, "many").len(), 2 + 40);
+            for index in [0, 17, 39].iter() {
+                let path = format!("many/file{:02}.bin", index);
+                let size = file_system.read_file(&path, &mut buffer).unwrap();
+                assert_eq!(
+                    &buffer[..size],
+                    &test_support::pattern(index * 100, *index)[..]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn partial_reads() {
+        let kernel = kernel();
+        let mut device = MemoryDevice(image(FatType::Fat16));
+        let mut file_system = FileSystem::new(&mut device).unwrap();
+        let mut file = file_system.open("boot/Image-5.10.0").unwrap();
+        assert_eq!(file.size() as usize, kernel.len());
+
+        // Across cluster boundaries, backwards and past the end.
+        let mut buffer = [0; 700];
+        for position in [1000, 300, 2000, 0, 2600].iter() {
+            file.seek(*position);
+            let size = file_system.read(&mut file, &mut buffer).unwrap();
+            let end = cmp::min(*position as usize + buffer.len(), kernel.len());
+
+            assert_eq!(&buffer[..size], &kernel[*position as usize..end]);
+            assert_eq!(file.position() as usize, end);
+        }
+
+        file.seek(kernel.len() as u32 + 10);
+        assert_eq!(file_system.read(&mut file, &mut buffer), Ok(0));
+
+        assert_eq!(
+            file_system.read_file("boot/Image-5.10.0", &mut buffer),
+            Err(Error::BufferTooSmall(kernel.len() as u32))
+        );
+    }
+
+    #[test]
+    fn bad_cluster_chains() {
+        let check = |fat_type, edit: &dyn Fn(&mut Image), expected| {
+            let (mut image, _) = tree(fat_type);
+            edit(&mut image);
+
+            let mut device = MemoryDevice(image.data);
+            let mut file_system = FileSystem::new(&mut device).unwrap();
+            let mut buffer = vec![0; 0x2000];
+
+            assert_eq!(
+                file_system.read_file("boot/Image-5.10.0", &mut buffer),
+                Err(expected),
+                "{:?}",
+                fat_type
+            );
+        };
+
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32].iter() {
+            let fat_type = *fat_type;
+            let cluster_count = Image::new(fat_type).cluster_count;
+            let high = cluster_count - 10;
+
+            // Ends before the size of the file.
+            check(
+                fat_type,
+                &|image| image.set_fat(13, END_OF_CHAIN),
+                Error::TruncatedFile,
+            );
+            // Free, reserved and out of range clusters.
+            check(
+                fat_type,
+                &|image| image.set_fat(11, 0),
+                Error::BadCluster(0),
+            );
+            check(
+                fat_type,
+                &|image| image.set_fat(11, 1),
+                Error::BadCluster(1),
+            );
+            let past_end = cluster_count + 2;
+            check(
+                fat_type,
+                &|image| image.set_fat(high, past_end),
+                Error::BadCluster(past_end),
+            );
+        }
+
+        // A loop, only caught by the chain being longer than the filesystem.
+        let (mut image, _) = tree(FatType::Fat12);
+        image.set_fat(11, 10);
+        let cluster_count = image.cluster_count;
+        let mut device = MemoryDevice(image.data);
+        let mut file_system = FileSystem::new(&mut device).unwrap();
+        let mut file = file_system.open("boot/Image-5.10.0").unwrap();
+        file.size = u32::MAX;
+        let mut buffer = vec![0; 0x1000];
+        assert!(file_system.read(&mut file, &mut buffer).is_ok());
+        file.seek(cluster_count * 512);
+        assert_eq!(
+            file_system.read(&mut file, &mut buffer),
+            Err(Error::BadCluster(11))
+        );
+    }
+
+    #[test]
+    fn long_names() {
+        let mut image = Image::new(FatType::Fat12);
+        let short = |name: &[u8; 11]| short_entry(name, ATTRIBUTE_ARCHIVE, 0, 0);
+
+        let mut root = Vec::new();
+        // Exactly filling two entries, without terminator nor padding.
+        root.extend(with_long_name(
+            "Twenty-six characters.txt",
+            short(b"TWENTY~1TXT"),
+        ));
+        root.extend(with_long_name(
+            "A long name over three entries, ünicode.tar.gz",
+            short(b"ALONGN~1GZ "),
+        ));
+        // Changed by a system unaware of long names, the checksum doesn't match anymore.
+        let mut stale = with_long_name("stale long name", short(b"STALE~1    "));
+        stale.last_mut().unwrap()[..11].copy_from_slice(b"RENAMED    ");
+        root.extend(stale);
+        // Parts missing or out of order.
+        let mut broken = with_long_name("A name in three parts, broken", short(b"BROKEN~1   "));
+        broken.remove(1);
+        root.extend(broken);
+        let mut swapped = with_long_name("Two parts swapped around..", short(b"SWAPPE~1   "));
+        swapped.swap(0, 1);
+        root.extend(swapped);
+        // A deleted entry in the middle of a name.
+        let mut deleted = with_long_name("Deleted in the middle", short(b"DELETE~1   "));
+        deleted.insert(1, {
+            let mut entry = short(b"OLD     TXT");
+            entry[0] = DELETED_ENTRY;
+            entry
+        });
+        root.extend(deleted);
+        image.write_dir(None, &root);
+
+        let mut device = MemoryDevice(image.data);
+        let mut file_system = FileSystem::new(&mut device).unwrap();
+
+        assert_eq!(
+            names(&mut file_system, "/"),
+            [
+                "Twenty-six characters.txt",
+                "A long name over three entries, ünicode.tar.gz",
+                "RENAMED",
+                "BROKEN~1",
+                "SWAPPE~1",
+                "DELETE~1",
+            ]
+        );
+
+        assert!(file_system.open("twenty-six CHARACTERS.TXT").is_ok());
+        assert!(file_system
+            .open("a long name over three entries, Ünicode.tar.gz")
+            .is_ok());
+        assert!(file_system.open("alongn~1.gz").is_ok());
+        assert_eq!(
+            file_system.open("stale long name").err(),
+            Some(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn short_names() {
+        let mut image = Image::new(FatType::Fat16);
+        let mut readme = short_entry(b"README  TXT", ATTRIBUTE_ARCHIVE, 0, 0);
+        readme[12] = LOWERCASE_BASE | LOWERCASE_EXTENSION;
+        let mut mixed = short_entry(b"MIXED   TXT", ATTRIBUTE_ARCHIVE, 0, 0);
+        mixed[12] = LOWERCASE_EXTENSION;
+        let mut deleted = short_entry(b"DELETED TXT", ATTRIBUTE_ARCHIVE, 0, 0);
+        deleted[0] = DELETED_ENTRY;
+
+        image.write_dir(
+            None,
+            &[
+                short_entry(b"LABEL      ", ATTRIBUTE_VOLUME_ID, 0, 0),
+                readme,
+                mixed,
+                deleted,
+                short_entry(b"KERNEL     ", ATTRIBUTE_ARCHIVE, 0, 0),
+                short_entry(b"CAF\x82    BIN", ATTRIBUTE_ARCHIVE, 0, 0),
+                [0; 32],
+                short_entry(b"HIDDEN  TXT", ATTRIBUTE_ARCHIVE, 0, 0),
+            ],
+        );
+
+        let mut device = MemoryDevice(image.data);
+        let mut file_system = FileSystem::new(&mut device).unwrap();
+
+        // The end of directory marker hides what follows.
+        assert_eq!(
+            names(&mut file_system, "/"),
+            ["readme.txt", "MIXED.txt", "KERNEL", "CAF\u{FFFD}.BIN"]
+        );
+        assert!(file_system.open("README.TXT").is_ok());
+        assert!(file_system.open("kernel").is_ok());
+        assert_eq!(file_system.open("deleted.txt").err(), Some(Error::NotFound));
+        assert_eq!(file_system.open("hidden.txt").err(), Some(Error::NotFound));
+        assert_eq!(file_system.open("label").err(), Some(Error::NotFound));
+    }
+
+    #[test]
+    fn lookup_errors() {
+        let mut device = MemoryDevice(image(FatType::Fat32));
+        let mut file_system = FileSystem::new(&mut device).unwrap();
+
+        assert_eq!(file_system.open("boot").err(), Some(Error::IsADirectory));
+        assert_eq!(file_system.open("/").err(), Some(Error::IsADirectory));
+        assert_eq!(
+            file_system.open_dir("boot/Image-5.10.0").err(),
+            Some(Error::NotADirectory)
+        );
+        assert_eq!(
+            file_system.open("empty.txt/x").err(),
+            Some(Error::NotADirectory)
+        );
+        assert_eq!(
+            file_system.open("boot/missing").err(),
+            Some(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn bad_boot_sectors() {
+        let check = |fat_type, edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut data = image(fat_type);
+            edit(&mut data);
+
+            let mut device = MemoryDevice(data);
+            FileSystem::new(&mut device).err()
+        };
+
+        assert_eq!(
+            check(FatType::Fat16, &|data| data[510] = 0),
+            Some(Error::NotFat)
+        );
+        assert_eq!(
+            check(FatType::Fat16, &|data| data[0] = 0),
+            Some(Error::NotFat)
+        );
+        // 768 bytes sectors.
+        assert_eq!(
+            check(FatType::Fat16, &|data| data[12] = 3),
+            Some(Error::NotFat)
+        );
+        assert_eq!(
+            check(FatType::Fat16, &|data| data[13] = 3),
+            Some(Error::NotFat)
+        );
+        // Bigger than the device.
+        assert_eq!(
+            check(FatType::Fat12, &|data| data.truncate(data.len() - 512)),
+            Some(Error::NotFat)
+        );
+        // No root directory.
+        assert_eq!(
+            check(FatType::Fat12, &|data| data[17..19]
+                .copy_from_slice(&[0, 0])),
+            Some(Error::NotFat)
+        );
+        assert_eq!(
+            check(FatType::Fat32, &|data| data[17] = 16),
+            Some(Error::NotFat)
+        );
+        assert_eq!(
+            check(FatType::Fat32, &|data| data[44] = 1),
+            Some(Error::BadCluster(1))
+        );
+        // Only the second FAT is in use, and there are two.
+        assert_eq!(
+            check(FatType::Fat32, &|data| data[40] =
+                FAT32_NO_MIRRORING as u8 | 1),
+            None
+        );
+        assert_eq!(
+            check(FatType::Fat32, &|data| data[40] =
+                FAT32_NO_MIRRORING as u8 | 2),
+            Some(Error::NotFat)
+        );
+    }
+}
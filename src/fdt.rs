@@ -0,0 +1,1886 @@
+//! Flattened device tree parsing and in place editing.
+//!
+//! Nodes are referred to by the offset of their `FDT_BEGIN_NODE` token in the structure block,
+//! like libfdt does. Editing a tree moves what follows the edited part, so offsets obtained
+//! before an edit have to be looked up again.
+//!
+//! Overlays are applied like libfdt does, see [`FdtEditor::apply_overlay`].
+//!
+//! Only `core` is used, the module can be built and tested on the host.
+
+use core::mem;
+use core::str;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// Nesting of the nodes of an overlay fragment, their path is kept on the stack when merging.
+const MAX_OVERLAY_DEPTH: usize = 16;
+
+/// Version written by [`FdtEditor`], the oldest version including `size_dt_struct`.
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_RESERVE_ENTRY_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+const TAG_SIZE: usize = mem::size_of::<u32>();
+/// Tag, value length and name offset.
+const PROPERTY_HEADER_SIZE: usize = 3 * TAG_SIZE;
+
+// Header fields offsets.
+const HEADER_MAGIC: usize = 0;
+const HEADER_TOTAL_SIZE: usize = 4;
+const HEADER_OFF_DT_STRUCT: usize = 8;
+const HEADER_OFF_DT_STRINGS: usize = 12;
+const HEADER_OFF_MEM_RSVMAP: usize = 16;
+const HEADER_VERSION: usize = 20;
+const HEADER_LAST_COMP_VERSION: usize = 24;
+const HEADER_BOOT_CPUID_PHYS: usize = 28;
+const HEADER_SIZE_DT_STRINGS: usize = 32;
+const HEADER_SIZE_DT_STRUCT: usize = 36;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    BadMagic,
+    /// Versions older than 17 are not supported.
+    BadVersion(u32),
+    /// The blob is smaller than its header tells.
+    Truncated,
+    /// The structure block is malformed.
+    BadStructure,
+    /// The buffer is too small for the edit.
+    NoSpace,
+    NotFound,
+    /// A node or property name is empty or contains a `/` or a NUL byte.
+    BadName,
+    /// An overlay has a fragment without target or a malformed fixup.
+    BadOverlay,
+}
+
+fn align4(value: usize) -> usize {
+    (value + 3) & !3
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = data.get(offset..offset + 4).ok_or(Error::Truncated)?;
+
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, Error> {
+    Ok(((read_u32(data, offset)? as u64) << 32) | read_u32(data, offset + 4)? as u64)
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn write_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Read a NUL terminated string, returning it and the offset after the terminator.
+fn read_string(data: &[u8], offset: usize) -> Result<(&str, usize), Error> {
+    let bytes = data.get(offset..).ok_or(Error::BadStructure)?;
+    let length = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .ok_or(Error::BadStructure)?;
+    let string = str::from_utf8(&bytes[..length]).map_err(|_| Error::BadStructure)?;
+
+    Ok((string, offset + length + 1))
+}
+
+fn is_phandle_property(name: &str) -> bool {
+    name == "phandle" || name == "linux,phandle"
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.bytes().any(|byte| byte == b'/' || byte == 0) {
+        return Err(Error::BadName);
+    }
+
+    Ok(())
+}
+
+/// A property of a node.
+#[derive(Copy, Clone, Debug)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> Property<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        if self.value.len() != 4 {
+            return None;
+        }
+
+        read_u32(self.value, 0).ok()
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        if self.value.len() != 8 {
+            return None;
+        }
+
+        read_u64(self.value, 0).ok()
+    }
+
+    /// The value as a string, without its terminator.
+    pub fn as_str(&self) -> Option<&'a str> {
+        let (string, end) = read_string(self.value, 0).ok()?;
+
+        if end != self.value.len() {
+            return None;
+        }
+
+        Some(string)
+    }
+
+    /// Iterate over the cells of the value.
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a {
+        let value = self.value;
+
+        (0..value.len() / 4).map(move |index| read_u32(value, index * 4).unwrap())
+    }
+}
+
+/// Total size of the blob starting with `header`, to know how much of it to read.
+pub fn blob_size(header: &[u8]) -> Result<usize, Error> {
+    if read_u32(header, HEADER_MAGIC)? != FDT_MAGIC {
+        return Err(Error::BadMagic);
+    }
+
+    Ok(read_u32(header, HEADER_TOTAL_SIZE)? as usize)
+}
+
+/// A read only view of a device tree blob.
+#[derive(Copy, Clone)]
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structure: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Check the header of `blob` and the position of its blocks.
+    pub fn new(blob: &'a [u8]) -> Result<Self, Error> {
+        if blob.len() < FDT_HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+
+        if read_u32(blob, HEADER_MAGIC)? != FDT_MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        let version = read_u32(blob, HEADER_VERSION)?;
+        if version < FDT_VERSION {
+            return Err(Error::BadVersion(version));
+        }
+
+        let total_size = read_u32(blob, HEADER_TOTAL_SIZE)? as usize;
+        if total_size > blob.len() {
+            return Err(Error::Truncated);
+        }
+
+        let blob = &blob[..total_size];
+        let get_block = |offset_field: usize, size_field: usize| -> Result<&'a [u8], Error> {
+            let offset = read_u32(blob, offset_field)? as usize;
+            let size = read_u32(blob, size_field)? as usize;
+
+            blob.get(offset..offset + size).ok_or(Error::Truncated)
+        };
+
+        let fdt = Fdt {
+            blob,
+            structure: get_block(HEADER_OFF_DT_STRUCT, HEADER_SIZE_DT_STRUCT)?,
+            strings: get_block(HEADER_OFF_DT_STRINGS, HEADER_SIZE_DT_STRINGS)?,
+        };
+
+        // Make sure the reservation map is terminated.
+        fdt.reserved_memory()
+            .try_fold((), |_, entry| entry.map(|_| ()))?;
+
+        Ok(fdt)
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.blob.len()
+    }
+
+    pub fn boot_cpuid(&self) -> u32 {
+        read_u32(self.blob, HEADER_BOOT_CPUID_PHYS).unwrap()
+    }
+
+    /// Iterate over the memory reservation map, as (address, size).
+    pub fn reserved_memory(&self) -> impl Iterator<Item = Result<(u64, u64), Error>> + 'a {
+        let blob = self.blob;
+        let mut offset = read_u32(blob, HEADER_OFF_MEM_RSVMAP).unwrap() as usize;
+        let mut done = false;
+
+        core::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+
+            let entry = read_u64(blob, offset)
+                .and_then(|address| Ok((address, read_u64(blob, offset + 8)?)));
+            offset += FDT_RESERVE_ENTRY_SIZE;
+
+            match entry {
+                Ok((0, 0)) => {
+                    done = true;
+                    None
+                }
+                Ok(entry) => Some(Ok(entry)),
+                Err(error) => {
+                    done = true;
+                    Some(Err(error))
+                }
+            }
+        })
+    }
+
+    /// Read the tag at `offset`, returning it and the offset of the next one.
+    fn next_tag(&self, offset: usize) -> Result<(u32, usize), Error> {
+        let tag = read_u32(self.structure, offset).map_err(|_| Error::BadStructure)?;
+
+        let next_offset = match tag {
+            FDT_BEGIN_NODE => align4(read_string(self.structure, offset + TAG_SIZE)?.1),
+            FDT_PROP => {
+                let length = read_u32(self.structure, offset + TAG_SIZE)? as usize;
+                align4(offset + PROPERTY_HEADER_SIZE + length)
+            }
+            FDT_END_NODE | FDT_NOP | FDT_END => offset + TAG_SIZE,
+            _ => return Err(Error::BadStructure),
+        };
+
+        if next_offset > self.structure.len() {
+            return Err(Error::BadStructure);
+        }
+
+        Ok((tag, next_offset))
+    }
+
+    /// Offset of the root node.
+    pub fn root(&self) -> Result<usize, Error> {
+        let mut offset = 0;
+
+        loop {
+            match self.next_tag(offset)? {
+                (FDT_BEGIN_NODE, _) => return Ok(offset),
+                (FDT_NOP, next_offset) => offset = next_offset,
+                _ => return Err(Error::BadStructure),
+            }
+        }
+    }
+
+    fn check_node(&self, node: usize) -> Result<(), Error> {
+        match self.next_tag(node)? {
+            (FDT_BEGIN_NODE, _) => Ok(()),
+            _ => Err(Error::BadStructure),
+        }
+    }
+
+    /// Name of a node, unit address included.
+    pub fn node_name(&self, node: usize) -> Result<&'a str, Error> {
+        self.check_node(node)?;
+
+        Ok(read_string(self.structure, node + TAG_SIZE)?.0)
+    }
+
+    /// Offset of the first tag after the properties of `node`.
+    fn end_of_properties(&self, node: usize) -> Result<usize, Error> {
+        self.check_node(node)?;
+
+        let (_, mut offset) = self.next_tag(node)?;
+
+        loop {
+            match self.next_tag(offset)? {
+                (FDT_PROP, next_offset) | (FDT_NOP, next_offset) => offset = next_offset,
+                _ => return Ok(offset),
+            }
+        }
+    }
+
+    /// Offset following the `FDT_END_NODE` of `node`.
+    fn end_of_node(&self, node: usize) -> Result<usize, Error> {
+        self.check_node(node)?;
+
+        let mut depth = 0;
+        let mut offset = node;
+
+        loop {
+            let (tag, next_offset) = self.next_tag(offset)?;
+
+            match tag {
+                FDT_BEGIN_NODE => depth += 1,
+                FDT_END_NODE => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(next_offset);
+                    }
+                }
+                FDT_END => return Err(Error::BadStructure),
+                _ => {}
+            }
+
+            offset = next_offset;
+        }
+    }
+
+    /// Read the property at `offset`.
+    fn property_at(&self, offset: usize) -> Result<Property<'a>, Error> {
+        let length = read_u32(self.structure, offset + TAG_SIZE)? as usize;
+        let name_offset = read_u32(self.structure, offset + 2 * TAG_SIZE)? as usize;
+        let value_start = offset + PROPERTY_HEADER_SIZE;
+
+        Ok(Property {
+            name: read_string(self.strings, name_offset)?.0,
+            value: self
+                .structure
+                .get(value_start..value_start + length)
+                .ok_or(Error::BadStructure)?,
+        })
+    }
+
+    /// Iterate over the properties of `node`, with their offsets.
+    fn property_offsets(
+        &self,
+        node: usize,
+    ) -> impl Iterator<Item = Result<(usize, Property<'a>), Error>> + 'a {
+        let fdt = *self;
+        let mut offset = fdt.next_tag(node).map(|(_, offset)| offset);
+
+        core::iter::from_fn(move || loop {
+            let current_offset = match offset {
+                Ok(current_offset) => current_offset,
+                Err(Error::NotFound) => return None,
+                Err(error) => {
+                    offset = Err(Error::NotFound);
+                    return Some(Err(error));
+                }
+            };
+
+            match fdt.next_tag(current_offset) {
+                Ok((FDT_PROP, next_offset)) => {
+                    offset = Ok(next_offset);
+                    return Some(
+                        fdt.property_at(current_offset)
+                            .map(|property| (current_offset, property)),
+                    );
+                }
+                Ok((FDT_NOP, next_offset)) => offset = Ok(next_offset),
+                Ok(_) => {
+                    offset = Err(Error::NotFound);
+                    return None;
+                }
+                Err(error) => {
+                    offset = Err(Error::NotFound);
+                    return Some(Err(error));
+                }
+            }
+        })
+    }
+
+    /// Iterate over the properties of `node`.
+    pub fn properties(&self, node: usize) -> impl Iterator<Item = Property<'a>> + 'a {
+        // Iteration stops after the first error.
+        self.property_offsets(node)
+            .filter_map(Result::ok)
+            .map(|(_, property)| property)
+    }
+
+    fn find_property_offset(
+        &self,
+        node: usize,
+        name: &str,
+    ) -> Result<(usize, Property<'a>), Error> {
+        self.check_node(node)?;
+
+        for entry in self.property_offsets(node) {
+            let (offset, property) = entry?;
+
+            if property.name == name {
+                return Ok((offset, property));
+            }
+        }
+
+        Err(Error::NotFound)
+    }
+
+    pub fn get_property(&self, node: usize, name: &str) -> Option<Property<'a>> {
+        self.find_property_offset(node, name)
+            .ok()
+            .map(|(_, property)| property)
+    }
+
+    /// Iterate over the children of `node`.
+    pub fn subnodes(&self, node: usize) -> impl Iterator<Item = usize> + 'a {
+        let fdt = *self;
+        let mut offset = fdt.end_of_properties(node).ok();
+
+        core::iter::from_fn(move || loop {
+            let current_offset = offset?;
+
+            match fdt.next_tag(current_offset) {
+                Ok((FDT_BEGIN_NODE, _)) => {
+                    offset = fdt.end_of_node(current_offset).ok();
+                    return Some(current_offset);
+                }
+                Ok((FDT_NOP, next_offset)) => offset = Some(next_offset),
+                _ => {
+                    offset = None;
+                    return None;
+                }
+            }
+        })
+    }
+
+    /// Find a child of `node`, `name` matching with or without the unit address.
+    pub fn find_subnode(&self, node: usize, name: &str) -> Option<usize> {
+        self.subnodes(node).find(|child| {
+            let child_name = match self.node_name(*child) {
+                Ok(child_name) => child_name,
+                Err(_) => return false,
+            };
+
+            child_name == name
+                || (!name.contains('@')
+                    && child_name.starts_with(name)
+                    && child_name[name.len()..].starts_with('@'))
+        })
+    }
+
+    /// Find a node from its path, which can start with an alias.
+    pub fn find_node(&self, path: &str) -> Option<usize> {
+        let (mut node, relative_path) = if path.starts_with('/') {
+            (self.root().ok()?, path)
+        } else {
+            let alias_end = path.find('/').unwrap_or_else(|| path.len());
+            let aliases = self.find_node("/aliases")?;
+            let alias_path = self.get_property(aliases, &path[..alias_end])?.as_str()?;
+
+            (self.find_node(alias_path)?, &path[alias_end..])
+        };
+
+        for name in relative_path.split('/').filter(|name| !name.is_empty()) {
+            node = self.find_subnode(node, name)?;
+        }
+
+        Some(node)
+    }
+
+    /// Parent of `node`, found by walking the tree from the root.
+    pub fn parent(&self, node: usize) -> Option<usize> {
+        let mut parent = self.root().ok()?;
+
+        loop {
+            let child = self.subnodes(parent).find(|child| {
+                self.end_of_node(*child)
+                    .map(|end| *child <= node && node < end)
+                    .unwrap_or(false)
+            })?;
+
+            if child == node {
+                return Some(parent);
+            }
+
+            parent = child;
+        }
+    }
+
+    /// Find a child of `node` named exactly `name`.
+    fn find_exact_subnode(&self, node: usize, name: &str) -> Option<usize> {
+        self.subnodes(node)
+            .find(|child| self.node_name(*child) == Ok(name))
+    }
+
+    /// Iterate over the properties of all the nodes, with the offset of their node.
+    fn tree_properties(&self) -> impl Iterator<Item = Result<(usize, Property<'a>), Error>> + 'a {
+        let fdt = *self;
+        let mut offset = Some(0);
+        // Properties come before the children, they belong to the last node started.
+        let mut node = 0;
+
+        core::iter::from_fn(move || loop {
+            let current_offset = offset?;
+
+            match fdt.next_tag(current_offset) {
+                Ok((FDT_END, _)) => {
+                    offset = None;
+                    return None;
+                }
+                Ok((tag, next_offset)) => {
+                    offset = Some(next_offset);
+
+                    match tag {
+                        FDT_BEGIN_NODE => node = current_offset,
+                        FDT_PROP => {
+                            return Some(
+                                fdt.property_at(current_offset)
+                                    .map(|property| (node, property)),
+                            )
+                        }
+                        _ => {}
+                    }
+                }
+                Err(error) => {
+                    offset = None;
+                    return Some(Err(error));
+                }
+            }
+        })
+    }
+
+    /// Highest phandle of the tree, 0 without any.
+    fn max_phandle(&self) -> Result<u32, Error> {
+        let mut max_phandle = 0;
+
+        for entry in self.tree_properties() {
+            let (_, property) = entry?;
+
+            if is_phandle_property(property.name) {
+                match property.as_u32() {
+                    Some(u32::MAX) | None => return Err(Error::BadStructure),
+                    Some(phandle) => max_phandle = max_phandle.max(phandle),
+                }
+            }
+        }
+
+        Ok(max_phandle)
+    }
+
+    /// Find the node with the given phandle.
+    pub fn find_node_by_phandle(&self, phandle: u32) -> Option<usize> {
+        self.tree_properties()
+            .filter_map(Result::ok)
+            .find(|(_, property)| {
+                is_phandle_property(property.name) && property.as_u32() == Some(phandle)
+            })
+            .map(|(node, _)| node)
+    }
+}
+
+/// Reference of an overlay to a label of the tree it applies to, from `__fixups__`.
+struct Fixup<'a> {
+    label: &'a str,
+    path: &'a str,
+    property: &'a str,
+    /// Offset of the phandle in the value of the property.
+    offset: usize,
+}
+
+impl<'a> Fixup<'a> {
+    /// Parse a "path:property:offset" reference to `label`.
+    fn parse(label: &'a str, reference: &'a [u8]) -> Result<Self, Error> {
+        let reference = str::from_utf8(reference).map_err(|_| Error::BadOverlay)?;
+        let mut parts = reference.split(':');
+
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(path), Some(property), Some(offset), None) => Ok(Fixup {
+                label,
+                path,
+                property,
+                offset: offset.parse().map_err(|_| Error::BadOverlay)?,
+            }),
+            _ => Err(Error::BadOverlay),
+        }
+    }
+}
+
+/// An overlay being applied.
+struct Overlay<'a> {
+    fdt: Fdt<'a>,
+    fixups: Option<usize>,
+    local_fixups: Option<usize>,
+    /// Added to the phandles of the overlay so they follow those of the tree.
+    phandle_offset: u32,
+}
+
+impl<'a> Overlay<'a> {
+    fn fixups(&self) -> impl Iterator<Item = Result<Fixup<'a>, Error>> + 'a {
+        let fdt = self.fdt;
+
+        self.fixups
+            .into_iter()
+            .flat_map(move |node| fdt.properties(node))
+            .flat_map(|property| {
+                property
+                    .value
+                    .split(|byte| *byte == 0)
+                    .filter(|reference| !reference.is_empty())
+                    .map(move |reference| Fixup::parse(property.name, reference))
+            })
+    }
+
+    /// Label of the tree the phandle at `offset` in the property `name` of `node` refers to.
+    fn find_fixup(&self, node: usize, name: &str, offset: usize) -> Result<Option<&'a str>, Error> {
+        for fixup in self.fixups() {
+            let fixup = fixup?;
+
+            if fixup.property == name
+                && fixup.offset == offset
+                && self.fdt.find_node(fixup.path) == Some(node)
+            {
+                return Ok(Some(fixup.label));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// A device tree being edited in a buffer bigger than the blob, like libfdt `fdt_open_into`.
+///
+/// Blocks are laid out as header, reservation map, structure and strings, followed by the
+/// free space.
+pub struct FdtEditor<'a> {
+    buffer: &'a mut [u8],
+}
+
+impl<'a> FdtEditor<'a> {
+    /// Copy `blob` in `buffer` to edit it.
+    pub fn open_into(blob: &[u8], buffer: &'a mut [u8]) -> Result<Self, Error> {
+        let fdt = Fdt::new(blob)?;
+
+        let reservation_count = fdt.reserved_memory().count();
+        let reservation_map_size = (reservation_count + 1) * FDT_RESERVE_ENTRY_SIZE;
+
+        let structure_offset = FDT_HEADER_SIZE + reservation_map_size;
+        let strings_offset = structure_offset + fdt.structure.len();
+        let used_size = strings_offset + fdt.strings.len();
+
+        if used_size > buffer.len() || buffer.len() > u32::MAX as usize {
+            return Err(Error::NoSpace);
+        }
+
+        let reservation_map_offset = read_u32(blob, HEADER_OFF_MEM_RSVMAP)? as usize;
+        buffer[FDT_HEADER_SIZE..structure_offset].copy_from_slice(
+            &blob[reservation_map_offset..reservation_map_offset + reservation_map_size],
+        );
+        buffer[structure_offset..strings_offset].copy_from_slice(fdt.structure);
+        buffer[strings_offset..used_size].copy_from_slice(fdt.strings);
+
+        write_u32(buffer, HEADER_MAGIC, FDT_MAGIC);
+        write_u32(buffer, HEADER_TOTAL_SIZE, buffer.len() as u32);
+        write_u32(buffer, HEADER_OFF_DT_STRUCT, structure_offset as u32);
+        write_u32(buffer, HEADER_OFF_DT_STRINGS, strings_offset as u32);
+        write_u32(buffer, HEADER_OFF_MEM_RSVMAP, FDT_HEADER_SIZE as u32);
+        write_u32(buffer, HEADER_VERSION, FDT_VERSION);
+        write_u32(
+            buffer,
+            HEADER_LAST_COMP_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+        );
+        write_u32(buffer, HEADER_BOOT_CPUID_PHYS, fdt.boot_cpuid());
+        write_u32(buffer, HEADER_SIZE_DT_STRINGS, fdt.strings.len() as u32);
+        write_u32(buffer, HEADER_SIZE_DT_STRUCT, fdt.structure.len() as u32);
+
+        Ok(FdtEditor { buffer })
+    }
+
+    /// A view of the tree, to look up the nodes to edit.
+    pub fn fdt(&self) -> Fdt {
+        Fdt::new(self.buffer).unwrap()
+    }
+
+    fn header(&self, field: usize) -> usize {
+        read_u32(self.buffer, field).unwrap() as usize
+    }
+
+    fn used_size(&self) -> usize {
+        self.header(HEADER_OFF_DT_STRINGS) + self.header(HEADER_SIZE_DT_STRINGS)
+    }
+
+    /// Replace `old_size` bytes at `offset` by `new_size` bytes, moving what follows.
+    fn splice(&mut self, offset: usize, old_size: usize, new_size: usize) -> Result<(), Error> {
+        let used_size = self.used_size();
+
+        if used_size - old_size + new_size > self.buffer.len() {
+            return Err(Error::NoSpace);
+        }
+
+        self.buffer
+            .copy_within(offset + old_size..used_size, offset + new_size);
+
+        Ok(())
+    }
+
+    fn add_to_header(&mut self, field: usize, old_size: usize, new_size: usize) {
+        let value = self.header(field) - old_size + new_size;
+
+        write_u32(self.buffer, field, value as u32);
+    }
+
+    /// Resize a part of the structure block at `offset`.
+    fn splice_structure(
+        &mut self,
+        offset: usize,
+        old_size: usize,
+        new_size: usize,
+    ) -> Result<(), Error> {
+        let structure_offset = self.header(HEADER_OFF_DT_STRUCT);
+
+        self.splice(structure_offset + offset, old_size, new_size)?;
+        self.add_to_header(HEADER_SIZE_DT_STRUCT, old_size, new_size);
+        self.add_to_header(HEADER_OFF_DT_STRINGS, old_size, new_size);
+
+        Ok(())
+    }
+
+    /// Offset of `name` in the strings block, added if missing.
+    fn find_or_add_string(&mut self, name: &str) -> Result<usize, Error> {
+        let strings_offset = self.header(HEADER_OFF_DT_STRINGS);
+        let strings_size = self.header(HEADER_SIZE_DT_STRINGS);
+        let strings = &self.buffer[strings_offset..strings_offset + strings_size];
+
+        // Also matches suffixes of longer strings, like libfdt.
+        let mut offset = 0;
+        while offset < strings.len() {
+            let (string, next_offset) = read_string(strings, offset)?;
+
+            if string.ends_with(name) {
+                return Ok(offset + string.len() - name.len());
+            }
+
+            offset = next_offset;
+        }
+
+        let new_string_offset = strings_offset + strings_size;
+        if new_string_offset + name.len() + 1 > self.buffer.len() {
+            return Err(Error::NoSpace);
+        }
+
+        self.buffer[new_string_offset..new_string_offset + name.len()]
+            .copy_from_slice(name.as_bytes());
+        self.buffer[new_string_offset + name.len()] = 0;
+        self.add_to_header(HEADER_SIZE_DT_STRINGS, 0, name.len() + 1);
+
+        Ok(strings_size)
+    }
+
+    fn write_structure(&mut self, offset: usize, data: &[u8]) {
+        let start = self.header(HEADER_OFF_DT_STRUCT) + offset;
+
+        self.buffer[start..start + data.len()].copy_from_slice(data);
+    }
+
+    fn write_structure_u32(&mut self, offset: usize, value: u32) {
+        self.write_structure(offset, &value.to_be_bytes());
+    }
+
+    /// Resize the value of a property of `node` to `length` zeroed bytes, creating it if
+    /// needed, returning the offset of the value.
+    fn resize_property(&mut self, node: usize, name: &str, length: usize) -> Result<usize, Error> {
+        check_name(name)?;
+
+        let existing_property = match self.fdt().find_property_offset(node, name) {
+            Ok((offset, property)) => Some((offset, property.value.len())),
+            Err(Error::NotFound) => None,
+            Err(error) => return Err(error),
+        };
+
+        let offset = match existing_property {
+            Some((offset, old_length)) => {
+                self.splice_structure(
+                    offset + PROPERTY_HEADER_SIZE,
+                    align4(old_length),
+                    align4(length),
+                )?;
+
+                offset
+            }
+            None => {
+                let offset = self.fdt().end_of_properties(node)?;
+                let name_offset = self.find_or_add_string(name)?;
+
+                self.splice_structure(offset, 0, PROPERTY_HEADER_SIZE + align4(length))?;
+                self.write_structure_u32(offset, FDT_PROP);
+                self.write_structure_u32(offset + 2 * TAG_SIZE, name_offset as u32);
+
+                offset
+            }
+        };
+
+        self.write_structure_u32(offset + TAG_SIZE, length as u32);
+
+        let value_offset = offset + PROPERTY_HEADER_SIZE;
+        let start = self.header(HEADER_OFF_DT_STRUCT) + value_offset;
+        for byte in self.buffer[start..start + align4(length)].iter_mut() {
+            *byte = 0;
+        }
+
+        Ok(value_offset)
+    }
+
+    /// Set a property of `node`, creating it if needed.
+    pub fn set_property(&mut self, node: usize, name: &str, value: &[u8]) -> Result<(), Error> {
+        let value_offset = self.resize_property(node, name, value.len())?;
+
+        self.write_structure(value_offset, value);
+
+        Ok(())
+    }
+
+    pub fn set_property_u32(&mut self, node: usize, name: &str, value: u32) -> Result<(), Error> {
+        self.set_property(node, name, &value.to_be_bytes())
+    }
+
+    pub fn set_property_u64(&mut self, node: usize, name: &str, value: u64) -> Result<(), Error> {
+        self.set_property(node, name, &value.to_be_bytes())
+    }
+
+    /// Set a string property, the terminator is added.
+    pub fn set_property_str(&mut self, node: usize, name: &str, value: &str) -> Result<(), Error> {
+        if value.bytes().any(|byte| byte == 0) {
+            return Err(Error::BadName);
+        }
+
+        // The value is zeroed, leaving the terminator in place.
+        let value_offset = self.resize_property(node, name, value.len() + 1)?;
+        self.write_structure(value_offset, value.as_bytes());
+
+        Ok(())
+    }
+
+    pub fn delete_property(&mut self, node: usize, name: &str) -> Result<(), Error> {
+        let (offset, property) = self.fdt().find_property_offset(node, name)?;
+        let size = PROPERTY_HEADER_SIZE + align4(property.value.len());
+
+        self.splice_structure(offset, size, 0)
+    }
+
+    /// Add an empty child to `parent`, returning its offset.
+    pub fn add_subnode(&mut self, parent: usize, name: &str) -> Result<usize, Error> {
+        check_name(name)?;
+
+        let fdt = self.fdt();
+        if fdt
+            .subnodes(parent)
+            .any(|child| fdt.node_name(child) == Ok(name))
+        {
+            return Err(Error::BadName);
+        }
+
+        // Added first, after the properties, like libfdt.
+        let offset = fdt.end_of_properties(parent)?;
+        let name_size = align4(name.len() + 1);
+        let size = TAG_SIZE + name_size + TAG_SIZE;
+
+        self.splice_structure(offset, 0, size)?;
+        self.write_structure_u32(offset, FDT_BEGIN_NODE);
+        self.write_structure(offset + TAG_SIZE, name.as_bytes());
+        for padding_offset in name.len()..name_size {
+            self.write_structure(offset + TAG_SIZE + padding_offset, &[0]);
+        }
+        self.write_structure_u32(offset + TAG_SIZE + name_size, FDT_END_NODE);
+
+        Ok(offset)
+    }
+
+    /// Find a child of `parent`, adding it if missing.
+    pub fn get_or_add_subnode(&mut self, parent: usize, name: &str) -> Result<usize, Error> {
+        match self.fdt().find_subnode(parent, name) {
+            Some(node) => Ok(node),
+            None => self.add_subnode(parent, name),
+        }
+    }
+
+    /// Delete `node` and its children.
+    pub fn delete_node(&mut self, node: usize) -> Result<(), Error> {
+        let fdt = self.fdt();
+        if fdt.root()? == node {
+            return Err(Error::BadName);
+        }
+
+        let end = fdt.end_of_node(node)?;
+
+        self.splice_structure(node, end - node, 0)
+    }
+
+    /// Add an entry to the memory reservation map.
+    pub fn add_reservation(&mut self, address: u64, size: u64) -> Result<(), Error> {
+        let reservation_count = self.fdt().reserved_memory().count();
+        let offset =
+            self.header(HEADER_OFF_MEM_RSVMAP) + reservation_count * FDT_RESERVE_ENTRY_SIZE;
+
+        self.splice(offset, 0, FDT_RESERVE_ENTRY_SIZE)?;
+        self.add_to_header(HEADER_OFF_DT_STRUCT, 0, FDT_RESERVE_ENTRY_SIZE);
+        self.add_to_header(HEADER_OFF_DT_STRINGS, 0, FDT_RESERVE_ENTRY_SIZE);
+
+        write_u64(self.buffer, offset, address);
+        write_u64(self.buffer, offset + 8, size);
+
+        Ok(())
+    }
+
+    /// Apply an overlay built with `dtc -@`, like libfdt `fdt_overlay_apply`.
+    ///
+    /// The phandles of the overlay are moved after those of the tree, and its references to
+    /// labels of the tree are resolved with `__symbols__`. The labels of the overlay aren't
+    /// added to `__symbols__`, so an overlay can't refer to those of a previous one.
+    pub fn apply_overlay(&mut self, overlay: &[u8]) -> Result<(), Error> {
+        let fdt = Fdt::new(overlay)?;
+        let root = fdt.root()?;
+        let overlay = Overlay {
+            fdt,
+            fixups: fdt.find_exact_subnode(root, "__fixups__"),
+            local_fixups: fdt.find_exact_subnode(root, "__local_fixups__"),
+            phandle_offset: self.fdt().max_phandle()?,
+        };
+
+        // Other children, like `__symbols__`, have no `__overlay__`.
+        for fragment in fdt.subnodes(root) {
+            let content = match fdt.find_exact_subnode(fragment, "__overlay__") {
+                Some(content) => content,
+                None => continue,
+            };
+
+            let local_fixups = overlay
+                .local_fixups
+                .and_then(|node| fdt.find_exact_subnode(node, fdt.node_name(fragment).ok()?))
+                .and_then(|node| fdt.find_exact_subnode(node, "__overlay__"));
+            let target = self.find_overlay_target(&overlay, fragment)?;
+
+            self.merge_overlay_node(&overlay, target, content, local_fixups)?;
+        }
+
+        Ok(())
+    }
+
+    /// Phandle of the node of the tree with `label`.
+    fn find_symbol(&self, label: &str) -> Result<u32, Error> {
+        let fdt = self.fdt();
+        let node = fdt
+            .find_node("/__symbols__")
+            .and_then(|symbols| fdt.get_property(symbols, label)?.as_str())
+            .and_then(|path| fdt.find_node(path))
+            .ok_or(Error::NotFound)?;
+
+        fdt.properties(node)
+            .find(|property| is_phandle_property(property.name))
+            .and_then(|property| property.as_u32())
+            .ok_or(Error::NotFound)
+    }
+
+    /// The node of the tree `fragment` applies to, given by phandle or path.
+    fn find_overlay_target(&self, overlay: &Overlay, fragment: usize) -> Result<usize, Error> {
+        let fdt = self.fdt();
+
+        if let Some(target) = overlay.fdt.get_property(fragment, "target") {
+            let phandle = match overlay.find_fixup(fragment, "target", 0)? {
+                Some(label) => self.find_symbol(label)?,
+                None => target.as_u32().ok_or(Error::BadOverlay)?,
+            };
+
+            return fdt.find_node_by_phandle(phandle).ok_or(Error::NotFound);
+        }
+
+        let path = overlay
+            .fdt
+            .get_property(fragment, "target-path")
+            .and_then(|property| property.as_str())
+            .ok_or(Error::BadOverlay)?;
+
+        fdt.find_node(path).ok_or(Error::NotFound)
+    }
+
+    /// Merge the overlay node `content` and its children in `target`, `local_fixups` being
+    /// the node of `__local_fixups__` matching `content`.
+    fn merge_overlay_node(
+        &mut self,
+        overlay: &Overlay,
+        target: usize,
+        content: usize,
+        local_fixups: Option<usize>,
+    ) -> Result<(), Error> {
+        let fdt = overlay.fdt;
+        // Nodes of the tree, of the overlay and of its local fixups, from `content` to the
+        // node being merged. Edits only move what follows the node being merged.
+        let mut path = [(0, 0, None); MAX_OVERLAY_DEPTH];
+        let mut depth = 0;
+        let mut offset = content;
+
+        loop {
+            let (tag, next_offset) = fdt.next_tag(offset)?;
+
+            match tag {
+                FDT_BEGIN_NODE if depth == 0 => {
+                    path[0] = (target, content, local_fixups);
+                    depth = 1;
+                }
+                FDT_BEGIN_NODE => {
+                    if depth == MAX_OVERLAY_DEPTH {
+                        return Err(Error::BadOverlay);
+                    }
+
+                    let (parent, _, parent_fixups) = path[depth - 1];
+                    let name = fdt.node_name(offset)?;
+                    let node = match self.fdt().find_exact_subnode(parent, name) {
+                        Some(node) => node,
+                        None => self.add_subnode(parent, name)?,
+                    };
+                    let fixups = parent_fixups.and_then(|node| fdt.find_exact_subnode(node, name));
+
+                    path[depth] = (node, offset, fixups);
+                    depth += 1;
+                }
+                FDT_PROP => {
+                    let (node, overlay_node, fixups) = path[depth - 1];
+                    let property = fdt.property_at(offset)?;
+
+                    self.set_overlay_property(overlay, node, overlay_node, fixups, property)?;
+                }
+                FDT_END_NODE => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                FDT_END => return Err(Error::BadStructure),
+                _ => {}
+            }
+
+            offset = next_offset;
+        }
+    }
+
+    /// Copy `property` of the overlay node `overlay_node` to `node`, fixing up the phandles
+    /// it contains.
+    fn set_overlay_property(
+        &mut self,
+        overlay: &Overlay,
+        node: usize,
+        overlay_node: usize,
+        local_fixups: Option<usize>,
+        property: Property,
+    ) -> Result<(), Error> {
+        let value_offset = self.resize_property(node, property.name, property.value.len())?;
+        self.write_structure(value_offset, property.value);
+
+        // Phandles of the overlay and references to them.
+        let own_phandle = if is_phandle_property(property.name) {
+            Some(0)
+        } else {
+            None
+        };
+        let local_references = local_fixups
+            .and_then(|node| overlay.fdt.get_property(node, property.name))
+            .into_iter()
+            .flat_map(|fixup| fixup.cells())
+            .map(|offset| offset as usize);
+
+        for offset in own_phandle.into_iter().chain(local_references) {
+            let phandle = read_u32(property.value, offset)
+                .ok()
+                .and_then(|phandle| phandle.checked_add(overlay.phandle_offset))
+                .ok_or(Error::BadOverlay)?;
+
+            self.write_structure_u32(value_offset + offset, phandle);
+        }
+
+        // References to labels of the tree.
+        for fixup in overlay.fixups() {
+            let fixup = fixup?;
+
+            if fixup.property != property.name
+                || overlay.fdt.find_node(fixup.path) != Some(overlay_node)
+            {
+                continue;
+            }
+
+            if fixup.offset + TAG_SIZE > property.value.len() {
+                return Err(Error::BadOverlay);
+            }
+
+            let phandle = self.find_symbol(fixup.label)?;
+            self.write_structure_u32(value_offset + fixup.offset, phandle);
+        }
+
+        Ok(())
+    }
+
+    /// The edited blob, free space included.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buffer
+    }
+
+    /// Finish editing, dropping the free space at the end of the blob.
+    pub fn pack(self) -> &'a [u8] {
+        let used_size = self.used_size();
+
+        write_u32(self.buffer, HEADER_TOTAL_SIZE, used_size as u32);
+
+        &self.buffer[..used_size]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    /// Builds structure blocks token by token.
+    #[derive(Default)]
+    struct StructureBuilder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl StructureBuilder {
+        fn u32(&mut self, value: u32) -> &mut Self {
+            self.structure.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+
+        fn begin_node(&mut self, name: &str) -> &mut Self {
+            self.u32(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.structure.resize(align4(self.structure.len()), 0);
+            self
+        }
+
+        fn end_node(&mut self) -> &mut Self {
+            self.u32(FDT_END_NODE)
+        }
+
+        fn property(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_offset = self.strings.len();
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+
+            self.u32(FDT_PROP)
+                .u32(value.len() as u32)
+                .u32(name_offset as u32);
+            self.structure.extend_from_slice(value);
+            self.structure.resize(align4(self.structure.len()), 0);
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.u32(FDT_END)
+        }
+
+        fn build(&self, reservations: &[(u64, u64)]) -> Vec<u8> {
+            let structure_offset = FDT_HEADER_SIZE + (reservations.len() + 1) * 16;
+            let strings_offset = structure_offset + self.structure.len();
+            let total_size = strings_offset + self.strings.len();
+
+            let mut blob = vec![0; FDT_HEADER_SIZE];
+            for (address, size) in reservations.iter().chain(&[(0, 0)]) {
+                blob.extend_from_slice(&address.to_be_bytes());
+                blob.extend_from_slice(&size.to_be_bytes());
+            }
+            blob.extend_from_slice(&self.structure);
+            blob.extend_from_slice(&self.strings);
+
+            write_u32(&mut blob, HEADER_MAGIC, FDT_MAGIC);
+            write_u32(&mut blob, HEADER_TOTAL_SIZE, total_size as u32);
+            write_u32(&mut blob, HEADER_OFF_DT_STRUCT, structure_offset as u32);
+            write_u32(&mut blob, HEADER_OFF_DT_STRINGS, strings_offset as u32);
+            write_u32(&mut blob, HEADER_OFF_MEM_RSVMAP, FDT_HEADER_SIZE as u32);
+            write_u32(&mut blob, HEADER_VERSION, FDT_VERSION);
+            write_u32(&mut blob, HEADER_LAST_COMP_VERSION, 16);
+            write_u32(&mut blob, HEADER_BOOT_CPUID_PHYS, 0);
+            write_u32(&mut blob, HEADER_SIZE_DT_STRINGS, self.strings.len() as u32);
+            write_u32(
+                &mut blob,
+                HEADER_SIZE_DT_STRUCT,
+                self.structure.len() as u32,
+            );
+            blob
+        }
+    }
+
+    fn sample() -> Vec<u8> {
+        StructureBuilder::default()
+            .begin_node("")
+            .property("#address-cells", &2u32.to_be_bytes())
+            .property("model", b"p2371-2180\0")
+            .begin_node("aliases")
+            .property("serial0", b"/serial@70006000\0")
+            .end_node()
+            .begin_node("memory@80000000")
+            .property("reg", &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0])
+            .end_node()
+            .begin_node("serial@70006000")
+            .property("status", b"okay\0")
+            .end_node()
+            .end_node()
+            .end()
+            .build(&[(0x8000_0000, 0x1000)])
+    }
+
+    fn cells(values: &[u32]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn names(fdt: &Fdt, node: usize) -> Vec<String> {
+        test_support::names(fdt.subnodes(node), |child| {
+            fdt.node_name(*child).unwrap().to_string()
+        })
+    }
+
+    #[test]
+    fn parse() {
+        let blob = sample();
+        let fdt = Fdt::new(&blob).unwrap();
+        let root = fdt.root().unwrap();
+
+        assert_eq!(fdt.total_size(), blob.len());
+        assert_eq!(
+            fdt.reserved_memory().collect::<Vec<_>>(),
+            [Ok((0x8000_0000, 0x1000))]
+        );
+        assert_eq!(fdt.node_name(root), Ok(""));
+        assert_eq!(
+            names(&fdt, root),
+            ["aliases", "memory@80000000", "serial@70006000"]
+        );
+        assert_eq!(
+            fdt.get_property(root, "#address-cells").unwrap().as_u32(),
+            Some(2)
+        );
+        assert_eq!(
+            fdt.get_property(root, "model").unwrap().as_str(),
+            Some("p2371-2180")
+        );
+
+        let memory = fdt.find_node("/memory").unwrap();
+        assert_eq!(
+            fdt.get_property(memory, "reg")
+                .unwrap()
+                .cells()
+                .collect::<Vec<_>>(),
+            [0, 0x8000_0000, 1, 0]
+        );
+        assert_eq!(fdt.parent(memory), Some(root));
+
+        let serial = fdt.find_node("serial0").unwrap();
+        assert_eq!(fdt.node_name(serial), Ok("serial@70006000"));
+        assert_eq!(fdt.find_node("/memory@90000000"), None);
+        assert_eq!(fdt.find_node("serial1"), None);
+    }
+
+    #[test]
+    fn bad_header() {
+        let blob = sample();
+
+        let mut bad_magic = blob.clone();
+        bad_magic[0] ^= 0xFF;
+        assert_eq!(Fdt::new(&bad_magic).err(), Some(Error::BadMagic));
+
+        let mut old_version = blob.clone();
+        write_u32(&mut old_version, HEADER_VERSION, 16);
+        assert_eq!(Fdt::new(&old_version).err(), Some(Error::BadVersion(16)));
+
+        assert_eq!(
+            Fdt::new(&blob[..FDT_HEADER_SIZE - 1]).err(),
+            Some(Error::Truncated)
+        );
+
+        // A total size covering only a part of the header.
+        let mut small_total_size = blob.clone();
+        write_u32(&mut small_total_size, HEADER_TOTAL_SIZE, 36);
+        assert_eq!(Fdt::new(&small_total_size).err(), Some(Error::Truncated));
+
+        // The strings block is last, one byte more is past the end of the blob.
+        let mut large_strings = blob;
+        let strings_size = read_u32(&large_strings, HEADER_SIZE_DT_STRINGS).unwrap();
+        write_u32(&mut large_strings, HEADER_SIZE_DT_STRINGS, strings_size + 1);
+        assert_eq!(Fdt::new(&large_strings).err(), Some(Error::Truncated));
+    }
+
+    #[test]
+    fn blob_sizes() {
+        let blob = sample();
+
+        // The magic and the total size are enough.
+        assert_eq!(blob_size(&blob), Ok(blob.len()));
+        assert_eq!(blob_size(&blob[..8]), Ok(blob.len()));
+        assert_eq!(blob_size(&blob[..7]), Err(Error::Truncated));
+        assert_eq!(blob_size(&[]), Err(Error::Truncated));
+
+        let mut bad_magic = blob.clone();
+        bad_magic[0] ^= 0xFF;
+        assert_eq!(blob_size(&bad_magic), Err(Error::BadMagic));
+
+        // The rest of the header is only checked once the blob is read.
+        let mut small_total_size = blob;
+        write_u32(&mut small_total_size, HEADER_TOTAL_SIZE, 36);
+        assert_eq!(blob_size(&small_total_size), Ok(36));
+        assert_eq!(Fdt::new(&small_total_size).err(), Some(Error::Truncated));
+    }
+
+    #[test]
+    fn truncated_blobs() {
+        let blob = sample();
+
+        for length in 0..blob.len() {
+            assert_eq!(
+                Fdt::new(&blob[..length]).err(),
+                Some(Error::Truncated),
+                "length {}",
+                length
+            );
+        }
+
+        // The extra bytes aren't part of the tree.
+        let mut padded = blob.clone();
+        padded.extend_from_slice(&[0xFF; 8]);
+        assert_eq!(Fdt::new(&padded).unwrap().total_size(), blob.len());
+    }
+
+    #[test]
+    fn unterminated_reservation_map() {
+        let mut builder = StructureBuilder::default();
+        builder.begin_node("").end_node().end();
+        let mut blob = builder.build(&[]);
+
+        // Turn the terminator into an entry, the map runs through the structure block and
+        // the end of the blob.
+        write_u64(&mut blob, FDT_HEADER_SIZE, 0x8000_0000);
+        assert_eq!(Fdt::new(&blob).err(), Some(Error::Truncated));
+    }
+
+    #[test]
+    fn bad_structure() {
+        let mut builder = StructureBuilder::default();
+        builder.begin_node("").property("a", &[1]);
+        // The node isn't closed.
+        let blob = builder.build(&[]);
+        let fdt = Fdt::new(&blob).unwrap();
+        let root = fdt.root().unwrap();
+        assert_eq!(fdt.end_of_node(root), Err(Error::BadStructure));
+        assert_eq!(fdt.subnodes(root).count(), 0);
+
+        let mut builder = StructureBuilder::default();
+        builder.u32(FDT_END_NODE);
+        let blob = builder.build(&[]);
+        assert_eq!(Fdt::new(&blob).unwrap().root(), Err(Error::BadStructure));
+
+        let mut builder = StructureBuilder::default();
+        builder.begin_node("").u32(0x42).end_node().end();
+        let blob = builder.build(&[]);
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.end_of_properties(0), Err(Error::BadStructure));
+
+        // A property value running past the structure block.
+        let mut builder = StructureBuilder::default();
+        builder
+            .begin_node("")
+            .u32(FDT_PROP)
+            .u32(64)
+            .u32(0)
+            .end_node()
+            .end();
+        let blob = builder.build(&[]);
+        let fdt = Fdt::new(&blob).unwrap();
+        assert!(fdt.get_property(0, "").is_none());
+        assert_eq!(fdt.end_of_node(0), Err(Error::BadStructure));
+    }
+
+    #[test]
+    fn string_offsets() {
+        let blob = sample();
+        let fdt = Fdt::new(&blob).unwrap();
+        let strings_size = fdt.strings.len();
+        let root = fdt.root().unwrap();
+        let (offset, _) = fdt.find_property_offset(root, "model").unwrap();
+        let name_offset_position =
+            read_u32(&blob, HEADER_OFF_DT_STRUCT).unwrap() as usize + offset + 2 * TAG_SIZE;
+
+        // The last string starts right before the terminator of the block.
+        let mut last_string = StructureBuilder::default();
+        last_string
+            .begin_node("")
+            .property("x", &[])
+            .end_node()
+            .end();
+        let last_string = last_string.build(&[]);
+        let fdt = Fdt::new(&last_string).unwrap();
+        assert_eq!(fdt.strings, b"x\0");
+        assert_eq!(fdt.properties(0).next().unwrap().name, "x");
+
+        // Pointing to the terminator gives an empty name, one further is out of the block.
+        let mut at_end = blob.clone();
+        write_u32(&mut at_end, name_offset_position, strings_size as u32 - 1);
+        let fdt = Fdt::new(&at_end).unwrap();
+        assert_eq!(fdt.get_property(root, "").unwrap().value, b"p2371-2180\0");
+
+        let mut past_end = blob.clone();
+        write_u32(&mut past_end, name_offset_position, strings_size as u32);
+        let fdt = Fdt::new(&past_end).unwrap();
+        assert_eq!(
+            fdt.find_property_offset(root, "model").err(),
+            Some(Error::BadStructure)
+        );
+        // Properties after the bad one aren't returned either.
+        assert_eq!(fdt.properties(root).count(), 1);
+
+        // A strings block without its last terminator.
+        let mut unterminated = blob;
+        let strings_size = strings_size as u32 - 1;
+        write_u32(&mut unterminated, HEADER_SIZE_DT_STRINGS, strings_size);
+        let fdt = Fdt::new(&unterminated).unwrap();
+        let serial = fdt.find_node("/serial").unwrap();
+        assert!(fdt.get_property(serial, "status").is_none());
+        assert!(fdt.get_property(root, "model").is_some());
+    }
+
+    #[test]
+    fn edit_round_trip() {
+        let blob = sample();
+        let mut buffer = vec![0xAA; blob.len() + 256];
+        let mut editor = FdtEditor::open_into(&blob, &mut buffer).unwrap();
+
+        let fdt = editor.fdt();
+        let root = fdt.root().unwrap();
+        let serial = fdt.find_node("/serial").unwrap();
+        editor
+            .set_property_str(serial, "status", "disabled")
+            .unwrap();
+
+        let chosen = editor.get_or_add_subnode(root, "chosen").unwrap();
+        editor
+            .set_property_str(chosen, "bootargs", "console=ttyS0")
+            .unwrap();
+        // "cells" is a suffix of "#address-cells", the string is shared.
+        editor
+            .set_property_u64(chosen, "cells", 0x1234_5678_9abc)
+            .unwrap();
+        editor.set_property_u32(chosen, "bootargs", 7).unwrap();
+
+        let aliases = editor.fdt().find_node("/aliases").unwrap();
+        editor.delete_node(aliases).unwrap();
+
+        let root = editor.fdt().root().unwrap();
+        editor.delete_property(root, "model").unwrap();
+        editor.add_reservation(0x9000_0000, 0x2000).unwrap();
+
+        let packed = editor.pack().to_vec();
+        let fdt = Fdt::new(&packed).unwrap();
+        let root = fdt.root().unwrap();
+
+        assert_eq!(fdt.total_size(), packed.len());
+        assert_eq!(
+            fdt.reserved_memory().collect::<Vec<_>>(),
+            [Ok((0x8000_0000, 0x1000)), Ok((0x9000_0000, 0x2000))]
+        );
+        assert_eq!(
+            names(&fdt, root),
+            ["chosen", "memory@80000000", "serial@70006000"]
+        );
+        assert!(fdt.get_property(root, "model").is_none());
+        assert_eq!(
+            fdt.get_property(root, "#address-cells").unwrap().as_u32(),
+            Some(2)
+        );
+
+        let chosen = fdt.find_node("/chosen").unwrap();
+        assert_eq!(
+            fdt.get_property(chosen, "bootargs").unwrap().as_u32(),
+            Some(7)
+        );
+        assert_eq!(
+            fdt.get_property(chosen, "cells").unwrap().as_u64(),
+            Some(0x1234_5678_9abc)
+        );
+        assert_eq!(
+            fdt.strings.len(),
+            Fdt::new(&blob).unwrap().strings.len() + "bootargs\0".len()
+        );
+
+        let serial = fdt.find_node("/serial@70006000").unwrap();
+        assert_eq!(
+            fdt.get_property(serial, "status").unwrap().as_str(),
+            Some("disabled")
+        );
+
+        // Opening a packed tree again and packing it doesn't change it.
+        let mut buffer = vec![0; packed.len()];
+        let editor = FdtEditor::open_into(&packed, &mut buffer).unwrap();
+        assert_eq!(editor.pack(), &packed[..]);
+    }
+
+    #[test]
+    fn edit_errors() {
+        let blob = sample();
+
+        let mut small = vec![0; blob.len() - 1];
+        assert_eq!(
+            FdtEditor::open_into(&blob, &mut small).err(),
+            Some(Error::NoSpace)
+        );
+
+        // No room left for anything.
+        let mut exact = vec![0; blob.len()];
+        let mut editor = FdtEditor::open_into(&blob, &mut exact).unwrap();
+        let root = editor.fdt().root().unwrap();
+        assert_eq!(editor.set_property_u32(root, "new", 1), Err(Error::NoSpace));
+        assert_eq!(
+            editor.add_reservation(0x9000_0000, 0x1000),
+            Err(Error::NoSpace)
+        );
+        // Shrinking a property always fits.
+        editor.set_property(root, "model", b"").unwrap();
+        assert_eq!(editor.fdt().total_size(), blob.len());
+
+        let mut buffer = vec![0; blob.len() + 64];
+        let mut editor = FdtEditor::open_into(&blob, &mut buffer).unwrap();
+        let root = editor.fdt().root().unwrap();
+        assert_eq!(editor.add_subnode(root, "aliases"), Err(Error::BadName));
+        assert_eq!(editor.add_subnode(root, "a/b"), Err(Error::BadName));
+        assert_eq!(editor.add_subnode(root, ""), Err(Error::BadName));
+        assert_eq!(
+            editor.set_property_str(root, "x", "a\0b"),
+            Err(Error::BadName)
+        );
+        assert_eq!(
+            editor.delete_property(root, "missing"),
+            Err(Error::NotFound)
+        );
+        assert_eq!(editor.delete_node(root), Err(Error::BadName));
+        // Offsets have to point to nodes.
+        assert_eq!(
+            editor.set_property_u32(root + 4, "x", 1),
+            Err(Error::BadStructure)
+        );
+    }
+
+    fn overlay_base() -> Vec<u8> {
+        StructureBuilder::default()
+            .begin_node("")
+            .begin_node("__symbols__")
+            .property("gpio", b"/gpio@6000d000\0")
+            .property("uart", b"/serial@70006000\0")
+            .property("nowhere", b"/missing\0")
+            .end_node()
+            .begin_node("gpio@6000d000")
+            .property("phandle", &cells(&[1]))
+            .end_node()
+            .begin_node("serial@70006000")
+            .property("status", b"disabled\0")
+            .property("phandle", &cells(&[2]))
+            .begin_node("console")
+            .end_node()
+            .end_node()
+            .begin_node("chosen")
+            .end_node()
+            .end_node()
+            .end()
+            .build(&[])
+    }
+
+    /// An overlay as built by `dtc -@`, without the end of its root node.
+    fn sample_overlay() -> StructureBuilder {
+        let mut builder = StructureBuilder::default();
+        builder
+            .begin_node("")
+            .begin_node("fragment@0")
+            .property("target", &cells(&[0xFFFF_FFFF]))
+            .begin_node("__overlay__")
+            .property("status", b"okay\0")
+            .property("reset-gpios", &cells(&[0xFFFF_FFFF, 5, 0]))
+            .begin_node("console")
+            .property("speed", &cells(&[115_200]))
+            .end_node()
+            .end_node()
+            .end_node()
+            .begin_node("fragment@1")
+            .property("target-path", b"/\0")
+            .begin_node("__overlay__")
+            .begin_node("fan")
+            .property("phandle", &cells(&[1]))
+            .property("gpios", &cells(&[0xFFFF_FFFF, 7]))
+            .end_node()
+            .begin_node("thermal")
+            .property("cooling-device", &cells(&[1, 0]))
+            .end_node()
+            .end_node()
+            .end_node()
+            .begin_node("fragment@2")
+            .property("target-path", b"/chosen\0")
+            .begin_node("__overlay__")
+            .property("bootargs", b"quiet\0")
+            .end_node()
+            .end_node()
+            .begin_node("__symbols__")
+            .property("fan", b"/fragment@1/__overlay__/fan\0")
+            .end_node()
+            .begin_node("__fixups__")
+            .property("uart", b"/fragment@0:target:0\0")
+            .property(
+                "gpio",
+                b"/fragment@0/__overlay__:reset-gpios:0\0/fragment@1/__overlay__/fan:gpios:0\0",
+            )
+            .end_node()
+            .begin_node("__local_fixups__")
+            .begin_node("fragment@1")
+            .begin_node("__overlay__")
+            .begin_node("thermal")
+            .property("cooling-device", &cells(&[0]))
+            .end_node()
+            .end_node()
+            .end_node()
+            .end_node();
+        builder
+    }
+
+    #[test]
+    fn overlay() {
+        let base = overlay_base();
+        let overlay = sample_overlay().end_node().end().build(&[]);
+        let mut buffer = vec![0; base.len() + 2 * overlay.len()];
+
+        let mut editor = FdtEditor::open_into(&base, &mut buffer).unwrap();
+        editor.apply_overlay(&overlay).unwrap();
+        let fdt = editor.fdt();
+        let root = fdt.root().unwrap();
+
+        // New nodes are added first, like libfdt does.
+        assert_eq!(
+            names(&fdt, root),
+            [
+                "thermal",
+                "fan",
+                "__symbols__",
+                "gpio@6000d000",
+                "serial@70006000",
+                "chosen"
+            ]
+        );
+
+        let serial = fdt.find_node("/serial@70006000").unwrap();
+        assert_eq!(
+            fdt.get_property(serial, "status").unwrap().as_str(),
+            Some("okay")
+        );
+        assert_eq!(
+            fdt.get_property(serial, "reset-gpios").unwrap().value,
+            &cells(&[1, 5, 0])[..]
+        );
+        // Existing children are merged.
+        assert_eq!(names(&fdt, serial), ["console"]);
+        let console = fdt.find_node("/serial@70006000/console").unwrap();
+        assert_eq!(
+            fdt.get_property(console, "speed").unwrap().as_u32(),
+            Some(115_200)
+        );
+
+        // Phandles of the overlay follow those of the tree.
+        let fan = fdt.find_node("/fan").unwrap();
+        assert_eq!(fdt.get_property(fan, "phandle").unwrap().as_u32(), Some(3));
+        assert_eq!(fdt.find_node_by_phandle(3), Some(fan));
+        assert_eq!(
+            fdt.get_property(fan, "gpios").unwrap().value,
+            &cells(&[1, 7])[..]
+        );
+        let thermal = fdt.find_node("/thermal").unwrap();
+        assert_eq!(
+            fdt.get_property(thermal, "cooling-device").unwrap().value,
+            &cells(&[3, 0])[..]
+        );
+
+        let chosen = fdt.find_node("/chosen").unwrap();
+        assert_eq!(
+            fdt.get_property(chosen, "bootargs").unwrap().as_str(),
+            Some("quiet")
+        );
+
+        // The overlay is left as is and can be applied again, its phandles moving further.
+        editor.apply_overlay(&overlay).unwrap();
+        let fdt = editor.fdt();
+        let fan = fdt.find_node("/fan").unwrap();
+        assert_eq!(fdt.get_property(fan, "phandle").unwrap().as_u32(), Some(4));
+    }
+
+    #[test]
+    fn overlay_errors() {
+        let base = overlay_base();
+        let apply = |overlay: &mut StructureBuilder| {
+            let overlay = overlay.end_node().end().build(&[]);
+            let mut buffer = vec![0; base.len() + overlay.len()];
+            let mut editor = FdtEditor::open_into(&base, &mut buffer).unwrap();
+
+            editor.apply_overlay(&overlay)
+        };
+        let fragment = |builder: &mut StructureBuilder, name: &str, value: &[u8]| {
+            builder
+                .begin_node("fragment@3")
+                .property(name, value)
+                .begin_node("__overlay__")
+                .property("status", b"okay\0")
+                .end_node()
+                .end_node();
+        };
+
+        let mut builder = sample_overlay();
+        fragment(&mut builder, "target-path", b"/missing\0");
+        assert_eq!(apply(&mut builder), Err(Error::NotFound));
+
+        let mut builder = sample_overlay();
+        fragment(&mut builder, "target", &cells(&[7]));
+        assert_eq!(apply(&mut builder), Err(Error::NotFound));
+
+        let mut builder = sample_overlay();
+        fragment(&mut builder, "compatible", b"nvidia,p3450-0000\0");
+        assert_eq!(apply(&mut builder), Err(Error::BadOverlay));
+
+        // Fragments without __overlay__ are ignored.
+        let mut builder = sample_overlay();
+        builder.begin_node("fragment@3").end_node();
+        assert_eq!(apply(&mut builder), Ok(()));
+
+        let fixup = |label: &str, reference: &[u8]| {
+            let mut builder = StructureBuilder::default();
+            builder
+                .begin_node("")
+                .begin_node("fragment@0")
+                .property("target-path", b"/chosen\0")
+                .begin_node("__overlay__")
+                .property("gpios", &cells(&[0xFFFF_FFFF, 1]))
+                .end_node()
+                .end_node()
+                .begin_node("__fixups__")
+                .property(label, reference)
+                .end_node();
+            builder
+        };
+
+        let mut builder = fixup("gpio", b"/fragment@0/__overlay__:gpios:0\0");
+        assert_eq!(apply(&mut builder), Ok(()));
+        let mut builder = fixup("gpio", b"/fragment@0/__overlay__:gpios:4\0");
+        assert_eq!(apply(&mut builder), Ok(()));
+        let mut builder = fixup("gpio", b"/fragment@0/__overlay__:gpios:5\0");
+        assert_eq!(apply(&mut builder), Err(Error::BadOverlay));
+        let mut builder = fixup("gpio", b"/fragment@0/__overlay__:gpios\0");
+        assert_eq!(apply(&mut builder), Err(Error::BadOverlay));
+        let mut builder = fixup("gpio", b"/fragment@0/__overlay__:gpios:zero\0");
+        assert_eq!(apply(&mut builder), Err(Error::BadOverlay));
+        let mut builder = fixup("i2c", b"/fragment@0/__overlay__:gpios:0\0");
+        assert_eq!(apply(&mut builder), Err(Error::NotFound));
+        // The symbol points to no node.
+        let mut builder = fixup("nowhere", b"/fragment@0/__overlay__:gpios:0\0");
+        assert_eq!(apply(&mut builder), Err(Error::NotFound));
+
+        // Phandles would overflow.
+        let mut builder = StructureBuilder::default();
+        builder
+            .begin_node("")
+            .begin_node("fragment@0")
+            .property("target-path", b"/\0")
+            .begin_node("__overlay__")
+            .begin_node("fan")
+            .property("phandle", &cells(&[u32::MAX - 1]))
+            .end_node()
+            .end_node()
+            .end_node();
+        assert_eq!(apply(&mut builder), Err(Error::BadOverlay));
+
+        // Too deep to be merged.
+        let mut builder = StructureBuilder::default();
+        builder
+            .begin_node("")
+            .begin_node("fragment@0")
+            .property("target-path", b"/\0")
+            .begin_node("__overlay__");
+        for _ in 0..MAX_OVERLAY_DEPTH {
+            builder.begin_node("node");
+        }
+        for _ in 0..MAX_OVERLAY_DEPTH {
+            builder.end_node();
+        }
+        builder.end_node().end_node();
+        assert_eq!(apply(&mut builder), Err(Error::BadOverlay));
+    }
+
+    /// The Jetson TX1 device tree of Linux, `make dtbs` builds it from
+    /// `arch/arm64/boot/dts/nvidia/tegra210-p2371-2180.dts`. It isn't distributed on its own,
+    /// copy it to `testdata` to run this test.
+    #[test]
+    #[ignore]
+    fn jetson_tx1() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/tegra210-p2371-2180.dtb"
+        );
+        let blob = std::fs::read(path).unwrap_or_else(|error| panic!("{}: {}", path, error));
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(blob_size(&blob), Ok(blob.len()));
+
+        let root = fdt.root().unwrap();
+        let compatible = fdt.get_property(root, "compatible").unwrap();
+        assert!(compatible
+            .value
+            .split(|byte| *byte == 0)
+            .any(|name| name == b"nvidia,p2371-2180"));
+        let memory = fdt.find_node("/memory@80000000").unwrap();
+        assert_eq!(
+            fdt.get_property(memory, "device_type").unwrap().as_str(),
+            Some("memory")
+        );
+        assert!(fdt.find_node("/serial@70006000").is_some());
+        let parent = fdt.get_property(root, "interrupt-parent").unwrap();
+        let gic = fdt.find_node_by_phandle(parent.as_u32().unwrap()).unwrap();
+        assert!(fdt
+            .node_name(gic)
+            .unwrap()
+            .starts_with("interrupt-controller@"));
+
+        let mut buffer = vec![0; blob.len() + 0x1000];
+        let mut editor = FdtEditor::open_into(&blob, &mut buffer).unwrap();
+        let chosen = editor.get_or_add_subnode(root, "chosen").unwrap();
+        editor
+            .set_property_str(chosen, "bootargs", "console=ttyS0,115200n8")
+            .unwrap();
+        // Offsets move with the edits.
+        let serial = editor.fdt().find_node("/serial@70006000").unwrap();
+        editor.set_property_str(serial, "status", "okay").unwrap();
+        editor.add_reservation(0xFF00_0000, 0x1_0000).unwrap();
+        let packed = editor.pack().to_vec();
+
+        // Everything else is as it was.
+        let edited = Fdt::new(&packed).unwrap();
+        let chosen = edited.find_node("/chosen").unwrap();
+        assert_eq!(
+            edited.get_property(chosen, "bootargs").unwrap().as_str(),
+            Some("console=ttyS0,115200n8")
+        );
+        let unchanged = |fdt: &Fdt| -> Vec<(String, String, Vec<u8>)> {
+            fdt.tree_properties()
+                .map(Result::unwrap)
+                .filter(|(node, property)| {
+                    let name = fdt.node_name(*node).unwrap();
+                    !(name == "chosen" && property.name == "bootargs"
+                        || name == "serial@70006000" && property.name == "status")
+                })
+                .map(|(node, property)| {
+                    (
+                        fdt.node_name(node).unwrap().to_string(),
+                        property.name.to_string(),
+                        property.value.to_vec(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(unchanged(&edited), unchanged(&fdt));
+        assert_eq!(
+            edited.reserved_memory().last(),
+            Some(Ok((0xFF00_0000, 0x1_0000)))
+        );
+    }
+}
@@ -0,0 +1,63 @@
+//! Access to files without knowing which of the supported filesystems holds them.
+
+use crate::block::BlockDevice;
+use crate::ext4;
+use crate::fat;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The device holds neither an ext4 nor a FAT filesystem.
+    UnknownFileSystem,
+    Fat(fat::Error),
+    Ext4(ext4::Error),
+}
+
+impl From<fat::Error> for Error {
+    fn from(error: fat::Error) -> Self {
+        Error::Fat(error)
+    }
+}
+
+impl From<ext4::Error> for Error {
+    fn from(error: ext4::Error) -> Self {
+        Error::Ext4(error)
+    }
+}
+
+pub enum FileSystem<'a> {
+    Fat(fat::FileSystem<'a>),
+    Ext4(ext4::FileSystem<'a>),
+}
+
+impl<'a> FileSystem<'a> {
+    /// Mount the ext4 or FAT filesystem of `device`.
+    pub fn new(device: &'a mut dyn BlockDevice) -> Result<Self, Error> {
+        // The superblock is read twice, the borrow of a failed mount would outlive the probe.
+        match ext4::FileSystem::new(&mut *device) {
+            Ok(_) => return Ok(FileSystem::Ext4(ext4::FileSystem::new(device)?)),
+            Err(ext4::Error::NotExt4) => {}
+            Err(error) => return Err(error.into()),
+        }
+
+        match fat::FileSystem::new(device) {
+            Ok(file_system) => Ok(FileSystem::Fat(file_system)),
+            Err(fat::Error::NotFat) => Err(Error::UnknownFileSystem),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileSystem::Fat(_) => "FAT",
+            FileSystem::Ext4(_) => "ext4",
+        }
+    }
+
+    /// Read the file at `path` to `buffer`, returning its size.
+    pub fn read_file(&mut self, path: &str, buffer: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            FileSystem::Fat(file_system) => Ok(file_system.read_file(path, buffer)?),
+            FileSystem::Ext4(file_system) => Ok(file_system.read_file(path, buffer)?),
+        }
+    }
+}
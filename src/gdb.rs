@@ -0,0 +1,874 @@
+//! GDB remote serial protocol stub.
+//!
+//! Once [`init`] is called, `brk` instructions and single steps stop in the stub, which then
+//! talks to GDB over the given transport (`target remote /dev/ttyUSB0` from gdb-multiarch).
+//! [`breakpoint`] (or the monitor `gdb` command) hands control to GDB a first time.
+//!
+//! Software breakpoints are `brk` instructions written over the code, remapping its page
+//! writable for the time of the write. Single stepping uses the architectural software step, which doesn't
+//! exist at EL3: there `step` is refused and continuing from a breakpoint skips it once.
+//!
+//! NOTE: Nothing else, logs included, must use the transport while GDB is attached.
+
+use core::cmp;
+use core::mem;
+use core::ptr;
+
+use crate::esr::{Esr, ExceptionClass};
+use crate::exception_vectors::{self, ExceptionInfo};
+use crate::mmu::{self, MapError, MemoryPermission};
+use crate::transport::Transport;
+use crate::utils;
+
+/// Maximum size of a packet, as advertised to GDB.
+const PACKET_SIZE: usize = 0x400;
+
+/// Maximum count of software breakpoints set at the same time.
+pub const MAX_BREAKPOINTS: usize = 16;
+
+/// `brk #0`, the instruction GDB uses for AArch64.
+const BREAKPOINT_INSTRUCTION: u32 = 0xd420_0000;
+
+/// Every stop is reported as a SIGTRAP.
+const STOP_REPLY: &[u8] = b"S05";
+
+// Error replies, errno values as GDB expects.
+const EFAULT: &[u8] = b"E0e";
+const EINVAL: &[u8] = b"E16";
+const ENOSPC: &[u8] = b"E1c";
+
+// Registers of the org.gnu.gdb.aarch64.core feature, x0 to x30 come first.
+const REGISTER_SP: usize = 31;
+const REGISTER_PC: usize = 32;
+const REGISTER_CPSR: usize = 33;
+const REGISTER_COUNT: usize = 34;
+
+const MDSCR_SS: u64 = 1 << 0;
+const MDSCR_KDE: u64 = 1 << 13;
+const MDCR_EL2_TDE: u64 = 1 << 8;
+const SPSR_D: u64 = 1 << 9;
+const SPSR_SS: u64 = 1 << 21;
+
+const TARGET_XML: &[u8] = br#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>aarch64</architecture>
+<feature name="org.gnu.gdb.aarch64.core">
+<reg name="x0" bitsize="64"/>
+<reg name="x1" bitsize="64"/>
+<reg name="x2" bitsize="64"/>
+<reg name="x3" bitsize="64"/>
+<reg name="x4" bitsize="64"/>
+<reg name="x5" bitsize="64"/>
+<reg name="x6" bitsize="64"/>
+<reg name="x7" bitsize="64"/>
+<reg name="x8" bitsize="64"/>
+<reg name="x9" bitsize="64"/>
+<reg name="x10" bitsize="64"/>
+<reg name="x11" bitsize="64"/>
+<reg name="x12" bitsize="64"/>
+<reg name="x13" bitsize="64"/>
+<reg name="x14" bitsize="64"/>
+<reg name="x15" bitsize="64"/>
+<reg name="x16" bitsize="64"/>
+<reg name="x17" bitsize="64"/>
+<reg name="x18" bitsize="64"/>
+<reg name="x19" bitsize="64"/>
+<reg name="x20" bitsize="64"/>
+<reg name="x21" bitsize="64"/>
+<reg name="x22" bitsize="64"/>
+<reg name="x23" bitsize="64"/>
+<reg name="x24" bitsize="64"/>
+<reg name="x25" bitsize="64"/>
+<reg name="x26" bitsize="64"/>
+<reg name="x27" bitsize="64"/>
+<reg name="x28" bitsize="64"/>
+<reg name="x29" bitsize="64"/>
+<reg name="x30" bitsize="64"/>
+<reg name="sp" bitsize="64" type="data_ptr"/>
+<reg name="pc" bitsize="64" type="code_ptr"/>
+<reg name="cpsr" bitsize="32"/>
+</feature>
+</target>
+"#;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+#[derive(Copy, Clone, Debug)]
+enum WriteError {
+    NotMapped,
+    Map(MapError),
+}
+
+impl From<MapError> for WriteError {
+    fn from(error: MapError) -> Self {
+        WriteError::Map(error)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    address: u64,
+    /// Instruction replaced by the `brk` while inserted.
+    original_instruction: Option<u32>,
+}
+
+struct Response {
+    data: [u8; PACKET_SIZE],
+    length: usize,
+}
+
+impl Response {
+    const fn new() -> Self {
+        Response {
+            data: [0; PACKET_SIZE],
+            length: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    fn remaining(&self) -> usize {
+        PACKET_SIZE - self.length
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.data[self.length..self.length + data.len()].copy_from_slice(data);
+        self.length += data.len();
+    }
+
+    fn push_hex_byte(&mut self, byte: u8) {
+        self.push(&[
+            HEX_DIGITS[(byte >> 4) as usize],
+            HEX_DIGITS[(byte & 0xf) as usize],
+        ]);
+    }
+
+    /// Push `size` bytes of `value` in target (little endian) order.
+    fn push_hex_value(&mut self, value: u64, size: usize) {
+        for index in 0..size {
+            self.push_hex_byte((value >> (index * 8)) as u8);
+        }
+    }
+
+    /// Push a number, most significant digit first.
+    fn push_hex_number(&mut self, value: u64) {
+        for index in (0..8).rev() {
+            self.push_hex_byte((value >> (index * 8)) as u8);
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.length]
+    }
+}
+
+struct Stub {
+    connection: Option<&'static mut dyn Transport>,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// GDB is connected and waits for a stop reply when the target stops.
+    attached: bool,
+    /// Single stepping off a breakpoint before inserting it back and continuing.
+    stepping_over_breakpoint: bool,
+}
+
+static mut STUB: Stub = Stub {
+    connection: None,
+    breakpoints: [None; MAX_BREAKPOINTS],
+    attached: false,
+    stepping_over_breakpoint: false,
+};
+
+// Kept out of the stack as exceptions are handled on the small rboot stack.
+static mut PACKET_BUFFER: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+static mut RESPONSE: Response = Response::new();
+
+fn from_hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(data: &[u8]) -> Option<u64> {
+    if data.is_empty() || data.len() > 16 {
+        return None;
+    }
+
+    data.iter().try_fold(0, |value, digit| {
+        Some((value << 4) | from_hex_digit(*digit)? as u64)
+    })
+}
+
+fn parse_hex_byte(data: &[u8]) -> Option<u8> {
+    Some((from_hex_digit(data[0])? << 4) | from_hex_digit(data[1])?)
+}
+
+/// Parse `size` bytes stored in target (little endian) order.
+fn parse_hex_value(data: &[u8], size: usize) -> Option<u64> {
+    if data.len() < size * 2 {
+        return None;
+    }
+
+    let mut value = 0;
+    for index in 0..size {
+        value |= (parse_hex_byte(&data[index * 2..])? as u64) << (index * 8);
+    }
+
+    Some(value)
+}
+
+fn split_at_byte(data: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = data.iter().position(|byte| *byte == separator)?;
+
+    Some((&data[..index], &data[index + 1..]))
+}
+
+/// Parse `address,length`, as used by the memory and breakpoint packets.
+fn parse_address_length(data: &[u8]) -> Option<(u64, u64)> {
+    let (address, length) = split_at_byte(data, b',')?;
+
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn read_packet(connection: &mut dyn Transport, buffer: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        // Skip acknowledgements and interrupt requests.
+        while connection.read_byte_blocking() != b'$' {}
+
+        let mut length = 0;
+        let mut checksum: u8 = 0;
+        let mut overflow = false;
+
+        loop {
+            let byte = connection.read_byte_blocking();
+            if byte == b'#' {
+                break;
+            }
+
+            checksum = checksum.wrapping_add(byte);
+
+            if length < buffer.len() {
+                buffer[length] = byte;
+                length += 1;
+            } else {
+                overflow = true;
+            }
+        }
+
+        let expected_checksum = [
+            connection.read_byte_blocking(),
+            connection.read_byte_blocking(),
+        ];
+
+        if !overflow && parse_hex_byte(&expected_checksum) == Some(checksum) {
+            connection.write_byte(b'+');
+            return length;
+        }
+
+        connection.write_byte(b'-');
+    }
+}
+
+fn send_packet(connection: &mut dyn Transport, data: &[u8]) {
+    let checksum = data
+        .iter()
+        .fold(0u8, |checksum, byte| checksum.wrapping_add(*byte));
+
+    loop {
+        connection.write_byte(b'$');
+        for byte in data {
+            connection.write_byte(*byte);
+        }
+        connection.write_byte(b'#');
+        connection.write_byte(HEX_DIGITS[(checksum >> 4) as usize]);
+        connection.write_byte(HEX_DIGITS[(checksum & 0xf) as usize]);
+
+        loop {
+            match connection.read_byte_blocking() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Stack pointer of the interrupted code, the exception frame being pushed on its stack.
+fn get_stack_pointer(exception: &ExceptionInfo) -> u64 {
+    exception as *const ExceptionInfo as u64 + mem::size_of::<ExceptionInfo>() as u64
+}
+
+fn get_register_size(register: usize) -> usize {
+    if register == REGISTER_CPSR {
+        4
+    } else {
+        8
+    }
+}
+
+fn get_register(exception: &ExceptionInfo, register: usize) -> Option<u64> {
+    match register {
+        0..=30 => Some(exception.x[register]),
+        REGISTER_SP => Some(get_stack_pointer(exception)),
+        REGISTER_PC => Some(exception.pc),
+        REGISTER_CPSR => Some(exception.cpsr),
+        _ => None,
+    }
+}
+
+/// Returns false for read-only registers.
+fn set_register(exception: &mut ExceptionInfo, register: usize, value: u64) -> bool {
+    match register {
+        0..=30 => exception.x[register] = value,
+        REGISTER_PC => exception.pc = value,
+        REGISTER_CPSR => exception.cpsr = value,
+        // Moving the stack would also move the exception frame.
+        REGISTER_SP => return value == get_stack_pointer(exception),
+        _ => return false,
+    }
+
+    true
+}
+
+/// Check that `size` bytes at `address` are mapped and readable or writable.
+fn is_accessible(address: u64, size: u64, write: bool) -> bool {
+    let end = match address.checked_add(size) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut page = utils::align_down(address, mmu::PAGE_SIZE);
+    while page < end {
+        let allowed = match mmu::translate(page) {
+            Some(translation) if write => translation.permission.is_writable(),
+            Some(translation) => translation.permission.is_readable(),
+            None => false,
+        };
+
+        if !allowed {
+            return false;
+        }
+
+        page += mmu::PAGE_SIZE;
+    }
+
+    true
+}
+
+/// Read memory with 32-bit accesses, so that MMIO registers can be inspected too.
+fn read_memory(response: &mut Response, address: u64, length: u64) -> bool {
+    let end = match address.checked_add(length) {
+        Some(end) if end <= u64::MAX - 3 => utils::align_up(end, 4),
+        _ => return false,
+    };
+    let start = utils::align_down(address, 4);
+
+    if !is_accessible(start, end - start, false) {
+        return false;
+    }
+
+    let mut word_address = start;
+    while word_address < end {
+        let word = match exception_vectors::probe_read32(word_address) {
+            Some(word) => word,
+            None => return false,
+        };
+
+        for (index, byte) in word.to_le_bytes().iter().enumerate() {
+            let byte_address = word_address + index as u64;
+
+            if byte_address >= address && byte_address < address + length {
+                response.push_hex_byte(*byte);
+            }
+        }
+
+        word_address += 4;
+    }
+
+    true
+}
+
+fn write_memory(address: u64, data: &[u8]) -> bool {
+    let length = data.len() as u64 / 2;
+
+    if !is_accessible(address, length, true) {
+        return false;
+    }
+
+    for index in 0..length {
+        let byte = match parse_hex_byte(&data[index as usize * 2..]) {
+            Some(byte) => byte,
+            None => return false,
+        };
+
+        unsafe { ptr::write_volatile((address + index) as *mut u8, byte) };
+    }
+
+    // GDB may be patching code.
+    mmu::clean_dcache_range(address, length);
+    mmu::invalidate_icache_all();
+
+    true
+}
+
+/// Write an instruction, making the page holding it writable until it is written.
+fn write_instruction(address: u64, instruction: u32) -> Result<(), WriteError> {
+    let translation = mmu::translate(address).ok_or(WriteError::NotMapped)?;
+    let remap = |permission| {
+        mmu::map_page(
+            utils::align_down(address, mmu::PAGE_SIZE),
+            utils::align_down(translation.paddr, mmu::PAGE_SIZE),
+            mmu::PAGE_SIZE,
+            permission,
+            translation.memory_attribute,
+        )
+    };
+
+    let writable = translation.permission.is_writable();
+    if !writable {
+        remap(MemoryPermission::RWX)?;
+    }
+
+    unsafe { ptr::write_volatile(address as *mut u32, instruction) };
+
+    mmu::clean_dcache_range(address, 4);
+    mmu::invalidate_icache_all();
+
+    if !writable {
+        remap(translation.permission)?;
+    }
+
+    Ok(())
+}
+
+fn is_single_step_supported() -> bool {
+    utils::get_current_el() != 3
+}
+
+fn read_mdscr() -> u64 {
+    let mdscr: u64;
+    unsafe {
+        asm!("mrs {mdscr}, mdscr_el1", mdscr = out(reg) mdscr, options(nostack));
+    }
+
+    mdscr
+}
+
+fn write_mdscr(mdscr: u64) {
+    unsafe {
+        asm!("msr mdscr_el1, {mdscr}; isb", mdscr = in(reg) mdscr, options(nostack));
+    }
+}
+
+/// Trap after the first instruction executed once `exception` returns.
+fn enable_single_step(exception: &mut ExceptionInfo) {
+    write_mdscr(read_mdscr() | MDSCR_SS | MDSCR_KDE);
+
+    // Debug exceptions must be unmasked in the stepped code.
+    exception.cpsr = (exception.cpsr | SPSR_SS) & !SPSR_D;
+}
+
+fn disable_single_step(exception: &mut ExceptionInfo) {
+    write_mdscr(read_mdscr() & !MDSCR_SS);
+
+    exception.cpsr &= !SPSR_SS;
+}
+
+/// Allow software step exceptions to be taken at the current EL.
+fn init_debug_exceptions() {
+    unsafe {
+        // Unlock the OS lock and clear the OS double lock, both blocking debug exceptions.
+        asm!("msr oslar_el1, xzr", options(nostack));
+        asm!("msr osdlr_el1, xzr", options(nostack));
+
+        if utils::get_current_el() == 2 {
+            let mut mdcr: u64;
+            asm!("mrs {mdcr}, mdcr_el2", mdcr = out(reg) mdcr, options(nostack));
+            mdcr |= MDCR_EL2_TDE;
+            asm!("msr mdcr_el2, {mdcr}", mdcr = in(reg) mdcr, options(nostack));
+        }
+
+        asm!("isb", options(nostack));
+    }
+}
+
+impl Stub {
+    fn has_breakpoint(&self, address: u64) -> bool {
+        self.breakpoints
+            .iter()
+            .flatten()
+            .any(|breakpoint| breakpoint.address == address)
+    }
+
+    fn add_breakpoint(&mut self, address: u64) -> Result<(), &'static [u8]> {
+        let executable = mmu::translate(address)
+            .map(|translation| translation.permission.is_executable())
+            .unwrap_or(false);
+
+        if address % 4 != 0 || !executable {
+            return Err(EFAULT);
+        }
+
+        if self.has_breakpoint(address) {
+            return Ok(());
+        }
+
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ENOSPC)?;
+
+        *slot = Some(Breakpoint {
+            address,
+            original_instruction: None,
+        });
+
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot {
+                if breakpoint.address == address {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    /// Write the breakpoints to memory, except the one at `skipped_address`.
+    fn insert_breakpoints(&mut self, skipped_address: Option<u64>) {
+        for breakpoint in self.breakpoints.iter_mut().flatten() {
+            if Some(breakpoint.address) == skipped_address {
+                continue;
+            }
+
+            let original_instruction =
+                unsafe { ptr::read_volatile(breakpoint.address as *const u32) };
+
+            match write_instruction(breakpoint.address, BREAKPOINT_INSTRUCTION) {
+                Ok(()) => breakpoint.original_instruction = Some(original_instruction),
+                Err(error) => warn!(
+                    "Cannot insert the breakpoint at {:#x}: {:?}",
+                    breakpoint.address, error
+                ),
+            }
+        }
+    }
+
+    /// Restore the code under the breakpoints, GDB then sees the original instructions.
+    ///
+    /// Pages made writable for the write are mapped back with their original permission.
+    fn remove_breakpoints(&mut self) {
+        for breakpoint in self.breakpoints.iter_mut().flatten() {
+            if let Some(original_instruction) = breakpoint.original_instruction.take() {
+                if let Err(error) = write_instruction(breakpoint.address, original_instruction) {
+                    warn!(
+                        "Cannot remove the breakpoint at {:#x}: {:?}",
+                        breakpoint.address, error
+                    );
+                }
+            }
+        }
+    }
+
+    /// Prepare the return from the exception, returns false if stepping isn't possible.
+    fn resume(&mut self, exception: &mut ExceptionInfo, step: bool) -> bool {
+        if step {
+            if !is_single_step_supported() {
+                return false;
+            }
+
+            // Only one instruction runs, breakpoints are useless.
+            enable_single_step(exception);
+        } else if self.has_breakpoint(exception.pc) {
+            // Step over the breakpoint we are stopped on before inserting it back.
+            if is_single_step_supported() {
+                self.stepping_over_breakpoint = true;
+                enable_single_step(exception);
+            } else {
+                self.insert_breakpoints(Some(exception.pc));
+            }
+        } else {
+            self.insert_breakpoints(None);
+        }
+
+        true
+    }
+
+    fn handle_query(&mut self, packet: &[u8], response: &mut Response) {
+        if packet.starts_with(b"qSupported") {
+            response.push(b"PacketSize=400;qXfer:features:read+");
+        } else if packet == b"qAttached" {
+            response.push(b"1");
+        } else if packet == b"qOffsets" {
+            // rboot relocates itself, the ELF is linked at 0.
+            let image_base = crate::rt::_start as usize as u64;
+
+            response.push(b"Text=");
+            response.push_hex_number(image_base);
+            response.push(b";Data=");
+            response.push_hex_number(image_base);
+            response.push(b";Bss=");
+            response.push_hex_number(image_base);
+        } else if packet.starts_with(b"qXfer:features:read:target.xml:") {
+            let arguments = &packet[b"qXfer:features:read:target.xml:".len()..];
+
+            match parse_address_length(arguments) {
+                Some((offset, length)) => {
+                    let offset = cmp::min(offset as usize, TARGET_XML.len());
+                    let length = cmp::min(length as usize, response.remaining() - 1);
+                    let end = cmp::min(offset + length, TARGET_XML.len());
+
+                    response.push(if end == TARGET_XML.len() { b"l" } else { b"m" });
+                    response.push(&TARGET_XML[offset..end]);
+                }
+                None => response.push(EINVAL),
+            }
+        }
+    }
+
+    /// Handle a packet, returns true when execution has to resume.
+    fn handle_packet(
+        &mut self,
+        exception: &mut ExceptionInfo,
+        packet: &[u8],
+        response: &mut Response,
+        skip_instruction: &mut bool,
+    ) -> bool {
+        self.attached = true;
+
+        match packet.first() {
+            Some(b'?') => response.push(STOP_REPLY),
+            Some(b'q') => self.handle_query(packet, response),
+            Some(b'H') => response.push(b"OK"),
+            Some(b'g') => {
+                for register in 0..REGISTER_COUNT {
+                    let value = get_register(exception, register).unwrap();
+                    response.push_hex_value(value, get_register_size(register));
+                }
+            }
+            Some(b'G') => {
+                let mut data = &packet[1..];
+
+                for register in 0..REGISTER_COUNT {
+                    let size = get_register_size(register);
+
+                    if let Some(value) = parse_hex_value(data, size) {
+                        set_register(exception, register, value);
+                        data = &data[size * 2..];
+                    }
+                }
+
+                response.push(b"OK");
+            }
+            Some(b'p') => match parse_hex(&packet[1..]) {
+                Some(register) if (register as usize) < REGISTER_COUNT => {
+                    let register = register as usize;
+                    let value = get_register(exception, register).unwrap();
+
+                    response.push_hex_value(value, get_register_size(register));
+                }
+                _ => response.push(EINVAL),
+            },
+            Some(b'P') => {
+                let register = split_at_byte(&packet[1..], b'=').and_then(|(register, value)| {
+                    let register = parse_hex(register)? as usize;
+
+                    Some((
+                        register,
+                        parse_hex_value(value, get_register_size(register))?,
+                    ))
+                });
+
+                match register {
+                    Some((register, value)) if set_register(exception, register, value) => {
+                        response.push(b"OK")
+                    }
+                    _ => response.push(EINVAL),
+                }
+            }
+            Some(b'm') => match parse_address_length(&packet[1..]) {
+                Some((address, length)) => {
+                    let length = cmp::min(length, (response.remaining() / 2) as u64);
+
+                    if !read_memory(response, address, length) {
+                        response.clear();
+                        response.push(EFAULT);
+                    }
+                }
+                None => response.push(EINVAL),
+            },
+            Some(b'M') => {
+                let arguments = split_at_byte(&packet[1..], b':')
+                    .and_then(|(range, data)| Some((parse_address_length(range)?, data)));
+
+                match arguments {
+                    Some(((address, length), data)) if data.len() as u64 == length * 2 => {
+                        if write_memory(address, data) {
+                            response.push(b"OK");
+                        } else {
+                            response.push(EFAULT);
+                        }
+                    }
+                    _ => response.push(EINVAL),
+                }
+            }
+            Some(b'Z') | Some(b'z') if packet.starts_with(b"Z0,") || packet.starts_with(b"z0,") => {
+                match parse_address_length(&packet[3..]) {
+                    Some((address, _)) if packet[0] == b'Z' => match self.add_breakpoint(address) {
+                        Ok(()) => response.push(b"OK"),
+                        Err(error) => response.push(error),
+                    },
+                    Some((address, _)) => {
+                        self.remove_breakpoint(address);
+                        response.push(b"OK");
+                    }
+                    None => response.push(EINVAL),
+                }
+            }
+            Some(b'c') | Some(b's') => {
+                let step = packet[0] == b's';
+
+                if packet.len() > 1 {
+                    match parse_hex(&packet[1..]) {
+                        Some(address) => {
+                            exception.pc = address;
+                            *skip_instruction = false;
+                        }
+                        None => {
+                            response.push(EINVAL);
+                            return false;
+                        }
+                    }
+                }
+
+                if *skip_instruction {
+                    *skip_instruction = false;
+                    exception.skip_instruction();
+
+                    // Going over the brk is the step.
+                    if step {
+                        response.push(STOP_REPLY);
+                        return false;
+                    }
+                }
+
+                if self.resume(exception, step) {
+                    return true;
+                }
+
+                response.push(EINVAL);
+            }
+            Some(b'D') | Some(b'k') => {
+                // Leave the code as it was before GDB came.
+                self.breakpoints = [None; MAX_BREAKPOINTS];
+                self.attached = false;
+
+                if *skip_instruction {
+                    exception.skip_instruction();
+                }
+
+                // Kill has no reply.
+                if packet[0] == b'D' {
+                    response.push(b"OK");
+                    let connection = self.connection.as_mut().unwrap();
+                    send_packet(&mut **connection, response.as_bytes());
+                }
+
+                return true;
+            }
+            _ => {}
+        }
+
+        false
+    }
+
+    fn handle_stop(&mut self, exception: &mut ExceptionInfo, class: ExceptionClass) {
+        let packet_buffer = unsafe { &mut PACKET_BUFFER };
+        let response = unsafe { &mut RESPONSE };
+
+        self.remove_breakpoints();
+
+        // brk instructions present in the code, unlike ours, have to be skipped on resume.
+        let mut skip_instruction =
+            class == ExceptionClass::Brk64 && !self.has_breakpoint(exception.pc);
+
+        if self.attached {
+            let connection = self.connection.as_mut().unwrap();
+            send_packet(&mut **connection, STOP_REPLY);
+        }
+
+        loop {
+            let connection = self.connection.as_mut().unwrap();
+            let length = read_packet(&mut **connection, packet_buffer);
+
+            response.clear();
+            if self.handle_packet(
+                exception,
+                &packet_buffer[..length],
+                response,
+                &mut skip_instruction,
+            ) {
+                return;
+            }
+
+            let connection = self.connection.as_mut().unwrap();
+            send_packet(&mut **connection, response.as_bytes());
+        }
+    }
+}
+
+fn debug_exception_handler(exception: &mut ExceptionInfo) -> bool {
+    let stub = unsafe { &mut STUB };
+    let class = Esr(exception.esr).exception_class();
+
+    if stub.connection.is_none() {
+        return false;
+    }
+
+    if class == ExceptionClass::SoftwareStepCurrentEl {
+        disable_single_step(exception);
+
+        if stub.stepping_over_breakpoint {
+            stub.stepping_over_breakpoint = false;
+            stub.insert_breakpoints(None);
+
+            return true;
+        }
+    }
+
+    stub.handle_stop(exception, class);
+
+    true
+}
+
+/// Debug rboot with GDB over `connection`, from the next [`breakpoint`] on.
+///
+/// # Safety
+///
+/// `connection` must not be used by anything else from now on.
+pub unsafe fn init(connection: &'static mut dyn Transport) {
+    STUB.connection = Some(connection);
+
+    init_debug_exceptions();
+
+    exception_vectors::register_handler(ExceptionClass::Brk64, Some(debug_exception_handler));
+    exception_vectors::register_handler(
+        ExceptionClass::SoftwareStepCurrentEl,
+        Some(debug_exception_handler),
+    );
+}
+
+/// Stop and wait for GDB.
+pub fn breakpoint() {
+    unsafe {
+        asm!("brk #0", options(nostack));
+    }
+}
@@ -0,0 +1,797 @@
+//! GUID partition table parsing.
+//!
+//! Both the primary and backup tables are checked, the backup being used when the primary is
+//! damaged. Partition entries are read from the device when needed, as the array is too big
+//! for rboot's stack.
+//!
+//! Only `core` and the block device API are used, the module can be built and tested on the
+//! host.
+
+use core::char;
+use core::fmt;
+use core::mem;
+use core::ptr;
+
+use crate::block::{self, BlockDevice, BLOCK_SIZE};
+use crate::crc;
+
+/// "EFI PART"
+const HEADER_SIGNATURE: u64 = 0x5452_4150_2049_4645;
+const MIN_HEADER_SIZE: usize = 92;
+const HEADER_CRC32_OFFSET: usize = 16;
+
+const PRIMARY_HEADER_LBA: u64 = 1;
+
+const MIN_ENTRY_SIZE: u32 = 128;
+/// 32 times what the specification asks to reserve, much more than any real disk uses.
+const MAX_ENTRY_COUNT: u32 = 4096;
+
+const NAME_LENGTH: usize = 36;
+
+/// A GUID, stored in the mixed endian layout of the specification.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+    pub const EFI_SYSTEM: Guid = Guid::new(
+        0xC12A_7328,
+        0xF81F,
+        0x11D2,
+        [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+    );
+    pub const BASIC_DATA: Guid = Guid::new(
+        0xEBD0_A0A2,
+        0xB9E5,
+        0x4433,
+        [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+    );
+    pub const LINUX_FILESYSTEM: Guid = Guid::new(
+        0x0FC6_3DAF,
+        0x8483,
+        0x4772,
+        [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+    );
+
+    /// The GUID written as `data1-data2-data3-data4[0..2]-data4[2..8]`.
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let data1 = data1.to_le_bytes();
+        let data2 = data2.to_le_bytes();
+        let data3 = data3.to_le_bytes();
+
+        Guid([
+            data1[0], data1[1], data1[2], data1[3], data2[0], data2[1], data3[0], data3[1],
+            data4[0], data4[1], data4[2], data4[3], data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = &self.0;
+
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            u16::from_le_bytes([bytes[4], bytes[5]]),
+            u16::from_le_bytes([bytes[6], bytes[7]])
+        )?;
+
+        for (index, byte) in bytes[8..].iter().enumerate() {
+            if index == 2 {
+                write!(f, "-")?;
+            }
+
+            write!(f, "{:02X}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct GptHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Guid,
+    partition_entry_lba: u64,
+    number_of_partition_entries: u32,
+    size_of_partition_entry: u32,
+    partition_entry_array_crc32: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PartitionEntry {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    /// Last block of the partition, inclusive.
+    pub last_lba: u64,
+    pub attributes: u64,
+    name: [u16; NAME_LENGTH],
+}
+
+impl PartitionEntry {
+    pub fn block_count(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    fn name_units(&self) -> impl Iterator<Item = u16> + '_ {
+        self.name.iter().copied().take_while(|unit| *unit != 0)
+    }
+
+    /// The partition name, invalid UTF-16 being replaced.
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.name_units()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    pub fn has_name(&self, name: &str) -> bool {
+        self.name_units().eq(name.encode_utf16())
+    }
+}
+
+impl fmt::Debug for PartitionEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PartitionEntry {{ name: \"")?;
+        for c in self.name() {
+            write!(f, "{}", c)?;
+        }
+
+        write!(
+            f,
+            "\", type_guid: {}, unique_guid: {}, first_lba: {}, last_lba: {}, attributes: {:#x} }}",
+            self.type_guid, self.unique_guid, self.first_lba, self.last_lba, self.attributes
+        )
+    }
+}
+
+/// Why a copy of the table can't be used.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HeaderError {
+    BadSignature,
+    BadHeaderCrc,
+    /// The header doesn't describe where it is stored.
+    BadLocation,
+    /// The header size or the partition entry array don't fit on the device.
+    BadLayout,
+    BadEntriesCrc,
+    Block(block::Error),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    Block(block::Error),
+    /// Neither the primary nor the backup table are valid.
+    NoValidTable {
+        primary: HeaderError,
+        backup: HeaderError,
+    },
+    /// The entry at this index is outside of the usable blocks.
+    InvalidEntry(u32),
+}
+
+impl From<block::Error> for Error {
+    fn from(error: block::Error) -> Self {
+        Error::Block(error)
+    }
+}
+
+fn read_header(device: &mut dyn BlockDevice, lba: u64) -> Result<GptHeader, HeaderError> {
+    let mut block = [0; BLOCK_SIZE];
+    device
+        .read_blocks(lba, &mut block)
+        .map_err(HeaderError::Block)?;
+
+    let header = unsafe { ptr::read_unaligned(block.as_ptr() as *const GptHeader) };
+
+    if header.signature != HEADER_SIGNATURE {
+        return Err(HeaderError::BadSignature);
+    }
+
+    let header_size = header.header_size as usize;
+    if !(MIN_HEADER_SIZE..=BLOCK_SIZE).contains(&header_size) {
+        return Err(HeaderError::BadLayout);
+    }
+
+    // The CRC covers the header with the CRC field zeroed.
+    block[HEADER_CRC32_OFFSET..HEADER_CRC32_OFFSET + 4].copy_from_slice(&[0; 4]);
+    if crc::crc32(&block[..header_size]) != header.header_crc32 {
+        return Err(HeaderError::BadHeaderCrc);
+    }
+
+    if header.my_lba != lba {
+        return Err(HeaderError::BadLocation);
+    }
+
+    let entry_size = header.size_of_partition_entry;
+    let entry_count = header.number_of_partition_entries;
+    if entry_size < MIN_ENTRY_SIZE
+        || entry_size % MIN_ENTRY_SIZE != 0
+        || entry_count > MAX_ENTRY_COUNT
+    {
+        return Err(HeaderError::BadLayout);
+    }
+
+    let block_count = device.block_count();
+    let entries_size = u64::from(entry_size) * u64::from(entry_count);
+    let entries_blocks = (entries_size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64;
+    let entries_end = header
+        .partition_entry_lba
+        .checked_add(entries_blocks)
+        .ok_or(HeaderError::BadLayout)?;
+    if entries_end > block_count
+        || header.first_usable_lba > header.last_usable_lba
+        || header.last_usable_lba >= block_count
+    {
+        return Err(HeaderError::BadLayout);
+    }
+
+    let mut crc = 0;
+    let mut remaining = entries_size as usize;
+    let mut lba = header.partition_entry_lba;
+    while remaining > 0 {
+        device
+            .read_blocks(lba, &mut block)
+            .map_err(HeaderError::Block)?;
+
+        let size = core::cmp::min(remaining, BLOCK_SIZE);
+        crc = crc::crc32_update(crc, &block[..size]);
+
+        remaining -= size;
+        lba += 1;
+    }
+
+    if crc != header.partition_entry_array_crc32 {
+        return Err(HeaderError::BadEntriesCrc);
+    }
+
+    Ok(header)
+}
+
+/// A validated partition table.
+#[derive(Copy, Clone, Debug)]
+pub struct Gpt {
+    pub disk_guid: Guid,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// The primary table is damaged, this is the backup one.
+    pub is_backup: bool,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+}
+
+impl Gpt {
+    /// Read the partition table of `device`, falling back to the backup copy.
+    pub fn read(device: &mut dyn BlockDevice) -> Result<Gpt, Error> {
+        let last_lba = device
+            .block_count()
+            .checked_sub(1)
+            .ok_or(block::Error::OutOfRange)?;
+
+        let primary = read_header(device, PRIMARY_HEADER_LBA);
+
+        // The backup is at the end of the disk, unless it was resized after partitioning.
+        let backup_lba = match primary {
+            Ok(header)
+                if header.alternate_lba > PRIMARY_HEADER_LBA
+                    && header.alternate_lba <= last_lba =>
+            {
+                header.alternate_lba
+            }
+            _ => last_lba,
+        };
+        let backup = read_header(device, backup_lba);
+
+        let (header, is_backup) = match (primary, backup) {
+            (Ok(primary), Ok(backup)) => {
+                if primary.partition_entry_array_crc32 != backup.partition_entry_array_crc32
+                    || primary.disk_guid != backup.disk_guid
+                {
+                    warn!("The primary and backup GPTs differ, using the primary one");
+                }
+
+                (primary, false)
+            }
+            (Ok(primary), Err(error)) => {
+                warn!("The backup GPT is invalid ({:?})", error);
+
+                (primary, false)
+            }
+            (Err(error), Ok(backup)) => {
+                warn!(
+                    "The primary GPT is invalid ({:?}), using the backup one",
+                    error
+                );
+
+                (backup, true)
+            }
+            (Err(primary), Err(backup)) => return Err(Error::NoValidTable { primary, backup }),
+        };
+
+        Ok(Gpt {
+            disk_guid: header.disk_guid,
+            first_usable_lba: header.first_usable_lba,
+            last_usable_lba: header.last_usable_lba,
+            is_backup,
+            entries_lba: header.partition_entry_lba,
+            entry_count: header.number_of_partition_entries,
+            entry_size: header.size_of_partition_entry,
+        })
+    }
+
+    /// Read entry `index`, `None` when unused.
+    pub fn get_entry(
+        &self,
+        device: &mut dyn BlockDevice,
+        index: u32,
+    ) -> Result<Option<PartitionEntry>, Error> {
+        if index >= self.entry_count {
+            return Ok(None);
+        }
+
+        let mut bytes = [0; mem::size_of::<PartitionEntry>()];
+        let offset =
+            self.entries_lba * BLOCK_SIZE as u64 + u64::from(index) * u64::from(self.entry_size);
+        block::read_bytes(device, offset, &mut bytes)?;
+
+        let entry = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const PartitionEntry) };
+
+        if entry.type_guid == Guid::UNUSED {
+            return Ok(None);
+        }
+
+        let usable = self.first_usable_lba..=self.last_usable_lba;
+        if !usable.contains(&entry.first_lba)
+            || !usable.contains(&entry.last_lba)
+            || entry.first_lba > entry.last_lba
+        {
+            return Err(Error::InvalidEntry(index));
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// The used entries with their index.
+    pub fn partitions<'a>(&'a self, device: &'a mut dyn BlockDevice) -> Partitions<'a> {
+        Partitions {
+            gpt: self,
+            device,
+            index: 0,
+        }
+    }
+
+    pub fn find_by_name(
+        &self,
+        device: &mut dyn BlockDevice,
+        name: &str,
+    ) -> Result<Option<PartitionEntry>, Error> {
+        self.find(device, |entry| entry.has_name(name))
+    }
+
+    /// The first partition of type `type_guid`.
+    pub fn find_by_type(
+        &self,
+        device: &mut dyn BlockDevice,
+        type_guid: &Guid,
+    ) -> Result<Option<PartitionEntry>, Error> {
+        self.find(device, |entry| entry.type_guid == *type_guid)
+    }
+
+    fn find<F: Fn(&PartitionEntry) -> bool>(
+        &self,
+        device: &mut dyn BlockDevice,
+        predicate: F,
+    ) -> Result<Option<PartitionEntry>, Error> {
+        for result in self.partitions(device) {
+            let (_, entry) = result?;
+
+            if predicate(&entry) {
+                return Ok(Some(entry));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Iterator over the used entries of a [`Gpt`].
+pub struct Partitions<'a> {
+    gpt: &'a Gpt,
+    device: &'a mut dyn BlockDevice,
+    index: u32,
+}
+
+impl<'a> Iterator for Partitions<'a> {
+    type Item = Result<(u32, PartitionEntry), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.gpt.entry_count {
+            let index = self.index;
+            self.index += 1;
+
+            match self.gpt.get_entry(self.device, index) {
+                Ok(Some(entry)) => return Some(Ok((index, entry))),
+                Ok(None) => {}
+                Err(error) => {
+                    self.index = self.gpt.entry_count;
+                    return Some(Err(error));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// A partition, as a block device of its own.
+pub struct Partition<'a> {
+    device: &'a mut dyn BlockDevice,
+    first_lba: u64,
+    block_count: u64,
+}
+
+impl<'a> Partition<'a> {
+    pub fn new(device: &'a mut dyn BlockDevice, entry: &PartitionEntry) -> Self {
+        Partition {
+            device,
+            first_lba: entry.first_lba,
+            block_count: entry.block_count(),
+        }
+    }
+}
+
+impl<'a> BlockDevice for Partition<'a> {
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        block::check_range(self.block_count, lba, buffer.len())?;
+
+        self.device.read_blocks(self.first_lba + lba, buffer)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        block::check_range(self.block_count, lba, buffer.len())?;
+
+        self.device.write_blocks(self.first_lba + lba, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MemoryDevice};
+
+    // The layout of testdata/gpt.sfdisk.
+    const BLOCK_COUNT: u64 = 256;
+    const ENTRY_COUNT: u32 = 128;
+    const ENTRIES_BLOCKS: u64 = 32;
+    const FIRST_USABLE_LBA: u64 = 2 + ENTRIES_BLOCKS;
+    const LAST_USABLE_LBA: u64 = BLOCK_COUNT - 2 - ENTRIES_BLOCKS;
+    const BACKUP_ENTRIES_LBA: u64 = LAST_USABLE_LBA + 1;
+
+    const DISK_GUID: Guid = Guid::new(0x1234_5678, 0x9ABC, 0xDEF0, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+    // Header fields offsets.
+    const MY_LBA: usize = 24;
+    const LAST_USABLE: usize = 48;
+    const ENTRIES_LBA: usize = 72;
+    const ENTRIES_COUNT: usize = 80;
+    const ENTRIES_CRC32: usize = 88;
+
+    fn block_offset(lba: u64) -> usize {
+        lba as usize * BLOCK_SIZE
+    }
+
+    fn write_entry(disk: &mut [u8], entries_lba: u64, index: u32, entry: &[u8; 128]) {
+        let offset = block_offset(entries_lba) + index as usize * 128;
+
+        disk[offset..offset + 128].copy_from_slice(entry);
+    }
+
+    fn entry(type_guid: Guid, first_lba: u64, last_lba: u64, name: &str) -> [u8; 128] {
+        let mut entry = [0; 128];
+        entry[..16].copy_from_slice(&type_guid.0);
+        entry[16..32].copy_from_slice(&[0xAA; 16]);
+        entry[32..40].copy_from_slice(&first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+        for (index, unit) in name.encode_utf16().enumerate() {
+            entry[56 + 2 * index..58 + 2 * index].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        entry
+    }
+
+    fn write_u32(disk: &mut [u8], offset: usize, value: u32) {
+        disk[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u64(disk: &mut [u8], offset: usize, value: u64) {
+        disk[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Recompute the CRCs of the header at `lba`, after editing it or its entries.
+    fn update_crcs(disk: &mut [u8], lba: u64) {
+        let header = block_offset(lba);
+        let mut entries_lba = [0; 8];
+        entries_lba.copy_from_slice(&disk[header + ENTRIES_LBA..header + ENTRIES_LBA + 8]);
+
+        // Entries placed out of the disk by a test keep their CRC.
+        let entries = (u64::from_le_bytes(entries_lba) as usize).checked_mul(BLOCK_SIZE);
+        let entries_size = ENTRY_COUNT as usize * 128;
+        if let Some(entries) = entries.and_then(|start| disk.get(start..start + entries_size)) {
+            let entries_crc = crc::crc32(entries);
+            write_u32(disk, header + ENTRIES_CRC32, entries_crc);
+        }
+
+        write_u32(disk, header + HEADER_CRC32_OFFSET, 0);
+        let header_crc = crc::crc32(&disk[header..header + MIN_HEADER_SIZE]);
+        write_u32(disk, header + HEADER_CRC32_OFFSET, header_crc);
+    }
+
+    /// Edit both copies of the entry `index`.
+    fn set_entry(disk: &mut [u8], index: u32, entry: &[u8; 128]) {
+        write_entry(disk, 2, index, entry);
+        write_entry(disk, BACKUP_ENTRIES_LBA, index, entry);
+        update_crcs(disk, PRIMARY_HEADER_LBA);
+        update_crcs(disk, BLOCK_COUNT - 1);
+    }
+
+    /// An ESP, an unused entry and a Linux partition whose first byte is 0x42.
+    fn disk() -> Vec<u8> {
+        test_support::image("gpt")
+    }
+
+    fn names(gpt: &Gpt, device: &mut dyn BlockDevice) -> Vec<String> {
+        test_support::names(gpt.partitions(device).map(Result::unwrap), |(_, entry)| {
+            entry.name().collect()
+        })
+    }
+
+    #[test]
+    fn read() {
+        let mut device = MemoryDevice(disk());
+        let gpt = Gpt::read(&mut device).unwrap();
+
+        assert!(!gpt.is_backup);
+        assert_eq!(gpt.disk_guid, DISK_GUID);
+        assert_eq!(gpt.first_usable_lba, FIRST_USABLE_LBA);
+        assert_eq!(gpt.last_usable_lba, LAST_USABLE_LBA);
+        assert_eq!(names(&gpt, &mut device), ["esp", "APP"]);
+        assert!(gpt.get_entry(&mut device, 1).unwrap().is_none());
+        assert!(gpt
+            .get_entry(&mut device, 2)
+            .unwrap()
+            .unwrap()
+            .has_name("APP"));
+        assert!(gpt.get_entry(&mut device, ENTRY_COUNT).unwrap().is_none());
+
+        let esp = gpt
+            .find_by_type(&mut device, &Guid::EFI_SYSTEM)
+            .unwrap()
+            .unwrap();
+        assert!(esp.has_name("esp"));
+        assert_eq!(esp.block_count(), 100 - FIRST_USABLE_LBA);
+
+        let app = gpt.find_by_name(&mut device, "APP").unwrap().unwrap();
+        assert_eq!(app.type_guid, Guid::LINUX_FILESYSTEM);
+        assert!(gpt.find_by_name(&mut device, "AP").unwrap().is_none());
+
+        let mut partition = Partition::new(&mut device, &app);
+        let mut block = [0; BLOCK_SIZE];
+        partition.read_blocks(0, &mut block).unwrap();
+        assert_eq!(block[0], 0x42);
+        assert_eq!(
+            partition.read_blocks(app.block_count(), &mut block),
+            Err(block::Error::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn guid_format() {
+        assert_eq!(
+            format!("{}", Guid::EFI_SYSTEM),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+        assert_eq!(&Guid::EFI_SYSTEM.0[..4], &[0x28, 0x73, 0x2A, 0xC1]);
+    }
+
+    #[test]
+    fn corrupt_primary_header() {
+        let mut disk = disk();
+        disk[block_offset(PRIMARY_HEADER_LBA) + 40] ^= 1;
+
+        let mut device = MemoryDevice(disk);
+        let gpt = Gpt::read(&mut device).unwrap();
+        assert!(gpt.is_backup);
+        assert_eq!(gpt.entries_lba, BACKUP_ENTRIES_LBA);
+        assert_eq!(names(&gpt, &mut device).len(), 2);
+
+        // The CRC field itself.
+        let mut disk = device.0;
+        disk[block_offset(PRIMARY_HEADER_LBA) + 40] ^= 1;
+        disk[block_offset(PRIMARY_HEADER_LBA) + HEADER_CRC32_OFFSET] ^= 1;
+        assert_eq!(
+            read_header(&mut MemoryDevice(disk), PRIMARY_HEADER_LBA).err(),
+            Some(HeaderError::BadHeaderCrc)
+        );
+    }
+
+    #[test]
+    fn bad_entries_crc() {
+        let mut disk = disk();
+        disk[block_offset(2) + 56] ^= 1;
+
+        let mut device = MemoryDevice(disk);
+        assert_eq!(
+            read_header(&mut device, PRIMARY_HEADER_LBA).err(),
+            Some(HeaderError::BadEntriesCrc)
+        );
+
+        let gpt = Gpt::read(&mut device).unwrap();
+        assert!(gpt.is_backup);
+        assert!(gpt.find_by_name(&mut device, "esp").unwrap().is_some());
+
+        // Nothing left to fall back to.
+        device.0[block_offset(BACKUP_ENTRIES_LBA) + 56] ^= 1;
+        assert_eq!(
+            Gpt::read(&mut device).err(),
+            Some(Error::NoValidTable {
+                primary: HeaderError::BadEntriesCrc,
+                backup: HeaderError::BadEntriesCrc,
+            })
+        );
+    }
+
+    #[test]
+    fn no_table() {
+        let mut device = MemoryDevice(vec![0; block_offset(BLOCK_COUNT)]);
+        assert_eq!(
+            Gpt::read(&mut device).err(),
+            Some(Error::NoValidTable {
+                primary: HeaderError::BadSignature,
+                backup: HeaderError::BadSignature,
+            })
+        );
+
+        assert_eq!(
+            Gpt::read(&mut MemoryDevice(Vec::new())).err(),
+            Some(Error::Block(block::Error::OutOfRange))
+        );
+    }
+
+    #[test]
+    fn bad_header_layout() {
+        let header = block_offset(PRIMARY_HEADER_LBA);
+        let check = |edit: &dyn Fn(&mut Vec<u8>), expected: HeaderError| {
+            let mut disk = disk();
+            edit(&mut disk);
+            update_crcs(&mut disk, PRIMARY_HEADER_LBA);
+
+            assert_eq!(
+                read_header(&mut MemoryDevice(disk), PRIMARY_HEADER_LBA).err(),
+                Some(expected)
+            );
+        };
+
+        check(
+            &|disk| write_u64(disk, header + MY_LBA, 2),
+            HeaderError::BadLocation,
+        );
+        // The entry array runs past the end of the disk.
+        check(
+            &|disk| write_u64(disk, header + ENTRIES_LBA, BLOCK_COUNT - ENTRIES_BLOCKS + 1),
+            HeaderError::BadLayout,
+        );
+        check(
+            &|disk| write_u64(disk, header + ENTRIES_LBA, u64::MAX),
+            HeaderError::BadLayout,
+        );
+        check(
+            &|disk| write_u64(disk, header + LAST_USABLE, BLOCK_COUNT),
+            HeaderError::BadLayout,
+        );
+        check(
+            &|disk| write_u64(disk, header + LAST_USABLE, FIRST_USABLE_LBA - 1),
+            HeaderError::BadLayout,
+        );
+        check(
+            &|disk| write_u32(disk, header + ENTRIES_COUNT, MAX_ENTRY_COUNT + 1),
+            HeaderError::BadLayout,
+        );
+        check(
+            &|disk| write_u32(disk, header + 84, 100),
+            HeaderError::BadLayout,
+        );
+        // The header CRC can't be checked with a size outside of the block.
+        let mut disk = disk();
+        write_u32(&mut disk, header + 12, BLOCK_SIZE as u32 + 1);
+        assert_eq!(
+            read_header(&mut MemoryDevice(disk), PRIMARY_HEADER_LBA).err(),
+            Some(HeaderError::BadLayout)
+        );
+    }
+
+    #[test]
+    fn grown_disk() {
+        // The backup isn't in the last block anymore, the primary header tells where it is.
+        let mut grown = disk();
+        grown.resize(block_offset(BLOCK_COUNT + 64), 0);
+
+        let mut device = MemoryDevice(grown);
+        assert!(!Gpt::read(&mut device).unwrap().is_backup);
+
+        // Without a valid primary header, the backup is looked for in the last block.
+        device.0[block_offset(2)] ^= 1;
+        assert_eq!(
+            Gpt::read(&mut device).err(),
+            Some(Error::NoValidTable {
+                primary: HeaderError::BadEntriesCrc,
+                backup: HeaderError::BadSignature,
+            })
+        );
+    }
+
+    #[test]
+    fn out_of_range_entries() {
+        let mut disk = disk();
+        set_entry(
+            &mut disk,
+            1,
+            &entry(Guid::BASIC_DATA, 100, LAST_USABLE_LBA + 1, "data"),
+        );
+        let mut device = MemoryDevice(disk);
+        let gpt = Gpt::read(&mut device).unwrap();
+
+        assert!(gpt.get_entry(&mut device, 0).unwrap().is_some());
+        assert_eq!(
+            gpt.get_entry(&mut device, 1).err(),
+            Some(Error::InvalidEntry(1))
+        );
+        // Iteration stops at the invalid entry.
+        let entries: Vec<_> = gpt.partitions(&mut device).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].as_ref().err(), Some(&Error::InvalidEntry(1)));
+        assert_eq!(
+            gpt.find_by_name(&mut device, "APP").err(),
+            Some(Error::InvalidEntry(1))
+        );
+
+        let check = |first_lba, last_lba| {
+            let mut disk = device.0.clone();
+            set_entry(
+                &mut disk,
+                1,
+                &entry(Guid::BASIC_DATA, first_lba, last_lba, "data"),
+            );
+            let mut device = MemoryDevice(disk);
+
+            gpt.get_entry(&mut device, 1).map(|entry| entry.is_some())
+        };
+
+        assert_eq!(check(FIRST_USABLE_LBA - 1, 99), Err(Error::InvalidEntry(1)));
+        assert_eq!(check(101, 100), Err(Error::InvalidEntry(1)));
+        assert_eq!(check(0, u64::MAX), Err(Error::InvalidEntry(1)));
+        assert_eq!(check(LAST_USABLE_LBA, LAST_USABLE_LBA), Ok(true));
+        assert_eq!(check(FIRST_USABLE_LBA, FIRST_USABLE_LBA), Ok(true));
+    }
+}
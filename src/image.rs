@@ -0,0 +1,242 @@
+//! Headers of Linux arm64 `Image` files and where they go in memory, following
+//! Documentation/arm64/booting.rst.
+//!
+//! Only `core` is used, the module can be built and tested on the host.
+
+use core::cmp;
+use core::mem;
+use core::ptr;
+
+/// "ARM\x64"
+pub const IMAGE_MAGIC: u32 = 0x644d_5241;
+
+/// The kernel has to be placed `text_offset` bytes after a 2M aligned base.
+const KERNEL_BASE_ALIGNMENT: u64 = 0x20_0000;
+
+/// Used by kernels older than 3.17, which have a zero `image_size`.
+const DEFAULT_TEXT_OFFSET: u64 = 0x8_0000;
+
+const FLAG_BIG_ENDIAN: u64 = 1 << 0;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ImageHeader {
+    pub code0: u32,
+    pub code1: u32,
+    pub text_offset: u64,
+    pub image_size: u64,
+    pub flags: u64,
+    pub reserved2: u64,
+    pub reserved3: u64,
+    pub reserved4: u64,
+    pub magic: u32,
+    /// Offset of the PE header, for EFI stub kernels.
+    pub reserved5: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The file is smaller than the header.
+    Truncated,
+    BadMagic,
+    /// Only little endian kernels can run with rboot.
+    BigEndian,
+    /// The kernel doesn't fit in the memory given to it, `required` being `u64::MAX` when
+    /// its end overflows.
+    NotEnoughMemory {
+        required: u64,
+        available: u64,
+    },
+}
+
+/// A validated kernel `Image`.
+pub struct Image<'a> {
+    data: &'a [u8],
+    header: ImageHeader,
+}
+
+/// Where a kernel goes, from `entry` to `end` exclusive.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Placement {
+    pub entry: u64,
+    pub end: u64,
+}
+
+impl<'a> Image<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < mem::size_of::<ImageHeader>() {
+            return Err(Error::Truncated);
+        }
+
+        let header = unsafe { ptr::read_unaligned(data.as_ptr() as *const ImageHeader) };
+
+        if header.magic != IMAGE_MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        // Before 3.17, flags are not defined and image_size is zero.
+        if header.image_size != 0 && header.flags & FLAG_BIG_ENDIAN != 0 {
+            return Err(Error::BigEndian);
+        }
+
+        Ok(Image { data, header })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn header(&self) -> &ImageHeader {
+        &self.header
+    }
+
+    pub fn text_offset(&self) -> u64 {
+        if self.header.image_size == 0 {
+            DEFAULT_TEXT_OFFSET
+        } else {
+            self.header.text_offset
+        }
+    }
+
+    /// Memory used by the kernel once loaded, BSS included when the header tells it.
+    ///
+    /// Files longer than the size of their header are copied whole, so they count too.
+    pub fn image_size(&self) -> u64 {
+        cmp::max(self.header.image_size, self.data.len() as u64)
+    }
+
+    /// Where the kernel goes in the `memory_size` bytes at `memory_start`.
+    pub fn placement(&self, memory_start: u64, memory_size: u64) -> Result<Placement, Error> {
+        let not_enough_memory = |required| Error::NotEnoughMemory {
+            required,
+            available: memory_size,
+        };
+
+        let memory_end = memory_start
+            .checked_add(memory_size)
+            .ok_or_else(|| not_enough_memory(u64::MAX))?;
+        let entry = memory_start
+            .checked_add(KERNEL_BASE_ALIGNMENT - 1)
+            .map(|end| end & !(KERNEL_BASE_ALIGNMENT - 1))
+            .and_then(|base| base.checked_add(self.text_offset()));
+        let end = entry.and_then(|entry| entry.checked_add(self.image_size()));
+
+        match (entry, end) {
+            (Some(entry), Some(end)) if end <= memory_end => Ok(Placement { entry, end }),
+            (_, Some(end)) => Err(not_enough_memory(end - memory_start)),
+            _ => Err(not_enough_memory(u64::MAX)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_file(text_offset: u64, image_size: u64, flags: u64, length: usize) -> Vec<u8> {
+        let mut data = vec![0; length];
+        data[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        data[16..24].copy_from_slice(&image_size.to_le_bytes());
+        data[24..32].copy_from_slice(&flags.to_le_bytes());
+        data[56..60].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn parse() {
+        let data = image_file(0x8_0000, 0x2_0000, 0, 0x1000);
+        let image = Image::parse(&data).unwrap();
+        assert_eq!(image.text_offset(), 0x8_0000);
+        assert_eq!(image.image_size(), 0x2_0000);
+        assert_eq!(image.data().len(), 0x1000);
+
+        // Defaults of kernels older than 3.17, flags being ignored.
+        let data = image_file(0x1234, 0, FLAG_BIG_ENDIAN, 0x1000);
+        let image = Image::parse(&data).unwrap();
+        assert_eq!(image.text_offset(), DEFAULT_TEXT_OFFSET);
+        assert_eq!(image.image_size(), 0x1000);
+
+        // Trailing data past image_size is copied too.
+        let data = image_file(0, 0x800, 0, 0x1000);
+        assert_eq!(Image::parse(&data).unwrap().image_size(), 0x1000);
+
+        let data = image_file(0, 0x2_0000, FLAG_BIG_ENDIAN, 0x1000);
+        assert_eq!(Image::parse(&data).err(), Some(Error::BigEndian));
+
+        let mut data = image_file(0, 0x2_0000, 0, 0x1000);
+        data[56] ^= 1;
+        assert_eq!(Image::parse(&data).err(), Some(Error::BadMagic));
+
+        let data = image_file(0, 0x2_0000, 0, 0x1000);
+        assert_eq!(Image::parse(&data[..63]).err(), Some(Error::Truncated));
+        assert!(Image::parse(&data[..64]).is_ok());
+    }
+
+    #[test]
+    fn placement() {
+        let data = image_file(0x8_0000, 0x10_0000, 0, 0x1000);
+        let image = Image::parse(&data).unwrap();
+
+        // The base is aligned up to 2MB.
+        assert_eq!(
+            image.placement(0x8100_0000, 0x0D00_0000),
+            Ok(Placement {
+                entry: 0x8108_0000,
+                end: 0x8118_0000,
+            })
+        );
+        assert_eq!(
+            image.placement(0x8100_1000, 0x0D00_0000),
+            Ok(Placement {
+                entry: 0x8128_0000,
+                end: 0x8138_0000,
+            })
+        );
+
+        // Exactly fitting, then one byte short.
+        assert!(image.placement(0x8100_0000, 0x18_0000).is_ok());
+        assert_eq!(
+            image.placement(0x8100_0000, 0x17_FFFF),
+            Err(Error::NotEnoughMemory {
+                required: 0x18_0000,
+                available: 0x17_FFFF,
+            })
+        );
+    }
+
+    #[test]
+    fn placement_overflows() {
+        let not_enough_memory = |available| {
+            Err(Error::NotEnoughMemory {
+                required: u64::MAX,
+                available,
+            })
+        };
+
+        let data = image_file(u64::MAX - 0x1000, 0x10_0000, 0, 0x1000);
+        let image = Image::parse(&data).unwrap();
+        assert_eq!(
+            image.placement(0x8100_0000, 0x1000),
+            not_enough_memory(0x1000)
+        );
+
+        let data = image_file(0x8_0000, u64::MAX - 0x1000, 0, 0x1000);
+        let image = Image::parse(&data).unwrap();
+        assert_eq!(
+            image.placement(0x8100_0000, 0x1000),
+            not_enough_memory(0x1000)
+        );
+
+        // The alignment of the base overflows.
+        let data = image_file(0, 0x1000, 0, 0x1000);
+        let image = Image::parse(&data).unwrap();
+        assert_eq!(
+            image.placement(u64::MAX - 0x1000, 0x1000),
+            not_enough_memory(0x1000)
+        );
+        assert_eq!(
+            image.placement(0x8000_0000, u64::MAX),
+            not_enough_memory(u64::MAX)
+        );
+    }
+}
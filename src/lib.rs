@@ -0,0 +1,31 @@
+//! Hardware independent parts of rboot.
+//!
+//! They live in a library so `cargo test` can run their unit tests on the host,
+//! the bootloader itself is the `rboot` binary.
+
+#![cfg_attr(not(test), no_std)]
+#![feature(asm)]
+
+#[macro_use]
+extern crate log;
+
+pub mod block;
+pub mod boot_config;
+pub mod crc;
+pub mod elf;
+pub mod esr;
+pub mod ext4;
+pub mod extlinux;
+pub mod fat;
+pub mod fdt;
+pub mod gpt;
+pub mod image;
+pub mod log_buffer;
+pub mod log_filter;
+pub mod page_table;
+pub mod sdmmc;
+#[cfg(test)]
+pub mod test_support;
+pub mod transport;
+pub mod utils;
+pub mod ymodem;
@@ -0,0 +1,536 @@
+//! Linux arm64 `Image` loading, following Documentation/arm64/booting.rst.
+
+pub use crate::image::Image;
+
+use core::convert::Infallible;
+use core::fmt::{self, Write};
+use core::iter;
+use core::mem;
+use core::ptr;
+use core::slice;
+use core::str;
+
+use crate::cpio::{self, CpioWriter};
+use crate::fdt::{self, Fdt, FdtEditor};
+use crate::image::{self, Placement};
+use crate::logger;
+use crate::mmu::{self, MapError, MemoryPermission};
+use crate::tegra210::board::p2371_2180 as board;
+use crate::tegra210::timer;
+use crate::tegra210::uart as uart_input;
+use crate::tegra210::usb;
+use crate::utils;
+
+extern "C" {
+    static __end__: u8;
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: u64 = 40;
+
+/// Size of `/chosen/rng-seed`, what U-Boot passes too.
+const RNG_SEED_SIZE: usize = 64;
+
+/// Values of `#address-cells` and `#size-cells` when missing.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// Regions in a `reg` property written by rboot.
+const MAX_REG_REGIONS: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The kernel header is invalid or the kernel doesn't fit in the memory given to it.
+    Image(image::Error),
+    /// The initramfs doesn't fit in the memory given to it.
+    NotEnoughMemory { required: u64, available: u64 },
+    /// The kernel would overwrite rboot.
+    Overlap,
+    /// The device tree is misaligned or its header is invalid.
+    InvalidDeviceTree,
+    /// The device tree couldn't be edited.
+    DeviceTree(fdt::Error),
+    /// Entries couldn't be appended to the initramfs.
+    Initrd(cpio::Error),
+    /// A region doesn't fit in the `#address-cells` and `#size-cells` of the device tree.
+    UnsupportedCells,
+    /// The destination memory couldn't be mapped.
+    Map(MapError),
+}
+
+impl From<image::Error> for Error {
+    fn from(error: image::Error) -> Self {
+        Error::Image(error)
+    }
+}
+
+impl From<cpio::Error> for Error {
+    fn from(error: cpio::Error) -> Self {
+        Error::Initrd(error)
+    }
+}
+
+impl From<fdt::Error> for Error {
+    fn from(error: fdt::Error) -> Self {
+        Error::DeviceTree(error)
+    }
+}
+
+impl From<MapError> for Error {
+    fn from(error: MapError) -> Self {
+        Error::Map(error)
+    }
+}
+
+/// Memory used by rboot, its page tables and stack included.
+pub fn get_rboot_range() -> (u64, u64) {
+    let start = crate::rt::_start as usize as u64;
+    let end = unsafe { &__end__ as *const u8 as u64 };
+
+    (start, utils::align_up(end, mmu::PAGE_SIZE))
+}
+
+/// A kernel copied in memory and ready to boot.
+#[derive(Copy, Clone, Debug)]
+pub struct LoadedKernel {
+    pub entry: u64,
+    pub size: u64,
+}
+
+/// Copy `image` in the `memory_size` bytes of DRAM at `memory_start`.
+pub fn load(image: &Image, memory_start: u64, memory_size: u64) -> Result<LoadedKernel, Error> {
+    let Placement { entry, end } = image.placement(memory_start, memory_size)?;
+
+    let (rboot_start, rboot_end) = get_rboot_range();
+    if entry < rboot_end && rboot_start < end {
+        return Err(Error::Overlap);
+    }
+
+    let mapping_start = utils::align_down(entry, mmu::PAGE_SIZE);
+    let mapping_end = utils::align_up(end, mmu::PAGE_SIZE);
+    mmu::map_normal_page(
+        mapping_start,
+        mapping_start,
+        mapping_end - mapping_start,
+        MemoryPermission::RWX,
+    )?;
+
+    // The file may already be in the destination region.
+    let data = image.data();
+    unsafe { ptr::copy(data.as_ptr(), entry as *mut u8, data.len()) };
+
+    Ok(LoadedKernel {
+        entry,
+        size: end - entry,
+    })
+}
+
+/// An initramfs copied after the kernel.
+#[derive(Copy, Clone, Debug)]
+pub struct LoadedInitrd {
+    pub start: u64,
+    pub size: u64,
+    /// Space available at `start` for appending entries.
+    capacity: u64,
+}
+
+impl LoadedInitrd {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    /// Append an archive with `files` and their parent directories, as (path, content).
+    ///
+    /// Paths are relative to the root of the initramfs.
+    pub fn append_files(&mut self, files: &[(&str, &[u8])]) -> Result<(), Error> {
+        let buffer =
+            unsafe { slice::from_raw_parts_mut(self.start as *mut u8, self.capacity as usize) };
+        let mut writer = CpioWriter::new(buffer, self.size as usize)?;
+
+        for (path, content) in files {
+            // Directories already in the initramfs are kept as they are by the kernel.
+            for (index, _) in path.match_indices('/') {
+                writer.add_directory(&path[..index], 0o755)?;
+            }
+
+            writer.add_file(path, content, 0o644)?;
+        }
+
+        self.size = writer.finish()? as u64;
+
+        Ok(())
+    }
+}
+
+/// Copy `initrd` after `kernel`, in the memory given to it ending at `memory_end`.
+///
+/// The kernel wants the initramfs in the same 1GB aligned window as itself, which the
+/// memory after it is. The device tree at `dtb` must be left untouched, and `initrd` must
+/// not have been overwritten by the kernel.
+pub fn load_initrd(
+    initrd: &[u8],
+    kernel: &LoadedKernel,
+    memory_end: u64,
+    dtb: (u64, u64),
+) -> Result<LoadedInitrd, Error> {
+    let start = utils::align_up(kernel.entry + kernel.size, mmu::PAGE_SIZE);
+    let size = initrd.len() as u64;
+
+    if start + size > memory_end {
+        return Err(Error::NotEnoughMemory {
+            required: start + size - kernel.entry,
+            available: memory_end.saturating_sub(kernel.entry),
+        });
+    }
+
+    let (rboot_start, rboot_end) = get_rboot_range();
+    let (dtb_start, dtb_end) = dtb;
+    if (start < rboot_end && rboot_start < memory_end)
+        || (start < dtb_end && dtb_start < memory_end)
+    {
+        return Err(Error::Overlap);
+    }
+
+    let source_start = initrd.as_ptr() as u64;
+    let kernel_end = kernel.entry + kernel.size;
+    if source_start < kernel_end && kernel.entry < source_start + size {
+        return Err(Error::Overlap);
+    }
+
+    mmu::map_normal_page(start, start, memory_end - start, MemoryPermission::RW)?;
+
+    // The file may already be in the destination region.
+    unsafe { ptr::copy(initrd.as_ptr(), start as *mut u8, initrd.len()) };
+
+    Ok(LoadedInitrd {
+        start,
+        size,
+        capacity: memory_end - start,
+    })
+}
+
+/// What rboot tells the kernel through `/chosen`, on top of what it always adds.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BootParameters<'a> {
+    /// Replaces the command line of the device tree when set.
+    pub bootargs: Option<&'a str>,
+    /// Start and end addresses of the initramfs.
+    pub initrd: Option<(u64, u64)>,
+}
+
+/// Formats node names and paths without allocating.
+struct StringBuffer<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> StringBuffer<'a> {
+    fn new(buffer: &'a mut [u8]) -> Self {
+        StringBuffer { buffer, length: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        str::from_utf8(&self.buffer[..self.length]).unwrap()
+    }
+}
+
+impl<'a> Write for StringBuffer<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.length + s.len();
+        if end > self.buffer.len() {
+            return Err(fmt::Error);
+        }
+
+        self.buffer[self.length..end].copy_from_slice(s.as_bytes());
+        self.length = end;
+
+        Ok(())
+    }
+}
+
+/// Read `#address-cells` and `#size-cells` of `node`, which apply to its children.
+fn get_cells(fdt: &Fdt, node: usize) -> (u32, u32) {
+    let get_cell = |name, default| {
+        fdt.get_property(node, name)
+            .and_then(|property| property.as_u32())
+            .unwrap_or(default)
+    };
+
+    (
+        get_cell("#address-cells", DEFAULT_ADDRESS_CELLS),
+        get_cell("#size-cells", DEFAULT_SIZE_CELLS),
+    )
+}
+
+/// Encode `regions` as a `reg` property value in `buffer`.
+fn encode_reg<'a>(
+    buffer: &'a mut [u8; MAX_REG_REGIONS * 16],
+    regions: &[(u64, u64)],
+    (address_cells, size_cells): (u32, u32),
+) -> Result<&'a [u8], Error> {
+    let mut length = 0;
+
+    if regions.len() > MAX_REG_REGIONS {
+        return Err(Error::UnsupportedCells);
+    }
+
+    for (address, size) in regions {
+        for (value, cells) in [(*address, address_cells), (*size, size_cells)].iter() {
+            match cells {
+                1 if *value <= u64::from(u32::MAX) => {
+                    buffer[length..length + 4].copy_from_slice(&(*value as u32).to_be_bytes());
+                    length += 4;
+                }
+                2 => {
+                    buffer[length..length + 8].copy_from_slice(&value.to_be_bytes());
+                    length += 8;
+                }
+                _ => return Err(Error::UnsupportedCells),
+            }
+        }
+    }
+
+    Ok(&buffer[..length])
+}
+
+fn read_system_counter() -> u64 {
+    let counter: u64;
+
+    unsafe { asm!("isb; mrs {counter}, cntpct_el0", counter = out(reg) counter, options(nostack)) };
+
+    counter
+}
+
+/// SplitMix64 output function.
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    value ^ (value >> 31)
+}
+
+/// Gather entropy from the jitter of the system counter against TIMERUS.
+///
+/// This is weak, there is no driver for the SE RNG yet. The kernel only relies on it for
+/// KASLR and mixes it with its own sources otherwise.
+fn get_entropy_seed() -> u64 {
+    let mut state = read_system_counter() ^ (u64::from(timer::get_microseconds()) << 32);
+
+    for _ in 0..64 {
+        let start = timer::get_microseconds();
+        let mut iterations = 0u64;
+
+        while timer::get_microseconds() == start {
+            iterations += 1;
+        }
+
+        state = mix(state ^ iterations ^ read_system_counter().rotate_left(32));
+    }
+
+    state
+}
+
+/// Set the command line, initramfs, console and seeds in `/chosen`.
+fn fixup_chosen(editor: &mut FdtEditor, parameters: &BootParameters) -> Result<(), Error> {
+    let root = editor.fdt().root()?;
+    let chosen = editor.get_or_add_subnode(root, "chosen")?;
+
+    if let Some(bootargs) = parameters.bootargs {
+        editor.set_property_str(chosen, "bootargs", bootargs)?;
+    }
+
+    match parameters.initrd {
+        Some((start, end)) => {
+            editor.set_property_u64(chosen, "linux,initrd-start", start)?;
+            editor.set_property_u64(chosen, "linux,initrd-end", end)?;
+        }
+        None => {
+            // Don't let the kernel look for a stale initramfs.
+            editor.delete_property(chosen, "linux,initrd-start").ok();
+            editor.delete_property(chosen, "linux,initrd-end").ok();
+        }
+    }
+
+    if let Some((uart, baud_rate)) = logger::get_console_uart() {
+        let mut path_buffer = [0; 64];
+        let mut path = StringBuffer::new(&mut path_buffer);
+        write!(path, "/serial@{:x}", uart_input::get_base(uart)).unwrap();
+
+        if editor.fdt().find_node(path.as_str()).is_some() {
+            write!(path, ":{}n8", baud_rate).unwrap();
+            editor.set_property_str(chosen, "stdout-path", path.as_str())?;
+        } else {
+            warn!("No {} node for stdout-path", path.as_str());
+        }
+    }
+
+    let mut seed = get_entropy_seed();
+    editor.set_property_u64(chosen, "kaslr-seed", mix(seed))?;
+
+    let mut rng_seed = [0; RNG_SEED_SIZE];
+    for chunk in rng_seed.chunks_mut(mem::size_of::<u64>()) {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        chunk.copy_from_slice(&mix(seed).to_ne_bytes());
+    }
+    editor.set_property(chosen, "rng-seed", &rng_seed)?;
+
+    Ok(())
+}
+
+/// Describe the DRAM banks of the board when the device tree has no memory node.
+///
+/// Memory nodes written by the previous boot stages are kept: they leave out the secure
+/// carveouts (TZDRAM, VPR...) that rboot doesn't know about.
+fn fixup_memory(editor: &mut FdtEditor) -> Result<(), Error> {
+    let root = editor.fdt().root()?;
+    let cells = get_cells(&editor.fdt(), root);
+
+    while let Some(memory) = editor.fdt().find_subnode(root, "memory") {
+        let has_regions = match editor.fdt().get_property(memory, "reg") {
+            Some(reg) => !reg.value.is_empty(),
+            None => false,
+        };
+
+        if has_regions {
+            return Ok(());
+        }
+
+        // Empty nodes would hide the one added below.
+        editor.delete_node(memory)?;
+    }
+
+    warn!("No memory node in the device tree, secure carveouts aren't reserved");
+
+    let mut name_buffer = [0; 32];
+    let mut name = StringBuffer::new(&mut name_buffer);
+    write!(name, "memory@{:x}", board::DRAM_BANKS[0].0).unwrap();
+
+    let mut reg_buffer = [0; MAX_REG_REGIONS * 16];
+    let reg = encode_reg(&mut reg_buffer, &board::DRAM_BANKS, cells)?;
+
+    let memory = editor.add_subnode(root, name.as_str())?;
+    editor.set_property_str(memory, "device_type", "memory")?;
+    editor.set_property(memory, "reg", reg)?;
+
+    Ok(())
+}
+
+/// Keep the kernel away from rboot and the board carveouts.
+fn fixup_reserved_memory(editor: &mut FdtEditor) -> Result<(), Error> {
+    let root = editor.fdt().root()?;
+    let root_cells = get_cells(&editor.fdt(), root);
+
+    let reserved_memory = match editor.fdt().find_subnode(root, "reserved-memory") {
+        Some(node) => node,
+        None => {
+            let node = editor.add_subnode(root, "reserved-memory")?;
+            editor.set_property_u32(node, "#address-cells", root_cells.0)?;
+            editor.set_property_u32(node, "#size-cells", root_cells.1)?;
+            editor.set_property(node, "ranges", &[])?;
+            node
+        }
+    };
+    let cells = get_cells(&editor.fdt(), reserved_memory);
+
+    let (rboot_start, rboot_end) = get_rboot_range();
+    let rboot = ("rboot", rboot_start, rboot_end - rboot_start);
+
+    for (region_name, address, size) in iter::once(&rboot).chain(board::CARVEOUTS.iter()) {
+        let mut name_buffer = [0; 64];
+        let mut name = StringBuffer::new(&mut name_buffer);
+        write!(name, "{}@{:x}", region_name, address).map_err(|_| fdt::Error::BadName)?;
+
+        let mut reg_buffer = [0; MAX_REG_REGIONS * 16];
+        let reg = encode_reg(&mut reg_buffer, &[(*address, *size)], cells)?;
+
+        let region = editor.get_or_add_subnode(reserved_memory, name.as_str())?;
+        editor.set_property(region, "reg", reg)?;
+        editor.set_property(region, "no-map", &[])?;
+    }
+
+    Ok(())
+}
+
+/// Copy the device tree `dtb` in `buffer`, adding what rboot knows about the system to it.
+///
+/// `/chosen` gets the boot parameters, the console, and seeds for KASLR and the kernel RNG.
+/// `/memory` is kept, or describes the DRAM banks of the board when missing. rboot and the
+/// board carveouts are added to `/reserved-memory`, keeping its existing regions.
+pub fn prepare_device_tree<'a>(
+    dtb: &[u8],
+    buffer: &'a mut [u8],
+    parameters: &BootParameters,
+) -> Result<&'a [u8], Error> {
+    let mut editor = FdtEditor::open_into(dtb, buffer)?;
+
+    fixup_chosen(&mut editor, parameters)?;
+    fixup_memory(&mut editor)?;
+    fixup_reserved_memory(&mut editor)?;
+
+    Ok(editor.pack())
+}
+
+/// The device tree blob at `dtb`, after checking its header.
+pub fn get_device_tree(dtb: u64) -> Result<&'static [u8], Error> {
+    let size = get_device_tree_size(dtb)?;
+
+    match mmu::translate(dtb + size - 1) {
+        Some(translation) if translation.permission.is_readable() => {}
+        _ => return Err(Error::InvalidDeviceTree),
+    }
+
+    Ok(unsafe { slice::from_raw_parts(dtb as *const u8, size as usize) })
+}
+
+fn get_device_tree_size(dtb: u64) -> Result<u64, Error> {
+    if dtb % 8 != 0 {
+        return Err(Error::InvalidDeviceTree);
+    }
+
+    match mmu::translate(dtb) {
+        Some(translation) if translation.permission.is_readable() => {}
+        _ => return Err(Error::InvalidDeviceTree),
+    }
+
+    let header = unsafe { ptr::read_volatile(dtb as *const [u32; 2]) };
+    let size = u32::from_be(header[1]) as u64;
+
+    if u32::from_be(header[0]) != FDT_MAGIC || size < FDT_HEADER_SIZE {
+        return Err(Error::InvalidDeviceTree);
+    }
+
+    Ok(size)
+}
+
+/// Jump to a loaded kernel with x0 pointing to the device tree at `dtb`, which has to
+/// describe `initrd` when there is one.
+///
+/// Only returns if the device tree is invalid.
+///
+/// # Safety
+///
+/// rboot doesn't exist anymore once the kernel starts, nothing it set up must be in use.
+pub unsafe fn boot(
+    kernel: &LoadedKernel,
+    initrd: Option<&LoadedInitrd>,
+    dtb: u64,
+) -> Result<Infallible, Error> {
+    let dtb_size = get_device_tree_size(dtb)?;
+
+    info!(
+        "Booting Linux at {:#x} with the device tree at {:#x}",
+        kernel.entry, dtb
+    );
+
+    // The kernel starts with the MMU and D-cache off.
+    mmu::clean_invalidate_dcache_range(kernel.entry, kernel.size);
+    mmu::clean_invalidate_dcache_range(dtb, dtb_size);
+    if let Some(initrd) = initrd {
+        mmu::clean_invalidate_dcache_range(initrd.start, initrd.size);
+    }
+
+    // The USB controller would keep writing setup packets to rboot memory.
+    usb::shutdown();
+
+    mmu::jump_with_mmu_disabled(kernel.entry, [dtb, 0, 0, 0])
+}
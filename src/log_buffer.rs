@@ -0,0 +1,235 @@
+//! Log ring buffer stored in a memory carveout, surviving warm reboots.
+//!
+//! Only `core` is used, the module can be built and tested on the host.
+
+use core::fmt;
+use core::mem;
+use core::ptr;
+
+use crate::crc;
+
+/// "RLOG"
+const LOG_BUFFER_MAGIC: u32 = 0x474F_4C52;
+
+#[repr(C)]
+struct Header {
+    magic: u32,
+    capacity: u32,
+    /// Offset of the next byte to write in the data area.
+    head: u32,
+    /// Count of valid bytes in the data area.
+    length: u32,
+    /// CRC-32 of the previous fields.
+    ///
+    /// Only the header is protected, checking the data would mean hashing the whole buffer on
+    /// each write. Corrupted data only shows up as garbage in the log.
+    checksum: u32,
+}
+
+pub struct LogBuffer {
+    header: *mut Header,
+    data: *mut u8,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    /// Create a buffer over `size` bytes at `address`.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be mapped, reserved to the buffer and should not be cacheable
+    /// as its content needs to reach memory before a reset.
+    pub unsafe fn new(address: u64, size: u64) -> Self {
+        let header_size = mem::size_of::<Header>() as u64;
+        assert!(size > header_size, "Log buffer is too small");
+
+        LogBuffer {
+            header: address as *mut Header,
+            data: (address + header_size) as *mut u8,
+            capacity: (size - header_size) as usize,
+        }
+    }
+
+    fn read_header(&self) -> Header {
+        unsafe { ptr::read_volatile(self.header) }
+    }
+
+    fn compute_checksum(header: &Header) -> u32 {
+        let mut crc = 0;
+
+        for value in [header.magic, header.capacity, header.head, header.length].iter() {
+            crc = crc::crc32_update(crc, &value.to_le_bytes());
+        }
+
+        crc
+    }
+
+    fn write_header(&mut self, head: u32, length: u32) {
+        let mut header = Header {
+            magic: LOG_BUFFER_MAGIC,
+            capacity: self.capacity as u32,
+            head,
+            length,
+            checksum: 0,
+        };
+
+        header.checksum = Self::compute_checksum(&header);
+
+        unsafe { ptr::write_volatile(self.header, header) };
+    }
+
+    /// Check that the buffer holds a log written by a previous session.
+    pub fn is_valid(&self) -> bool {
+        let header = self.read_header();
+
+        header.magic == LOG_BUFFER_MAGIC
+            && header.capacity as usize == self.capacity
+            && header.head < header.capacity
+            && header.length <= header.capacity
+            && header.checksum == Self::compute_checksum(&header)
+    }
+
+    /// Drop the content of the buffer.
+    pub fn reset(&mut self) {
+        self.write_header(0, 0);
+    }
+
+    /// Count of bytes currently stored.
+    pub fn len(&self) -> usize {
+        self.read_header().length as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Call `f` with the stored bytes, oldest first.
+    ///
+    /// The content is given in at most two parts as the buffer may have wrapped around.
+    pub fn for_each_part<F: FnMut(&[u8])>(&self, mut f: F) {
+        let header = self.read_header();
+        let head = header.head as usize;
+        let length = header.length as usize;
+
+        let data = unsafe { core::slice::from_raw_parts(self.data, self.capacity) };
+
+        if length <= head {
+            f(&data[head - length..head]);
+        } else {
+            f(&data[self.capacity - (length - head)..]);
+            f(&data[..head]);
+        }
+    }
+
+    /// Append bytes, overwriting the oldest ones when full.
+    pub fn write(&mut self, bytes: &[u8]) {
+        let header = self.read_header();
+        let mut head = header.head as usize;
+        let mut length = header.length as usize;
+
+        for byte in bytes {
+            unsafe { ptr::write_volatile(self.data.add(head), *byte) };
+
+            head = (head + 1) % self.capacity;
+            length = core::cmp::min(length + 1, self.capacity);
+        }
+
+        self.write_header(head as u32, length as u32);
+    }
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+    /// Backing memory of a buffer of `capacity` bytes, aligned for the header.
+    fn memory(capacity: usize) -> Vec<u32> {
+        vec![0; (HEADER_SIZE + capacity + 3) / 4]
+    }
+
+    fn buffer(memory: &mut [u32], capacity: usize) -> LogBuffer {
+        unsafe { LogBuffer::new(memory.as_mut_ptr() as u64, (HEADER_SIZE + capacity) as u64) }
+    }
+
+    fn parts(buffer: &LogBuffer) -> Vec<Vec<u8>> {
+        let mut parts = Vec::new();
+        buffer.for_each_part(|part| parts.push(part.to_vec()));
+        parts
+    }
+
+    #[test]
+    fn wrap_around() {
+        let mut memory = memory(8);
+        let mut buffer = buffer(&mut memory, 8);
+        buffer.reset();
+        assert!(buffer.is_empty());
+        assert_eq!(parts(&buffer), [b""]);
+
+        buffer.write(b"abcdef");
+        assert_eq!(buffer.len(), 6);
+        assert_eq!(parts(&buffer), [b"abcdef"]);
+
+        // Exactly full.
+        buffer.write(b"gh");
+        assert_eq!(buffer.len(), 8);
+        assert_eq!(parts(&buffer), [&b"abcdefgh"[..], b""]);
+
+        buffer.write(b"ij");
+        assert_eq!(buffer.len(), 8);
+        assert_eq!(parts(&buffer), [&b"cdefgh"[..], b"ij"]);
+
+        // More than the capacity at once.
+        write!(buffer, "0123456789").unwrap();
+        assert_eq!(parts(&buffer), [&b"2345"[..], b"6789"]);
+        assert!(buffer.is_valid());
+    }
+
+    #[test]
+    fn validity() {
+        let mut memory = memory(16);
+        let mut buffer = buffer(&mut memory, 16);
+        assert!(!buffer.is_valid());
+
+        buffer.reset();
+        buffer.write(b"last boot");
+        assert!(buffer.is_valid());
+
+        // Seen again after a reboot.
+        let buffer = self::buffer(&mut memory, 16);
+        assert!(buffer.is_valid());
+        assert_eq!(parts(&buffer), [b"last boot"]);
+
+        // With another size, or with a corrupted header.
+        let other_buffer = self::buffer(&mut memory, 12);
+        assert!(!other_buffer.is_valid());
+
+        let mut corrupted = memory.clone();
+        corrupted[2] ^= 1;
+        assert!(!self::buffer(&mut corrupted, 16).is_valid());
+
+        // Only the header is checked.
+        let mut corrupted = memory.clone();
+        corrupted[HEADER_SIZE / 4] ^= 1;
+        assert!(self::buffer(&mut corrupted, 16).is_valid());
+
+        // Consistent checksums over invalid positions.
+        let mut buffer = self::buffer(&mut memory, 16);
+        buffer.write_header(16, 0);
+        assert!(!buffer.is_valid());
+        buffer.write_header(0, 17);
+        assert!(!buffer.is_valid());
+        buffer.write_header(15, 16);
+        assert!(buffer.is_valid());
+    }
+}
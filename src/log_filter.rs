@@ -0,0 +1,229 @@
+//! env_logger style per module filtering.
+//!
+//! Directives are separated by commas, each being `module=level`, `module` (enabling
+//! everything for it) or `level` (for modules not matching any directive), for example
+//! `rboot::mmu=trace,libtegra=warn,info`.
+//!
+//! Only `core` is used, the module can be built and tested on the host.
+
+use core::str::{self, FromStr};
+
+use log::LevelFilter;
+
+/// Maximum count of module directives in a filter.
+pub const MAX_DIRECTIVES: usize = 16;
+
+/// Maximum length of the directives string.
+pub const MAX_FILTER_LENGTH: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterError {
+    TooLong,
+    TooManyDirectives,
+    InvalidLevel,
+    EmptyModule,
+}
+
+#[derive(Copy, Clone)]
+struct Directive {
+    module_start: usize,
+    module_end: usize,
+    level: LevelFilter,
+}
+
+impl Directive {
+    const EMPTY: Directive = Directive {
+        module_start: 0,
+        module_end: 0,
+        level: LevelFilter::Off,
+    };
+}
+
+pub struct Filter {
+    /// Copy of the parsed string, module names point in it.
+    text: [u8; MAX_FILTER_LENGTH],
+    directives: [Directive; MAX_DIRECTIVES],
+    directive_count: usize,
+    default_level: LevelFilter,
+}
+
+impl Filter {
+    /// Create a filter applying `level` to all modules.
+    pub const fn new(level: LevelFilter) -> Self {
+        Filter {
+            text: [0; MAX_FILTER_LENGTH],
+            directives: [Directive::EMPTY; MAX_DIRECTIVES],
+            directive_count: 0,
+            default_level: level,
+        }
+    }
+
+    pub fn parse(directives: &str) -> Result<Self, FilterError> {
+        if directives.len() > MAX_FILTER_LENGTH {
+            return Err(FilterError::TooLong);
+        }
+
+        // Like env_logger, modules not matching any directive are disabled unless a default
+        // level is given.
+        let mut filter = Filter::new(LevelFilter::Off);
+        filter.text[..directives.len()].copy_from_slice(directives.as_bytes());
+
+        for directive in directives.split(',') {
+            let directive = directive.trim();
+            if directive.is_empty() {
+                continue;
+            }
+
+            let (module, level) = match directive.find('=') {
+                Some(index) => {
+                    let level = LevelFilter::from_str(directive[index + 1..].trim())
+                        .map_err(|_| FilterError::InvalidLevel)?;
+
+                    (directive[..index].trim(), level)
+                }
+                None => match LevelFilter::from_str(directive) {
+                    Ok(level) => {
+                        filter.default_level = level;
+                        continue;
+                    }
+                    Err(_) => (directive, LevelFilter::Trace),
+                },
+            };
+
+            if module.is_empty() {
+                return Err(FilterError::EmptyModule);
+            }
+
+            if filter.directive_count == MAX_DIRECTIVES {
+                return Err(FilterError::TooManyDirectives);
+            }
+
+            // The module is a slice of the string, locate it in the copy.
+            let module_start = module.as_ptr() as usize - directives.as_ptr() as usize;
+
+            filter.directives[filter.directive_count] = Directive {
+                module_start,
+                module_end: module_start + module.len(),
+                level,
+            };
+            filter.directive_count += 1;
+        }
+
+        Ok(filter)
+    }
+
+    fn get_module(&self, directive: &Directive) -> &str {
+        // The text is a copy of a str and modules are delimited on ASCII characters.
+        str::from_utf8(&self.text[directive.module_start..directive.module_end]).unwrap()
+    }
+
+    /// Level enabled for records coming from `module_path`, the most specific directive wins.
+    pub fn get_level(&self, module_path: &str) -> LevelFilter {
+        let mut level = self.default_level;
+        let mut matched_length = 0;
+
+        for directive in self.directives[..self.directive_count].iter() {
+            let module = self.get_module(directive);
+
+            let matches = module_path.starts_with(module)
+                && (module_path.len() == module.len()
+                    || module_path[module.len()..].starts_with("::"));
+
+            if matches && module.len() >= matched_length {
+                level = directive.level;
+                matched_length = module.len();
+            }
+        }
+
+        level
+    }
+
+    /// Highest level enabled by any directive.
+    pub fn get_max_level(&self) -> LevelFilter {
+        self.directives[..self.directive_count]
+            .iter()
+            .map(|directive| directive.level)
+            .fold(self.default_level, core::cmp::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_match() {
+        let filter = Filter::parse("rboot::mmu=trace,rboot=info,rboot::mm=error").unwrap();
+
+        assert_eq!(filter.get_level("rboot"), LevelFilter::Info);
+        assert_eq!(filter.get_level("rboot::monitor"), LevelFilter::Info);
+        assert_eq!(filter.get_level("rboot::mm"), LevelFilter::Error);
+        assert_eq!(filter.get_level("rboot::mmu"), LevelFilter::Trace);
+        assert_eq!(filter.get_level("rboot::mmu::table"), LevelFilter::Trace);
+
+        // Module names only match up to a `::`.
+        assert_eq!(filter.get_level("rboot::mmux"), LevelFilter::Info);
+        assert_eq!(filter.get_level("rboot::mm_utils"), LevelFilter::Info);
+        assert_eq!(filter.get_level("rbootloader"), LevelFilter::Off);
+        assert_eq!(filter.get_level("rboo"), LevelFilter::Off);
+
+        // With the same module, the last directive wins.
+        let filter = Filter::parse("libtegra=warn,libtegra=debug").unwrap();
+        assert_eq!(filter.get_level("libtegra::uart"), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn default_level() {
+        let filter = Filter::new(LevelFilter::Info);
+        assert_eq!(filter.get_level("rboot"), LevelFilter::Info);
+        assert_eq!(filter.get_max_level(), LevelFilter::Info);
+
+        // Modules without a level get everything, others nothing.
+        let filter = Filter::parse("rboot::gdb").unwrap();
+        assert_eq!(filter.get_level("rboot::gdb"), LevelFilter::Trace);
+        assert_eq!(filter.get_level("rboot::mmu"), LevelFilter::Off);
+        assert_eq!(filter.get_max_level(), LevelFilter::Trace);
+
+        let filter = Filter::parse(" rboot::mmu = debug , , WARN ").unwrap();
+        assert_eq!(filter.get_level("rboot::mmu"), LevelFilter::Debug);
+        assert_eq!(filter.get_level("libtegra"), LevelFilter::Warn);
+        assert_eq!(filter.get_max_level(), LevelFilter::Debug);
+
+        let filter = Filter::parse("rboot=off,trace").unwrap();
+        assert_eq!(filter.get_level("rboot::mmu"), LevelFilter::Off);
+        assert_eq!(filter.get_level("libtegra"), LevelFilter::Trace);
+        assert_eq!(filter.get_max_level(), LevelFilter::Trace);
+
+        let filter = Filter::parse("").unwrap();
+        assert_eq!(filter.get_level("rboot"), LevelFilter::Off);
+        assert_eq!(filter.get_max_level(), LevelFilter::Off);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let parse = |directives: &str| Filter::parse(directives).err();
+
+        assert_eq!(parse("rboot=loud"), Some(FilterError::InvalidLevel));
+        assert_eq!(parse("rboot="), Some(FilterError::InvalidLevel));
+        assert_eq!(parse("=info"), Some(FilterError::EmptyModule));
+        assert_eq!(parse(" = info"), Some(FilterError::EmptyModule));
+
+        let directives: Vec<String> = (0..=MAX_DIRECTIVES)
+            .map(|index| format!("m{}=debug", index))
+            .collect();
+        assert_eq!(parse(&directives[..MAX_DIRECTIVES].join(",")), None);
+        assert_eq!(
+            parse(&directives.join(",")),
+            Some(FilterError::TooManyDirectives)
+        );
+
+        // Default levels aren't directives.
+        let mut directives = directives[..MAX_DIRECTIVES].to_vec();
+        directives.push("info".to_string());
+        assert_eq!(parse(&directives.join(",")), None);
+
+        let long = "m".repeat(MAX_FILTER_LENGTH);
+        assert_eq!(parse(&long), None);
+        assert_eq!(parse(&(long + "m")), Some(FilterError::TooLong));
+    }
+}
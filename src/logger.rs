@@ -1,67 +1,363 @@
-use core::fmt::Write;
+use core::fmt::{self, Write};
 
 use libtegra::uart::Uart;
-use log::{Level, Metadata, Record};
-use log::{LevelFilter, SetLoggerError};
-
-pub enum Type {
-    A,
-    B,
-    C,
-    D,
-    E,
-}
-
-struct UARTLogger {
-    level: Level,
-    uart_type: Type,
-}
-
-impl UARTLogger {
-    fn get_uart(&self) -> Uart {
-        match self.uart_type {
-            Type::A => Uart::A,
-            Type::B => Uart::B,
-            Type::C => Uart::C,
-            Type::D => Uart::D,
-            Type::E => Uart::E,
+use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+
+use crate::log_buffer::LogBuffer;
+use crate::log_filter::{Filter, FilterError};
+use crate::tegra210::timer;
+use crate::tegra210::uart as uart_input;
+use crate::tegra210::usb;
+
+/// Maximum count of sinks registered at the same time.
+pub const MAX_SINKS: usize = 4;
+
+/// Source of the timestamp printed before every record.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Timestamp {
+    None,
+    /// The Tegra TIMERUS counter, wraps after ~71 minutes.
+    TimerUs,
+    /// The architectural system counter (CNTPCT_EL0).
+    SystemCounter,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    pub timestamp: Timestamp,
+    pub module_path: bool,
+    /// Colour the level with ANSI escape codes on sinks supporting it.
+    pub color: bool,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Config {
+            timestamp: Timestamp::TimerUs,
+            module_path: true,
+            color: false,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An output of the logger.
+pub trait LogSink: Write {
+    /// Most verbose level written to the sink.
+    fn level(&self) -> LevelFilter;
+
+    /// Whether the sink understands ANSI escape codes.
+    fn supports_color(&self) -> bool {
+        false
+    }
+
+    /// Called after each record, for sinks buffering their output.
+    fn flush(&mut self) {}
+}
+
+/// Sink over any [`Write`] implementation, like a UART or a USB serial port.
+pub struct WriterSink<W> {
+    writer: W,
+    level: LevelFilter,
+    color: bool,
+}
+
+impl<W> WriterSink<W> {
+    pub const fn new(writer: W, level: LevelFilter, color: bool) -> Self {
+        WriterSink {
+            writer,
+            level,
+            color,
         }
     }
 
-    fn set_type(&mut self, uart_type: Type) {
-        self.uart_type = uart_type;
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+}
+
+impl<W: Write> Write for WriterSink<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.writer.write_str(s)
+    }
+}
+
+impl<W: Write> LogSink for WriterSink<W> {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn supports_color(&self) -> bool {
+        self.color
+    }
+}
+
+pub type UartSink = WriterSink<Uart>;
+
+/// Sink over the USB serial port, sending each record in one transfer.
+pub struct UsbSerialSink {
+    port: usb::SerialPort,
+    level: LevelFilter,
+    color: bool,
+}
+
+impl UsbSerialSink {
+    pub const fn new(port: usb::SerialPort, level: LevelFilter, color: bool) -> Self {
+        UsbSerialSink { port, level, color }
+    }
+}
+
+impl Write for UsbSerialSink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.port.write_str(s)
+    }
+}
+
+impl LogSink for UsbSerialSink {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn supports_color(&self) -> bool {
+        self.color
+    }
+
+    fn flush(&mut self) {
+        self.port.flush();
+    }
+}
+
+/// Sink recording logs in a memory [`LogBuffer`].
+pub struct MemorySink {
+    buffer: LogBuffer,
+    level: LevelFilter,
+}
+
+impl MemorySink {
+    pub fn new(buffer: LogBuffer, level: LevelFilter) -> Self {
+        MemorySink { buffer, level }
+    }
+
+    pub fn buffer(&self) -> &LogBuffer {
+        &self.buffer
+    }
+}
+
+impl Write for MemorySink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buffer.write_str(s)
+    }
+}
+
+impl LogSink for MemorySink {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+}
+
+fn get_system_counter_us() -> u64 {
+    let counter: u64;
+    let frequency: u64;
+
+    unsafe {
+        asm!("isb; mrs {counter}, cntpct_el0", counter = out(reg) counter, options(nostack));
+        asm!("mrs {frequency}, cntfrq_el0", frequency = out(reg) frequency, options(nostack));
+    }
+
+    if frequency == 0 {
+        return 0;
+    }
+
+    (counter / frequency) * 1_000_000 + (counter % frequency) * 1_000_000 / frequency
+}
+
+fn get_level_color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[31m",
+        Level::Warn => "\x1b[33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[34m",
+        Level::Trace => "\x1b[35m",
+    }
+}
+
+struct Logger {
+    config: Config,
+}
+
+impl Logger {
+    fn set_config(&mut self, config: Config) {
+        self.config = config;
     }
 
-    fn set_level(&mut self, level: Level) {
-        self.level = level;
+    fn write_record(&self, sink: &mut dyn LogSink, record: &Record) {
+        let timestamp = match self.config.timestamp {
+            Timestamp::None => None,
+            Timestamp::TimerUs => Some(timer::get_microseconds() as u64),
+            Timestamp::SystemCounter => Some(get_system_counter_us()),
+        };
+
+        if let Some(timestamp) = timestamp {
+            write!(
+                sink,
+                "[{:>5}.{:06}] ",
+                timestamp / 1_000_000,
+                timestamp % 1_000_000
+            )
+            .ok();
+        }
+
+        if self.config.color && sink.supports_color() {
+            write!(
+                sink,
+                "{}{:<5}\x1b[0m",
+                get_level_color(record.level()),
+                record.level()
+            )
+            .ok();
+        } else {
+            write!(sink, "{:<5}", record.level()).ok();
+        }
+
+        if self.config.module_path {
+            if let Some(module_path) = record.module_path() {
+                write!(sink, " {}", module_path).ok();
+            }
+        }
+
+        writeln!(sink, " - {}\r", record.args()).ok();
+        sink.flush();
     }
 }
 
-impl log::Log for UARTLogger {
+impl log::Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        self.level >= metadata.level()
+        let module_level = unsafe { FILTER.get_level(metadata.target()) };
+
+        module_level >= metadata.level() && get_max_level() >= metadata.level()
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            let mut uart = self.get_uart();
-            writeln!(&mut uart, "{} - {}\r", record.level(), record.args()).ok();
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Sinks are only ever written from the logger, which isn't reentrant.
+        let sinks = unsafe { &mut SINKS };
+
+        for sink in sinks.iter_mut().flatten() {
+            if sink.level() >= record.level() {
+                self.write_record(&mut **sink, record);
+            }
         }
     }
 
     fn flush(&self) {}
 }
 
-static mut LOGGER: UARTLogger = UARTLogger {
-    uart_type: Type::A,
-    level: Level::Info,
+static mut LOGGER: Logger = Logger {
+    config: Config::new(),
 };
 
-pub fn init(uart_type: Type, level: Level) -> Result<(), SetLoggerError> {
+// Kept out of the logger as sinks don't have to be shareable between threads.
+static mut SINKS: [Option<&'static mut dyn LogSink>; MAX_SINKS] = [None, None, None, None];
+
+static mut FILTER: Filter = Filter::new(LevelFilter::Trace);
+
+static mut PERSISTENT_LOG_SINK: Option<MemorySink> = None;
+
+static mut CONSOLE_UART: Option<(Uart, u32)> = None;
+
+pub fn init(config: Config) -> Result<(), SetLoggerError> {
     unsafe {
-        LOGGER.set_type(uart_type);
-        LOGGER.set_level(level);
+        LOGGER.set_config(config);
 
-        log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Trace))
+        log::set_logger(&LOGGER).map(|()| log::set_max_level(get_max_level()))
     }
 }
+
+fn get_max_level() -> LevelFilter {
+    let sinks_level = unsafe { SINKS.iter() }
+        .flatten()
+        .map(|sink| sink.level())
+        .max()
+        .unwrap_or(LevelFilter::Off);
+
+    core::cmp::min(sinks_level, unsafe { FILTER.get_max_level() })
+}
+
+/// Filter records per module using env_logger style directives (see [`crate::log_filter`]).
+///
+/// # Safety
+///
+/// Must not be called while logging.
+pub unsafe fn set_filter(directives: &str) -> Result<(), FilterError> {
+    FILTER = Filter::parse(directives)?;
+    log::set_max_level(get_max_level());
+
+    Ok(())
+}
+
+/// Start writing logs to `sink` too.
+///
+/// # Safety
+///
+/// Must not be called while logging.
+pub unsafe fn add_sink(sink: &'static mut dyn LogSink) {
+    let slot = SINKS
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("Too many log sinks");
+
+    *slot = Some(sink);
+    log::set_max_level(get_max_level());
+}
+
+/// Start writing logs to the UART of `sink`, remembering it as the console handed to payloads.
+///
+/// # Safety
+///
+/// See [`add_sink`].
+pub unsafe fn add_uart_sink(sink: &'static mut UartSink, baud_rate: u32) {
+    CONSOLE_UART = Some((uart_input::get_handle(sink.writer()), baud_rate));
+
+    add_sink(sink);
+}
+
+/// The UART logs are written to and its baud rate, if any.
+pub fn get_console_uart() -> Option<(&'static Uart, u32)> {
+    unsafe { CONSOLE_UART.as_ref() }.map(|(uart, baud_rate)| (uart, *baud_rate))
+}
+
+/// Also record logs in the persistent buffer at `address`, first replaying the log left there
+/// by the previous session to the other sinks.
+///
+/// # Safety
+///
+/// See [`LogBuffer::new`] and [`add_sink`].
+pub unsafe fn enable_persistent_log(address: u64, size: u64, level: LevelFilter) {
+    let mut persistent_log = LogBuffer::new(address, size);
+
+    if persistent_log.is_valid() && !persistent_log.is_empty() {
+        for sink in SINKS.iter_mut().flatten() {
+            writeln!(sink, "---- Previous session log ----\r").ok();
+            persistent_log.for_each_part(|part| {
+                for byte in part {
+                    let character = if byte.is_ascii() { *byte as char } else { '?' };
+                    sink.write_char(character).ok();
+                }
+            });
+            writeln!(sink, "---- End of previous session log ----\r").ok();
+            sink.flush();
+        }
+    }
+
+    persistent_log.reset();
+
+    add_sink(PERSISTENT_LOG_SINK.get_or_insert(MemorySink::new(persistent_log, level)));
+}
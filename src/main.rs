@@ -15,13 +15,30 @@ extern crate log;
 
 extern crate static_assertions;
 
+pub mod backtrace;
+pub mod chainload;
+pub mod cpio;
 pub mod exception_vectors;
+pub mod fb_console;
+pub mod fs;
+pub mod gdb;
+pub mod linux;
 pub mod logger;
 pub mod mmu;
+#[cfg(feature = "monitor")]
+pub mod monitor;
 pub mod rt;
 pub mod tegra210;
-pub mod utils;
 
+pub use rboot::{
+    block, boot_config, crc, elf, esr, ext4, extlinux, fat, fdt, gpt, image, log_buffer,
+    log_filter, page_table, sdmmc, transport, utils, ymodem,
+};
+
+use core::fmt::Write;
+use core::slice;
+
+use crate::boot_config::{BootConfig, ConfigBlob, ConfigError};
 use crate::tegra210::board;
 
 use libtegra::apb::misc::REGISTERS as APB;
@@ -29,7 +46,7 @@ use libtegra::car::Clock;
 use libtegra::pmc::{powergate_partition, Partition};
 use libtegra::tsec::{FalconError, Tsec};
 use libtegra::uart::{Uart, BAUD_115200};
-use log::Level;
+use log::LevelFilter;
 
 include!(concat!(env!("OUT_DIR"), "/falcon_fw.rs"));
 
@@ -66,10 +83,106 @@ extern "C" {
     static _stack_top: u8;
 }
 
+/// Boot configuration, filled in the built image by set-boot-config.sh.
+#[used]
+#[link_section = ".boot_config"]
+static BOOT_CONFIG: ConfigBlob = ConfigBlob::EMPTY;
+
+/// The boot configuration embedded in the image.
+pub fn get_boot_config() -> Result<BootConfig<'static>, ConfigError> {
+    extern "C" {
+        static __boot_config_start__: u8;
+        static __boot_config_end__: u8;
+    }
+
+    // Found through the linker script, BOOT_CONFIG would be read as its initial value.
+    let blob = unsafe {
+        let start = &__boot_config_start__ as *const u8;
+        let end = &__boot_config_end__ as *const u8;
+
+        slice::from_raw_parts(start, end as usize - start as usize)
+    };
+
+    BootConfig::parse(blob)
+}
+
+static mut UART_SINK: logger::UartSink = logger::UartSink::new(Uart::A, LevelFilter::Trace, false);
+
+static mut USB_SERIAL_SINK: Option<logger::UsbSerialSink> = None;
+
 fn log_init() {
     Uart::A.init(BAUD_115200);
 
-    logger::init(logger::Type::A, Level::Trace).unwrap();
+    logger::init(logger::Config::new()).unwrap();
+
+    unsafe {
+        logger::add_uart_sink(&mut UART_SINK, BAUD_115200);
+        logger::enable_persistent_log(
+            board::p2371_2180::PERSISTENT_LOG_ADDRESS,
+            board::p2371_2180::PERSISTENT_LOG_SIZE,
+            LevelFilter::Trace,
+        );
+    }
+
+    log_filter_init();
+
+    usb_serial_init();
+}
+
+/// Also send logs to a USB serial port, the host can open it at any time.
+fn usb_serial_init() {
+    tegra210::gic::init();
+
+    match unsafe { tegra210::usb::init() } {
+        Ok(port) => unsafe {
+            logger::add_sink(USB_SERIAL_SINK.get_or_insert(logger::UsbSerialSink::new(
+                port,
+                LevelFilter::Info,
+                true,
+            )));
+        },
+        Err(error) => warn!("Cannot bring up the USB serial port: {:?}", error),
+    }
+
+    tegra210::gic::enable_cpu_irqs();
+}
+
+fn log_filter_init() {
+    let config = get_boot_config().unwrap_or_else(|error| {
+        warn!("Invalid boot config: {:?}", error);
+        BootConfig::empty()
+    });
+
+    if let Some(directives) = config.get("log") {
+        if let Err(error) = unsafe { logger::set_filter(directives) } {
+            warn!("Invalid log directives in the boot config: {:?}", error);
+        }
+    }
+
+    // The prompt delays every boot, it is only there when asked for.
+    let prompt_ms = config.get_u32("log_prompt_ms").unwrap_or(0);
+    if prompt_ms == 0 {
+        return;
+    }
+
+    let mut uart = Uart::A;
+    writeln!(&mut uart, "Press any key to set log filter directives\r").ok();
+
+    if !tegra210::uart::wait_for_input(&uart, prompt_ms.saturating_mul(1000)) {
+        return;
+    }
+
+    // Drop the key used to enter the prompt.
+    uart.read_byte();
+
+    write!(&mut uart, "Log filter: ").ok();
+
+    let mut buffer = [0; log_filter::MAX_FILTER_LENGTH];
+    let directives = tegra210::uart::read_line(&uart, &mut buffer);
+
+    if let Err(error) = unsafe { logger::set_filter(directives) } {
+        warn!("Invalid log filter directives: {:?}", error);
+    }
 }
 
 fn bring_up_sors() {
@@ -1,8 +1,16 @@
 #![allow(clippy::identity_op)]
 
+use crate::tegra210::board::p2371_2180 as board;
 use crate::utils;
 use cortex_a::barrier::*;
-use register::register_bitfields;
+
+pub use crate::page_table::{
+    get_mem_attr_name, mem_attr, MapError, MappedRange, MemoryPermission, PageTable, Table,
+    Translation, PAGE_SIZE,
+};
+use crate::page_table::{
+    PAGE_GRANULE, PAGE_GRANULE_16K, PAGE_GRANULE_4K, TABLE_COUNT_FOR_ALL_PAGES, TARGET_BITS,
+};
 
 extern "C" {
     static mut __text_start__: u8;
@@ -19,392 +27,110 @@ extern "C" {
     static _stack_top: u8;
 }
 
-const PAGE_GRANULE_4K: usize = 12;
-const PAGE_GRANULE_16K: usize = 14;
-const PAGE_GRANULE_64K: usize = 16;
-
-const PAGE_GRANULE: usize = PAGE_GRANULE_4K;
-
-const ENTRY_SHIFT: usize = 3;
-const ENTRIES_PER_LEVEL_BITS: usize = PAGE_GRANULE - ENTRY_SHIFT;
-const ENTRIES_PER_LEVEL: usize = 1 << ENTRIES_PER_LEVEL_BITS;
-
-const L3_INDEX_LSB: usize = PAGE_GRANULE;
-const L2_INDEX_LSB: usize = L3_INDEX_LSB + ENTRIES_PER_LEVEL_BITS;
-const L1_INDEX_LSB: usize = L2_INDEX_LSB + ENTRIES_PER_LEVEL_BITS;
-const L0_INDEX_LSB: usize = L1_INDEX_LSB + ENTRIES_PER_LEVEL_BITS;
-
-// 33 bits address space
-const TARGET_BITS: usize = 33;
-
-const LVL2_ENTRY_SIZE: usize = 1 << (TARGET_BITS - L2_INDEX_LSB);
-const LVL3_ENTRY_SIZE: usize = 1 << (TARGET_BITS - L3_INDEX_LSB);
-
-const NUM_LVL1_ENTRIES: usize = 1 << (TARGET_BITS - L1_INDEX_LSB);
-const NUM_LVL2_ENTRIES: usize = LVL2_ENTRY_SIZE / ENTRIES_PER_LEVEL;
-const NUM_LVL3_ENTRIES: usize = LVL3_ENTRY_SIZE / NUM_LVL1_ENTRIES;
-
-#[repr(C)]
-#[repr(align(4096))]
-#[derive(Copy, Clone)]
-struct TableLVL3 {
-    entries: [u64; ENTRIES_PER_LEVEL],
-}
-
-#[repr(C)]
-#[repr(align(4096))]
-#[derive(Copy, Clone)]
-struct TableLVL2 {
-    entries: [u64; ENTRIES_PER_LEVEL],
-    lvl3: [TableLVL3; ENTRIES_PER_LEVEL],
-}
-
-#[repr(C)]
-#[repr(align(4096))]
-#[derive(Copy, Clone)]
-struct TableLVL1 {
-    entries: [u64; NUM_LVL1_ENTRIES],
-    lvl2: [TableLVL2; NUM_LVL1_ENTRIES],
-}
-
-static mut LVL1_TABLE: TableLVL1 = TableLVL1 {
-    entries: [0x0; NUM_LVL1_ENTRIES],
-    lvl2: [TableLVL2 {
-        entries: [0x0; ENTRIES_PER_LEVEL],
-        lvl3: [TableLVL3 {
-            entries: [0x0; ENTRIES_PER_LEVEL],
-        }; ENTRIES_PER_LEVEL],
-    }; NUM_LVL1_ENTRIES],
-};
-
-#[derive(Copy, Clone, PartialEq)]
-pub enum MemoryPermission {
-    Invalid,
-    R,
-    W,
-    X,
-    RW,
-    RX,
-    RWX,
-}
-
-register_bitfields! {u64,
-    STAGE1_NEXTLEVEL_DESCRIPTOR [
-        VALID OFFSET(0) NUMBITS(1) [
-            True = 1
-        ],
-
-        TYPE OFFSET(1) NUMBITS(1) [
-            Table = 1
-        ],
-
-        ADDRESS_4K OFFSET(12) NUMBITS(36) [],
-        ADDRESS_16K OFFSET(14) NUMBITS(34) [],
-        ADDRESS_64K OFFSET(16) NUMBITS(32) [],
-
-        PXN OFFSET(59) NUMBITS(1) [
-            False = 0,
-            True = 1
-        ],
-
-        XN OFFSET(60) NUMBITS(1) [
-            False = 0,
-            True = 1
-        ],
-
-        AP_TABLE OFFSET(61) NUMBITS(2) [
-            NO_EFFECT = 0b00,
-            NO_EL0 = 0b01,
-            NO_WRITE = 0b10,
-            NO_WRITE_EL0_READ = 0b11
-        ],
-
-        NS OFFSET(63) NUMBITS(1) [
-            False = 0,
-            True = 1
-        ]
-    ]
-}
-
-register_bitfields! {u64,
-    STAGE2_BLOCK_DESCRIPTOR [
-        VALID OFFSET(0) NUMBITS(1) [
-            True = 1
-        ],
-
-        TYPE OFFSET(1) NUMBITS(1) [
-            Block = 0
-        ],
+// Memory reserved for the kernel mapping tables (root table included).
+const KERNEL_TABLE_MEMORY: usize = 0x80000;
 
-        MEMORY_ATTR OFFSET(2) NUMBITS(4) [],
-
-        AP OFFSET(6) NUMBITS(2) [
-            RW_CURRENT_EL = 0b00,
-            RW_BOTH_EL = 0b01,
-            RO_CURRENT_EL = 0b10,
-            RO_BOTH_EL = 0b11
-        ],
-
-        SH OFFSET(8) NUMBITS(2) [
-            OuterShareable = 0b10,
-            InnerShareable = 0b11
-        ],
-
-        AF OFFSET(10) NUMBITS(1) [
-            False = 0,
-            True = 1
-        ],
-
-        ADDRESS_4K OFFSET(21) NUMBITS(27) [],
-        ADDRESS_16K OFFSET(25) NUMBITS(23) [],
-        ADDRESS_64K OFFSET(29) NUMBITS(19) [],
-
-        CONTIGUOUS OFFSET(52) NUMBITS(1) [],
-
-        XN OFFSET(54) NUMBITS(1) [
-            False = 0,
-            True = 1
-        ]
-    ]
+// With 64K pages a handful of tables map every page, don't reserve more than that.
+const KERNEL_TABLE_COUNT: usize = min(
+    KERNEL_TABLE_MEMORY / (1 << PAGE_GRANULE),
+    TABLE_COUNT_FOR_ALL_PAGES,
+);
 
+const fn min(a: usize, b: usize) -> usize {
+    if a < b {
+        a
+    } else {
+        b
+    }
 }
 
-register_bitfields! {u64,
-    STAGE2_NEXTLEVEL_DESCRIPTOR [
-        VALID OFFSET(0) NUMBITS(1) [
-            True = 1
-        ],
+static mut KERNEL_TABLES: [Table; KERNEL_TABLE_COUNT] = [Table::EMPTY; KERNEL_TABLE_COUNT];
+static mut KERNEL_PAGE_TABLE: Option<PageTable<'static>> = None;
 
-        TYPE OFFSET(1) NUMBITS(1) [
-            Table = 1
-        ],
+fn kernel_page_table() -> &'static mut PageTable<'static> {
+    unsafe {
+        if KERNEL_PAGE_TABLE.is_none() {
+            let mut page_table = PageTable::new(&mut KERNEL_TABLES);
+            page_table.set_tlb_invalidator(invalidate_tlb_range);
 
-        ADDRESS_4K OFFSET(12) NUMBITS(36) [],
-        ADDRESS_16K OFFSET(14) NUMBITS(34) [],
-        ADDRESS_64K OFFSET(16) NUMBITS(32) []
-    ]
-}
+            KERNEL_PAGE_TABLE = Some(page_table);
+        }
 
-register_bitfields! {u64,
-    STAGE3_TABLE_DESCRIPTOR [
-        VALID OFFSET(0) NUMBITS(1) [
-            True = 1
-        ],
-
-        TYPE OFFSET(1) NUMBITS(1) [
-            Table = 1
-        ],
-
-        MEMORY_ATTR OFFSET(2) NUMBITS(4) [],
-
-        AP OFFSET(6) NUMBITS(2) [
-            RW_CURRENT_EL = 0b00,
-            RW_BOTH_EL = 0b01,
-            RO_CURRENT_EL = 0b10,
-            RO_BOTH_EL = 0b11
-        ],
-
-        SH OFFSET(8) NUMBITS(2) [
-            OuterShareable = 0b10,
-            InnerShareable = 0b11
-        ],
-
-        AF OFFSET(10) NUMBITS(1) [
-            False = 0,
-            True = 1
-        ],
-
-        ADDRESS OFFSET(12) NUMBITS(36) [],
-
-        XN OFFSET(54) NUMBITS(1) [
-            False = 0,
-            True = 1
-        ]
-    ]
+        KERNEL_PAGE_TABLE.as_mut().unwrap()
+    }
 }
 
-pub mod mem_attr {
-    // Device-nGnRnE (strongly ordered)
-    pub const MMIO: u64 = 0;
-
-    // outer: writeback/alloc, inner: writeback/alloc
-    pub const NORMAL: u64 = 1;
-
-    // outer: non-cacheable, inner: non-cacheable
-    pub const NORMAL_UNCACHED: u64 = 2;
+/// Translate `vaddr` with the current kernel mapping.
+pub fn translate(vaddr: u64) -> Option<Translation> {
+    kernel_page_table().translate(vaddr)
 }
 
-fn create_lvl2_block_entry(vaddr: u64, paddr: u64, memory_attribute: u64) {
-    let lvl1_align_size = 1 << L1_INDEX_LSB;
-    let lvl2_align_size = 1 << L2_INDEX_LSB;
-
-    let lvl1_index = (vaddr / lvl1_align_size) as usize % NUM_LVL1_ENTRIES;
-    let lvl2_index = (vaddr / lvl2_align_size) as usize % ENTRIES_PER_LEVEL;
+/// Print the kernel mapping over the logger.
+pub fn dump_page_table() {
+    info!(
+        "Page table ({} tables used):",
+        kernel_page_table().used_tables()
+    );
 
-    let flags = STAGE2_BLOCK_DESCRIPTOR::VALID::True
-        + STAGE2_BLOCK_DESCRIPTOR::TYPE::Block
-        + STAGE2_BLOCK_DESCRIPTOR::MEMORY_ATTR.val(memory_attribute)
-        + STAGE2_BLOCK_DESCRIPTOR::AF::True
-        + STAGE2_BLOCK_DESCRIPTOR::SH::InnerShareable;
-    unsafe {
-        LVL1_TABLE.lvl2[lvl1_index].entries[lvl2_index] =
-            (flags + STAGE2_BLOCK_DESCRIPTOR::ADDRESS_4K.val(paddr >> L2_INDEX_LSB)).value;
-        dsb(SY);
-    };
+    kernel_page_table().for_each_range(|range| {
+        info!(
+            "  {:#011x}-{:#011x} -> {:#011x} {:?} {}",
+            range.vaddr,
+            range.vaddr + range.size,
+            range.paddr,
+            range.permission,
+            get_mem_attr_name(range.memory_attribute)
+        );
+    });
 }
 
-fn create_lvl2_table_entry(vaddr: u64, table_address: u64) {
-    let lvl1_align_size = 1 << L1_INDEX_LSB;
-    let lvl2_align_size = 1 << L2_INDEX_LSB;
-
-    let lvl1_index = (vaddr / lvl1_align_size) as usize % NUM_LVL1_ENTRIES;
-    let lvl2_index = (vaddr / lvl2_align_size) as usize % ENTRIES_PER_LEVEL;
-
-    let flags = STAGE2_NEXTLEVEL_DESCRIPTOR::VALID::True + STAGE2_NEXTLEVEL_DESCRIPTOR::TYPE::Table;
+/// Publish the descriptor updates to the table walker and drop stale TLB entries.
+pub fn sync_page_tables() {
+    // TLB maintenance
+    // TODO: EL2 & EL3
     unsafe {
-        LVL1_TABLE.lvl2[lvl1_index].entries[lvl2_index] = (flags
-            + STAGE2_NEXTLEVEL_DESCRIPTOR::ADDRESS_4K.val(table_address >> L3_INDEX_LSB))
-        .value;
         dsb(SY);
-    };
-}
-
-fn create_lvl3_page(vaddr: u64, paddr: u64, permission: MemoryPermission, memory_attribute: u64) {
-    let lvl1_align_size = 1 << L1_INDEX_LSB;
-    let lvl2_align_size = 1 << L2_INDEX_LSB;
-    let lvl3_align_size = 1 << L3_INDEX_LSB;
-
-    let lvl1_index = (vaddr / lvl1_align_size) as usize % NUM_LVL1_ENTRIES;
-    let lvl2_index = (vaddr / lvl2_align_size) as usize % ENTRIES_PER_LEVEL;
-    let lvl3_index = (vaddr / lvl3_align_size) as usize % ENTRIES_PER_LEVEL;
-
-    // LVL2 entry is missing, add one
-    unsafe {
-        if LVL1_TABLE.lvl2[lvl1_index].entries[lvl2_index] == 0 {
-            create_lvl2_table_entry(
-                vaddr,
-                &LVL1_TABLE.lvl2[lvl1_index].lvl3[lvl2_index].entries[0] as *const _ as u64,
-            );
-        }
-    }
-
-    let mut flags = STAGE3_TABLE_DESCRIPTOR::VALID::True
-        + STAGE3_TABLE_DESCRIPTOR::TYPE::Table
-        + STAGE3_TABLE_DESCRIPTOR::MEMORY_ATTR.val(memory_attribute)
-        + STAGE3_TABLE_DESCRIPTOR::SH::InnerShareable
-        + STAGE3_TABLE_DESCRIPTOR::AF::True;
-
-    flags = match permission {
-        MemoryPermission::R => {
-            flags + STAGE3_TABLE_DESCRIPTOR::AP::RO_CURRENT_EL + STAGE3_TABLE_DESCRIPTOR::XN::True
-        }
-        MemoryPermission::RW | MemoryPermission::W => {
-            flags + STAGE3_TABLE_DESCRIPTOR::AP::RW_CURRENT_EL + STAGE3_TABLE_DESCRIPTOR::XN::True
-        }
-        MemoryPermission::RWX => {
-            flags + STAGE3_TABLE_DESCRIPTOR::AP::RW_CURRENT_EL + STAGE3_TABLE_DESCRIPTOR::XN::False
-        }
-        MemoryPermission::RX | MemoryPermission::X => {
-            flags + STAGE3_TABLE_DESCRIPTOR::AP::RO_CURRENT_EL + STAGE3_TABLE_DESCRIPTOR::XN::False
-        }
-        _ => flags,
+        isb(SY);
     };
 
-    unsafe {
-        let value = if permission == MemoryPermission::Invalid {
-            0
-        } else {
-            (flags + STAGE3_TABLE_DESCRIPTOR::ADDRESS.val(paddr >> PAGE_GRANULE)).value
-        };
-
-        LVL1_TABLE.lvl2[lvl1_index].lvl3[lvl2_index].entries[lvl3_index] = value;
-        dsb(SY);
-    };
+    invalidate_tlb_all()
 }
 
-pub fn map_normal_page(vaddr: u64, paddr: u64, size: u64, permission: MemoryPermission) {
+pub fn map_normal_page(
+    vaddr: u64,
+    paddr: u64,
+    size: u64,
+    permission: MemoryPermission,
+) -> Result<(), MapError> {
     map_page(vaddr, paddr, size, permission, mem_attr::NORMAL)
 }
 
+/// Map a range in the kernel mapping.
+///
+/// On error, the part of the range mapped before running out of tables stays mapped.
 pub fn map_page(
     vaddr: u64,
     paddr: u64,
     size: u64,
     permission: MemoryPermission,
     memory_attribute: u64,
-) {
-    if size == 0 {
-        return;
-    }
-
-    let lvl3_align_size = 1 << L3_INDEX_LSB;
-    let size = utils::align_up(size, lvl3_align_size);
-
-    let mut vaddr = utils::align_down(vaddr, lvl3_align_size);
-    let mut paddr = utils::align_down(paddr, lvl3_align_size);
-    let mut page_count = size / lvl3_align_size;
-
-    while page_count != 0 {
-        create_lvl3_page(vaddr, paddr, permission, memory_attribute);
-        vaddr += lvl3_align_size;
-        paddr += lvl3_align_size;
-        page_count -= 1;
-    }
-
-    // TLB maintenance
-    // TODO: EL2 & EL3
-    unsafe {
-        dsb(SY);
-        isb(SY);
-    };
-
-    invalidate_tlb_all()
-}
-
-pub fn unmap_page(vaddr: u64, size: u64) {
-    if size == 0 {
-        return;
-    }
-
-    let lvl3_align_size = 1 << L3_INDEX_LSB;
-    let size = utils::align_up(size, lvl3_align_size);
+) -> Result<(), MapError> {
+    let result = kernel_page_table().map(vaddr, paddr, size, permission, memory_attribute);
 
-    let mut vaddr = utils::align_down(vaddr, lvl3_align_size);
-    let mut page_count = size / lvl3_align_size;
+    sync_page_tables();
 
-    while page_count != 0 {
-        create_lvl3_page(vaddr, 0, MemoryPermission::Invalid, mem_attr::NORMAL);
-        vaddr += lvl3_align_size;
-        page_count -= 1;
-    }
-
-    // TLB maintenance
-    // TODO: EL2 & EL3
-    unsafe {
-        dsb(SY);
-        isb(SY);
-    };
-
-    invalidate_tlb_all()
+    result
 }
 
-fn map_lvl2_block(vaddr: u64, paddr: u64, size: u64, memory_attribute: u64) {
-    let lvl2_align_size = 1 << L2_INDEX_LSB;
-    let size = utils::align_up(size, lvl2_align_size);
+pub fn unmap_page(vaddr: u64, size: u64) -> Result<(), MapError> {
+    let result = kernel_page_table().unmap(vaddr, size);
 
-    let mut vaddr = utils::align_down(vaddr, lvl2_align_size);
-    let mut paddr = utils::align_down(paddr, lvl2_align_size);
-    let mut page_count = size / lvl2_align_size;
+    sync_page_tables();
 
-    while page_count != 0 {
-        create_lvl2_block_entry(vaddr, paddr, memory_attribute);
-        vaddr += lvl2_align_size;
-        paddr += lvl2_align_size;
-        page_count -= 1;
-    }
+    result
 }
 
-unsafe fn init_executable_mapping() {
+unsafe fn init_executable_mapping() -> Result<(), MapError> {
     let text_start = &__text_start__ as *const _ as u64;
     let text_end = &__text_end__ as *const _ as u64;
     map_normal_page(
@@ -412,11 +138,11 @@ unsafe fn init_executable_mapping() {
         text_start,
         text_end - text_start,
         MemoryPermission::RX,
-    );
+    )?;
 
     let ro_start = &__rodata_start__ as *const _ as u64;
     let ro_end = &__rodata_end__ as *const _ as u64;
-    map_normal_page(ro_start, ro_start, ro_end - ro_start, MemoryPermission::R);
+    map_normal_page(ro_start, ro_start, ro_end - ro_start, MemoryPermission::R)?;
 
     let data_start = &__data_start__ as *const _ as u64;
     let data_end = &__data_end__ as *const _ as u64;
@@ -425,7 +151,7 @@ unsafe fn init_executable_mapping() {
         data_start,
         data_end - data_start,
         MemoryPermission::RW,
-    );
+    )?;
 
     let bss_start = &__bss_start__ as *const _ as u64;
     let bss_end = &__bss_end__ as *const _ as u64;
@@ -434,7 +160,7 @@ unsafe fn init_executable_mapping() {
         bss_start,
         bss_end - bss_start,
         MemoryPermission::RW,
-    );
+    )?;
 
     // Setup our stack
     let stack_start = &_stack_bottom as *const _ as u64;
@@ -444,7 +170,7 @@ unsafe fn init_executable_mapping() {
         stack_start,
         stack_end - stack_start,
         MemoryPermission::RW,
-    );
+    )?;
 
     // Also setup the exception vector
     let vectors_start = &__vectors_start__ as *const _ as u64;
@@ -454,28 +180,14 @@ unsafe fn init_executable_mapping() {
         vectors_start,
         vectors_end - vectors_start,
         MemoryPermission::RX,
-    );
-}
+    )?;
 
-fn init_page_mapping() {
-    let common_flags = STAGE1_NEXTLEVEL_DESCRIPTOR::VALID::True
-        + STAGE1_NEXTLEVEL_DESCRIPTOR::TYPE::Table
-        + STAGE1_NEXTLEVEL_DESCRIPTOR::NS::False;
+    Ok(())
+}
 
-    // Setup LVL1 entries
+fn init_page_mapping() -> Result<(), MapError> {
     unsafe {
-        for (lvl1_index, lvl1_entry) in LVL1_TABLE
-            .entries
-            .iter_mut()
-            .enumerate()
-            .take(NUM_LVL1_ENTRIES)
-        {
-            let address =
-                &LVL1_TABLE.lvl2[lvl1_index].entries[0] as *const _ as u64 >> PAGE_GRANULE;
-            *lvl1_entry =
-                (common_flags + STAGE1_NEXTLEVEL_DESCRIPTOR::ADDRESS_4K.val(address)).value;
-        }
-        init_executable_mapping();
+        init_executable_mapping()?;
     }
 
     // map some MMIOs
@@ -486,37 +198,70 @@ fn init_page_mapping() {
 
     const MMIO_RANGE_3_ADDR: u64 = 0x54400000;
     const MMIO_RANGE_4_ADDR: u64 = 0x54100000;
+    const MMIO_RANGE_5_ADDR: u64 = 0x7D000000;
 
-    map_lvl2_block(
+    map_page(
         MMIO_RANGE_0_ADDR,
         MMIO_RANGE_0_ADDR,
         MMIO_RANGE_SIZE,
+        MemoryPermission::RW,
         mem_attr::MMIO,
-    );
-    map_lvl2_block(
+    )?;
+    map_page(
         MMIO_RANGE_1_ADDR,
         MMIO_RANGE_1_ADDR,
         MMIO_RANGE_SIZE,
+        MemoryPermission::RW,
         mem_attr::MMIO,
-    );
-    map_lvl2_block(
+    )?;
+    map_page(
         MMIO_RANGE_2_ADDR,
         MMIO_RANGE_2_ADDR,
         MMIO_RANGE_SIZE,
+        MemoryPermission::RW,
         mem_attr::MMIO,
-    );
-    map_lvl2_block(
+    )?;
+    map_page(
         MMIO_RANGE_3_ADDR,
         MMIO_RANGE_3_ADDR,
         MMIO_RANGE_SIZE,
+        MemoryPermission::RW,
         mem_attr::MMIO,
-    );
-    map_lvl2_block(
+    )?;
+    map_page(
         MMIO_RANGE_4_ADDR,
         MMIO_RANGE_4_ADDR,
         MMIO_RANGE_SIZE,
+        MemoryPermission::RW,
         mem_attr::MMIO,
-    );
+    )?;
+
+    map_page(
+        MMIO_RANGE_5_ADDR,
+        MMIO_RANGE_5_ADDR,
+        MMIO_RANGE_SIZE,
+        MemoryPermission::RW,
+        mem_attr::MMIO,
+    )?;
+
+    // The persistent log must reach DRAM before any reset, keep it uncached
+    map_page(
+        board::PERSISTENT_LOG_ADDRESS,
+        board::PERSISTENT_LOG_ADDRESS,
+        board::PERSISTENT_LOG_SIZE,
+        MemoryPermission::RW,
+        mem_attr::NORMAL_UNCACHED,
+    )?;
+
+    // Default destination of the loading commands
+    map_normal_page(
+        board::PAYLOAD_ADDRESS,
+        board::PAYLOAD_ADDRESS,
+        board::PAYLOAD_SIZE,
+        MemoryPermission::RW,
+    )?;
+
+    Ok(())
 }
 
 fn get_sctlr() -> u64 {
@@ -592,6 +337,47 @@ pub fn invalidate_tlb_all() {
     }
 }
 
+/// Above this number of TLB entries, a range is invalidated with the whole TLB.
+const MAX_TLB_INVALIDATE_ENTRIES: u64 = 64;
+
+/// Drop the TLB entries of a range whose descriptors were invalidated, on all the cores.
+fn invalidate_tlb_range(vaddr: u64, size: u64, stride: u64) {
+    let current_el = utils::get_current_el();
+
+    unsafe {
+        // The invalid descriptors must be visible to the table walkers first.
+        asm!("dsb ishst", options(nostack));
+
+        if size / stride > MAX_TLB_INVALIDATE_ENTRIES {
+            match current_el {
+                1 => asm!("tlbi vmalle1is", options(nostack)),
+                2 => asm!("tlbi alle2is", options(nostack)),
+                3 => asm!("tlbi alle3is", options(nostack)),
+                _ => unimplemented!(),
+            }
+        } else {
+            let mut address = vaddr;
+
+            while address < vaddr + size {
+                // The operand holds VA[55:12], whatever the granule is.
+                let operand = address >> 12;
+
+                match current_el {
+                    1 => asm!("tlbi vaae1is, {va}", va = in(reg) operand, options(nostack)),
+                    2 => asm!("tlbi vae2is, {va}", va = in(reg) operand, options(nostack)),
+                    3 => asm!("tlbi vae3is, {va}", va = in(reg) operand, options(nostack)),
+                    _ => unimplemented!(),
+                }
+
+                address += stride;
+            }
+        }
+
+        dsb(ISH);
+        isb(SY);
+    }
+}
+
 pub fn invalidate_icache_all() {
     unsafe {
         asm!("ic iallu");
@@ -600,6 +386,121 @@ pub fn invalidate_icache_all() {
     }
 }
 
+/// Size in bytes of the smallest data cache line (CTR_EL0.DminLine).
+fn get_dcache_line_size() -> u64 {
+    let ctr: u64;
+    unsafe {
+        asm!("mrs {ctr}, ctr_el0", ctr = out(reg) ctr, options(nostack));
+    }
+
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// Clean the data cache to the point of coherency for a range.
+pub fn clean_dcache_range(address: u64, size: u64) {
+    let line_size = get_dcache_line_size();
+    let mut line = utils::align_down(address, line_size);
+
+    unsafe {
+        while line < address + size {
+            asm!("dc cvac, {line}", line = in(reg) line, options(nostack));
+            line += line_size;
+        }
+
+        dsb(SY);
+    }
+}
+
+/// Clean and invalidate the data cache to the point of coherency for a range.
+pub fn clean_invalidate_dcache_range(address: u64, size: u64) {
+    let line_size = get_dcache_line_size();
+    let mut line = utils::align_down(address, line_size);
+
+    unsafe {
+        while line < address + size {
+            asm!("dc civac, {line}", line = in(reg) line, options(nostack));
+            line += line_size;
+        }
+
+        dsb(SY);
+    }
+}
+
+/// Turn the MMU and the data cache off and branch to `entry` with x0-x3 set to `arguments`.
+///
+/// # Safety
+///
+/// Everything used by `entry` must have been cleaned to the point of coherency and be at the
+/// same physical address. Memory isn't accessed anymore once the data cache is off.
+pub unsafe fn jump_with_mmu_disabled(entry: u64, arguments: [u64; 4]) -> ! {
+    asm!("msr daifset, #0xf", options(nostack));
+
+    match utils::get_current_el() {
+        1 => asm!(
+            "
+            mrs x4, sctlr_el1
+            bic x4, x4, #(1 << 0)
+            bic x4, x4, #(1 << 2)
+            msr sctlr_el1, x4
+            isb
+            ic iallu
+            dsb sy
+            isb
+            br x5
+            ",
+            in("x0") arguments[0],
+            in("x1") arguments[1],
+            in("x2") arguments[2],
+            in("x3") arguments[3],
+            in("x4") 0,
+            in("x5") entry,
+            options(noreturn),
+        ),
+        2 => asm!(
+            "
+            mrs x4, sctlr_el2
+            bic x4, x4, #(1 << 0)
+            bic x4, x4, #(1 << 2)
+            msr sctlr_el2, x4
+            isb
+            ic iallu
+            dsb sy
+            isb
+            br x5
+            ",
+            in("x0") arguments[0],
+            in("x1") arguments[1],
+            in("x2") arguments[2],
+            in("x3") arguments[3],
+            in("x4") 0,
+            in("x5") entry,
+            options(noreturn),
+        ),
+        3 => asm!(
+            "
+            mrs x4, sctlr_el3
+            bic x4, x4, #(1 << 0)
+            bic x4, x4, #(1 << 2)
+            msr sctlr_el3, x4
+            isb
+            ic iallu
+            dsb sy
+            isb
+            br x5
+            ",
+            in("x0") arguments[0],
+            in("x1") arguments[1],
+            in("x2") arguments[2],
+            in("x3") arguments[3],
+            in("x4") 0,
+            in("x5") entry,
+            options(noreturn),
+        ),
+        // CurrentEL can't even be read at EL0, which rboot never runs at.
+        _ => unreachable!(),
+    }
+}
+
 pub fn enable_icache() {
     invalidate_icache_all();
     set_sctlr(get_sctlr() | (1 << 12));
@@ -674,8 +575,31 @@ unsafe fn set_mair_ttbr_tcr(mair: u64, ttbr: u64, tcr: u64) {
     isb(SY);
 }
 
+/// Check ID_AA64MMFR0_EL1 for support of the translation granule rboot was built for.
+fn is_page_granule_supported() -> bool {
+    let mmfr0: u64;
+    unsafe {
+        asm!("mrs {mmfr0}, id_aa64mmfr0_el1", mmfr0 = out(reg) mmfr0, options(nostack));
+    }
+
+    match PAGE_GRANULE {
+        // TGran4, 0b1111 means not supported
+        PAGE_GRANULE_4K => (mmfr0 >> 28) & 0xf != 0xf,
+        // TGran16, 0b0000 means not supported
+        PAGE_GRANULE_16K => (mmfr0 >> 20) & 0xf != 0x0,
+        // TGran64, 0b1111 means not supported
+        _ => (mmfr0 >> 24) & 0xf != 0xf,
+    }
+}
+
 pub unsafe fn setup() {
-    init_page_mapping();
+    assert!(
+        is_page_granule_supported(),
+        "{}KB translation granule not supported by this CPU",
+        PAGE_SIZE / 1024
+    );
+
+    init_page_mapping().expect("Cannot map rboot and the MMIO ranges");
 
     // Make sure TLB/cache operations are activated.
     enable_maintenance_operations();
@@ -686,11 +610,18 @@ pub unsafe fn setup() {
         | (0xFF << (mem_attr::NORMAL * 8))
         | (0x44 << (mem_attr::NORMAL_UNCACHED * 8));
 
-    let ttbr = &LVL1_TABLE.entries[0] as *const _ as u64;
+    let ttbr = kernel_page_table().root_address();
+
+    let tcr_tg0: u64 = match PAGE_GRANULE {
+        PAGE_GRANULE_4K => 0b00,
+        PAGE_GRANULE_16K => 0b10,
+        _ => 0b01,
+    };
 
     // TODO: register field for this
-    // TCR_PS_40BIT | TCR_TG0_4K | MMU_MEMORY_TCR_OUTER_RGN0(MMU_MEMORY_RGN_WRITE_BACK_ALLOCATE) | MMU_MEMORY_TCR_INNER_RGN0(MMU_MEMORY_RGN_WRITE_BACK_ALLOCATE) | MMU_MEMORY_TCR_T0SZ(MONBITS))
-    let tcr: u64 = (2 << 16) | (0 << 14) | (1 << 10) | (1 << 8) | ((64 - TARGET_BITS as u64) << 0);
+    // TCR_PS_40BIT | TCR_TG0 | MMU_MEMORY_TCR_OUTER_RGN0(MMU_MEMORY_RGN_WRITE_BACK_ALLOCATE) | MMU_MEMORY_TCR_INNER_RGN0(MMU_MEMORY_RGN_WRITE_BACK_ALLOCATE) | MMU_MEMORY_TCR_T0SZ(MONBITS))
+    let tcr: u64 =
+        (2 << 16) | (tcr_tg0 << 14) | (1 << 10) | (1 << 8) | ((64 - TARGET_BITS as u64) << 0);
     set_mair_ttbr_tcr(mair, ttbr, tcr);
 
     // Invalidate icache as we are going to activate it.
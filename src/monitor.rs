@@ -0,0 +1,1623 @@
+//! Interactive shell on the UART, used for bring-up.
+//!
+//! Other modules can add their own commands with [`register_command`].
+
+use core::fmt::Write;
+use core::mem;
+use core::ptr;
+use core::slice;
+use core::str;
+
+use libtegra::uart::Uart;
+
+use crate::block::{self, BlockDevice};
+use crate::chainload;
+use crate::elf::ElfFile;
+use crate::exception_vectors;
+use crate::ext4;
+use crate::extlinux::{self, Config, Entry};
+use crate::fat;
+use crate::fdt::{self, Fdt, FdtEditor};
+use crate::fs::FileSystem;
+use crate::gdb;
+use crate::gpt::{Gpt, Partition};
+use crate::linux::{self, BootParameters, Image, LoadedInitrd};
+use crate::mmu;
+use crate::rt;
+use crate::tegra210::board::p2371_2180 as board;
+use crate::tegra210::sdmmc::{self, Controller};
+use crate::tegra210::uart::{self as uart_input, UartTransport};
+use crate::utils;
+use crate::ymodem;
+
+const PROMPT: &str = "rboot> ";
+
+const MAX_LINE_LENGTH: usize = 128;
+const HISTORY_SIZE: usize = 8;
+const MAX_ARGUMENTS: usize = 8;
+
+/// Maximum count of commands registered in addition to the built-in ones.
+pub const MAX_COMMANDS: usize = 32;
+
+/// Bytes displayed by `md` when no length is given.
+const DEFAULT_DUMP_LENGTH: u64 = 0x40;
+
+/// Space kept for extlinux.conf at the start of the payload region.
+const MAX_EXTLINUX_CONFIG_SIZE: usize = 0x1_0000;
+/// Partition L4T flashes the kernel device tree to, used by extlinux entries without FDT.
+const KERNEL_DTB_PARTITION: &str = "kernel-dtb";
+/// Device tree overlays an extlinux entry can give.
+const MAX_FDT_OVERLAYS: usize = 8;
+/// Asks for the number of the extlinux entry to boot.
+const EXTLINUX_PROMPT: &str = "Entry number (enter for the default): ";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CommandError {
+    /// The arguments don't match the command usage.
+    Usage,
+    InvalidNumber,
+    /// The address isn't mapped with the required permissions or the access faulted.
+    BadAddress(u64),
+    Failed(&'static str),
+}
+
+/// Handle a command, `arguments` not including the command name.
+pub type CommandHandler =
+    fn(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError>;
+
+#[derive(Copy, Clone)]
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub handler: CommandHandler,
+}
+
+static mut COMMANDS: [Option<Command>; MAX_COMMANDS] = [None; MAX_COMMANDS];
+
+/// UART the monitor runs on.
+static mut MONITOR_UART: Option<Uart> = None;
+
+const BUILTIN_COMMANDS: [Command; 22] = [
+    Command {
+        name: "help",
+        usage: "help",
+        help: "List the available commands",
+        handler: command_help,
+    },
+    Command {
+        name: "md",
+        usage: "md <address> [length]",
+        help: "Display memory",
+        handler: command_md,
+    },
+    Command {
+        name: "mw",
+        usage: "mw <address> <value> [count]",
+        help: "Write 32-bit words to memory",
+        handler: command_mw,
+    },
+    Command {
+        name: "mf",
+        usage: "mf <address> <length> <byte>",
+        help: "Fill memory with a byte",
+        handler: command_mf,
+    },
+    Command {
+        name: "peek",
+        usage: "peek <address>",
+        help: "Read a 32-bit register",
+        handler: command_peek,
+    },
+    Command {
+        name: "poke",
+        usage: "poke <address> <value>",
+        help: "Write a 32-bit register",
+        handler: command_poke,
+    },
+    Command {
+        name: "go",
+        usage: "go <address> [size]",
+        help: "Call code at address, cleaning the data cache over size bytes first",
+        handler: command_go,
+    },
+    Command {
+        name: "bootelf",
+        usage: "bootelf <address> <size> [base]",
+        help: "Load an ELF file, at base if position independent, and call its entry point",
+        handler: command_bootelf,
+    },
+    Command {
+        name: "booti",
+        usage: "booti <address> <size> <dtb> [<initrd> <size>]",
+        help: "Boot a Linux arm64 Image with the device tree at dtb",
+        handler: command_booti,
+    },
+    Command {
+        name: "loady",
+        usage: "loady [address size]",
+        help: "Receive a file with YMODEM or XMODEM-1K, in the payload region by default",
+        handler: command_loady,
+    },
+    Command {
+        name: "gpt",
+        usage: "gpt <sd|emmc>",
+        help: "List the partitions of the SD card or the eMMC",
+        handler: command_gpt,
+    },
+    Command {
+        name: "fatls",
+        usage: "fatls <sd|emmc>[:<partition>] [<path>]",
+        help: "List a directory of a FAT filesystem",
+        handler: command_fatls,
+    },
+    Command {
+        name: "fatload",
+        usage: "fatload <sd|emmc>[:<partition>] <path> [<address> <size>]",
+        help: "Read a file of a FAT filesystem, in the payload region by default",
+        handler: command_fatload,
+    },
+    Command {
+        name: "ext4ls",
+        usage: "ext4ls <sd|emmc>[:<partition>] [<path>]",
+        help: "List a directory of an ext4 filesystem",
+        handler: command_ext4ls,
+    },
+    Command {
+        name: "ext4load",
+        usage: "ext4load <sd|emmc>[:<partition>] <path> [<address> <size>]",
+        help: "Read a file of an ext4 filesystem, in the payload region by default",
+        handler: command_ext4load,
+    },
+    Command {
+        name: "extlinux",
+        usage: "extlinux <sd|emmc>[:<partition>] [<label>]",
+        help: "Boot an entry of /boot/extlinux/extlinux.conf, asking which one by default",
+        handler: command_extlinux,
+    },
+    Command {
+        name: "mmcread",
+        usage: "mmcread <sd|emmc> <block> <count> <address>",
+        help: "Read blocks from the SD card or the eMMC to memory",
+        handler: command_mmcread,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "Reset the SoC",
+        handler: command_reboot,
+    },
+    Command {
+        name: "rcm",
+        usage: "rcm",
+        help: "Reboot to RCM",
+        handler: command_rcm,
+    },
+    Command {
+        name: "tsec",
+        usage: "tsec",
+        help: "Run the TSEC firmware",
+        handler: command_tsec,
+    },
+    Command {
+        name: "mmu",
+        usage: "mmu <dump|translate> [address]",
+        help: "Dump the page table or translate an address",
+        handler: command_mmu,
+    },
+    Command {
+        name: "gdb",
+        usage: "gdb",
+        help: "Hand the UART over to GDB until it detaches",
+        handler: command_gdb,
+    },
+];
+
+/// Add a command, returning the previous one with the same name.
+pub fn register_command(command: Command) -> Option<Command> {
+    let commands = unsafe { &mut COMMANDS };
+
+    for slot in commands.iter_mut() {
+        if let Some(registered_command) = slot {
+            if registered_command.name == command.name {
+                return slot.replace(command);
+            }
+        }
+    }
+
+    let slot = commands
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("Too many monitor commands");
+    *slot = Some(command);
+
+    None
+}
+
+fn find_command(name: &str) -> Option<Command> {
+    let registered_commands = unsafe { COMMANDS.iter() }.flatten();
+
+    // Registered commands can override the built-in ones.
+    registered_commands
+        .chain(BUILTIN_COMMANDS.iter())
+        .find(|command| command.name == name)
+        .copied()
+}
+
+/// Parse a decimal or 0x prefixed hexadecimal number.
+pub fn parse_number(value: &str) -> Result<u64, CommandError> {
+    let result = if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+
+    result.map_err(|_| CommandError::InvalidNumber)
+}
+
+/// Check that `size` bytes at `address` are mapped and readable or writable.
+fn check_access(address: u64, size: u64, write: bool) -> Result<(), CommandError> {
+    let end = address
+        .checked_add(size)
+        .ok_or(CommandError::BadAddress(address))?;
+    let mut page = utils::align_down(address, mmu::PAGE_SIZE);
+
+    while page < end {
+        let translation = mmu::translate(page).ok_or(CommandError::BadAddress(page))?;
+
+        let allowed = if write {
+            translation.permission.is_writable()
+        } else {
+            translation.permission.is_readable()
+        };
+
+        if !allowed {
+            return Err(CommandError::BadAddress(page));
+        }
+
+        page += mmu::PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+fn command_help(output: &mut dyn Write, _arguments: &[&str]) -> Result<(), CommandError> {
+    let registered_commands = unsafe { COMMANDS.iter() }.flatten();
+
+    for command in BUILTIN_COMMANDS.iter().chain(registered_commands) {
+        writeln!(output, "{:<32} {}\r", command.usage, command.help).ok();
+    }
+
+    writeln!(output, "{:<32} {}\r", "exit", "Leave the monitor").ok();
+
+    Ok(())
+}
+
+fn command_md(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (address, length) = match arguments {
+        [address] => (parse_number(address)?, DEFAULT_DUMP_LENGTH),
+        [address, length] => (parse_number(address)?, parse_number(length)?),
+        _ => return Err(CommandError::Usage),
+    };
+
+    // Whole lines are displayed.
+    let address = utils::align_down(address, 4);
+    let length = length
+        .checked_add(15)
+        .map(|length| utils::align_down(length, 16))
+        .ok_or(CommandError::BadAddress(address))?;
+    let end = address
+        .checked_add(length)
+        .ok_or(CommandError::BadAddress(address))?;
+    check_access(address, length, false)?;
+
+    let mut line_address = address;
+    while line_address < end {
+        let mut bytes = [0u8; 16];
+
+        write!(output, "{:016x}:", line_address).ok();
+
+        for (index, word_bytes) in bytes.chunks_mut(4).enumerate() {
+            // Mapped devices may still abort, like powered off or clock gated controllers.
+            let word_address = line_address + index as u64 * 4;
+            let value = match exception_vectors::probe_read32(word_address) {
+                Some(value) => value,
+                None => {
+                    writeln!(output, "\r").ok();
+                    return Err(CommandError::BadAddress(word_address));
+                }
+            };
+            word_bytes.copy_from_slice(&value.to_le_bytes());
+
+            write!(output, " {:08x}", value).ok();
+        }
+
+        write!(output, "  ").ok();
+        for byte in bytes.iter() {
+            let character = if (0x20..0x7f).contains(byte) {
+                *byte as char
+            } else {
+                '.'
+            };
+
+            write!(output, "{}", character).ok();
+        }
+        writeln!(output, "\r").ok();
+
+        line_address += 16;
+    }
+
+    Ok(())
+}
+
+fn command_mw(_output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (address, value, count) = match arguments {
+        [address, value] => (parse_number(address)?, parse_number(value)?, 1),
+        [address, value, count] => (
+            parse_number(address)?,
+            parse_number(value)?,
+            parse_number(count)?,
+        ),
+        _ => return Err(CommandError::Usage),
+    };
+
+    let size = count
+        .checked_mul(4)
+        .ok_or(CommandError::BadAddress(address))?;
+    check_access(address, size, true)?;
+
+    for index in 0..count {
+        unsafe { ptr::write_volatile((address + index * 4) as *mut u32, value as u32) };
+    }
+
+    Ok(())
+}
+
+fn command_mf(_output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (address, length, value) = match arguments {
+        [address, length, value] => (
+            parse_number(address)?,
+            parse_number(length)?,
+            parse_number(value)?,
+        ),
+        _ => return Err(CommandError::Usage),
+    };
+
+    if value > 0xff {
+        return Err(CommandError::InvalidNumber);
+    }
+
+    check_access(address, length, true)?;
+
+    unsafe { ptr::write_bytes(address as *mut u8, value as u8, length as usize) };
+
+    Ok(())
+}
+
+fn command_peek(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let address = match arguments {
+        [address] => parse_number(address)?,
+        _ => return Err(CommandError::Usage),
+    };
+
+    let value =
+        exception_vectors::probe_read32(address).ok_or(CommandError::BadAddress(address))?;
+
+    writeln!(output, "{:#010x}: {:#010x}\r", address, value).ok();
+
+    Ok(())
+}
+
+fn command_poke(_output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (address, value) = match arguments {
+        [address, value] => (parse_number(address)?, parse_number(value)?),
+        _ => return Err(CommandError::Usage),
+    };
+
+    check_access(address, 4, true)?;
+
+    unsafe { ptr::write_volatile(address as *mut u32, value as u32) };
+
+    Ok(())
+}
+
+fn command_go(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (address, size) = match arguments {
+        [address] => (parse_number(address)?, 0),
+        [address, size] => (parse_number(address)?, parse_number(size)?),
+        _ => return Err(CommandError::Usage),
+    };
+
+    let translation = mmu::translate(address).ok_or(CommandError::BadAddress(address))?;
+    if !translation.permission.is_executable() {
+        return Err(CommandError::BadAddress(address));
+    }
+
+    mmu::clean_dcache_range(address, size);
+    mmu::invalidate_icache_all();
+
+    let entry: extern "C" fn() -> u64 = unsafe { mem::transmute(address as usize) };
+    let result = entry();
+
+    writeln!(output, "Returned {:#x}\r", result).ok();
+
+    Ok(())
+}
+
+fn command_bootelf(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (address, size, base) = match arguments {
+        [address, size] => (parse_number(address)?, parse_number(size)?, None),
+        [address, size, base] => (
+            parse_number(address)?,
+            parse_number(size)?,
+            Some(parse_number(base)?),
+        ),
+        _ => return Err(CommandError::Usage),
+    };
+
+    check_access(address, size, false)?;
+
+    let data = unsafe { slice::from_raw_parts(address as *const u8, size as usize) };
+    let result = ElfFile::parse(data)
+        .map_err(chainload::Error::from)
+        .and_then(|file| chainload::load(&file, base));
+
+    let image = match result {
+        Ok(image) => image,
+        Err(error) => {
+            writeln!(output, "Cannot load the ELF file: {:?}\r", error).ok();
+            return Err(CommandError::Failed("Invalid ELF file"));
+        }
+    };
+
+    writeln!(
+        output,
+        "Loaded at {:#x}-{:#x}, entry point {:#x}\r",
+        image.start,
+        image.start + image.size,
+        image.entry
+    )
+    .ok();
+
+    let result = unsafe { chainload::start(&image) };
+    writeln!(output, "Returned {:#x}\r", result).ok();
+
+    Ok(())
+}
+
+/// Check that a blob used after loading the kernel isn't overwritten by it.
+fn check_outside_kernel_region(address: u64, size: u64) -> Result<(), CommandError> {
+    let kernel_end = board::KERNEL_ADDRESS + board::KERNEL_SIZE;
+
+    if address < kernel_end && board::KERNEL_ADDRESS < address + size {
+        return Err(CommandError::BadAddress(address));
+    }
+
+    Ok(())
+}
+
+fn command_booti(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (address, size, dtb, initrd) = match arguments {
+        [address, size, dtb] => (
+            parse_number(address)?,
+            parse_number(size)?,
+            parse_number(dtb)?,
+            None,
+        ),
+        [address, size, dtb, initrd, initrd_size] => (
+            parse_number(address)?,
+            parse_number(size)?,
+            parse_number(dtb)?,
+            Some((parse_number(initrd)?, parse_number(initrd_size)?)),
+        ),
+        _ => return Err(CommandError::Usage),
+    };
+
+    check_access(address, size, false)?;
+    if let Some((initrd_address, initrd_size)) = initrd {
+        check_access(initrd_address, initrd_size, false)?;
+        check_outside_kernel_region(initrd_address, initrd_size)?;
+    }
+
+    let source_dtb = linux::get_device_tree(dtb).map_err(|error| {
+        writeln!(output, "Invalid device tree: {:?}\r", error).ok();
+        CommandError::BadAddress(dtb)
+    })?;
+    check_outside_kernel_region(dtb, source_dtb.len() as u64)?;
+
+    let dtb_end = board::DEVICE_TREE_ADDRESS + board::DEVICE_TREE_SIZE;
+    if dtb < dtb_end && board::DEVICE_TREE_ADDRESS < dtb + source_dtb.len() as u64 {
+        return Err(CommandError::BadAddress(dtb));
+    }
+
+    let data = unsafe { slice::from_raw_parts(address as *const u8, size as usize) };
+    let initrd_data = initrd.map(|(initrd_address, initrd_size)| unsafe {
+        slice::from_raw_parts(initrd_address as *const u8, initrd_size as usize)
+    });
+
+    boot_linux(output, data, initrd_data, source_dtb, None)
+}
+
+/// Append the boot configuration to the initramfs when it has an `initrd_config_path`.
+fn append_boot_config(initrd: &mut LoadedInitrd) -> Result<(), linux::Error> {
+    let config = crate::get_boot_config().unwrap_or_default();
+
+    match config.get("initrd_config_path") {
+        Some(path) => {
+            initrd.append_files(&[(path.trim_start_matches('/'), config.text().as_bytes())])
+        }
+        None => Ok(()),
+    }
+}
+
+/// Load and boot the kernel Image in `data`, with the device tree `source_dtb` completed with
+/// the boot parameters.
+///
+/// None of the data may be in the kernel or device tree regions. Only returns on errors.
+fn boot_linux(
+    output: &mut dyn Write,
+    data: &[u8],
+    initrd_data: Option<&[u8]>,
+    source_dtb: &[u8],
+    bootargs: Option<&str>,
+) -> Result<(), CommandError> {
+    let dtb_end = board::DEVICE_TREE_ADDRESS + board::DEVICE_TREE_SIZE;
+
+    mmu::map_normal_page(
+        board::DEVICE_TREE_ADDRESS,
+        board::DEVICE_TREE_ADDRESS,
+        board::DEVICE_TREE_SIZE,
+        mmu::MemoryPermission::RW,
+    )
+    .map_err(|_| CommandError::Failed("Cannot map the device tree region"))?;
+
+    let result = Image::parse(data)
+        .map_err(linux::Error::from)
+        .and_then(|image| linux::load(&image, board::KERNEL_ADDRESS, board::KERNEL_SIZE))
+        .and_then(|kernel| {
+            let initrd = match initrd_data {
+                Some(initrd_data) => {
+                    let mut initrd = linux::load_initrd(
+                        initrd_data,
+                        &kernel,
+                        board::KERNEL_ADDRESS + board::KERNEL_SIZE,
+                        (board::DEVICE_TREE_ADDRESS, dtb_end),
+                    )?;
+                    append_boot_config(&mut initrd)?;
+
+                    Some(initrd)
+                }
+                None => None,
+            };
+
+            let parameters = BootParameters {
+                bootargs,
+                initrd: initrd.map(|initrd| (initrd.start, initrd.end())),
+            };
+            let buffer = unsafe {
+                slice::from_raw_parts_mut(
+                    board::DEVICE_TREE_ADDRESS as *mut u8,
+                    board::DEVICE_TREE_SIZE as usize,
+                )
+            };
+            let device_tree = linux::prepare_device_tree(source_dtb, buffer, &parameters)?;
+
+            unsafe { linux::boot(&kernel, initrd.as_ref(), device_tree.as_ptr() as u64) }
+        });
+
+    if let Err(error) = result {
+        writeln!(output, "Cannot boot Linux: {:?}\r", error).ok();
+    }
+
+    Err(CommandError::Failed("Boot failed"))
+}
+
+// Kept out of the stack as it is quite small.
+static mut PACKET_BUFFER: [u8; ymodem::MAX_PACKET_SIZE] = [0; ymodem::MAX_PACKET_SIZE];
+
+/// Check that `size` bytes at `address` are DRAM used neither by rboot nor by a carveout.
+fn check_destination(address: u64, size: u64) -> Result<(), CommandError> {
+    let end = address
+        .checked_add(size)
+        .ok_or(CommandError::BadAddress(address))?;
+    let overlaps = |start: u64, region_size: u64| address < start + region_size && start < end;
+
+    let in_dram = board::DRAM_BANKS
+        .iter()
+        .any(|(start, bank_size)| address >= *start && end <= start + bank_size);
+
+    let (rboot_start, rboot_end) = linux::get_rboot_range();
+
+    if size == 0
+        || !in_dram
+        || overlaps(rboot_start, rboot_end - rboot_start)
+        || board::CARVEOUTS
+            .iter()
+            .any(|(_, start, region_size)| overlaps(*start, *region_size))
+    {
+        return Err(CommandError::BadAddress(address));
+    }
+
+    Ok(())
+}
+
+fn command_loady(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (address, size) = match arguments {
+        [] => (board::PAYLOAD_ADDRESS, board::PAYLOAD_SIZE),
+        [address, size] => (parse_number(address)?, parse_number(size)?),
+        _ => return Err(CommandError::Usage),
+    };
+
+    let uart = match unsafe { MONITOR_UART.as_ref() } {
+        Some(uart) => uart_input::get_handle(uart),
+        None => return Err(CommandError::Failed("Monitor isn't running on a UART")),
+    };
+
+    check_destination(address, size)?;
+
+    let mapping_start = utils::align_down(address, mmu::PAGE_SIZE);
+    let mapping_end = utils::align_up(address + size, mmu::PAGE_SIZE);
+    mmu::map_normal_page(
+        mapping_start,
+        mapping_start,
+        mapping_end - mapping_start,
+        mmu::MemoryPermission::RWX,
+    )
+    .map_err(|_| CommandError::Failed("Cannot map the destination"))?;
+
+    writeln!(output, "Waiting for the transfer to {:#x}\r", address).ok();
+
+    let buffer = unsafe { &mut PACKET_BUFFER };
+    let destination = unsafe { slice::from_raw_parts_mut(address as *mut u8, size as usize) };
+
+    match ymodem::receive(&mut UartTransport(uart), buffer, destination) {
+        Ok(info) => writeln!(
+            output,
+            "Received \"{}\" ({:#x} bytes) at {:#x}\r",
+            info.name(),
+            info.size,
+            address
+        ),
+        Err(error) => writeln!(output, "Transfer failed: {:?}\r", error),
+    }
+    .ok();
+
+    Ok(())
+}
+
+fn parse_controller(device: &str) -> Result<Controller, CommandError> {
+    match device {
+        "sd" => Ok(Controller::Sdmmc1),
+        "emmc" => Ok(Controller::Sdmmc4),
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn open_card(
+    output: &mut dyn Write,
+    controller: Controller,
+) -> Result<&'static mut dyn BlockDevice, CommandError> {
+    match unsafe { sdmmc::open(controller) } {
+        Ok(device) => Ok(device),
+        Err(error) => {
+            writeln!(output, "Cannot initialize the card: {:?}\r", error).ok();
+            Err(CommandError::Failed("No card"))
+        }
+    }
+}
+
+fn command_gpt(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let controller = match arguments {
+        [device] => parse_controller(device)?,
+        _ => return Err(CommandError::Usage),
+    };
+
+    let device = open_card(output, controller)?;
+    let table = match Gpt::read(device) {
+        Ok(table) => table,
+        Err(error) => {
+            writeln!(output, "No partition table: {:?}\r", error).ok();
+            return Err(CommandError::Failed("No partition table"));
+        }
+    };
+
+    for result in table.partitions(device) {
+        match result {
+            Ok((index, entry)) => {
+                write!(output, "{:>3} ", index).ok();
+                for c in entry.name() {
+                    write!(output, "{}", c).ok();
+                }
+                writeln!(
+                    output,
+                    " {:#x}-{:#x} {}\r",
+                    entry.first_lba, entry.last_lba, entry.type_guid
+                )
+                .ok();
+            }
+            Err(error) => {
+                writeln!(output, "Cannot read the partitions: {:?}\r", error).ok();
+                return Err(CommandError::Failed("Cannot read the partitions"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The controller and partition name of `volume`, written `<sd|emmc>[:<partition>]`.
+fn parse_volume(volume: &str) -> Result<(Controller, Option<&str>), CommandError> {
+    match volume.find(':') {
+        Some(index) => Ok((
+            parse_controller(&volume[..index])?,
+            Some(&volume[index + 1..]),
+        )),
+        None => Ok((parse_controller(volume)?, None)),
+    }
+}
+
+/// The GPT partition called `name` on the card of `controller`.
+fn open_partition(
+    output: &mut dyn Write,
+    controller: Controller,
+    name: &str,
+) -> Result<Partition<'static>, CommandError> {
+    let device = open_card(output, controller)?;
+
+    let entry = match Gpt::read(device).and_then(|table| table.find_by_name(device, name)) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return Err(CommandError::Failed("No such partition")),
+        Err(error) => {
+            writeln!(output, "No partition table: {:?}\r", error).ok();
+            return Err(CommandError::Failed("No partition table"));
+        }
+    };
+
+    Ok(Partition::new(device, &entry))
+}
+
+/// Run `handler` on the card or the GPT partition named in `volume`, written
+/// `<sd|emmc>[:<partition>]`.
+fn with_volume<F>(output: &mut dyn Write, volume: &str, handler: F) -> Result<(), CommandError>
+where
+    F: FnOnce(&mut dyn Write, &mut dyn BlockDevice) -> Result<(), CommandError>,
+{
+    match parse_volume(volume)? {
+        (controller, Some(name)) => {
+            let mut partition = open_partition(output, controller, name)?;
+            handler(output, &mut partition)
+        }
+        (controller, None) => {
+            let device = open_card(output, controller)?;
+            handler(output, device)
+        }
+    }
+}
+
+fn mount_fat<'a>(
+    output: &mut dyn Write,
+    device: &'a mut dyn BlockDevice,
+) -> Result<fat::FileSystem<'a>, CommandError> {
+    fat::FileSystem::new(device).map_err(|error| {
+        writeln!(output, "Cannot mount the filesystem: {:?}\r", error).ok();
+        CommandError::Failed("No FAT filesystem")
+    })
+}
+
+fn command_fatls(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (volume, path) = match arguments {
+        [volume] => (*volume, "/"),
+        [volume, path] => (*volume, *path),
+        _ => return Err(CommandError::Usage),
+    };
+
+    with_volume(output, volume, |output, device| {
+        let mut file_system = mount_fat(output, device)?;
+
+        let dir = file_system.open_dir(path).map_err(|error| {
+            writeln!(output, "Cannot open {}: {:?}\r", path, error).ok();
+            CommandError::Failed("Cannot open the directory")
+        })?;
+
+        for entry in file_system.read_dir(dir) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    writeln!(output, "Cannot read the directory: {:?}\r", error).ok();
+                    return Err(CommandError::Failed("Cannot read the directory"));
+                }
+            };
+
+            write!(output, "{:>10} ", entry.size).ok();
+            for c in entry.name() {
+                write!(output, "{}", c).ok();
+            }
+            writeln!(output, "{}\r", if entry.is_directory() { "/" } else { "" }).ok();
+        }
+
+        Ok(())
+    })
+}
+
+fn command_fatload(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (volume, path, address, size) = match arguments {
+        [volume, path] => (*volume, *path, board::PAYLOAD_ADDRESS, board::PAYLOAD_SIZE),
+        [volume, path, address, size] => {
+            (*volume, *path, parse_number(address)?, parse_number(size)?)
+        }
+        _ => return Err(CommandError::Usage),
+    };
+
+    check_access(address, size, true)?;
+
+    with_volume(output, volume, |output, device| {
+        let mut file_system = mount_fat(output, device)?;
+
+        let buffer = unsafe { slice::from_raw_parts_mut(address as *mut u8, size as usize) };
+        match file_system.read_file(path, buffer) {
+            Ok(size) => {
+                writeln!(output, "Read {:#x} bytes at {:#x}\r", size, address).ok();
+
+                Ok(())
+            }
+            Err(error) => {
+                writeln!(output, "Cannot read {}: {:?}\r", path, error).ok();
+
+                Err(CommandError::Failed("Read failed"))
+            }
+        }
+    })
+}
+
+fn mount_ext4<'a>(
+    output: &mut dyn Write,
+    device: &'a mut dyn BlockDevice,
+) -> Result<ext4::FileSystem<'a>, CommandError> {
+    ext4::FileSystem::new(device).map_err(|error| {
+        writeln!(output, "Cannot mount the filesystem: {:?}\r", error).ok();
+        CommandError::Failed("No ext4 filesystem")
+    })
+}
+
+fn command_ext4ls(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (volume, path) = match arguments {
+        [volume] => (*volume, "/"),
+        [volume, path] => (*volume, *path),
+        _ => return Err(CommandError::Usage),
+    };
+
+    with_volume(output, volume, |output, device| {
+        let mut file_system = mount_ext4(output, device)?;
+
+        let dir = file_system.open_dir(path).map_err(|error| {
+            writeln!(output, "Cannot open {}: {:?}\r", path, error).ok();
+            CommandError::Failed("Cannot open the directory")
+        })?;
+
+        for entry in file_system.read_dir(&dir) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    writeln!(output, "Cannot read the directory: {:?}\r", error).ok();
+                    return Err(CommandError::Failed("Cannot read the directory"));
+                }
+            };
+
+            let suffix = match entry.file_type {
+                ext4::FileType::Directory => "/",
+                ext4::FileType::Symlink => "@",
+                _ => "",
+            };
+
+            writeln!(
+                output,
+                "{:>10} {}{}\r",
+                entry.inode,
+                str::from_utf8(entry.name()).unwrap_or("<invalid UTF-8>"),
+                suffix
+            )
+            .ok();
+        }
+
+        Ok(())
+    })
+}
+
+fn command_ext4load(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (volume, path, address, size) = match arguments {
+        [volume, path] => (*volume, *path, board::PAYLOAD_ADDRESS, board::PAYLOAD_SIZE),
+        [volume, path, address, size] => {
+            (*volume, *path, parse_number(address)?, parse_number(size)?)
+        }
+        _ => return Err(CommandError::Usage),
+    };
+
+    check_access(address, size, true)?;
+
+    with_volume(output, volume, |output, device| {
+        let mut file_system = mount_ext4(output, device)?;
+
+        let buffer = unsafe { slice::from_raw_parts_mut(address as *mut u8, size as usize) };
+        match file_system.read_file(path, buffer) {
+            Ok(size) => {
+                writeln!(output, "Read {:#x} bytes at {:#x}\r", size, address).ok();
+
+                Ok(())
+            }
+            Err(error) => {
+                writeln!(output, "Cannot read {}: {:?}\r", path, error).ok();
+
+                Err(CommandError::Failed("Read failed"))
+            }
+        }
+    })
+}
+
+/// Take the first `size` bytes of `space`, moving it to the next page after them.
+fn take_space<'b>(space: &mut &'b mut [u8], size: usize) -> &'b mut [u8] {
+    let (data, rest) = mem::take(space).split_at_mut(size);
+    let padding = (utils::align_up(size, mmu::PAGE_SIZE as usize) - size).min(rest.len());
+    *space = &mut rest[padding..];
+
+    data
+}
+
+/// Read the file at `path`, relative to the extlinux.conf directory, at the start of `space`
+/// and move `space` to the next page after it.
+fn load_extlinux_file<'b>(
+    output: &mut dyn Write,
+    file_system: &mut FileSystem,
+    path: &str,
+    space: &mut &'b mut [u8],
+) -> Result<&'b [u8], CommandError> {
+    let mut path_buffer = [0; ext4::MAX_PATH_LENGTH];
+    let full_path = extlinux::resolve_path(extlinux::CONFIG_DIRECTORY, path, &mut path_buffer)
+        .ok_or(CommandError::Failed("Path too long"))?;
+
+    let size = file_system
+        .read_file(full_path, &mut space[..])
+        .map_err(|error| {
+            writeln!(output, "Cannot read {}: {:?}\r", full_path, error).ok();
+            CommandError::Failed("Read failed")
+        })?;
+    writeln!(output, "Read {} ({:#x} bytes)\r", full_path, size).ok();
+
+    Ok(take_space(space, size))
+}
+
+/// The device tree of entries without FDT, as cboot does: the one the previous stage passed
+/// or else the one of the kernel-dtb partition on the card of `controller`, copied to `space`.
+///
+/// The card is opened again, devices opened on it before can't be used anymore.
+fn load_fallback_dtb<'b>(
+    output: &mut dyn Write,
+    controller: Controller,
+    space: &mut &'b mut [u8],
+) -> Result<&'b [u8], CommandError> {
+    let address = rt::previous_stage_argument();
+    if let Ok(dtb) = linux::get_device_tree(address) {
+        // Unless the files of the entry were loaded over it.
+        let payload_end = board::PAYLOAD_ADDRESS + board::PAYLOAD_SIZE;
+        let overwritten =
+            address < payload_end && board::PAYLOAD_ADDRESS < address + dtb.len() as u64;
+
+        if !overwritten && dtb.len() <= space.len() {
+            writeln!(output, "Using the device tree of the previous stage\r").ok();
+
+            let data = take_space(space, dtb.len());
+            data.copy_from_slice(dtb);
+            return Ok(data);
+        }
+    }
+
+    let mut partition = open_partition(output, controller, KERNEL_DTB_PARTITION)?;
+    let read_failed = |output: &mut dyn Write, error| {
+        writeln!(
+            output,
+            "Cannot read {}: {:?}\r",
+            KERNEL_DTB_PARTITION, error
+        )
+        .ok();
+        CommandError::Failed("Read failed")
+    };
+
+    let mut header = [0; block::BLOCK_SIZE];
+    partition
+        .read_blocks(0, &mut header)
+        .map_err(|error| read_failed(output, error))?;
+
+    let size = fdt::blob_size(&header)
+        .map_err(|_| CommandError::Failed("No device tree in the kernel-dtb partition"))?;
+    let blocks_size = utils::align_up(size, block::BLOCK_SIZE);
+    if blocks_size > space.len() {
+        return Err(CommandError::Failed("The device tree is too big"));
+    }
+
+    partition
+        .read_blocks(0, &mut space[..blocks_size])
+        .map_err(|error| read_failed(output, error))?;
+    writeln!(
+        output,
+        "Read {} ({:#x} bytes)\r",
+        KERNEL_DTB_PARTITION, size
+    )
+    .ok();
+
+    Ok(take_space(space, size))
+}
+
+/// Apply `overlays` to `dtb` in `space`, `overlays_size` being their total size.
+fn apply_fdt_overlays<'b>(
+    output: &mut dyn Write,
+    dtb: &[u8],
+    overlays: &[Option<(&str, &[u8])>],
+    overlays_size: usize,
+    space: &mut &'b mut [u8],
+) -> Result<&'b [u8], CommandError> {
+    // What an overlay adds to the tree is smaller than the overlay itself.
+    let size = dtb.len() + overlays_size;
+    if size > space.len() {
+        return Err(CommandError::Failed(
+            "No space for the device tree overlays",
+        ));
+    }
+
+    let mut editor = FdtEditor::open_into(dtb, take_space(space, size))
+        .map_err(|_| CommandError::Failed("Invalid device tree"))?;
+
+    for (path, overlay) in overlays.iter().flatten() {
+        editor.apply_overlay(overlay).map_err(|error| {
+            writeln!(output, "Cannot apply {}: {:?}\r", path, error).ok();
+            CommandError::Failed("Invalid device tree overlay")
+        })?;
+        writeln!(output, "Applied {}\r", path).ok();
+    }
+
+    Ok(editor.pack())
+}
+
+/// Load the files of `entry` in `space` and boot it. Only returns on errors.
+///
+/// `file_system` is on the card of `controller`, which the device tree may be read from once
+/// the files are loaded.
+fn boot_extlinux_entry(
+    output: &mut dyn Write,
+    file_system: &mut FileSystem,
+    controller: Controller,
+    entry: &Entry,
+    mut space: &mut [u8],
+) -> Result<(), CommandError> {
+    writeln!(output, "Booting {}\r", entry.title()).ok();
+
+    let linux = entry.linux.ok_or(CommandError::Failed("No kernel"))?;
+
+    let data = load_extlinux_file(output, file_system, linux, &mut space)?;
+    let initrd_data = match entry.initrd {
+        Some(initrd) => Some(load_extlinux_file(output, file_system, initrd, &mut space)?),
+        None => None,
+    };
+
+    // Read before the fallback device tree, which makes `file_system` unusable.
+    let mut overlays = [None; MAX_FDT_OVERLAYS];
+    let mut overlays_size = 0;
+    for (index, path) in entry.fdt_overlays().enumerate() {
+        let slot = overlays
+            .get_mut(index)
+            .ok_or(CommandError::Failed("Too many device tree overlays"))?;
+        let overlay = load_extlinux_file(output, file_system, path, &mut space)?;
+
+        overlays_size += overlay.len();
+        *slot = Some((path, overlay));
+    }
+
+    let mut source_dtb = match entry.fdt {
+        Some(fdt) => load_extlinux_file(output, file_system, fdt, &mut space)?,
+        None => load_fallback_dtb(output, controller, &mut space)?,
+    };
+
+    if overlays[0].is_some() {
+        source_dtb = apply_fdt_overlays(output, source_dtb, &overlays, overlays_size, &mut space)?;
+    }
+
+    let bootargs = match entry.append {
+        Some(append) => {
+            // cboot passes its own command line as ${cbootargs}, the one of the device tree
+            // stands in for it.
+            let cbootargs = Fdt::new(source_dtb)
+                .ok()
+                .and_then(|fdt| {
+                    let chosen = fdt.find_node("/chosen")?;
+                    fdt.get_property(chosen, "bootargs")?.as_str()
+                })
+                .unwrap_or("");
+
+            Some(
+                extlinux::expand_append(append, cbootargs, space)
+                    .ok_or(CommandError::Failed("Command line too long"))?,
+            )
+        }
+        None => None,
+    };
+
+    boot_linux(output, data, initrd_data, source_dtb, bootargs)
+}
+
+/// Show the entries of `config` and let the user pick one on the monitor UART until the
+/// timeout runs out.
+fn choose_extlinux_entry<'a>(
+    output: &mut dyn Write,
+    config: &Config<'a>,
+) -> Result<Entry<'a>, CommandError> {
+    let default = config
+        .default_entry()
+        .ok_or(CommandError::Failed("No boot entry"))?;
+
+    if let Some(title) = config.menu_title {
+        writeln!(output, "{}\r", title).ok();
+    }
+    for (index, entry) in config.entries().enumerate() {
+        let marker = if entry.label == default.label {
+            '*'
+        } else {
+            ' '
+        };
+        writeln!(output, "{} {:>2}: {}\r", marker, index + 1, entry.title()).ok();
+    }
+
+    let (uart, timeout) = match (unsafe { MONITOR_UART.as_ref() }, config.timeout) {
+        (Some(uart), Some(timeout)) => (uart_input::get_handle(uart), timeout),
+        _ => return Ok(default),
+    };
+
+    if timeout > 0 {
+        writeln!(
+            output,
+            "Booting {} in {}.{}s, press a key to stop\r",
+            default.title(),
+            timeout / 10,
+            timeout % 10
+        )
+        .ok();
+    } else {
+        write!(output, "{}", EXTLINUX_PROMPT).ok();
+    }
+
+    // Counts down in tenths of a second until a key is pressed.
+    let mut remaining = if timeout > 0 { Some(timeout) } else { None };
+    // The number typed so far and its count of digits.
+    let mut number = 0usize;
+    let mut digits = 0;
+    loop {
+        if remaining == Some(0) {
+            return Ok(default);
+        }
+
+        if !uart_input::wait_for_input(&uart, 100_000) {
+            remaining = remaining.map(|remaining| remaining - 1);
+            continue;
+        }
+
+        if remaining.take().is_some() {
+            write!(output, "{}", EXTLINUX_PROMPT).ok();
+        }
+
+        match uart.read_byte() {
+            b'\r' | b'\n' if digits == 0 => {
+                writeln!(output, "\r").ok();
+                return Ok(default);
+            }
+            b'\r' | b'\n' => {
+                writeln!(output, "\r").ok();
+                if let Some(entry) = number
+                    .checked_sub(1)
+                    .and_then(|index| config.entries().nth(index))
+                {
+                    return Ok(entry);
+                }
+
+                writeln!(output, "No entry {}\r", number).ok();
+                write!(output, "{}", EXTLINUX_PROMPT).ok();
+                number = 0;
+                digits = 0;
+            }
+            digit @ b'0'..=b'9' => {
+                let value = number
+                    .checked_mul(10)
+                    .and_then(|number| number.checked_add(usize::from(digit - b'0')));
+
+                if let Some(value) = value {
+                    number = value;
+                    digits += 1;
+                    write!(output, "{}", digit as char).ok();
+                }
+            }
+            // Backspace and delete.
+            0x08 | 0x7f if digits > 0 => {
+                number /= 10;
+                digits -= 1;
+                write!(output, "\x08 \x08").ok();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn command_extlinux(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (volume, label) = match arguments {
+        [volume] => (*volume, None),
+        [volume, label] => (*volume, Some(*label)),
+        _ => return Err(CommandError::Usage),
+    };
+
+    check_access(board::PAYLOAD_ADDRESS, board::PAYLOAD_SIZE, true)?;
+    let (controller, _) = parse_volume(volume)?;
+
+    with_volume(output, volume, |output, device| {
+        let mut file_system = FileSystem::new(device).map_err(|error| {
+            writeln!(output, "Cannot mount the filesystem: {:?}\r", error).ok();
+            CommandError::Failed("No filesystem")
+        })?;
+
+        // The configuration stays at the start of the payload region, the files follow it.
+        let payload = unsafe {
+            slice::from_raw_parts_mut(
+                board::PAYLOAD_ADDRESS as *mut u8,
+                board::PAYLOAD_SIZE as usize,
+            )
+        };
+        let (config_buffer, space) = payload.split_at_mut(MAX_EXTLINUX_CONFIG_SIZE);
+
+        let size = file_system
+            .read_file(extlinux::CONFIG_PATH, config_buffer)
+            .map_err(|error| {
+                writeln!(
+                    output,
+                    "Cannot read {}: {:?}\r",
+                    extlinux::CONFIG_PATH,
+                    error
+                )
+                .ok();
+                CommandError::Failed("Read failed")
+            })?;
+        let text = str::from_utf8(&config_buffer[..size])
+            .map_err(|_| CommandError::Failed("The configuration isn't UTF-8"))?;
+
+        let config = Config::parse(text).map_err(|error| {
+            writeln!(output, "{}: {}\r", extlinux::CONFIG_PATH, error).ok();
+            CommandError::Failed("Invalid configuration")
+        })?;
+
+        let entry = match label {
+            Some(label) => config
+                .find(label)
+                .ok_or(CommandError::Failed("No such label"))?,
+            None => choose_extlinux_entry(output, &config)?,
+        };
+
+        boot_extlinux_entry(output, &mut file_system, controller, &entry, space)
+    })
+}
+
+fn command_mmcread(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    let (controller, lba, count, address) = match arguments {
+        [device, lba, count, address] => (
+            parse_controller(device)?,
+            parse_number(lba)?,
+            parse_number(count)?,
+            parse_number(address)?,
+        ),
+        _ => return Err(CommandError::Usage),
+    };
+
+    let size = count
+        .checked_mul(block::BLOCK_SIZE as u64)
+        .ok_or(CommandError::BadAddress(address))?;
+    check_access(address, size, true)?;
+
+    let device = open_card(output, controller)?;
+
+    let buffer = unsafe { slice::from_raw_parts_mut(address as *mut u8, size as usize) };
+    if let Err(error) = device.read_blocks(lba, buffer) {
+        writeln!(output, "Read failed: {:?}\r", error).ok();
+        return Err(CommandError::Failed("Read failed"));
+    }
+
+    Ok(())
+}
+
+fn command_reboot(_output: &mut dyn Write, _arguments: &[&str]) -> Result<(), CommandError> {
+    unsafe { rt::reboot() };
+
+    Ok(())
+}
+
+fn command_rcm(_output: &mut dyn Write, _arguments: &[&str]) -> Result<(), CommandError> {
+    unsafe { rt::reboot_to_rcm() };
+
+    Ok(())
+}
+
+fn command_tsec(output: &mut dyn Write, _arguments: &[&str]) -> Result<(), CommandError> {
+    let mut mailbox0 = 0;
+    let mut mailbox1 = 0;
+
+    crate::TSEC.init();
+    let result = crate::execute_tsec_fw(&*crate::FALCON_FW, 0, &mut mailbox0, &mut mailbox1);
+    crate::TSEC.finalize();
+
+    writeln!(
+        output,
+        "{:?}, mailbox0: {:#x}, mailbox1: {:#x}\r",
+        result, mailbox0, mailbox1
+    )
+    .ok();
+
+    Ok(())
+}
+
+fn command_mmu(output: &mut dyn Write, arguments: &[&str]) -> Result<(), CommandError> {
+    match arguments {
+        ["dump"] => mmu::dump_page_table(),
+        ["translate", address] => {
+            let address = parse_number(address)?;
+            let translation = mmu::translate(address).ok_or(CommandError::BadAddress(address))?;
+
+            writeln!(
+                output,
+                "{:#x} -> {:#x} {:?} {}\r",
+                address,
+                translation.paddr,
+                translation.permission,
+                mmu::get_mem_attr_name(translation.memory_attribute)
+            )
+            .ok();
+        }
+        _ => return Err(CommandError::Usage),
+    }
+
+    Ok(())
+}
+
+/// Connection given to the GDB stub, which keeps it forever.
+static mut GDB_UART: Option<UartTransport> = None;
+
+fn command_gdb(output: &mut dyn Write, _arguments: &[&str]) -> Result<(), CommandError> {
+    let uart = match unsafe { MONITOR_UART.as_ref() } {
+        Some(uart) => uart_input::get_handle(uart),
+        None => return Err(CommandError::Failed("Monitor isn't running on a UART")),
+    };
+
+    writeln!(output, "Waiting for GDB, detach to come back\r").ok();
+
+    unsafe { gdb::init(GDB_UART.get_or_insert(UartTransport(uart))) };
+    gdb::breakpoint();
+
+    Ok(())
+}
+
+struct LineEditor {
+    buffer: [u8; MAX_LINE_LENGTH],
+    length: usize,
+    cursor: usize,
+    history: [[u8; MAX_LINE_LENGTH]; HISTORY_SIZE],
+    history_lengths: [usize; HISTORY_SIZE],
+    /// Count of lines ever added to the history, the last HISTORY_SIZE ones are kept.
+    history_count: usize,
+}
+
+impl LineEditor {
+    const fn new() -> Self {
+        LineEditor {
+            buffer: [0; MAX_LINE_LENGTH],
+            length: 0,
+            cursor: 0,
+            history: [[0; MAX_LINE_LENGTH]; HISTORY_SIZE],
+            history_lengths: [0; HISTORY_SIZE],
+            history_count: 0,
+        }
+    }
+
+    fn redraw(&self, uart: &mut Uart) {
+        write!(uart, "\r{}", PROMPT).ok();
+
+        for byte in &self.buffer[..self.length] {
+            uart.write_byte(*byte);
+        }
+
+        // Clear the end of the line and move back to the cursor.
+        write!(uart, "\x1b[K").ok();
+        if self.cursor < self.length {
+            write!(uart, "\x1b[{}D", self.length - self.cursor).ok();
+        }
+    }
+
+    fn load_history(&mut self, index: usize) {
+        let slot = index % HISTORY_SIZE;
+
+        self.length = self.history_lengths[slot];
+        self.buffer[..self.length].copy_from_slice(&self.history[slot][..self.length]);
+        self.cursor = self.length;
+    }
+
+    fn add_history(&mut self) {
+        if self.length == 0 {
+            return;
+        }
+
+        if self.history_count > 0 {
+            let last = (self.history_count - 1) % HISTORY_SIZE;
+
+            if self.history[last][..self.history_lengths[last]] == self.buffer[..self.length] {
+                return;
+            }
+        }
+
+        let slot = self.history_count % HISTORY_SIZE;
+        self.history[slot][..self.length].copy_from_slice(&self.buffer[..self.length]);
+        self.history_lengths[slot] = self.length;
+        self.history_count += 1;
+    }
+
+    fn insert(&mut self, byte: u8) {
+        if self.length == MAX_LINE_LENGTH {
+            return;
+        }
+
+        self.buffer
+            .copy_within(self.cursor..self.length, self.cursor + 1);
+        self.buffer[self.cursor] = byte;
+        self.length += 1;
+        self.cursor += 1;
+    }
+
+    fn remove(&mut self, position: usize) {
+        self.buffer.copy_within(position + 1..self.length, position);
+        self.length -= 1;
+    }
+
+    fn read_line(&mut self, uart: &mut Uart) -> &str {
+        let oldest_history = self.history_count.saturating_sub(HISTORY_SIZE);
+        let mut history_index = self.history_count;
+
+        self.length = 0;
+        self.cursor = 0;
+        self.redraw(uart);
+
+        loop {
+            match uart.read_byte() {
+                b'\r' | b'\n' => break,
+                // Ctrl-C
+                0x03 => {
+                    write!(uart, "^C").ok();
+                    self.length = 0;
+                    break;
+                }
+                // Ctrl-A
+                0x01 => self.cursor = 0,
+                // Ctrl-E
+                0x05 => self.cursor = self.length,
+                // Ctrl-U
+                0x15 => {
+                    self.length = 0;
+                    self.cursor = 0;
+                }
+                0x08 | 0x7f => {
+                    if self.cursor > 0 {
+                        self.cursor -= 1;
+                        self.remove(self.cursor);
+                    }
+                }
+                0x1b => {
+                    if uart.read_byte() != b'[' {
+                        continue;
+                    }
+
+                    match uart.read_byte() {
+                        b'A' if history_index > oldest_history => {
+                            history_index -= 1;
+                            self.load_history(history_index);
+                        }
+                        b'B' if history_index < self.history_count => {
+                            history_index += 1;
+
+                            if history_index == self.history_count {
+                                self.length = 0;
+                                self.cursor = 0;
+                            } else {
+                                self.load_history(history_index);
+                            }
+                        }
+                        b'C' if self.cursor < self.length => self.cursor += 1,
+                        b'D' if self.cursor > 0 => self.cursor -= 1,
+                        // Delete key
+                        b'3' => {
+                            if uart.read_byte() == b'~' && self.cursor < self.length {
+                                self.remove(self.cursor);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                byte if (0x20..0x7f).contains(&byte) => self.insert(byte),
+                _ => {}
+            }
+
+            self.redraw(uart);
+        }
+
+        writeln!(uart, "\r").ok();
+        self.add_history();
+
+        // Only ASCII characters are accepted.
+        str::from_utf8(&self.buffer[..self.length]).unwrap()
+    }
+}
+
+// Kept out of the stack as it is quite small.
+static mut LINE_EDITOR: LineEditor = LineEditor::new();
+
+fn execute(uart: &mut Uart, line: &str) {
+    let mut arguments = [""; MAX_ARGUMENTS];
+    let mut argument_count = 0;
+
+    for argument in line.split_whitespace() {
+        if argument_count == MAX_ARGUMENTS {
+            writeln!(uart, "Too many arguments\r").ok();
+            return;
+        }
+
+        arguments[argument_count] = argument;
+        argument_count += 1;
+    }
+
+    if argument_count == 0 {
+        return;
+    }
+
+    let command = match find_command(arguments[0]) {
+        Some(command) => command,
+        None => {
+            writeln!(uart, "Unknown command \"{}\", try \"help\"\r", arguments[0]).ok();
+            return;
+        }
+    };
+
+    match (command.handler)(uart, &arguments[1..argument_count]) {
+        Ok(()) => {}
+        Err(CommandError::Usage) => {
+            writeln!(uart, "Usage: {}\r", command.usage).ok();
+        }
+        Err(CommandError::InvalidNumber) => {
+            writeln!(uart, "Invalid number\r").ok();
+        }
+        Err(CommandError::BadAddress(address)) => {
+            writeln!(uart, "Bad address {:#x}\r", address).ok();
+        }
+        Err(CommandError::Failed(reason)) => {
+            writeln!(uart, "{}\r", reason).ok();
+        }
+    }
+}
+
+/// Run the monitor on `uart` until the "exit" command is entered.
+pub fn run(mut uart: Uart) {
+    unsafe { MONITOR_UART = Some(uart_input::get_handle(&uart)) };
+
+    writeln!(
+        &mut uart,
+        "rboot monitor, type \"help\" for the commands list\r"
+    )
+    .ok();
+
+    let line_editor = unsafe { &mut LINE_EDITOR };
+
+    loop {
+        let line = line_editor.read_line(&mut uart);
+
+        if line.trim() == "exit" {
+            break;
+        }
+
+        execute(&mut uart, line);
+    }
+}
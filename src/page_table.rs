@@ -0,0 +1,1410 @@
+//! AArch64 stage 1 translation tables, built in software over a caller-supplied buffer.
+//!
+//! Nothing here touches the MMU: the kernel mapping built on top of it lives in `mmu`.
+
+#![allow(clippy::identity_op)]
+
+use core::ptr;
+
+use crate::utils;
+use register::register_bitfields;
+
+pub const PAGE_GRANULE_4K: usize = 12;
+pub const PAGE_GRANULE_16K: usize = 14;
+pub const PAGE_GRANULE_64K: usize = 16;
+
+#[cfg(all(feature = "granule_16k", feature = "granule_64k"))]
+compile_error!("Only one of granule_16k and granule_64k can be enabled");
+
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+pub const PAGE_GRANULE: usize = PAGE_GRANULE_4K;
+#[cfg(feature = "granule_16k")]
+pub const PAGE_GRANULE: usize = PAGE_GRANULE_16K;
+#[cfg(feature = "granule_64k")]
+pub const PAGE_GRANULE: usize = PAGE_GRANULE_64K;
+
+pub const PAGE_SIZE: u64 = 1 << PAGE_GRANULE;
+
+const ENTRY_SHIFT: usize = 3;
+const ENTRIES_PER_LEVEL_BITS: usize = PAGE_GRANULE - ENTRY_SHIFT;
+const ENTRIES_PER_LEVEL: usize = 1 << ENTRIES_PER_LEVEL_BITS;
+
+const L3_INDEX_LSB: usize = PAGE_GRANULE;
+
+// 33 bits address space
+pub const TARGET_BITS: usize = 33;
+
+// The walk ends with LVL3 pages and starts at the first level able to
+// resolve TARGET_BITS (LVL1 with 4K pages, LVL2 with 16K and 64K pages).
+const START_LEVEL: usize = start_level();
+const LAST_LEVEL: usize = 3;
+
+const NUM_ROOT_ENTRIES: usize = 1 << (TARGET_BITS - level_index_lsb(START_LEVEL));
+
+/// Number of tables (root table included) needed to map every page of the address space.
+pub const TABLE_COUNT_FOR_ALL_PAGES: usize = table_count_for_all_pages();
+
+/// A translation table of any level.
+#[repr(C)]
+#[cfg_attr(
+    not(any(feature = "granule_16k", feature = "granule_64k")),
+    repr(align(4096))
+)]
+#[cfg_attr(feature = "granule_16k", repr(align(16384)))]
+#[cfg_attr(feature = "granule_64k", repr(align(65536)))]
+#[derive(Copy, Clone)]
+pub struct Table {
+    entries: [u64; ENTRIES_PER_LEVEL],
+}
+
+impl Table {
+    pub const EMPTY: Table = Table {
+        entries: [0x0; ENTRIES_PER_LEVEL],
+    };
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MemoryPermission {
+    Invalid,
+    R,
+    W,
+    X,
+    RW,
+    RX,
+    RWX,
+}
+
+impl MemoryPermission {
+    pub fn is_readable(self) -> bool {
+        matches!(
+            self,
+            MemoryPermission::R
+                | MemoryPermission::RW
+                | MemoryPermission::RX
+                | MemoryPermission::RWX
+        )
+    }
+
+    pub fn is_writable(self) -> bool {
+        matches!(
+            self,
+            MemoryPermission::W | MemoryPermission::RW | MemoryPermission::RWX
+        )
+    }
+
+    pub fn is_executable(self) -> bool {
+        matches!(
+            self,
+            MemoryPermission::X | MemoryPermission::RX | MemoryPermission::RWX
+        )
+    }
+}
+
+register_bitfields! {u64,
+    STAGE1_NEXTLEVEL_DESCRIPTOR [
+        VALID OFFSET(0) NUMBITS(1) [
+            True = 1
+        ],
+
+        TYPE OFFSET(1) NUMBITS(1) [
+            Table = 1
+        ],
+
+        ADDRESS_4K OFFSET(12) NUMBITS(36) [],
+        ADDRESS_16K OFFSET(14) NUMBITS(34) [],
+        ADDRESS_64K OFFSET(16) NUMBITS(32) [],
+
+        PXN OFFSET(59) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
+        XN OFFSET(60) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
+        AP_TABLE OFFSET(61) NUMBITS(2) [
+            NO_EFFECT = 0b00,
+            NO_EL0 = 0b01,
+            NO_WRITE = 0b10,
+            NO_WRITE_EL0_READ = 0b11
+        ],
+
+        NS OFFSET(63) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ]
+    ]
+}
+
+register_bitfields! {u64,
+    STAGE2_BLOCK_DESCRIPTOR [
+        VALID OFFSET(0) NUMBITS(1) [
+            True = 1
+        ],
+
+        TYPE OFFSET(1) NUMBITS(1) [
+            Block = 0
+        ],
+
+        MEMORY_ATTR OFFSET(2) NUMBITS(4) [],
+
+        AP OFFSET(6) NUMBITS(2) [
+            RW_CURRENT_EL = 0b00,
+            RW_BOTH_EL = 0b01,
+            RO_CURRENT_EL = 0b10,
+            RO_BOTH_EL = 0b11
+        ],
+
+        SH OFFSET(8) NUMBITS(2) [
+            OuterShareable = 0b10,
+            InnerShareable = 0b11
+        ],
+
+        AF OFFSET(10) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
+        ADDRESS_4K OFFSET(21) NUMBITS(27) [],
+        ADDRESS_16K OFFSET(25) NUMBITS(23) [],
+        ADDRESS_64K OFFSET(29) NUMBITS(19) [],
+
+        CONTIGUOUS OFFSET(52) NUMBITS(1) [],
+
+        XN OFFSET(54) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ]
+    ]
+
+}
+
+register_bitfields! {u64,
+    STAGE2_NEXTLEVEL_DESCRIPTOR [
+        VALID OFFSET(0) NUMBITS(1) [
+            True = 1
+        ],
+
+        TYPE OFFSET(1) NUMBITS(1) [
+            Table = 1
+        ],
+
+        ADDRESS_4K OFFSET(12) NUMBITS(36) [],
+        ADDRESS_16K OFFSET(14) NUMBITS(34) [],
+        ADDRESS_64K OFFSET(16) NUMBITS(32) []
+    ]
+}
+
+register_bitfields! {u64,
+    STAGE3_TABLE_DESCRIPTOR [
+        VALID OFFSET(0) NUMBITS(1) [
+            True = 1
+        ],
+
+        TYPE OFFSET(1) NUMBITS(1) [
+            Table = 1
+        ],
+
+        MEMORY_ATTR OFFSET(2) NUMBITS(4) [],
+
+        AP OFFSET(6) NUMBITS(2) [
+            RW_CURRENT_EL = 0b00,
+            RW_BOTH_EL = 0b01,
+            RO_CURRENT_EL = 0b10,
+            RO_BOTH_EL = 0b11
+        ],
+
+        SH OFFSET(8) NUMBITS(2) [
+            OuterShareable = 0b10,
+            InnerShareable = 0b11
+        ],
+
+        AF OFFSET(10) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
+        ADDRESS_4K OFFSET(12) NUMBITS(36) [],
+        ADDRESS_16K OFFSET(14) NUMBITS(34) [],
+        ADDRESS_64K OFFSET(16) NUMBITS(32) [],
+
+        CONTIGUOUS OFFSET(52) NUMBITS(1) [],
+
+        XN OFFSET(54) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ]
+    ]
+}
+
+pub mod mem_attr {
+    // Device-nGnRnE (strongly ordered)
+    pub const MMIO: u64 = 0;
+
+    // outer: writeback/alloc, inner: writeback/alloc
+    pub const NORMAL: u64 = 1;
+
+    // outer: non-cacheable, inner: non-cacheable
+    pub const NORMAL_UNCACHED: u64 = 2;
+}
+
+pub fn get_mem_attr_name(memory_attribute: u64) -> &'static str {
+    match memory_attribute {
+        mem_attr::MMIO => "MMIO",
+        mem_attr::NORMAL => "NORMAL",
+        mem_attr::NORMAL_UNCACHED => "NORMAL_UNCACHED",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Result of a software table walk.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Translation {
+    pub paddr: u64,
+    pub permission: MemoryPermission,
+    pub memory_attribute: u64,
+}
+
+/// A virtually and physically contiguous range sharing the same attributes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MappedRange {
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub size: u64,
+    pub permission: MemoryPermission,
+    pub memory_attribute: u64,
+}
+
+impl MappedRange {
+    fn try_merge(&mut self, other: &MappedRange) -> bool {
+        if self.vaddr + self.size == other.vaddr
+            && self.paddr + self.size == other.paddr
+            && self.permission == other.permission
+            && self.memory_attribute == other.memory_attribute
+        {
+            self.size += other.size;
+            return true;
+        }
+
+        false
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MapError {
+    /// All the tables of the buffer are in use.
+    OutOfTables,
+    /// The range doesn't fit in the translated address space.
+    OutOfRange,
+}
+
+const fn level_index_lsb(level: usize) -> usize {
+    L3_INDEX_LSB + (LAST_LEVEL - level) * ENTRIES_PER_LEVEL_BITS
+}
+
+const fn start_level() -> usize {
+    let mut level = LAST_LEVEL;
+
+    while level_index_lsb(level) + ENTRIES_PER_LEVEL_BITS < TARGET_BITS {
+        level -= 1;
+    }
+
+    level
+}
+
+fn level_index(vaddr: u64, level: usize) -> usize {
+    let entry_count = if level == START_LEVEL {
+        NUM_ROOT_ENTRIES
+    } else {
+        ENTRIES_PER_LEVEL
+    };
+
+    (vaddr >> level_index_lsb(level)) as usize % entry_count
+}
+
+const fn table_count_for_all_pages() -> usize {
+    let mut count = 1;
+    let mut level_tables = 1;
+    let mut level = START_LEVEL;
+
+    while level < LAST_LEVEL {
+        level_tables *= if level == START_LEVEL {
+            NUM_ROOT_ENTRIES
+        } else {
+            ENTRIES_PER_LEVEL
+        };
+        count += level_tables;
+        level += 1;
+    }
+
+    count
+}
+
+const fn level_entry_size(level: usize) -> u64 {
+    1 << level_index_lsb(level)
+}
+
+const fn is_block_level(level: usize) -> bool {
+    // LVL1 blocks are only available with 4K pages (without 52 bits addressing).
+    level == 2 || (level == 1 && PAGE_GRANULE == PAGE_GRANULE_4K)
+}
+
+/// Number of adjacent entries covered by a contiguous hint at a given level.
+const fn contiguous_entry_count(level: usize) -> usize {
+    match PAGE_GRANULE {
+        PAGE_GRANULE_4K => 16,
+        PAGE_GRANULE_16K if level == LAST_LEVEL => 128,
+        _ => 32,
+    }
+}
+
+// Output address bits of block and page descriptors.
+const OUTPUT_ADDRESS_MASK: u64 = 0x0000_ffff_ffff_ffff & !(PAGE_SIZE - 1);
+
+// Contiguous hint bit of block and page descriptors.
+const CONTIGUOUS_MASK: u64 = 1 << 52;
+
+const VALID_MASK: u64 = 1 << 0;
+
+fn is_valid_descriptor(descriptor: u64) -> bool {
+    STAGE2_NEXTLEVEL_DESCRIPTOR::VALID.is_set(descriptor)
+}
+
+/// Decode a block or page descriptor for the address `vaddr` it covers at `level`.
+fn decode_leaf_descriptor(descriptor: u64, vaddr: u64, level: usize) -> Translation {
+    let offset_mask = level_entry_size(level) - 1;
+
+    // AP[2] selects read-only, XN is at the same place for blocks and pages.
+    let read_only = STAGE2_BLOCK_DESCRIPTOR::AP.read(descriptor) & 0b10 != 0;
+    let execute_never = STAGE2_BLOCK_DESCRIPTOR::XN.is_set(descriptor);
+
+    let permission = match (read_only, execute_never) {
+        (true, true) => MemoryPermission::R,
+        (false, true) => MemoryPermission::RW,
+        (false, false) => MemoryPermission::RWX,
+        (true, false) => MemoryPermission::RX,
+    };
+
+    Translation {
+        paddr: (descriptor & OUTPUT_ADDRESS_MASK & !offset_mask) | (vaddr & offset_mask),
+        permission,
+        memory_attribute: STAGE2_BLOCK_DESCRIPTOR::MEMORY_ATTR.read(descriptor),
+    }
+}
+
+fn is_table_descriptor(descriptor: u64, level: usize) -> bool {
+    level != LAST_LEVEL
+        && STAGE2_NEXTLEVEL_DESCRIPTOR::VALID.is_set(descriptor)
+        && STAGE2_NEXTLEVEL_DESCRIPTOR::TYPE.is_set(descriptor)
+}
+
+fn create_table_descriptor(table_address: u64) -> u64 {
+    let address = match PAGE_GRANULE {
+        PAGE_GRANULE_4K => STAGE2_NEXTLEVEL_DESCRIPTOR::ADDRESS_4K.val(table_address >> 12),
+        PAGE_GRANULE_16K => STAGE2_NEXTLEVEL_DESCRIPTOR::ADDRESS_16K.val(table_address >> 14),
+        _ => STAGE2_NEXTLEVEL_DESCRIPTOR::ADDRESS_64K.val(table_address >> 16),
+    };
+
+    (STAGE2_NEXTLEVEL_DESCRIPTOR::VALID::True + STAGE2_NEXTLEVEL_DESCRIPTOR::TYPE::Table + address)
+        .value
+}
+
+fn get_table_address(descriptor: u64) -> u64 {
+    match PAGE_GRANULE {
+        PAGE_GRANULE_4K => STAGE2_NEXTLEVEL_DESCRIPTOR::ADDRESS_4K.read(descriptor) << 12,
+        PAGE_GRANULE_16K => STAGE2_NEXTLEVEL_DESCRIPTOR::ADDRESS_16K.read(descriptor) << 14,
+        _ => STAGE2_NEXTLEVEL_DESCRIPTOR::ADDRESS_64K.read(descriptor) << 16,
+    }
+}
+
+fn create_block_descriptor(
+    paddr: u64,
+    permission: MemoryPermission,
+    memory_attribute: u64,
+    contiguous: bool,
+) -> u64 {
+    if permission == MemoryPermission::Invalid {
+        return 0;
+    }
+
+    let mut flags = STAGE2_BLOCK_DESCRIPTOR::VALID::True
+        + STAGE2_BLOCK_DESCRIPTOR::TYPE::Block
+        + STAGE2_BLOCK_DESCRIPTOR::MEMORY_ATTR.val(memory_attribute)
+        + STAGE2_BLOCK_DESCRIPTOR::AF::True
+        + STAGE2_BLOCK_DESCRIPTOR::SH::InnerShareable
+        + STAGE2_BLOCK_DESCRIPTOR::CONTIGUOUS.val(contiguous as u64);
+
+    flags = match permission {
+        MemoryPermission::R => {
+            flags + STAGE2_BLOCK_DESCRIPTOR::AP::RO_CURRENT_EL + STAGE2_BLOCK_DESCRIPTOR::XN::True
+        }
+        MemoryPermission::RW | MemoryPermission::W => {
+            flags + STAGE2_BLOCK_DESCRIPTOR::AP::RW_CURRENT_EL + STAGE2_BLOCK_DESCRIPTOR::XN::True
+        }
+        MemoryPermission::RWX => {
+            flags + STAGE2_BLOCK_DESCRIPTOR::AP::RW_CURRENT_EL + STAGE2_BLOCK_DESCRIPTOR::XN::False
+        }
+        MemoryPermission::RX | MemoryPermission::X => {
+            flags + STAGE2_BLOCK_DESCRIPTOR::AP::RO_CURRENT_EL + STAGE2_BLOCK_DESCRIPTOR::XN::False
+        }
+        _ => flags,
+    };
+
+    // Block output addresses are at least LVL2 aligned.
+    let address = match PAGE_GRANULE {
+        PAGE_GRANULE_4K => STAGE2_BLOCK_DESCRIPTOR::ADDRESS_4K.val(paddr >> 21),
+        PAGE_GRANULE_16K => STAGE2_BLOCK_DESCRIPTOR::ADDRESS_16K.val(paddr >> 25),
+        _ => STAGE2_BLOCK_DESCRIPTOR::ADDRESS_64K.val(paddr >> 29),
+    };
+
+    (flags + address).value
+}
+
+fn create_page_descriptor(
+    paddr: u64,
+    permission: MemoryPermission,
+    memory_attribute: u64,
+    contiguous: bool,
+) -> u64 {
+    if permission == MemoryPermission::Invalid {
+        return 0;
+    }
+
+    let mut flags = STAGE3_TABLE_DESCRIPTOR::VALID::True
+        + STAGE3_TABLE_DESCRIPTOR::TYPE::Table
+        + STAGE3_TABLE_DESCRIPTOR::MEMORY_ATTR.val(memory_attribute)
+        + STAGE3_TABLE_DESCRIPTOR::SH::InnerShareable
+        + STAGE3_TABLE_DESCRIPTOR::AF::True
+        + STAGE3_TABLE_DESCRIPTOR::CONTIGUOUS.val(contiguous as u64);
+
+    flags = match permission {
+        MemoryPermission::R => {
+            flags + STAGE3_TABLE_DESCRIPTOR::AP::RO_CURRENT_EL + STAGE3_TABLE_DESCRIPTOR::XN::True
+        }
+        MemoryPermission::RW | MemoryPermission::W => {
+            flags + STAGE3_TABLE_DESCRIPTOR::AP::RW_CURRENT_EL + STAGE3_TABLE_DESCRIPTOR::XN::True
+        }
+        MemoryPermission::RWX => {
+            flags + STAGE3_TABLE_DESCRIPTOR::AP::RW_CURRENT_EL + STAGE3_TABLE_DESCRIPTOR::XN::False
+        }
+        MemoryPermission::RX | MemoryPermission::X => {
+            flags + STAGE3_TABLE_DESCRIPTOR::AP::RO_CURRENT_EL + STAGE3_TABLE_DESCRIPTOR::XN::False
+        }
+        _ => flags,
+    };
+
+    let address = match PAGE_GRANULE {
+        PAGE_GRANULE_4K => STAGE3_TABLE_DESCRIPTOR::ADDRESS_4K.val(paddr >> 12),
+        PAGE_GRANULE_16K => STAGE3_TABLE_DESCRIPTOR::ADDRESS_16K.val(paddr >> 14),
+        _ => STAGE3_TABLE_DESCRIPTOR::ADDRESS_64K.val(paddr >> 16),
+    };
+
+    (flags + address).value
+}
+
+/// Drop the TLB entries of `size` bytes at `vaddr`, cached with at most `stride` bytes each.
+///
+/// Called between the two writes of a break-before-make sequence, the descriptors of
+/// the range are invalid at that point.
+pub type TlbInvalidator = fn(vaddr: u64, size: u64, stride: u64);
+
+/// Translation table builder working on a caller-supplied table buffer.
+///
+/// The first table of the buffer is the root table, the others are handed out
+/// as new levels are needed. Building descriptors has no effect on the MMU
+/// state: once done, call `mmu::sync_page_tables` (or program the root address
+/// in TTBR0) to make them visible.
+///
+/// Valid descriptors are only replaced with break-before-make: the entry is invalidated,
+/// the TLB invalidator set with [`PageTable::set_tlb_invalidator`] is called and then
+/// the new descriptor is written. This keeps tables the MMU is walking consistent.
+pub struct PageTable<'a> {
+    tables: &'a mut [Table],
+    used_tables: usize,
+    invalidate_tlb: Option<TlbInvalidator>,
+}
+
+impl<'a> PageTable<'a> {
+    pub fn new(tables: &'a mut [Table]) -> Self {
+        assert!(!tables.is_empty(), "PageTable needs at least a root table");
+
+        tables[0] = Table::EMPTY;
+
+        PageTable {
+            tables,
+            used_tables: 1,
+            invalidate_tlb: None,
+        }
+    }
+
+    /// Set the TLB maintenance to do when replacing valid descriptors, once the tables are live.
+    pub fn set_tlb_invalidator(&mut self, invalidate_tlb: TlbInvalidator) {
+        self.invalidate_tlb = Some(invalidate_tlb);
+    }
+
+    /// Address to program in TTBR0.
+    pub fn root_address(&self) -> u64 {
+        &self.tables[0] as *const _ as u64
+    }
+
+    /// Number of tables of the buffer in use.
+    pub fn used_tables(&self) -> usize {
+        self.used_tables
+    }
+
+    /// Raw descriptor covering `vaddr` at the given level, if the walk reaches it.
+    pub fn descriptor(&self, vaddr: u64, level: usize) -> Option<u64> {
+        let table_index = self.find_table(vaddr, level)?;
+
+        Some(self.tables[table_index].entries[level_index(vaddr, level)])
+    }
+
+    /// Walk the tables like the MMU would for `vaddr`.
+    pub fn translate(&self, vaddr: u64) -> Option<Translation> {
+        if vaddr >= 1 << TARGET_BITS {
+            return None;
+        }
+
+        let mut table_index = 0;
+
+        for level in START_LEVEL..=LAST_LEVEL {
+            let descriptor = self.tables[table_index].entries[level_index(vaddr, level)];
+
+            if !is_valid_descriptor(descriptor) {
+                return None;
+            }
+
+            if is_table_descriptor(descriptor, level) {
+                table_index = self.table_index(descriptor);
+            } else {
+                return Some(decode_leaf_descriptor(descriptor, vaddr, level));
+            }
+        }
+
+        None
+    }
+
+    /// Call `callback` for every mapped range, merging adjacent entries with the same attributes.
+    pub fn for_each_range<F: FnMut(&MappedRange)>(&self, mut callback: F) {
+        let mut current: Option<MappedRange> = None;
+
+        self.visit_table(0, START_LEVEL, 0, &mut |range: &MappedRange| {
+            if let Some(current_range) = current.as_mut() {
+                if current_range.try_merge(range) {
+                    return;
+                }
+
+                callback(current_range);
+            }
+
+            current = Some(*range);
+        });
+
+        if let Some(current_range) = current {
+            callback(&current_range);
+        }
+    }
+
+    fn visit_table(
+        &self,
+        table_index: usize,
+        level: usize,
+        base_vaddr: u64,
+        callback: &mut dyn FnMut(&MappedRange),
+    ) {
+        let entry_count = if level == START_LEVEL {
+            NUM_ROOT_ENTRIES
+        } else {
+            ENTRIES_PER_LEVEL
+        };
+        let entry_size = level_entry_size(level);
+
+        for entry_index in 0..entry_count {
+            let descriptor = self.tables[table_index].entries[entry_index];
+            let vaddr = base_vaddr + entry_index as u64 * entry_size;
+
+            if !is_valid_descriptor(descriptor) {
+                continue;
+            }
+
+            if is_table_descriptor(descriptor, level) {
+                self.visit_table(self.table_index(descriptor), level + 1, vaddr, callback);
+            } else {
+                let translation = decode_leaf_descriptor(descriptor, vaddr, level);
+
+                callback(&MappedRange {
+                    vaddr,
+                    paddr: translation.paddr,
+                    size: entry_size,
+                    permission: translation.permission,
+                    memory_attribute: translation.memory_attribute,
+                });
+            }
+        }
+    }
+
+    fn table_address(&self, table_index: usize) -> u64 {
+        &self.tables[table_index] as *const _ as u64
+    }
+
+    fn table_index(&self, descriptor: u64) -> usize {
+        let address = get_table_address(descriptor);
+
+        ((address - self.root_address()) / core::mem::size_of::<Table>() as u64) as usize
+    }
+
+    fn allocate_table(&mut self) -> Result<usize, MapError> {
+        if self.used_tables == self.tables.len() {
+            return Err(MapError::OutOfTables);
+        }
+
+        let table_index = self.used_tables;
+        self.tables[table_index] = Table::EMPTY;
+        self.used_tables += 1;
+
+        Ok(table_index)
+    }
+
+    /// Find the table holding the `level` entry of `vaddr` without creating anything.
+    fn find_table(&self, vaddr: u64, level: usize) -> Option<usize> {
+        let mut table_index = 0;
+
+        for current_level in START_LEVEL..level {
+            let descriptor = self.tables[table_index].entries[level_index(vaddr, current_level)];
+
+            if !is_table_descriptor(descriptor, current_level) {
+                return None;
+            }
+
+            table_index = self.table_index(descriptor);
+        }
+
+        Some(table_index)
+    }
+
+    /// Find the table holding the `level` entry of `vaddr`, splitting blocks on the way.
+    ///
+    /// Missing levels are created when `allocate_missing` is set, otherwise
+    /// `None` is returned as nothing is mapped there.
+    fn get_or_create_table(
+        &mut self,
+        vaddr: u64,
+        level: usize,
+        allocate_missing: bool,
+    ) -> Result<Option<usize>, MapError> {
+        let mut table_index = 0;
+
+        for current_level in START_LEVEL..level {
+            let entry_index = level_index(vaddr, current_level);
+            let descriptor = self.tables[table_index].entries[entry_index];
+
+            table_index = if is_table_descriptor(descriptor, current_level) {
+                self.table_index(descriptor)
+            } else if is_valid_descriptor(descriptor) {
+                let new_table_index = self.allocate_table()?;
+                self.split_block(new_table_index, descriptor, current_level);
+
+                self.clear_contiguous_hint(table_index, entry_index, vaddr, current_level);
+                self.replace_entry(
+                    table_index,
+                    entry_index,
+                    vaddr,
+                    current_level,
+                    create_table_descriptor(self.table_address(new_table_index)),
+                );
+
+                new_table_index
+            } else if allocate_missing {
+                let new_table_index = self.allocate_table()?;
+                self.tables[table_index].entries[entry_index] =
+                    create_table_descriptor(self.table_address(new_table_index));
+
+                new_table_index
+            } else {
+                return Ok(None);
+            };
+        }
+
+        Ok(Some(table_index))
+    }
+
+    /// Fill a table of the next level with the equivalent of a block descriptor.
+    fn split_block(&mut self, table_index: usize, block: u64, level: usize) {
+        let child_level = level + 1;
+        let child_size = level_entry_size(child_level);
+
+        let address = block & OUTPUT_ADDRESS_MASK;
+        let mut attributes = block & !OUTPUT_ADDRESS_MASK & !CONTIGUOUS_MASK;
+
+        if child_level == LAST_LEVEL {
+            attributes |= STAGE3_TABLE_DESCRIPTOR::TYPE::Table.value;
+        }
+
+        for (index, entry) in self.tables[table_index].entries.iter_mut().enumerate() {
+            *entry = attributes | (address + index as u64 * child_size);
+        }
+    }
+
+    fn invalidate_tlb(&self, vaddr: u64, size: u64, stride: u64) {
+        if let Some(invalidate_tlb) = self.invalidate_tlb {
+            invalidate_tlb(vaddr, size, stride);
+        }
+    }
+
+    /// Write the descriptor of the `level` entry covering `vaddr`, with break-before-make.
+    fn replace_entry(
+        &mut self,
+        table_index: usize,
+        entry_index: usize,
+        vaddr: u64,
+        level: usize,
+        descriptor: u64,
+    ) {
+        let entry = &mut self.tables[table_index].entries[entry_index] as *mut u64;
+        let old_descriptor = unsafe { ptr::read_volatile(entry) };
+
+        if is_valid_descriptor(old_descriptor) {
+            let entry_size = level_entry_size(level);
+
+            // Anything from a page to the entry size may be cached for a table.
+            let stride = if is_table_descriptor(old_descriptor, level) {
+                PAGE_SIZE
+            } else {
+                entry_size
+            };
+
+            unsafe { ptr::write_volatile(entry, 0) };
+            self.invalidate_tlb(utils::align_down(vaddr, entry_size), entry_size, stride);
+        }
+
+        unsafe { ptr::write_volatile(entry, descriptor) };
+    }
+
+    /// Drop the contiguous hint of the entry group holding an entry about to change.
+    ///
+    /// The TLB may hold a single entry for the group, so the whole group is broken.
+    fn clear_contiguous_hint(
+        &mut self,
+        table_index: usize,
+        entry_index: usize,
+        vaddr: u64,
+        level: usize,
+    ) {
+        if self.tables[table_index].entries[entry_index] & CONTIGUOUS_MASK == 0 {
+            return;
+        }
+
+        let group_size = contiguous_entry_count(level);
+        let group_start = entry_index - entry_index % group_size;
+        let group_byte_size = level_entry_size(level) * group_size as u64;
+
+        let group = &mut self.tables[table_index].entries[group_start..group_start + group_size];
+        for entry in group
+            .iter_mut()
+            .filter(|entry| **entry & CONTIGUOUS_MASK != 0)
+        {
+            unsafe { ptr::write_volatile(entry, *entry & !VALID_MASK) };
+        }
+
+        self.invalidate_tlb(
+            utils::align_down(vaddr, group_byte_size),
+            group_byte_size,
+            level_entry_size(level),
+        );
+
+        // Invalid descriptors keep their other bits, restore them without the hint.
+        let group = &mut self.tables[table_index].entries[group_start..group_start + group_size];
+        for entry in group
+            .iter_mut()
+            .filter(|entry| **entry & CONTIGUOUS_MASK != 0)
+        {
+            unsafe { ptr::write_volatile(entry, (*entry | VALID_MASK) & !CONTIGUOUS_MASK) };
+        }
+    }
+
+    fn set_entry(&mut self, vaddr: u64, level: usize, descriptor: u64) -> Result<(), MapError> {
+        let table_index = match self.get_or_create_table(vaddr, level, descriptor != 0)? {
+            Some(table_index) => table_index,
+            None => return Ok(()),
+        };
+
+        let entry_index = level_index(vaddr, level);
+
+        self.clear_contiguous_hint(table_index, entry_index, vaddr, level);
+        self.replace_entry(table_index, entry_index, vaddr, level, descriptor);
+
+        Ok(())
+    }
+
+    /// Pick the biggest block level both addresses are aligned on.
+    fn select_level(vaddr: u64, paddr: u64, size: u64) -> usize {
+        for level in START_LEVEL..LAST_LEVEL {
+            let entry_size = level_entry_size(level);
+
+            if is_block_level(level) && (vaddr | paddr) % entry_size == 0 && size >= entry_size {
+                return level;
+            }
+        }
+
+        LAST_LEVEL
+    }
+
+    fn map_range(
+        &mut self,
+        mut vaddr: u64,
+        mut paddr: u64,
+        mut size: u64,
+        permission: MemoryPermission,
+        memory_attribute: u64,
+    ) -> Result<(), MapError> {
+        while size != 0 {
+            let level = Self::select_level(vaddr, paddr, size);
+            let entry_size = level_entry_size(level);
+
+            let group_size = contiguous_entry_count(level);
+            let group_byte_size = entry_size * group_size as u64;
+            let contiguous = permission != MemoryPermission::Invalid
+                && (vaddr | paddr) % group_byte_size == 0
+                && size >= group_byte_size;
+
+            let entry_count = if contiguous { group_size } else { 1 };
+
+            for _ in 0..entry_count {
+                let descriptor = if level == LAST_LEVEL {
+                    create_page_descriptor(paddr, permission, memory_attribute, contiguous)
+                } else {
+                    create_block_descriptor(paddr, permission, memory_attribute, contiguous)
+                };
+
+                self.set_entry(vaddr, level, descriptor)?;
+
+                vaddr += entry_size;
+                paddr += entry_size;
+                size -= entry_size;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_range(vaddr: u64, size: u64) -> Result<(), MapError> {
+        match vaddr.checked_add(size) {
+            Some(end) if end <= 1 << TARGET_BITS => Ok(()),
+            _ => Err(MapError::OutOfRange),
+        }
+    }
+
+    /// Map a range, using the biggest blocks and contiguous hints the alignment allows.
+    ///
+    /// Blocks partially covered by the range are split into tables. Tables
+    /// replaced by a block are not reclaimed.
+    pub fn map(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        size: u64,
+        permission: MemoryPermission,
+        memory_attribute: u64,
+    ) -> Result<(), MapError> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let size = utils::align_up(size, PAGE_SIZE);
+
+        let vaddr = utils::align_down(vaddr, PAGE_SIZE);
+        let paddr = utils::align_down(paddr, PAGE_SIZE);
+
+        Self::check_range(vaddr, size)?;
+
+        self.map_range(vaddr, paddr, size, permission, memory_attribute)
+    }
+
+    pub fn unmap(&mut self, vaddr: u64, size: u64) -> Result<(), MapError> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let size = utils::align_up(size, PAGE_SIZE);
+        let vaddr = utils::align_down(vaddr, PAGE_SIZE);
+
+        Self::check_range(vaddr, size)?;
+
+        self.map_range(
+            vaddr,
+            vaddr,
+            size,
+            MemoryPermission::Invalid,
+            mem_attr::NORMAL,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const BLOCK_LEVEL: usize = 2;
+    const BLOCK_SIZE: u64 = level_entry_size(BLOCK_LEVEL);
+    const DRAM: u64 = 0x8000_0000;
+
+    thread_local! {
+        static INVALIDATIONS: RefCell<Vec<(u64, u64, u64)>> = RefCell::new(Vec::new());
+    }
+
+    fn record_invalidation(vaddr: u64, size: u64, stride: u64) {
+        INVALIDATIONS.with(|invalidations| invalidations.borrow_mut().push((vaddr, size, stride)));
+    }
+
+    fn take_invalidations() -> Vec<(u64, u64, u64)> {
+        INVALIDATIONS.with(|invalidations| invalidations.borrow_mut().split_off(0))
+    }
+
+    fn new_tables(count: usize) -> Vec<Table> {
+        vec![Table::EMPTY; count]
+    }
+
+    fn translation(paddr: u64, permission: MemoryPermission) -> Translation {
+        Translation {
+            paddr,
+            permission,
+            memory_attribute: mem_attr::NORMAL,
+        }
+    }
+
+    #[test]
+    fn page_descriptor_encoding() {
+        let paddr = DRAM + 5 * PAGE_SIZE;
+
+        let descriptor = create_page_descriptor(
+            paddr,
+            MemoryPermission::RW,
+            mem_attr::NORMAL_UNCACHED,
+            false,
+        );
+        assert_eq!(descriptor & 0b11, 0b11);
+        assert_eq!((descriptor >> 2) & 0b111, mem_attr::NORMAL_UNCACHED);
+        assert_eq!((descriptor >> 6) & 0b11, 0b00);
+        assert_eq!((descriptor >> 8) & 0b11, 0b11);
+        assert_ne!(descriptor & (1 << 10), 0);
+        assert_ne!(descriptor & (1 << 54), 0);
+        assert_eq!(descriptor & CONTIGUOUS_MASK, 0);
+        assert_eq!(descriptor & OUTPUT_ADDRESS_MASK, paddr);
+
+        let descriptor = create_page_descriptor(paddr, MemoryPermission::RX, mem_attr::MMIO, true);
+        assert_eq!((descriptor >> 2) & 0b111, mem_attr::MMIO);
+        assert_eq!((descriptor >> 6) & 0b11, 0b10);
+        assert_eq!(descriptor & (1 << 54), 0);
+        assert_ne!(descriptor & CONTIGUOUS_MASK, 0);
+
+        let descriptor =
+            create_page_descriptor(paddr, MemoryPermission::R, mem_attr::NORMAL, false);
+        assert_eq!((descriptor >> 6) & 0b11, 0b10);
+        assert_ne!(descriptor & (1 << 54), 0);
+
+        assert_eq!(
+            create_page_descriptor(paddr, MemoryPermission::Invalid, mem_attr::NORMAL, false),
+            0
+        );
+    }
+
+    #[test]
+    fn block_and_table_descriptor_encoding() {
+        let block = create_block_descriptor(DRAM, MemoryPermission::RWX, mem_attr::NORMAL, false);
+        assert_eq!(block & 0b11, 0b01);
+        assert_eq!(block & OUTPUT_ADDRESS_MASK, DRAM);
+        assert_eq!((block >> 6) & 0b11, 0b00);
+        assert_eq!(block & (1 << 54), 0);
+        assert!(!is_table_descriptor(block, BLOCK_LEVEL));
+
+        let table_address = 0x1234 * PAGE_SIZE;
+        let table = create_table_descriptor(table_address);
+        assert_eq!(table, table_address | 0b11);
+        assert_eq!(get_table_address(table), table_address);
+        assert!(is_table_descriptor(table, BLOCK_LEVEL));
+        // The same bits are a page at the last level.
+        assert!(!is_table_descriptor(table, LAST_LEVEL));
+    }
+
+    #[test]
+    fn leaf_descriptors_decode_to_what_was_encoded() {
+        let permissions = [
+            (MemoryPermission::R, MemoryPermission::R),
+            (MemoryPermission::W, MemoryPermission::RW),
+            (MemoryPermission::X, MemoryPermission::RX),
+            (MemoryPermission::RW, MemoryPermission::RW),
+            (MemoryPermission::RX, MemoryPermission::RX),
+            (MemoryPermission::RWX, MemoryPermission::RWX),
+        ];
+        let offset = PAGE_SIZE - 8;
+
+        for &(permission, decoded) in permissions.iter() {
+            let page = create_page_descriptor(DRAM, permission, mem_attr::NORMAL, false);
+            assert_eq!(
+                decode_leaf_descriptor(page, DRAM + offset, LAST_LEVEL),
+                translation(DRAM + offset, decoded)
+            );
+
+            let block = create_block_descriptor(DRAM, permission, mem_attr::NORMAL, false);
+            assert_eq!(
+                decode_leaf_descriptor(block, DRAM + BLOCK_SIZE - 8, BLOCK_LEVEL),
+                translation(DRAM + BLOCK_SIZE - 8, decoded)
+            );
+        }
+    }
+
+    #[test]
+    fn aligned_ranges_use_blocks() {
+        let mut tables = new_tables(8);
+        let mut page_table = PageTable::new(&mut tables);
+
+        page_table
+            .map(
+                DRAM,
+                DRAM,
+                BLOCK_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+
+        let block = page_table.descriptor(DRAM, BLOCK_LEVEL).unwrap();
+        assert!(is_valid_descriptor(block) && !is_table_descriptor(block, BLOCK_LEVEL));
+        assert_eq!(page_table.descriptor(DRAM, LAST_LEVEL), None);
+
+        assert_eq!(
+            page_table.translate(DRAM + BLOCK_SIZE - 4),
+            Some(translation(DRAM + BLOCK_SIZE - 4, MemoryPermission::RW))
+        );
+        assert_eq!(page_table.translate(DRAM + BLOCK_SIZE), None);
+        assert_eq!(page_table.translate(DRAM - 4), None);
+    }
+
+    #[test]
+    fn pages_are_walked_through_every_level() {
+        let mut tables = new_tables(8);
+        let mut page_table = PageTable::new(&mut tables);
+        let vaddr = DRAM + 3 * PAGE_SIZE;
+        let paddr = 0x1_0000_0000 + 7 * PAGE_SIZE;
+
+        page_table
+            .map(
+                vaddr,
+                paddr,
+                PAGE_SIZE,
+                MemoryPermission::RX,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+
+        assert_eq!(page_table.used_tables(), 1 + LAST_LEVEL - START_LEVEL);
+        for level in START_LEVEL..LAST_LEVEL {
+            assert!(is_table_descriptor(
+                page_table.descriptor(vaddr, level).unwrap(),
+                level
+            ));
+        }
+
+        assert_eq!(
+            page_table.translate(vaddr + 0x10),
+            Some(translation(paddr + 0x10, MemoryPermission::RX))
+        );
+        assert_eq!(page_table.translate(vaddr + PAGE_SIZE), None);
+        assert_eq!(page_table.translate(vaddr - PAGE_SIZE), None);
+    }
+
+    #[test]
+    fn remapping_a_page_splits_its_block() {
+        let mut tables = new_tables(8);
+        let mut page_table = PageTable::new(&mut tables);
+        let page = DRAM + 9 * PAGE_SIZE;
+
+        page_table
+            .map(
+                DRAM,
+                DRAM,
+                BLOCK_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+        page_table
+            .map(page, page, PAGE_SIZE, MemoryPermission::R, mem_attr::NORMAL)
+            .unwrap();
+
+        assert!(is_table_descriptor(
+            page_table.descriptor(DRAM, BLOCK_LEVEL).unwrap(),
+            BLOCK_LEVEL
+        ));
+        assert_eq!(
+            page_table.translate(page),
+            Some(translation(page, MemoryPermission::R))
+        );
+        assert_eq!(
+            page_table.translate(page - PAGE_SIZE),
+            Some(translation(page - PAGE_SIZE, MemoryPermission::RW))
+        );
+        assert_eq!(
+            page_table.translate(DRAM + BLOCK_SIZE - PAGE_SIZE),
+            Some(translation(
+                DRAM + BLOCK_SIZE - PAGE_SIZE,
+                MemoryPermission::RW
+            ))
+        );
+
+        page_table.unmap(page, PAGE_SIZE).unwrap();
+        assert_eq!(page_table.translate(page), None);
+        assert_eq!(
+            page_table.translate(page + PAGE_SIZE),
+            Some(translation(page + PAGE_SIZE, MemoryPermission::RW))
+        );
+    }
+
+    #[test]
+    fn changing_an_entry_drops_the_contiguous_hint_of_its_group() {
+        let mut tables = new_tables(8);
+        let mut page_table = PageTable::new(&mut tables);
+        let group_size = contiguous_entry_count(LAST_LEVEL) as u64;
+
+        page_table
+            .map(
+                DRAM,
+                DRAM,
+                group_size * PAGE_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+
+        for index in 0..group_size {
+            let descriptor = page_table
+                .descriptor(DRAM + index * PAGE_SIZE, LAST_LEVEL)
+                .unwrap();
+            assert_ne!(descriptor & CONTIGUOUS_MASK, 0);
+        }
+
+        page_table
+            .map(DRAM, DRAM, PAGE_SIZE, MemoryPermission::R, mem_attr::NORMAL)
+            .unwrap();
+
+        for index in 0..group_size {
+            let descriptor = page_table
+                .descriptor(DRAM + index * PAGE_SIZE, LAST_LEVEL)
+                .unwrap();
+            assert_eq!(descriptor & CONTIGUOUS_MASK, 0);
+        }
+    }
+
+    #[test]
+    fn new_entries_need_no_tlb_maintenance() {
+        let mut tables = new_tables(8);
+        let mut page_table = PageTable::new(&mut tables);
+        page_table.set_tlb_invalidator(record_invalidation);
+
+        page_table
+            .map(
+                DRAM,
+                DRAM,
+                BLOCK_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+        page_table
+            .map(
+                DRAM + BLOCK_SIZE,
+                DRAM + BLOCK_SIZE,
+                PAGE_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+
+        assert_eq!(take_invalidations(), []);
+    }
+
+    #[test]
+    fn valid_entries_are_broken_before_being_replaced() {
+        let mut tables = new_tables(8);
+        let mut page_table = PageTable::new(&mut tables);
+        page_table.set_tlb_invalidator(record_invalidation);
+        let page = DRAM + 9 * PAGE_SIZE;
+
+        page_table
+            .map(
+                DRAM,
+                DRAM,
+                BLOCK_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+
+        // Splitting the block, the pages of the new table are live once it is linked.
+        page_table
+            .map(page, page, PAGE_SIZE, MemoryPermission::R, mem_attr::NORMAL)
+            .unwrap();
+        assert_eq!(
+            take_invalidations(),
+            [(DRAM, BLOCK_SIZE, BLOCK_SIZE), (page, PAGE_SIZE, PAGE_SIZE)]
+        );
+
+        page_table.unmap(page, PAGE_SIZE).unwrap();
+        assert_eq!(take_invalidations(), [(page, PAGE_SIZE, PAGE_SIZE)]);
+
+        // Replacing the table, its pages may be in the TLB.
+        page_table
+            .map(
+                DRAM,
+                DRAM,
+                BLOCK_SIZE,
+                MemoryPermission::RX,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+        assert_eq!(take_invalidations(), [(DRAM, BLOCK_SIZE, PAGE_SIZE)]);
+        assert_eq!(
+            page_table.translate(page),
+            Some(translation(page, MemoryPermission::RX))
+        );
+    }
+
+    #[test]
+    fn contiguous_groups_are_broken_as_a_whole() {
+        let mut tables = new_tables(8);
+        let mut page_table = PageTable::new(&mut tables);
+        page_table.set_tlb_invalidator(record_invalidation);
+        let group_byte_size = contiguous_entry_count(LAST_LEVEL) as u64 * PAGE_SIZE;
+        let page = DRAM + PAGE_SIZE;
+
+        page_table
+            .map(
+                DRAM,
+                DRAM,
+                group_byte_size,
+                MemoryPermission::RW,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+        assert_eq!(take_invalidations(), []);
+
+        page_table
+            .map(page, page, PAGE_SIZE, MemoryPermission::R, mem_attr::NORMAL)
+            .unwrap();
+        assert_eq!(
+            take_invalidations(),
+            [
+                (DRAM, group_byte_size, PAGE_SIZE),
+                (page, PAGE_SIZE, PAGE_SIZE)
+            ]
+        );
+        assert_eq!(
+            page_table.translate(DRAM),
+            Some(translation(DRAM, MemoryPermission::RW))
+        );
+        assert_eq!(
+            page_table.translate(page),
+            Some(translation(page, MemoryPermission::R))
+        );
+    }
+
+    #[test]
+    fn adjacent_entries_are_reported_as_one_range() {
+        let mut tables = new_tables(8);
+        let mut page_table = PageTable::new(&mut tables);
+
+        page_table
+            .map(
+                DRAM - PAGE_SIZE,
+                DRAM - PAGE_SIZE,
+                BLOCK_SIZE + 2 * PAGE_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL,
+            )
+            .unwrap();
+        page_table
+            .map(
+                0x5000_0000,
+                0x5000_0000,
+                PAGE_SIZE,
+                MemoryPermission::RW,
+                mem_attr::MMIO,
+            )
+            .unwrap();
+
+        let mut ranges = Vec::new();
+        page_table.for_each_range(|range| ranges.push(*range));
+
+        assert_eq!(
+            ranges,
+            [
+                MappedRange {
+                    vaddr: 0x5000_0000,
+                    paddr: 0x5000_0000,
+                    size: PAGE_SIZE,
+                    permission: MemoryPermission::RW,
+                    memory_attribute: mem_attr::MMIO,
+                },
+                MappedRange {
+                    vaddr: DRAM - PAGE_SIZE,
+                    paddr: DRAM - PAGE_SIZE,
+                    size: BLOCK_SIZE + 2 * PAGE_SIZE,
+                    permission: MemoryPermission::RW,
+                    memory_attribute: mem_attr::NORMAL,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut tables = new_tables(1);
+        let mut page_table = PageTable::new(&mut tables);
+
+        assert_eq!(
+            page_table.map(
+                DRAM,
+                DRAM,
+                PAGE_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL
+            ),
+            Err(MapError::OutOfTables)
+        );
+        assert_eq!(
+            page_table.map(
+                1 << TARGET_BITS,
+                0,
+                PAGE_SIZE,
+                MemoryPermission::RW,
+                mem_attr::NORMAL
+            ),
+            Err(MapError::OutOfRange)
+        );
+        assert_eq!(
+            page_table.unmap((1 << TARGET_BITS) - PAGE_SIZE, 2 * PAGE_SIZE),
+            Err(MapError::OutOfRange)
+        );
+        assert_eq!(
+            page_table.unmap(u64::MAX - PAGE_SIZE, PAGE_SIZE),
+            Err(MapError::OutOfRange)
+        );
+
+        // Unmapping never needs tables.
+        assert_eq!(page_table.unmap(DRAM, PAGE_SIZE), Ok(()));
+        assert_eq!(page_table.translate(1 << TARGET_BITS), None);
+    }
+
+    #[test]
+    fn table_count_for_all_pages() {
+        // Each entry of the levels above the last one points to a table.
+        let count: u64 = (START_LEVEL..LAST_LEVEL)
+            .map(|level| (1 << TARGET_BITS) / level_entry_size(level))
+            .sum();
+
+        assert_eq!(TABLE_COUNT_FOR_ALL_PAGES as u64, 1 + count);
+    }
+}
@@ -4,6 +4,11 @@ use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::ptr;
 
+use crate::backtrace;
+use crate::elf::{
+    ElfDyn, ElfRel, ElfRela, DT_NULL, DT_REL, DT_RELA, DT_RELACOUNT, DT_RELAENT, DT_RELCOUNT,
+    DT_RELENT, R_AARCH64_RELATIVE,
+};
 use crate::exception_vectors;
 use crate::mmu;
 
@@ -26,6 +31,7 @@ macro_rules! entry {
 fn panic(panic_info: &PanicInfo<'_>) -> ! {
     let mut uart_a = &mut Uart::A;
     writeln!(&mut uart_a, "PANIC: {}\r", panic_info).ok();
+    backtrace::write_backtrace(&mut uart_a, None, backtrace::get_frame_pointer());
     unsafe {
         reboot_to_rcm();
     };
@@ -57,6 +63,21 @@ pub unsafe extern "C" fn reboot_to_rcm() {
     );
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn reboot() {
+    asm!(
+        "
+        movz x1, #0xE400
+        movk x1, #0x7000, lsl 16
+        ldr w0, [x1]
+        orr w0, w0, #0x10
+        str w0, [x1]
+        ",
+        out("x0") _,
+        out("x1") _,
+    );
+}
+
 #[link_section = ".text.crt0"]
 #[naked]
 #[no_mangle]
@@ -75,6 +96,7 @@ pub unsafe extern "C" fn _start() -> ! {
 pub unsafe extern "C" fn trampoline() -> ! {
     asm!(
         "
+        mov x19, x0
         adrp x0, _stack_top
         add x0, x0, #:lo12:_stack_top
         mov sp, x0
@@ -86,44 +108,13 @@ pub unsafe extern "C" fn trampoline() -> ! {
         adrp x1, __bss_end__
         add x1, x1, #:lo12:__bss_end__
         bl clean_bss
+        mov x0, x19
         bl _start_with_stack
         ",
         options(noreturn),
     )
 }
 
-const DT_NULL: isize = 0;
-const DT_RELA: isize = 7;
-const DT_RELASZ: isize = 8;
-const DT_RELAENT: isize = 9;
-const DT_RELACOUNT: isize = 0x6ffffff9;
-const DT_REL: isize = 17;
-const DT_RELSZ: isize = 18;
-const DT_RELENT: isize = 19;
-const DT_RELCOUNT: isize = 0x6ffffffa;
-
-const R_AARCH64_RELATIVE: usize = 0x403;
-
-#[repr(C)]
-#[derive(Debug)]
-struct ElfDyn {
-    tag: isize,
-    val: usize,
-}
-
-#[repr(C)]
-struct ElfRel {
-    offset: usize,
-    info: usize,
-}
-
-#[repr(C)]
-struct ElfRela {
-    offset: usize,
-    info: usize,
-    addend: isize,
-}
-
 #[no_mangle]
 pub unsafe extern "C" fn relocate_self(aslr_base: *mut u8) -> u32 {
     let mut dynamic =
@@ -214,8 +205,17 @@ unsafe extern "C" fn clean_bss(start_bss: *mut u8, end_bss: *mut u8) {
     );
 }
 
+/// First argument rboot was started with, the device tree when the previous stage passes one.
+static mut PREVIOUS_STAGE_ARGUMENT: u64 = 0;
+
+pub fn previous_stage_argument() -> u64 {
+    unsafe { PREVIOUS_STAGE_ARGUMENT }
+}
+
 #[no_mangle]
-pub unsafe extern "C" fn _start_with_stack() -> ! {
+pub unsafe extern "C" fn _start_with_stack(previous_stage_argument: u64) -> ! {
+    PREVIOUS_STAGE_ARGUMENT = previous_stage_argument;
+
     exception_vectors::setup();
     mmu::setup();
 
@@ -225,6 +225,10 @@ pub unsafe extern "C" fn _start_with_stack() -> ! {
     }
 
     main();
+
+    #[cfg(feature = "monitor")]
+    crate::monitor::run(Uart::A);
+
     reboot_to_rcm();
 
     loop {}
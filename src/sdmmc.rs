@@ -0,0 +1,1701 @@
+//! SDMMC host driver, for SD cards and eMMC.
+//!
+//! The controllers are SDHCI compatible with a few Tegra additions. Transfers use ADMA2 through
+//! a bounce buffer, and cards are switched to high speed on the widest bus they support. UHS
+//! and HS200 modes need tuning and aren't supported.
+//!
+//! Register accesses go through [`SdmmcRegisters`], so the command state machine can run
+//! against a model of the controller. The Tegra controllers are in `tegra210::sdmmc`.
+
+use core::cmp;
+use core::mem;
+
+use crate::block::{self, BlockDevice, BLOCK_SIZE};
+
+const SDHCI_ADMA_SYSTEM_ADDRESS: usize = 0x58;
+const SDHCI_ADMA_SYSTEM_ADDRESS_HIGH: usize = 0x5C;
+const SDHCI_ARGUMENT: usize = 0x08;
+/// Block size in the low half, block count in the high half.
+const SDHCI_BLOCK: usize = 0x04;
+/// Clock control in the low half, then data timeout control and software reset.
+const SDHCI_CLOCK_CONTROL: usize = 0x2C;
+/// Host control in the low byte, then power control.
+const SDHCI_HOST_CONTROL: usize = 0x28;
+/// Normal interrupts in the low half, errors in the high half.
+const SDHCI_INT_STATUS: usize = 0x30;
+const SDHCI_INT_STATUS_ENABLE: usize = 0x34;
+const SDHCI_INT_SIGNAL_ENABLE: usize = 0x38;
+const SDHCI_PRESENT_STATE: usize = 0x24;
+const SDHCI_RESPONSE: usize = 0x10;
+/// Transfer mode in the low half, command in the high half. Tegra needs them written at once.
+const SDHCI_TRANSFER_MODE: usize = 0x0C;
+
+const TEGRA_VENDOR_CLOCK_CONTROL: usize = 0x100;
+const TEGRA_SDMEMCOMPPADCTRL: usize = 0x1E0;
+const TEGRA_AUTO_CAL_CONFIG: usize = 0x1E4;
+const TEGRA_AUTO_CAL_STATUS: usize = 0x1EC;
+
+const PRESENT_STATE_COMMAND_INHIBIT: u32 = 1 << 0;
+const PRESENT_STATE_DATA_INHIBIT: u32 = 1 << 1;
+
+const HOST_CONTROL_DATA_WIDTH_4: u32 = 1 << 1;
+const HOST_CONTROL_HIGH_SPEED: u32 = 1 << 2;
+const HOST_CONTROL_DMA_MASK: u32 = 3 << 3;
+const HOST_CONTROL_DMA_ADMA2_32: u32 = 2 << 3;
+const HOST_CONTROL_DATA_WIDTH_8: u32 = 1 << 5;
+const POWER_CONTROL_MASK: u32 = 0xF << 8;
+const POWER_CONTROL_ON: u32 = 1 << 8;
+const POWER_CONTROL_1V8: u32 = 5 << 9;
+const POWER_CONTROL_3V3: u32 = 7 << 9;
+
+const CLOCK_CONTROL_INTERNAL_ENABLE: u32 = 1 << 0;
+const CLOCK_CONTROL_INTERNAL_STABLE: u32 = 1 << 1;
+const CLOCK_CONTROL_SD_ENABLE: u32 = 1 << 2;
+const CLOCK_CONTROL_DIVIDER_MASK: u32 = 0xFFC0;
+const TIMEOUT_CONTROL_MASK: u32 = 0xF << 16;
+/// TMCLK * 2^27, the longest data timeout.
+const TIMEOUT_CONTROL_MAX: u32 = 0xE << 16;
+const SOFTWARE_RESET_ALL: u32 = 1 << 24;
+const SOFTWARE_RESET_COMMAND: u32 = 1 << 25;
+const SOFTWARE_RESET_DATA: u32 = 1 << 26;
+
+const INT_COMMAND_COMPLETE: u32 = 1 << 0;
+const INT_TRANSFER_COMPLETE: u32 = 1 << 1;
+const INT_ERROR: u32 = 1 << 15;
+/// Command and transfer completion, and all the errors.
+const INT_STATUS_ENABLE_MASK: u32 = 0x07FF_0003;
+
+const TRANSFER_MODE_DMA: u32 = 1 << 0;
+const TRANSFER_MODE_BLOCK_COUNT: u32 = 1 << 1;
+const TRANSFER_MODE_AUTO_CMD12: u32 = 1 << 2;
+const TRANSFER_MODE_READ: u32 = 1 << 4;
+const TRANSFER_MODE_MULTI_BLOCK: u32 = 1 << 5;
+
+const COMMAND_RESPONSE_136: u32 = 1;
+const COMMAND_RESPONSE_48: u32 = 2;
+const COMMAND_RESPONSE_48_BUSY: u32 = 3;
+const COMMAND_CRC_CHECK: u32 = 1 << 3;
+const COMMAND_INDEX_CHECK: u32 = 1 << 4;
+const COMMAND_DATA_PRESENT: u32 = 1 << 5;
+
+const VENDOR_CLOCK_CONTROL_SDMMC_CLOCK: u32 = 1 << 0;
+const SDMEMCOMPPADCTRL_PAD_E_INPUT_OR_E_PWRD: u32 = 1 << 31;
+const AUTO_CAL_CONFIG_START: u32 = 1 << 31;
+const AUTO_CAL_CONFIG_ENABLE: u32 = 1 << 29;
+const AUTO_CAL_STATUS_ACTIVE: u32 = 1 << 31;
+
+const ADMA_VALID: u16 = 1 << 0;
+const ADMA_END: u16 = 1 << 1;
+const ADMA_ACTION_TRANSFER: u16 = 2 << 4;
+
+/// Bytes moved by a descriptor, a power of two so the 16 bits length never wraps.
+const ADMA_DESCRIPTOR_LENGTH: usize = 0x8000;
+const BOUNCE_BUFFER_SIZE: usize = 0x1_0000;
+const ADMA_DESCRIPTOR_COUNT: usize = BOUNCE_BUFFER_SIZE / ADMA_DESCRIPTOR_LENGTH;
+
+const CMD_GO_IDLE_STATE: u8 = 0;
+const CMD_MMC_SEND_OP_COND: u8 = 1;
+const CMD_ALL_SEND_CID: u8 = 2;
+const CMD_SEND_RELATIVE_ADDR: u8 = 3;
+const CMD_SWITCH: u8 = 6;
+const CMD_SELECT_CARD: u8 = 7;
+const CMD_SEND_IF_COND: u8 = 8;
+const CMD_MMC_SEND_EXT_CSD: u8 = 8;
+const CMD_SEND_CSD: u8 = 9;
+const CMD_SEND_STATUS: u8 = 13;
+const CMD_SET_BLOCKLEN: u8 = 16;
+const CMD_READ_SINGLE_BLOCK: u8 = 17;
+const CMD_READ_MULTIPLE_BLOCK: u8 = 18;
+const CMD_WRITE_BLOCK: u8 = 24;
+const CMD_WRITE_MULTIPLE_BLOCK: u8 = 25;
+const CMD_APP_CMD: u8 = 55;
+const ACMD_SET_BUS_WIDTH: u8 = 6;
+const ACMD_SD_SEND_OP_COND: u8 = 41;
+
+/// Check pattern and 2.7-3.6V range of SEND_IF_COND.
+const SD_IF_COND: u32 = 0x1AA;
+/// 3.2-3.4V
+const SD_OCR_VOLTAGES: u32 = 0x0030_0000;
+/// 1.7-1.95V and 2.7-3.6V
+const MMC_OCR_VOLTAGES: u32 = 0x00FF_8080;
+const OCR_HIGH_CAPACITY: u32 = 1 << 30;
+const OCR_POWER_UP_DONE: u32 = 1 << 31;
+
+/// Query the high speed function of the SD access mode group.
+const SD_SWITCH_CHECK_HIGH_SPEED: u32 = 0x00FF_FFF1;
+const SD_SWITCH_SET: u32 = 1 << 31;
+const SD_SWITCH_STATUS_SIZE: usize = 64;
+const SD_BUS_WIDTH_4: u32 = 2;
+
+/// eMMC don't choose their RCA, this is what the host gives to the only one on the bus.
+const MMC_RCA: u16 = 1;
+const MMC_SWITCH_WRITE_BYTE: u32 = 3 << 24;
+const EXT_CSD_BUS_WIDTH: u32 = 183;
+const EXT_CSD_BUS_WIDTH_8: u32 = 2;
+const EXT_CSD_HS_TIMING: u32 = 185;
+const EXT_CSD_CARD_TYPE: usize = 196;
+const EXT_CSD_CARD_TYPE_HS_26: u8 = 1 << 0;
+const EXT_CSD_CARD_TYPE_HS_52: u8 = 1 << 1;
+const EXT_CSD_SEC_COUNT: usize = 212;
+
+/// Error bits of the card status, in R1 responses.
+const CARD_STATUS_ERRORS: u32 = 0xFDF9_8088;
+const CARD_STATUS_ILLEGAL_COMMAND: u32 = 1 << 22;
+const CARD_STATUS_READY_FOR_DATA: u32 = 1 << 8;
+const CARD_STATUS_STATE_SHIFT: u32 = 9;
+const CARD_STATE_TRANSFER: u32 = 4;
+
+const IDENTIFICATION_CLOCK_KHZ: u32 = 400;
+const SD_DEFAULT_CLOCK_KHZ: u32 = 25_000;
+const SD_HIGH_SPEED_CLOCK_KHZ: u32 = 50_000;
+const MMC_LEGACY_CLOCK_KHZ: u32 = 20_000;
+const MMC_HIGH_SPEED_26_CLOCK_KHZ: u32 = 26_000;
+const MMC_HIGH_SPEED_52_CLOCK_KHZ: u32 = 52_000;
+/// Slowest clock asked from the CAR, the controller divides it further.
+pub const MIN_HOST_CLOCK_KHZ: u32 = 24_000;
+
+const RESET_TIMEOUT_US: u32 = 100_000;
+const CLOCK_TIMEOUT_US: u32 = 100_000;
+const COMMAND_TIMEOUT_US: u32 = 100_000;
+const TRANSFER_TIMEOUT_US: u32 = 1_000_000;
+const BUSY_TIMEOUT_US: u32 = 1_000_000;
+const POWER_UP_TIMEOUT_US: u32 = 1_000_000;
+const AUTO_CAL_TIMEOUT_US: u32 = 10_000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The controller or the card didn't answer in time.
+    Timeout,
+    /// The controller reported errors, as in its error interrupt status.
+    Controller { command: u8, status: u16 },
+    /// The card status in the response has error bits set.
+    CardStatus { command: u8, status: u32 },
+    /// The card doesn't answer like an SD card or an eMMC.
+    UnsupportedCard,
+    /// The controller can't reach the memory used for DMA.
+    DmaAddress,
+    /// The card must be initialized first.
+    NotInitialized,
+}
+
+impl From<Error> for block::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Timeout => block::Error::Timeout,
+            _ => block::Error::Io,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CardType {
+    Sd,
+    Mmc,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CardInfo {
+    pub card_type: CardType,
+    /// Blocks are addressed by index rather than byte offset (SDHC, SDXC and eMMC over 2GB).
+    pub high_capacity: bool,
+    pub rca: u16,
+    pub cid: [u32; 4],
+    pub block_count: u64,
+    pub bus_width: u8,
+    pub clock_khz: u32,
+}
+
+/// Access to a controller, over MMIO or to a model of it.
+pub trait SdmmcRegisters {
+    fn read(&self, offset: usize) -> u32;
+
+    fn write(&mut self, offset: usize, value: u32);
+
+    /// Set the clock fed to the controller, returning the rate obtained in kHz.
+    fn set_host_clock(&mut self, rate_khz: u32) -> u32;
+
+    /// Microseconds from a free running counter.
+    fn get_microseconds(&self) -> u32;
+
+    /// Make `size` bytes at `address` coherent for the controller, returning their DMA
+    /// address.
+    fn map_dma(&mut self, address: u64, size: usize) -> Option<u32>;
+
+    /// Make what the controller wrote at `address` visible to the CPU.
+    fn unmap_dma(&mut self, address: u64, size: usize);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Response {
+    None,
+    /// 48 bits with the card status.
+    R1,
+    /// R1 followed by busy signalling on DAT0.
+    R1b,
+    /// 136 bits with the CID or CSD.
+    R2,
+    /// 48 bits with the OCR, without CRC.
+    R3,
+    /// 48 bits with the RCA.
+    R6,
+    /// 48 bits with the SEND_IF_COND echo.
+    R7,
+}
+
+impl Response {
+    fn get_command_flags(self) -> u32 {
+        match self {
+            Response::None => 0,
+            Response::R1 | Response::R6 | Response::R7 => {
+                COMMAND_RESPONSE_48 | COMMAND_CRC_CHECK | COMMAND_INDEX_CHECK
+            }
+            Response::R1b => COMMAND_RESPONSE_48_BUSY | COMMAND_CRC_CHECK | COMMAND_INDEX_CHECK,
+            Response::R2 => COMMAND_RESPONSE_136 | COMMAND_CRC_CHECK,
+            Response::R3 => COMMAND_RESPONSE_48,
+        }
+    }
+}
+
+/// Data moved through the bounce buffer by a command.
+#[derive(Copy, Clone, Debug)]
+struct Transfer {
+    block_size: usize,
+    block_count: usize,
+    write: bool,
+}
+
+impl Transfer {
+    fn size(&self) -> usize {
+        self.block_size * self.block_count
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct AdmaDescriptor {
+    attributes: u16,
+    length: u16,
+    address: u32,
+}
+
+impl AdmaDescriptor {
+    const EMPTY: Self = AdmaDescriptor {
+        attributes: 0,
+        length: 0,
+        address: 0,
+    };
+}
+
+/// Memory the controller accesses, aligned on cache lines so maintenance doesn't spill.
+#[repr(C, align(64))]
+pub struct DmaMemory {
+    descriptors: [AdmaDescriptor; ADMA_DESCRIPTOR_COUNT],
+    buffer: [u8; BOUNCE_BUFFER_SIZE],
+}
+
+impl DmaMemory {
+    pub const fn new() -> Self {
+        DmaMemory {
+            descriptors: [AdmaDescriptor::EMPTY; ADMA_DESCRIPTOR_COUNT],
+            buffer: [0; BOUNCE_BUFFER_SIZE],
+        }
+    }
+}
+
+impl Default for DmaMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The CSD or CID of an R2 response, the controller drops its CRC byte.
+fn get_long_response(response: [u32; 4]) -> u128 {
+    let value = response
+        .iter()
+        .rev()
+        .fold(0u128, |value, word| (value << 32) | u128::from(*word));
+
+    value << 8
+}
+
+fn get_bits(value: u128, start: u32, size: u32) -> u64 {
+    ((value >> start) & ((1 << size) - 1)) as u64
+}
+
+/// Capacity in blocks of a card with a version 1 CSD, used by SDSC and byte addressed eMMC.
+fn get_csd_v1_block_count(csd: u128) -> u64 {
+    let device_size = get_bits(csd, 62, 12);
+    let multiplier = get_bits(csd, 47, 3);
+    let read_block_length = get_bits(csd, 80, 4);
+
+    ((device_size + 1) << (multiplier + 2) << read_block_length) / BLOCK_SIZE as u64
+}
+
+fn check_card_status(command: u8, status: u32) -> Result<(), Error> {
+    if status & CARD_STATUS_ERRORS != 0 {
+        return Err(Error::CardStatus { command, status });
+    }
+
+    Ok(())
+}
+
+/// A controller and the card on it.
+pub struct Sdmmc<'a, R> {
+    registers: R,
+    dma: &'a mut DmaMemory,
+    card_type: CardType,
+    card: Option<CardInfo>,
+}
+
+impl<'a, R: SdmmcRegisters> Sdmmc<'a, R> {
+    pub fn new(registers: R, dma: &'a mut DmaMemory, card_type: CardType) -> Self {
+        Sdmmc {
+            registers,
+            dma,
+            card_type,
+            card: None,
+        }
+    }
+
+    pub fn registers(&self) -> &R {
+        &self.registers
+    }
+
+    /// The card found by [`Sdmmc::init`].
+    pub fn card_info(&self) -> Option<&CardInfo> {
+        self.card.as_ref()
+    }
+
+    fn wait_for<F: Fn(&R) -> bool>(&self, timeout_us: u32, condition: F) -> Result<(), Error> {
+        let start = self.registers.get_microseconds();
+
+        loop {
+            if condition(&self.registers) {
+                return Ok(());
+            }
+
+            let elapsed = self.registers.get_microseconds().wrapping_sub(start);
+            if elapsed > timeout_us {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    fn set_bits(&mut self, offset: usize, mask: u32, value: u32) {
+        let register = self.registers.read(offset);
+
+        self.registers.write(offset, (register & !mask) | value);
+    }
+
+    fn reset(&mut self, mask: u32) -> Result<(), Error> {
+        self.set_bits(SDHCI_CLOCK_CONTROL, 0, mask);
+
+        self.wait_for(RESET_TIMEOUT_US, |registers| {
+            registers.read(SDHCI_CLOCK_CONTROL) & mask == 0
+        })
+    }
+
+    /// Run the pad drive strength calibration, which the controller needs after a reset.
+    fn calibrate_pads(&mut self) {
+        self.set_bits(
+            TEGRA_SDMEMCOMPPADCTRL,
+            0,
+            SDMEMCOMPPADCTRL_PAD_E_INPUT_OR_E_PWRD,
+        );
+        self.set_bits(
+            TEGRA_AUTO_CAL_CONFIG,
+            0,
+            AUTO_CAL_CONFIG_START | AUTO_CAL_CONFIG_ENABLE,
+        );
+
+        let result = self.wait_for(AUTO_CAL_TIMEOUT_US, |registers| {
+            registers.read(TEGRA_AUTO_CAL_STATUS) & AUTO_CAL_STATUS_ACTIVE == 0
+        });
+
+        if result.is_err() {
+            // The pads still work with the default drive strengths.
+            warn!("SDMMC pad calibration timed out");
+            self.set_bits(TEGRA_AUTO_CAL_CONFIG, AUTO_CAL_CONFIG_ENABLE, 0);
+        }
+
+        self.set_bits(
+            TEGRA_SDMEMCOMPPADCTRL,
+            SDMEMCOMPPADCTRL_PAD_E_INPUT_OR_E_PWRD,
+            0,
+        );
+    }
+
+    fn power_on(&mut self) {
+        let voltage = match self.card_type {
+            CardType::Sd => POWER_CONTROL_3V3,
+            CardType::Mmc => POWER_CONTROL_1V8,
+        };
+
+        self.set_bits(SDHCI_HOST_CONTROL, POWER_CONTROL_MASK, voltage);
+        self.set_bits(
+            SDHCI_HOST_CONTROL,
+            POWER_CONTROL_MASK,
+            voltage | POWER_CONTROL_ON,
+        );
+        self.set_bits(
+            TEGRA_VENDOR_CLOCK_CONTROL,
+            0,
+            VENDOR_CLOCK_CONTROL_SDMMC_CLOCK,
+        );
+    }
+
+    /// Run the card clock at `rate_khz` or just below, returning the rate obtained.
+    fn set_clock(&mut self, rate_khz: u32) -> Result<u32, Error> {
+        self.set_bits(SDHCI_CLOCK_CONTROL, CLOCK_CONTROL_SD_ENABLE, 0);
+
+        let host_khz = self
+            .registers
+            .set_host_clock(cmp::max(rate_khz, MIN_HOST_CLOCK_KHZ));
+
+        // The 10 bits divider divides by twice its value, zero bypasses it.
+        let divider = if host_khz <= rate_khz {
+            0
+        } else {
+            cmp::min((host_khz + 2 * rate_khz - 1) / (2 * rate_khz), 0x3FF)
+        };
+        let card_khz = if divider == 0 {
+            host_khz
+        } else {
+            host_khz / (2 * divider)
+        };
+
+        self.set_bits(
+            SDHCI_CLOCK_CONTROL,
+            CLOCK_CONTROL_DIVIDER_MASK,
+            ((divider & 0xFF) << 8) | ((divider >> 8) << 6) | CLOCK_CONTROL_INTERNAL_ENABLE,
+        );
+        self.wait_for(CLOCK_TIMEOUT_US, |registers| {
+            registers.read(SDHCI_CLOCK_CONTROL) & CLOCK_CONTROL_INTERNAL_STABLE != 0
+        })?;
+        self.set_bits(SDHCI_CLOCK_CONTROL, 0, CLOCK_CONTROL_SD_ENABLE);
+
+        Ok(card_khz)
+    }
+
+    fn set_bus_width(&mut self, bus_width: u8) {
+        let value = match bus_width {
+            8 => HOST_CONTROL_DATA_WIDTH_8,
+            4 => HOST_CONTROL_DATA_WIDTH_4,
+            _ => 0,
+        };
+
+        self.set_bits(
+            SDHCI_HOST_CONTROL,
+            HOST_CONTROL_DATA_WIDTH_4 | HOST_CONTROL_DATA_WIDTH_8,
+            value,
+        );
+    }
+
+    fn set_high_speed(&mut self, high_speed: bool) {
+        let value = if high_speed {
+            HOST_CONTROL_HIGH_SPEED
+        } else {
+            0
+        };
+
+        self.set_bits(SDHCI_HOST_CONTROL, HOST_CONTROL_HIGH_SPEED, value);
+    }
+
+    fn wait_for_interrupt(&mut self, command: u8, mask: u32, timeout_us: u32) -> Result<(), Error> {
+        let start = self.registers.get_microseconds();
+
+        loop {
+            let status = self.registers.read(SDHCI_INT_STATUS);
+
+            if status & INT_ERROR != 0 {
+                return Err(Error::Controller {
+                    command,
+                    status: (status >> 16) as u16,
+                });
+            }
+
+            if status & mask == mask {
+                self.registers.write(SDHCI_INT_STATUS, mask);
+                return Ok(());
+            }
+
+            let elapsed = self.registers.get_microseconds().wrapping_sub(start);
+            if elapsed > timeout_us {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    fn setup_dma(&mut self, transfer: &Transfer) -> Result<(), Error> {
+        let size = transfer.size();
+        let buffer_address = self.dma.buffer.as_ptr() as u64;
+        let buffer = self
+            .registers
+            .map_dma(buffer_address, size)
+            .ok_or(Error::DmaAddress)?;
+
+        let descriptor_count = (size + ADMA_DESCRIPTOR_LENGTH - 1) / ADMA_DESCRIPTOR_LENGTH;
+        for (index, descriptor) in self.dma.descriptors[..descriptor_count]
+            .iter_mut()
+            .enumerate()
+        {
+            let offset = index * ADMA_DESCRIPTOR_LENGTH;
+            let mut attributes = ADMA_VALID | ADMA_ACTION_TRANSFER;
+            if index + 1 == descriptor_count {
+                attributes |= ADMA_END;
+            }
+
+            *descriptor = AdmaDescriptor {
+                attributes,
+                length: cmp::min(size - offset, ADMA_DESCRIPTOR_LENGTH) as u16,
+                address: buffer + offset as u32,
+            };
+        }
+
+        let descriptors_address = self.dma.descriptors.as_ptr() as u64;
+        let descriptors = self
+            .registers
+            .map_dma(
+                descriptors_address,
+                descriptor_count * mem::size_of::<AdmaDescriptor>(),
+            )
+            .ok_or(Error::DmaAddress)?;
+
+        self.registers.write(SDHCI_ADMA_SYSTEM_ADDRESS, descriptors);
+        self.registers.write(SDHCI_ADMA_SYSTEM_ADDRESS_HIGH, 0);
+        self.registers.write(
+            SDHCI_BLOCK,
+            transfer.block_size as u32 | ((transfer.block_count as u32) << 16),
+        );
+
+        Ok(())
+    }
+
+    fn send_command(
+        &mut self,
+        command: u8,
+        argument: u32,
+        response: Response,
+        transfer: Option<Transfer>,
+    ) -> Result<[u32; 4], Error> {
+        let uses_data_lines = transfer.is_some() || response == Response::R1b;
+
+        let mut inhibit = PRESENT_STATE_COMMAND_INHIBIT;
+        if uses_data_lines {
+            inhibit |= PRESENT_STATE_DATA_INHIBIT;
+        }
+        self.wait_for(COMMAND_TIMEOUT_US, |registers| {
+            registers.read(SDHCI_PRESENT_STATE) & inhibit == 0
+        })?;
+
+        self.registers.write(SDHCI_INT_STATUS, u32::MAX);
+
+        let mut command_register = (u32::from(command) << 8) | response.get_command_flags();
+        let mut transfer_mode = 0;
+
+        if let Some(transfer) = transfer {
+            self.setup_dma(&transfer)?;
+
+            command_register |= COMMAND_DATA_PRESENT;
+            transfer_mode |= TRANSFER_MODE_DMA | TRANSFER_MODE_BLOCK_COUNT;
+
+            if !transfer.write {
+                transfer_mode |= TRANSFER_MODE_READ;
+            }
+
+            if transfer.block_count > 1 {
+                transfer_mode |= TRANSFER_MODE_MULTI_BLOCK | TRANSFER_MODE_AUTO_CMD12;
+            }
+        }
+
+        self.registers.write(SDHCI_ARGUMENT, argument);
+        self.registers.write(
+            SDHCI_TRANSFER_MODE,
+            (command_register << 16) | transfer_mode,
+        );
+
+        let mut result = self
+            .wait_for_interrupt(command, INT_COMMAND_COMPLETE, COMMAND_TIMEOUT_US)
+            .map(|()| {
+                let mut words = [0; 4];
+
+                if response == Response::R2 {
+                    for (index, word) in words.iter_mut().enumerate() {
+                        *word = self.registers.read(SDHCI_RESPONSE + index * 4);
+                    }
+                } else {
+                    words[0] = self.registers.read(SDHCI_RESPONSE);
+                }
+
+                words
+            });
+
+        if result.is_ok() && uses_data_lines {
+            let timeout_us = if transfer.is_some() {
+                TRANSFER_TIMEOUT_US
+            } else {
+                BUSY_TIMEOUT_US
+            };
+
+            if let Err(error) = self.wait_for_interrupt(command, INT_TRANSFER_COMPLETE, timeout_us)
+            {
+                result = Err(error);
+            }
+        }
+
+        if let Some(transfer) = transfer {
+            if !transfer.write {
+                let buffer_address = self.dma.buffer.as_ptr() as u64;
+                self.registers.unmap_dma(buffer_address, transfer.size());
+            }
+        }
+
+        if result.is_err() {
+            self.reset(SOFTWARE_RESET_COMMAND | SOFTWARE_RESET_DATA)
+                .ok();
+        }
+
+        let words = result?;
+
+        if response == Response::R1 || response == Response::R1b {
+            check_card_status(command, words[0])?;
+        }
+
+        Ok(words)
+    }
+
+    fn send_app_command(
+        &mut self,
+        rca: u16,
+        command: u8,
+        argument: u32,
+        response: Response,
+    ) -> Result<[u32; 4], Error> {
+        match self.send_command(CMD_APP_CMD, u32::from(rca) << 16, Response::R1, None) {
+            // Left by a command the card didn't know, like SEND_IF_COND on old SD cards.
+            Err(Error::CardStatus { status, .. })
+                if status & CARD_STATUS_ERRORS == CARD_STATUS_ILLEGAL_COMMAND => {}
+            result => {
+                result?;
+            }
+        }
+
+        self.send_command(command, argument, response, None)
+    }
+
+    /// Wait for the card to be back in the transfer state, after a write or a switch.
+    fn wait_until_ready(&mut self, rca: u16) -> Result<(), Error> {
+        let start = self.registers.get_microseconds();
+
+        loop {
+            let status =
+                self.send_command(CMD_SEND_STATUS, u32::from(rca) << 16, Response::R1, None)?[0];
+            let state = (status >> CARD_STATUS_STATE_SHIFT) & 0xF;
+
+            if status & CARD_STATUS_READY_FOR_DATA != 0 && state == CARD_STATE_TRANSFER {
+                return Ok(());
+            }
+
+            let elapsed = self.registers.get_microseconds().wrapping_sub(start);
+            if elapsed > BUSY_TIMEOUT_US {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    /// Send SEND_OP_COND commands until the card is powered up, returning its OCR.
+    fn wait_for_power_up(&mut self, argument: u32) -> Result<u32, Error> {
+        let start = self.registers.get_microseconds();
+
+        loop {
+            let ocr = match self.card_type {
+                CardType::Sd => {
+                    self.send_app_command(0, ACMD_SD_SEND_OP_COND, argument, Response::R3)?[0]
+                }
+                CardType::Mmc => {
+                    self.send_command(CMD_MMC_SEND_OP_COND, argument, Response::R3, None)?[0]
+                }
+            };
+
+            if ocr & OCR_POWER_UP_DONE != 0 {
+                return Ok(ocr);
+            }
+
+            let elapsed = self.registers.get_microseconds().wrapping_sub(start);
+            if elapsed > POWER_UP_TIMEOUT_US {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    fn init_sd(&mut self) -> Result<CardInfo, Error> {
+        self.send_command(CMD_GO_IDLE_STATE, 0, Response::None, None)?;
+
+        // Only cards following the version 2 of the specification answer SEND_IF_COND.
+        let version2 = match self.send_command(CMD_SEND_IF_COND, SD_IF_COND, Response::R7, None) {
+            Ok(response) if response[0] & 0xFFF == SD_IF_COND => true,
+            Ok(_) => return Err(Error::UnsupportedCard),
+            Err(_) => false,
+        };
+
+        let mut argument = SD_OCR_VOLTAGES;
+        if version2 {
+            argument |= OCR_HIGH_CAPACITY;
+        }
+
+        let ocr = self.wait_for_power_up(argument)?;
+        let high_capacity = ocr & OCR_HIGH_CAPACITY != 0;
+
+        let cid = self.send_command(CMD_ALL_SEND_CID, 0, Response::R2, None)?;
+        let rca =
+            (self.send_command(CMD_SEND_RELATIVE_ADDR, 0, Response::R6, None)?[0] >> 16) as u16;
+        let csd = get_long_response(self.send_command(
+            CMD_SEND_CSD,
+            u32::from(rca) << 16,
+            Response::R2,
+            None,
+        )?);
+
+        let block_count = match get_bits(csd, 126, 2) {
+            0 => get_csd_v1_block_count(csd),
+            1 => (get_bits(csd, 48, 22) + 1) * 1024,
+            _ => return Err(Error::UnsupportedCard),
+        };
+
+        self.send_command(CMD_SELECT_CARD, u32::from(rca) << 16, Response::R1b, None)?;
+
+        self.send_app_command(rca, ACMD_SET_BUS_WIDTH, SD_BUS_WIDTH_4, Response::R1)?;
+        self.set_bus_width(4);
+
+        if !high_capacity {
+            self.send_command(CMD_SET_BLOCKLEN, BLOCK_SIZE as u32, Response::R1, None)?;
+        }
+
+        // Cards older than SD 1.10 don't know SWITCH, and stay at the default speed.
+        let transfer = Transfer {
+            block_size: SD_SWITCH_STATUS_SIZE,
+            block_count: 1,
+            write: false,
+        };
+        let high_speed = self
+            .send_command(
+                CMD_SWITCH,
+                SD_SWITCH_SET | SD_SWITCH_CHECK_HIGH_SPEED,
+                Response::R1,
+                Some(transfer),
+            )
+            .map(|_| self.dma.buffer[16] & 0xF == 1)
+            .unwrap_or(false);
+
+        self.set_high_speed(high_speed);
+        let clock_khz = self.set_clock(if high_speed {
+            SD_HIGH_SPEED_CLOCK_KHZ
+        } else {
+            SD_DEFAULT_CLOCK_KHZ
+        })?;
+
+        Ok(CardInfo {
+            card_type: CardType::Sd,
+            high_capacity,
+            rca,
+            cid,
+            block_count,
+            bus_width: 4,
+            clock_khz,
+        })
+    }
+
+    fn mmc_switch(&mut self, index: u32, value: u32) -> Result<(), Error> {
+        let argument = MMC_SWITCH_WRITE_BYTE | (index << 16) | (value << 8);
+
+        self.send_command(CMD_SWITCH, argument, Response::R1b, None)?;
+        self.wait_until_ready(MMC_RCA)
+    }
+
+    fn init_mmc(&mut self) -> Result<CardInfo, Error> {
+        self.send_command(CMD_GO_IDLE_STATE, 0, Response::None, None)?;
+
+        let ocr = self.wait_for_power_up(MMC_OCR_VOLTAGES | OCR_HIGH_CAPACITY)?;
+        let high_capacity = ocr & OCR_HIGH_CAPACITY != 0;
+
+        let cid = self.send_command(CMD_ALL_SEND_CID, 0, Response::R2, None)?;
+        self.send_command(
+            CMD_SEND_RELATIVE_ADDR,
+            u32::from(MMC_RCA) << 16,
+            Response::R1,
+            None,
+        )?;
+        let csd = get_long_response(self.send_command(
+            CMD_SEND_CSD,
+            u32::from(MMC_RCA) << 16,
+            Response::R2,
+            None,
+        )?);
+
+        self.send_command(
+            CMD_SELECT_CARD,
+            u32::from(MMC_RCA) << 16,
+            Response::R1b,
+            None,
+        )?;
+
+        let transfer = Transfer {
+            block_size: BLOCK_SIZE,
+            block_count: 1,
+            write: false,
+        };
+        self.send_command(CMD_MMC_SEND_EXT_CSD, 0, Response::R1, Some(transfer))?;
+
+        let ext_csd = &self.dma.buffer[..BLOCK_SIZE];
+        let card_type = ext_csd[EXT_CSD_CARD_TYPE];
+        let mut sector_count = [0; 4];
+        sector_count.copy_from_slice(&ext_csd[EXT_CSD_SEC_COUNT..EXT_CSD_SEC_COUNT + 4]);
+
+        // Cards over 2GB are sector addressed and only report their size in the EXT_CSD.
+        let block_count = if high_capacity {
+            u64::from(u32::from_le_bytes(sector_count))
+        } else {
+            get_csd_v1_block_count(csd)
+        };
+
+        self.mmc_switch(EXT_CSD_BUS_WIDTH, EXT_CSD_BUS_WIDTH_8)?;
+        self.set_bus_width(8);
+
+        let clock_khz = if card_type & (EXT_CSD_CARD_TYPE_HS_26 | EXT_CSD_CARD_TYPE_HS_52) != 0 {
+            self.mmc_switch(EXT_CSD_HS_TIMING, 1)?;
+            self.set_high_speed(true);
+
+            if card_type & EXT_CSD_CARD_TYPE_HS_52 != 0 {
+                self.set_clock(MMC_HIGH_SPEED_52_CLOCK_KHZ)?
+            } else {
+                self.set_clock(MMC_HIGH_SPEED_26_CLOCK_KHZ)?
+            }
+        } else {
+            self.set_clock(MMC_LEGACY_CLOCK_KHZ)?
+        };
+
+        Ok(CardInfo {
+            card_type: CardType::Mmc,
+            high_capacity,
+            rca: MMC_RCA,
+            cid,
+            block_count,
+            bus_width: 8,
+            clock_khz,
+        })
+    }
+
+    /// Reset the controller and bring up the card, negotiating its bus width and speed.
+    pub fn init(&mut self) -> Result<&CardInfo, Error> {
+        self.card = None;
+
+        self.reset(SOFTWARE_RESET_ALL)?;
+        self.calibrate_pads();
+        self.power_on();
+
+        self.set_bits(
+            SDHCI_CLOCK_CONTROL,
+            TIMEOUT_CONTROL_MASK,
+            TIMEOUT_CONTROL_MAX,
+        );
+        self.registers
+            .write(SDHCI_INT_STATUS_ENABLE, INT_STATUS_ENABLE_MASK);
+        self.registers.write(SDHCI_INT_SIGNAL_ENABLE, 0);
+        self.set_bits(
+            SDHCI_HOST_CONTROL,
+            HOST_CONTROL_DMA_MASK,
+            HOST_CONTROL_DMA_ADMA2_32,
+        );
+
+        self.set_bus_width(1);
+        self.set_high_speed(false);
+        self.set_clock(IDENTIFICATION_CLOCK_KHZ)?;
+
+        let card = match self.card_type {
+            CardType::Sd => self.init_sd()?,
+            CardType::Mmc => self.init_mmc()?,
+        };
+
+        info!(
+            "{:?} card: {} blocks, {} bits bus at {}kHz",
+            card.card_type, card.block_count, card.bus_width, card.clock_khz
+        );
+
+        self.card = Some(card);
+
+        Ok(self.card.as_ref().unwrap())
+    }
+
+    /// Move `block_count` blocks between the card and the bounce buffer.
+    fn transfer_blocks(&mut self, lba: u64, block_count: usize, write: bool) -> Result<(), Error> {
+        let card = self.card.ok_or(Error::NotInitialized)?;
+
+        let argument = if card.high_capacity {
+            lba as u32
+        } else {
+            (lba * BLOCK_SIZE as u64) as u32
+        };
+
+        let command = match (write, block_count > 1) {
+            (false, false) => CMD_READ_SINGLE_BLOCK,
+            (false, true) => CMD_READ_MULTIPLE_BLOCK,
+            (true, false) => CMD_WRITE_BLOCK,
+            (true, true) => CMD_WRITE_MULTIPLE_BLOCK,
+        };
+
+        let transfer = Transfer {
+            block_size: BLOCK_SIZE,
+            block_count,
+            write,
+        };
+        self.send_command(command, argument, Response::R1, Some(transfer))?;
+
+        if write {
+            self.wait_until_ready(card.rca)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, R: SdmmcRegisters> BlockDevice for Sdmmc<'a, R> {
+    fn block_count(&self) -> u64 {
+        self.card.map(|card| card.block_count).unwrap_or(0)
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        block::check_range(self.block_count(), lba, buffer.len())?;
+
+        let mut chunk_lba = lba;
+        for chunk in buffer.chunks_mut(BOUNCE_BUFFER_SIZE) {
+            let block_count = chunk.len() / BLOCK_SIZE;
+
+            self.transfer_blocks(chunk_lba, block_count, false)
+                .map_err(|error| {
+                    warn!("SDMMC read at block {} failed: {:?}", chunk_lba, error);
+                    error
+                })?;
+            chunk.copy_from_slice(&self.dma.buffer[..chunk.len()]);
+
+            chunk_lba += block_count as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        block::check_range(self.block_count(), lba, buffer.len())?;
+
+        let mut chunk_lba = lba;
+        for chunk in buffer.chunks(BOUNCE_BUFFER_SIZE) {
+            let block_count = chunk.len() / BLOCK_SIZE;
+
+            self.dma.buffer[..chunk.len()].copy_from_slice(chunk);
+            self.transfer_blocks(chunk_lba, block_count, true)
+                .map_err(|error| {
+                    warn!("SDMMC write at block {} failed: {:?}", chunk_lba, error);
+                    error
+                })?;
+
+            chunk_lba += block_count as u64;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use core::cell::Cell;
+    use core::ptr;
+
+    const INT_COMMAND_TIMEOUT_ERROR: u32 = 1 << 16;
+    const INT_DATA_CRC_ERROR: u32 = 1 << 21;
+    const CARD_STATUS_ADDRESS_ERROR: u32 = 1 << 30;
+
+    const SD_RCA: u16 = 0x1234;
+    const CID: [u32; 4] = [0x1111_1111, 0x2222_2222, 0x3333_3333, 0x0044_4444];
+
+    /// A card answering commands like the SD and eMMC specifications tell.
+    struct Card {
+        card_type: CardType,
+        high_capacity: bool,
+        /// Answers SEND_IF_COND.
+        sd_version2: bool,
+        /// CSD of the card, CRC included.
+        csd: u128,
+        high_speed: bool,
+        /// SEND_OP_COND answers before the card is powered up.
+        power_up_polls: u32,
+        rca: u16,
+        selected: bool,
+        app_command: bool,
+        /// Reported in the status of the next command, like cards do.
+        illegal_command: bool,
+        /// Card status bits added to the response of a command.
+        status_error: Option<(u8, u32)>,
+        /// Data transfers of the command fail with a CRC error.
+        data_error: Option<u8>,
+        blocks: Vec<u8>,
+        switches: Vec<u32>,
+    }
+
+    /// What a command does on the data lines.
+    enum Data {
+        None,
+        Read(Vec<u8>),
+        /// Data to store at a byte offset.
+        Write(usize),
+    }
+
+    impl Card {
+        fn sd(high_capacity: bool, block_count: u64) -> Self {
+            let csd = if high_capacity {
+                (1 << 126) | (u128::from(block_count / 1024 - 1) << 48)
+            } else {
+                // 512 bytes blocks, 4 << 7 blocks per C_SIZE unit.
+                (9 << 80) | (u128::from(block_count / 512 - 1) << 62) | (7 << 47)
+            };
+
+            Card {
+                card_type: CardType::Sd,
+                high_capacity,
+                sd_version2: high_capacity,
+                csd,
+                high_speed: true,
+                power_up_polls: 2,
+                rca: SD_RCA,
+                selected: false,
+                app_command: false,
+                illegal_command: false,
+                status_error: None,
+                data_error: None,
+                blocks: vec![0; block_count as usize * BLOCK_SIZE],
+                switches: Vec::new(),
+            }
+        }
+
+        fn mmc(block_count: u64) -> Self {
+            Card {
+                card_type: CardType::Mmc,
+                high_capacity: true,
+                sd_version2: false,
+                // A CSD v1 of 2GB, the EXT_CSD tells the real size.
+                csd: (9 << 80) | (0xFFF << 62) | (7 << 47),
+                rca: 0,
+                ..Card::sd(true, block_count)
+            }
+        }
+
+        fn status(&mut self, command: u8) -> u32 {
+            let state = if self.selected {
+                CARD_STATE_TRANSFER
+            } else {
+                3
+            };
+            let mut status = (state << CARD_STATUS_STATE_SHIFT) | CARD_STATUS_READY_FOR_DATA;
+
+            if self.illegal_command {
+                self.illegal_command = false;
+                status |= CARD_STATUS_ILLEGAL_COMMAND;
+            }
+
+            match self.status_error {
+                Some((error_command, error)) if error_command == command => status | error,
+                _ => status,
+            }
+        }
+
+        fn offset(&self, argument: u32) -> usize {
+            if self.high_capacity {
+                argument as usize * BLOCK_SIZE
+            } else {
+                argument as usize
+            }
+        }
+
+        fn read_blocks(&self, argument: u32, size: usize) -> Data {
+            let offset = self.offset(argument);
+
+            Data::Read(self.blocks[offset..offset + size].to_vec())
+        }
+
+        /// The response, or None if the card stays silent.
+        fn respond(&mut self, command: u8, argument: u32, size: usize) -> Option<([u32; 4], Data)> {
+            let app_command = mem::replace(&mut self.app_command, false);
+            let r1 = |card: &mut Card| [card.status(command), 0, 0, 0];
+            let csd_words = |csd: u128| {
+                let csd = csd >> 8;
+                [
+                    csd as u32,
+                    (csd >> 32) as u32,
+                    (csd >> 64) as u32,
+                    (csd >> 96) as u32,
+                ]
+            };
+
+            let response = match (self.card_type, app_command, command) {
+                (_, _, CMD_GO_IDLE_STATE) => {
+                    self.selected = false;
+                    ([0; 4], Data::None)
+                }
+                (CardType::Sd, false, CMD_SEND_IF_COND) => {
+                    if !self.sd_version2 {
+                        self.illegal_command = true;
+                        return None;
+                    }
+
+                    ([argument & 0xFFF, 0, 0, 0], Data::None)
+                }
+                (CardType::Sd, _, CMD_APP_CMD) => {
+                    self.app_command = true;
+                    (r1(self), Data::None)
+                }
+                (CardType::Sd, true, ACMD_SD_SEND_OP_COND)
+                | (CardType::Mmc, false, CMD_MMC_SEND_OP_COND) => {
+                    let mut ocr = argument & 0x00FF_FF80;
+                    if self.high_capacity && argument & OCR_HIGH_CAPACITY != 0 {
+                        ocr |= OCR_HIGH_CAPACITY;
+                    }
+
+                    if self.power_up_polls == 0 {
+                        ocr |= OCR_POWER_UP_DONE;
+                    } else {
+                        self.power_up_polls -= 1;
+                    }
+
+                    ([ocr, 0, 0, 0], Data::None)
+                }
+                (_, _, CMD_ALL_SEND_CID) => (CID, Data::None),
+                (CardType::Sd, _, CMD_SEND_RELATIVE_ADDR) => {
+                    ([u32::from(self.rca) << 16, 0, 0, 0], Data::None)
+                }
+                (CardType::Mmc, _, CMD_SEND_RELATIVE_ADDR) => {
+                    self.rca = (argument >> 16) as u16;
+                    (r1(self), Data::None)
+                }
+                (_, _, CMD_SEND_CSD) => (csd_words(self.csd), Data::None),
+                (_, _, CMD_SELECT_CARD) => {
+                    assert_eq!(argument >> 16, u32::from(self.rca));
+                    self.selected = true;
+                    (r1(self), Data::None)
+                }
+                (CardType::Sd, true, ACMD_SET_BUS_WIDTH) | (_, _, CMD_SET_BLOCKLEN) => {
+                    (r1(self), Data::None)
+                }
+                (CardType::Sd, false, CMD_SWITCH) => {
+                    let mut status = vec![0; SD_SWITCH_STATUS_SIZE];
+                    status[16] = if self.high_speed { 1 } else { 0 };
+                    (r1(self), Data::Read(status))
+                }
+                (CardType::Mmc, false, CMD_SWITCH) => {
+                    self.switches.push(argument);
+                    (r1(self), Data::None)
+                }
+                (CardType::Mmc, false, CMD_MMC_SEND_EXT_CSD) => {
+                    let mut ext_csd = vec![0; BLOCK_SIZE];
+                    ext_csd[EXT_CSD_CARD_TYPE] = if self.high_speed {
+                        EXT_CSD_CARD_TYPE_HS_26 | EXT_CSD_CARD_TYPE_HS_52
+                    } else {
+                        0
+                    };
+                    let sector_count = (self.blocks.len() / BLOCK_SIZE) as u32;
+                    ext_csd[EXT_CSD_SEC_COUNT..EXT_CSD_SEC_COUNT + 4]
+                        .copy_from_slice(&sector_count.to_le_bytes());
+                    (r1(self), Data::Read(ext_csd))
+                }
+                (_, _, CMD_SEND_STATUS) => (r1(self), Data::None),
+                (_, _, CMD_READ_SINGLE_BLOCK) | (_, _, CMD_READ_MULTIPLE_BLOCK) => {
+                    (r1(self), self.read_blocks(argument, size))
+                }
+                (_, _, CMD_WRITE_BLOCK) | (_, _, CMD_WRITE_MULTIPLE_BLOCK) => {
+                    (r1(self), Data::Write(self.offset(argument)))
+                }
+                _ => {
+                    self.illegal_command = true;
+                    return None;
+                }
+            };
+
+            Some(response)
+        }
+    }
+
+    /// An SDHCI controller with `card` on its bus.
+    struct MockRegisters {
+        registers: [u32; 0x80],
+        time: Cell<u32>,
+        card: Card,
+        /// Commands sent, as (index, argument, transfer mode).
+        commands: Vec<(u8, u32, u32)>,
+        resets: Vec<u32>,
+        host_clocks: Vec<u32>,
+        /// Memory given to `map_dma`, its index is in the DMA address.
+        mappings: Vec<u64>,
+        /// The command line stays busy.
+        stuck: bool,
+    }
+
+    impl MockRegisters {
+        fn new(card: Card) -> Self {
+            MockRegisters {
+                registers: [0; 0x80],
+                time: Cell::new(0),
+                card,
+                commands: Vec::new(),
+                resets: Vec::new(),
+                host_clocks: Vec::new(),
+                mappings: Vec::new(),
+                stuck: false,
+            }
+        }
+
+        fn command_indexes(&self) -> Vec<u8> {
+            self.commands.iter().map(|command| command.0).collect()
+        }
+
+        fn translate(&self, dma_address: u32) -> *mut u8 {
+            let address = self.mappings[(dma_address >> 24) as usize - 1];
+
+            (address + u64::from(dma_address & 0xFF_FFFF)) as *mut u8
+        }
+
+        /// Move `size` bytes through the ADMA2 descriptors, from or to `data`.
+        fn run_dma(&mut self, data: &mut [u8], write: bool) {
+            let mut descriptor = self.translate(self.registers[SDHCI_ADMA_SYSTEM_ADDRESS / 4]);
+            let mut offset = 0;
+
+            loop {
+                let entry = unsafe { ptr::read(descriptor as *const AdmaDescriptor) };
+                assert_ne!(entry.attributes & ADMA_VALID, 0);
+
+                let length = entry.length as usize;
+                let memory = self.translate(entry.address);
+                unsafe {
+                    if write {
+                        ptr::copy(memory, data[offset..].as_mut_ptr(), length);
+                    } else {
+                        ptr::copy(data[offset..].as_ptr(), memory, length);
+                    }
+                }
+                offset += length;
+
+                if entry.attributes & ADMA_END != 0 {
+                    break;
+                }
+                descriptor = unsafe { descriptor.add(mem::size_of::<AdmaDescriptor>()) };
+            }
+
+            assert_eq!(offset, data.len());
+        }
+
+        fn issue(&mut self, value: u32) {
+            let command = ((value >> 24) & 0x3F) as u8;
+            let flags = (value >> 16) & 0xFF;
+            let argument = self.registers[SDHCI_ARGUMENT / 4];
+            let block = self.registers[SDHCI_BLOCK / 4];
+            let size = (block & 0xFFF) as usize * (block >> 16) as usize;
+
+            self.commands.push((command, argument, value & 0xFFFF));
+
+            let (response, data) = match self.card.respond(command, argument, size) {
+                Some(response) => response,
+                None => {
+                    self.registers[SDHCI_INT_STATUS / 4] |= INT_ERROR | INT_COMMAND_TIMEOUT_ERROR;
+                    return;
+                }
+            };
+
+            self.registers[SDHCI_RESPONSE / 4..SDHCI_RESPONSE / 4 + 4].copy_from_slice(&response);
+            self.registers[SDHCI_INT_STATUS / 4] |= INT_COMMAND_COMPLETE;
+
+            if flags & COMMAND_DATA_PRESENT != 0 {
+                if self.card.data_error == Some(command) {
+                    self.registers[SDHCI_INT_STATUS / 4] |= INT_ERROR | INT_DATA_CRC_ERROR;
+                    return;
+                }
+
+                match data {
+                    Data::Read(mut data) => {
+                        assert_ne!(value & TRANSFER_MODE_READ, 0);
+                        self.run_dma(&mut data, false);
+                    }
+                    Data::Write(offset) => {
+                        assert_eq!(value & TRANSFER_MODE_READ, 0);
+                        let mut data = vec![0; size];
+                        self.run_dma(&mut data, true);
+                        self.card.blocks[offset..offset + size].copy_from_slice(&data);
+                    }
+                    Data::None => panic!("No data for command {}", command),
+                }
+            }
+
+            if flags & COMMAND_DATA_PRESENT != 0 || flags & 3 == COMMAND_RESPONSE_48_BUSY {
+                self.registers[SDHCI_INT_STATUS / 4] |= INT_TRANSFER_COMPLETE;
+            }
+        }
+    }
+
+    impl SdmmcRegisters for MockRegisters {
+        fn read(&self, offset: usize) -> u32 {
+            match offset {
+                SDHCI_PRESENT_STATE if self.stuck => PRESENT_STATE_COMMAND_INHIBIT,
+                _ => self.registers[offset / 4],
+            }
+        }
+
+        fn write(&mut self, offset: usize, value: u32) {
+            match offset {
+                SDHCI_CLOCK_CONTROL => {
+                    let resets = SOFTWARE_RESET_ALL | SOFTWARE_RESET_COMMAND | SOFTWARE_RESET_DATA;
+                    if value & resets != 0 {
+                        self.resets.push(value & resets);
+                    }
+
+                    let mut value = value & !resets;
+                    if value & CLOCK_CONTROL_INTERNAL_ENABLE != 0 {
+                        value |= CLOCK_CONTROL_INTERNAL_STABLE;
+                    }
+                    self.registers[offset / 4] = value;
+                }
+                // Write 1 to clear.
+                SDHCI_INT_STATUS => self.registers[offset / 4] &= !value,
+                SDHCI_TRANSFER_MODE => {
+                    self.registers[offset / 4] = value;
+                    self.issue(value);
+                }
+                TEGRA_AUTO_CAL_CONFIG => {
+                    self.registers[offset / 4] = value & !AUTO_CAL_CONFIG_START
+                }
+                _ => self.registers[offset / 4] = value,
+            }
+        }
+
+        fn set_host_clock(&mut self, rate_khz: u32) -> u32 {
+            self.host_clocks.push(rate_khz);
+            rate_khz
+        }
+
+        fn get_microseconds(&self) -> u32 {
+            let time = self.time.get();
+            self.time.set(time.wrapping_add(10));
+            time
+        }
+
+        fn map_dma(&mut self, address: u64, _size: usize) -> Option<u32> {
+            let index = match self.mappings.iter().position(|mapping| *mapping == address) {
+                Some(index) => index,
+                None => {
+                    self.mappings.push(address);
+                    self.mappings.len() - 1
+                }
+            };
+
+            Some(((index as u32) + 1) << 24)
+        }
+
+        fn unmap_dma(&mut self, _address: u64, _size: usize) {}
+    }
+
+    #[test]
+    fn sd_high_capacity_init() {
+        let mut dma = Box::new(DmaMemory::new());
+        let mut sdmmc = Sdmmc::new(
+            MockRegisters::new(Card::sd(true, 0x10_0000)),
+            &mut dma,
+            CardType::Sd,
+        );
+
+        let card = *sdmmc.init().unwrap();
+        assert_eq!(card.card_type, CardType::Sd);
+        assert!(card.high_capacity);
+        assert_eq!(card.rca, SD_RCA);
+        assert_eq!(card.cid, CID);
+        assert_eq!(card.block_count, 0x10_0000);
+        assert_eq!(card.bus_width, 4);
+        assert_eq!(card.clock_khz, SD_HIGH_SPEED_CLOCK_KHZ);
+
+        let registers = sdmmc.registers();
+        assert_eq!(
+            registers.command_indexes(),
+            [0, 8, 55, 41, 55, 41, 55, 41, 2, 3, 9, 7, 55, 6, 6]
+        );
+        // Host capacity support is only asked after SEND_IF_COND succeeded.
+        assert_eq!(registers.commands[3].1, SD_OCR_VOLTAGES | OCR_HIGH_CAPACITY);
+        assert_eq!(registers.resets, [SOFTWARE_RESET_ALL]);
+        assert_eq!(registers.host_clocks[0], MIN_HOST_CLOCK_KHZ);
+
+        let host_control = registers.read(SDHCI_HOST_CONTROL);
+        assert_eq!(
+            host_control & (HOST_CONTROL_DATA_WIDTH_4 | HOST_CONTROL_HIGH_SPEED),
+            HOST_CONTROL_DATA_WIDTH_4 | HOST_CONTROL_HIGH_SPEED
+        );
+        assert_eq!(
+            host_control & HOST_CONTROL_DMA_MASK,
+            HOST_CONTROL_DMA_ADMA2_32
+        );
+        assert_eq!(
+            host_control & POWER_CONTROL_MASK,
+            POWER_CONTROL_3V3 | POWER_CONTROL_ON
+        );
+    }
+
+    #[test]
+    fn sd_version1_init() {
+        let mut card = Card::sd(false, 0x2_0000);
+        card.high_speed = false;
+
+        let mut dma = Box::new(DmaMemory::new());
+        let mut sdmmc = Sdmmc::new(MockRegisters::new(card), &mut dma, CardType::Sd);
+
+        let card = *sdmmc.init().unwrap();
+        assert!(!card.high_capacity);
+        assert_eq!(card.block_count, 0x2_0000);
+        assert_eq!(card.clock_khz, SD_DEFAULT_CLOCK_KHZ);
+
+        let registers = sdmmc.registers();
+        // The first APP_CMD reports SEND_IF_COND as illegal, which isn't an error.
+        assert_eq!(
+            registers.command_indexes(),
+            [0, 8, 55, 41, 55, 41, 55, 41, 2, 3, 9, 7, 55, 6, 16, 6]
+        );
+        assert_eq!(registers.commands[3].1, SD_OCR_VOLTAGES);
+        assert_eq!(
+            registers.resets,
+            [
+                SOFTWARE_RESET_ALL,
+                SOFTWARE_RESET_COMMAND | SOFTWARE_RESET_DATA
+            ]
+        );
+        assert_eq!(
+            registers.read(SDHCI_HOST_CONTROL) & HOST_CONTROL_HIGH_SPEED,
+            0
+        );
+
+        // Byte addressed.
+        let mut buffer = vec![0; BLOCK_SIZE];
+        sdmmc.read_blocks(3, &mut buffer).unwrap();
+        assert_eq!(
+            *sdmmc.registers().commands.last().unwrap(),
+            (
+                CMD_READ_SINGLE_BLOCK,
+                3 * BLOCK_SIZE as u32,
+                TRANSFER_MODE_DMA | TRANSFER_MODE_BLOCK_COUNT | TRANSFER_MODE_READ
+            )
+        );
+    }
+
+    #[test]
+    fn mmc_init() {
+        let mut dma = Box::new(DmaMemory::new());
+        let mut sdmmc = Sdmmc::new(
+            MockRegisters::new(Card::mmc(0x74_0000)),
+            &mut dma,
+            CardType::Mmc,
+        );
+
+        let card = *sdmmc.init().unwrap();
+        assert_eq!(card.card_type, CardType::Mmc);
+        assert!(card.high_capacity);
+        assert_eq!(card.rca, MMC_RCA);
+        assert_eq!(card.block_count, 0x74_0000);
+        assert_eq!(card.bus_width, 8);
+        assert_eq!(card.clock_khz, MMC_HIGH_SPEED_52_CLOCK_KHZ);
+
+        let registers = sdmmc.registers();
+        assert_eq!(
+            registers.command_indexes(),
+            [0, 1, 1, 1, 2, 3, 9, 7, 8, 6, 13, 6, 13]
+        );
+        assert_eq!(
+            registers.card.switches,
+            [
+                MMC_SWITCH_WRITE_BYTE | (EXT_CSD_BUS_WIDTH << 16) | (EXT_CSD_BUS_WIDTH_8 << 8),
+                MMC_SWITCH_WRITE_BYTE | (EXT_CSD_HS_TIMING << 16) | (1 << 8),
+            ]
+        );
+        assert_eq!(
+            registers.read(SDHCI_HOST_CONTROL) & POWER_CONTROL_MASK,
+            POWER_CONTROL_1V8 | POWER_CONTROL_ON
+        );
+    }
+
+    #[test]
+    fn clock_divider() {
+        let mut dma = Box::new(DmaMemory::new());
+        let mut sdmmc = Sdmmc::new(
+            MockRegisters::new(Card::mmc(0x1000)),
+            &mut dma,
+            CardType::Mmc,
+        );
+
+        // 24MHz divided by 2 * 30.
+        assert_eq!(sdmmc.set_clock(IDENTIFICATION_CLOCK_KHZ), Ok(400));
+        let clock_control = sdmmc.registers().read(SDHCI_CLOCK_CONTROL);
+        assert_eq!(clock_control & CLOCK_CONTROL_DIVIDER_MASK, 30 << 8);
+        assert_ne!(clock_control & CLOCK_CONTROL_SD_ENABLE, 0);
+
+        // The upper 2 bits of the divider.
+        assert_eq!(sdmmc.set_clock(10), Ok(MIN_HOST_CLOCK_KHZ / (2 * 0x3FF)));
+        let clock_control = sdmmc.registers().read(SDHCI_CLOCK_CONTROL);
+        assert_eq!(clock_control & CLOCK_CONTROL_DIVIDER_MASK, 0xFFC0);
+
+        assert_eq!(sdmmc.set_clock(52_000), Ok(52_000));
+        let clock_control = sdmmc.registers().read(SDHCI_CLOCK_CONTROL);
+        assert_eq!(clock_control & CLOCK_CONTROL_DIVIDER_MASK, 0);
+    }
+
+    #[test]
+    fn read_write() {
+        let mut dma = Box::new(DmaMemory::new());
+        let mut sdmmc = Sdmmc::new(
+            MockRegisters::new(Card::sd(true, 0x1000)),
+            &mut dma,
+            CardType::Sd,
+        );
+        sdmmc.init().unwrap();
+
+        // More than the bounce buffer, split in two transfers.
+        let block_count = BOUNCE_BUFFER_SIZE / BLOCK_SIZE + 3;
+        let data = test_support::pattern(block_count * BLOCK_SIZE, 0);
+        let command_count = sdmmc.registers().commands.len();
+        sdmmc.write_blocks(10, &data).unwrap();
+
+        let registers = sdmmc.registers();
+        let multi_block = TRANSFER_MODE_DMA
+            | TRANSFER_MODE_BLOCK_COUNT
+            | TRANSFER_MODE_MULTI_BLOCK
+            | TRANSFER_MODE_AUTO_CMD12;
+        assert_eq!(
+            registers.commands[command_count..],
+            [
+                (CMD_WRITE_MULTIPLE_BLOCK, 10, multi_block),
+                (CMD_SEND_STATUS, u32::from(SD_RCA) << 16, 0),
+                (CMD_WRITE_MULTIPLE_BLOCK, 10 + 128, multi_block),
+                (CMD_SEND_STATUS, u32::from(SD_RCA) << 16, 0),
+            ]
+        );
+        assert_eq!(
+            registers.card.blocks[10 * BLOCK_SIZE..10 * BLOCK_SIZE + data.len()],
+            data[..]
+        );
+
+        let mut buffer = vec![0; data.len()];
+        sdmmc.read_blocks(10, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+
+        let mut block = vec![0; BLOCK_SIZE];
+        sdmmc.read_blocks(11, &mut block).unwrap();
+        assert_eq!(block, data[BLOCK_SIZE..2 * BLOCK_SIZE]);
+        assert_eq!(
+            *sdmmc.registers().commands.last().unwrap(),
+            (
+                CMD_READ_SINGLE_BLOCK,
+                11,
+                TRANSFER_MODE_DMA | TRANSFER_MODE_BLOCK_COUNT | TRANSFER_MODE_READ
+            )
+        );
+    }
+
+    #[test]
+    fn access_errors() {
+        let mut dma = Box::new(DmaMemory::new());
+        let mut sdmmc = Sdmmc::new(
+            MockRegisters::new(Card::sd(true, 0x1000)),
+            &mut dma,
+            CardType::Sd,
+        );
+        let mut buffer = vec![0; BLOCK_SIZE];
+
+        // Nothing is there before init.
+        assert_eq!(sdmmc.block_count(), 0);
+        assert_eq!(
+            sdmmc.transfer_blocks(0, 1, false),
+            Err(Error::NotInitialized)
+        );
+
+        sdmmc.init().unwrap();
+        assert_eq!(
+            sdmmc.read_blocks(0x1000, &mut buffer),
+            Err(block::Error::OutOfRange)
+        );
+        assert_eq!(
+            sdmmc.read_blocks(0, &mut buffer[..100]),
+            Err(block::Error::BadBufferSize)
+        );
+        assert!(sdmmc.registers().commands.len() < 20);
+    }
+
+    #[test]
+    fn transfer_errors() {
+        let mut card = Card::sd(true, 0x1000);
+        card.data_error = Some(CMD_READ_SINGLE_BLOCK);
+        card.status_error = Some((CMD_WRITE_BLOCK, CARD_STATUS_ADDRESS_ERROR));
+
+        let mut dma = Box::new(DmaMemory::new());
+        let mut sdmmc = Sdmmc::new(MockRegisters::new(card), &mut dma, CardType::Sd);
+        sdmmc.init().unwrap();
+
+        // The controller reports the CRC error, its command and data lines are reset.
+        assert_eq!(
+            sdmmc.transfer_blocks(0, 1, false),
+            Err(Error::Controller {
+                command: CMD_READ_SINGLE_BLOCK,
+                status: (INT_DATA_CRC_ERROR >> 16) as u16,
+            })
+        );
+        assert_eq!(
+            sdmmc.registers().resets.last(),
+            Some(&(SOFTWARE_RESET_COMMAND | SOFTWARE_RESET_DATA))
+        );
+
+        let mut buffer = vec![0; BLOCK_SIZE];
+        assert_eq!(sdmmc.read_blocks(0, &mut buffer), Err(block::Error::Io));
+
+        // The error is cleared for the next command.
+        let mut buffer = vec![0; 2 * BLOCK_SIZE];
+        sdmmc.read_blocks(1, &mut buffer).unwrap();
+
+        let status = (CARD_STATE_TRANSFER << CARD_STATUS_STATE_SHIFT)
+            | CARD_STATUS_READY_FOR_DATA
+            | CARD_STATUS_ADDRESS_ERROR;
+        assert_eq!(
+            sdmmc.transfer_blocks(0, 1, true),
+            Err(Error::CardStatus {
+                command: CMD_WRITE_BLOCK,
+                status,
+            })
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut card = Card::sd(true, 0x1000);
+        card.power_up_polls = u32::MAX;
+
+        let mut dma = Box::new(DmaMemory::new());
+        let mut sdmmc = Sdmmc::new(MockRegisters::new(card), &mut dma, CardType::Sd);
+        assert_eq!(sdmmc.init().err(), Some(Error::Timeout));
+        assert!(sdmmc.card_info().is_none());
+
+        let mut registers = MockRegisters::new(Card::mmc(0x1000));
+        registers.stuck = true;
+        let mut sdmmc = Sdmmc::new(registers, &mut dma, CardType::Mmc);
+        assert_eq!(sdmmc.init().err(), Some(Error::Timeout));
+        assert!(sdmmc.registers().commands.is_empty());
+
+        // A card answering nothing at all.
+        let mut card = Card::mmc(0x1000);
+        card.card_type = CardType::Sd;
+        let mut sdmmc = Sdmmc::new(MockRegisters::new(card), &mut dma, CardType::Mmc);
+        assert_eq!(
+            sdmmc.init().err(),
+            Some(Error::Controller {
+                command: CMD_MMC_SEND_OP_COND,
+                status: (INT_COMMAND_TIMEOUT_ERROR >> 16) as u16,
+            })
+        );
+    }
+}
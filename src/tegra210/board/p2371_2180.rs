@@ -5,6 +5,32 @@ use libtegra::pinmux::{
     PinFunction, PinGrP, PinIo, PinIoHv as PinEIoHv, PinLock, PinOd, PinPull, PinTristate,
 };
 
+/// DRAM banks of the 4GB of the P2180 module, as (address, size).
+pub const DRAM_BANKS: [(u64, u64); 1] = [(0x8000_0000, 0x1_0000_0000)];
+
+/// DRAM region the Linux kernel is placed in, after rboot and its page tables.
+pub const KERNEL_ADDRESS: u64 = 0x8100_0000;
+pub const KERNEL_SIZE: u64 = 0x0D00_0000;
+
+/// DRAM carveout holding the persistent log, kept untouched across warm reboots.
+pub const PERSISTENT_LOG_ADDRESS: u64 = 0x8E00_0000;
+pub const PERSISTENT_LOG_SIZE: u64 = 0x10_0000;
+
+/// DRAM region the device tree handed to Linux is prepared in.
+pub const DEVICE_TREE_ADDRESS: u64 = 0x8E10_0000;
+pub const DEVICE_TREE_SIZE: u64 = 0x10_0000;
+
+/// DRAM carveouts that must stay out of the way of payloads, as (name, address, size).
+pub const CARVEOUTS: [(&str, u64, u64); 1] = [(
+    "persistent-log",
+    PERSISTENT_LOG_ADDRESS,
+    PERSISTENT_LOG_SIZE,
+)];
+
+/// DRAM region receiving payloads uploaded over the UART or read from storage, mapped at boot.
+pub const PAYLOAD_ADDRESS: u64 = 0x9000_0000;
+pub const PAYLOAD_SIZE: u64 = 0x1000_0000;
+
 pub const GPIO_CONFIG: [(Gpio, gpio::Config); 59] = [
     (tegra_gpio!(A, 5), gpio::Config::Input),
     (tegra_gpio!(B, 0), gpio::Config::Input),
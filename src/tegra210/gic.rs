@@ -0,0 +1,292 @@
+//! GIC-400 distributor and CPU interface driver.
+
+use register::mmio::{ReadOnly, ReadWrite, WriteOnly};
+
+use crate::utils;
+
+#[allow(dead_code)]
+#[repr(C)]
+struct DistributorRegisters {
+    ctlr: ReadWrite<u32>,
+    typer: ReadOnly<u32>,
+    iidr: ReadOnly<u32>,
+    _reserved0: [u32; 29],
+    igroupr: [ReadWrite<u32>; 32],
+    isenabler: [ReadWrite<u32>; 32],
+    icenabler: [ReadWrite<u32>; 32],
+    ispendr: [ReadWrite<u32>; 32],
+    icpendr: [ReadWrite<u32>; 32],
+    isactiver: [ReadWrite<u32>; 32],
+    icactiver: [ReadWrite<u32>; 32],
+    ipriorityr: [ReadWrite<u8>; 1024],
+    itargetsr: [ReadWrite<u8>; 1024],
+    icfgr: [ReadWrite<u32>; 64],
+    _reserved1: [u32; 64],
+    nsacr: [ReadWrite<u32>; 64],
+    sgir: WriteOnly<u32>,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct CpuInterfaceRegisters {
+    ctlr: ReadWrite<u32>,
+    pmr: ReadWrite<u32>,
+    bpr: ReadWrite<u32>,
+    iar: ReadOnly<u32>,
+    eoir: WriteOnly<u32>,
+    rpr: ReadOnly<u32>,
+    hppir: ReadOnly<u32>,
+}
+
+const GICD: *const DistributorRegisters = 0x5004_1000 as *const DistributorRegisters;
+const GICC: *const CpuInterfaceRegisters = 0x5004_2000 as *const CpuInterfaceRegisters;
+
+/// Interrupt IDs handled by the driver (SGIs, PPIs and the Tegra210 SPIs).
+pub const MAX_INTERRUPTS: usize = 256;
+
+/// First shared peripheral interrupt ID.
+pub const SPI_BASE: u32 = 32;
+
+/// Interrupt IDs starting from this value are special (1023 is spurious).
+const SPECIAL_INTERRUPT_BASE: u32 = 1020;
+
+/// Lowest priority, used as default for all interrupts.
+pub const LOWEST_PRIORITY: u8 = 0xF0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Trigger {
+    Level,
+    Edge,
+}
+
+pub type IrqHandler = fn(irq: u32);
+
+static mut IRQ_HANDLERS: [Option<IrqHandler>; MAX_INTERRUPTS] = [None; MAX_INTERRUPTS];
+
+fn split_bit(irq: u32) -> (usize, u32) {
+    ((irq / 32) as usize, 1 << (irq % 32))
+}
+
+/// Number of interrupt IDs implemented by the distributor.
+pub fn get_interrupt_count() -> usize {
+    let lines = unsafe { (*GICD).typer.get() } & 0x1f;
+
+    core::cmp::min(32 * (lines as usize + 1), MAX_INTERRUPTS)
+}
+
+/// Route physical IRQs and FIQs to the current EL so they can be taken while running there.
+fn route_irqs_to_current_el() {
+    unsafe {
+        match utils::get_current_el() {
+            3 => {
+                let mut scr: u64;
+                asm!("mrs {scr}, scr_el3", scr = out(reg) scr, options(nostack));
+                // SCR_EL3.IRQ and SCR_EL3.FIQ
+                scr |= (1 << 1) | (1 << 2);
+                asm!("msr scr_el3, {scr}", scr = in(reg) scr, options(nostack));
+            }
+            2 => {
+                let mut hcr: u64;
+                asm!("mrs {hcr}, hcr_el2", hcr = out(reg) hcr, options(nostack));
+                // HCR_EL2.IMO and HCR_EL2.FMO
+                hcr |= (1 << 4) | (1 << 3);
+                asm!("msr hcr_el2, {hcr}", hcr = in(reg) hcr, options(nostack));
+            }
+            _ => {}
+        }
+
+        asm!("isb");
+    }
+}
+
+/// Reset the distributor and CPU interface with every interrupt disabled.
+pub fn init() {
+    let interrupt_count = get_interrupt_count();
+
+    unsafe {
+        (*GICD).ctlr.set(0);
+
+        for index in 0..interrupt_count / 32 {
+            (*GICD).icenabler[index].set(0xFFFF_FFFF);
+            (*GICD).icpendr[index].set(0xFFFF_FFFF);
+            (*GICD).icactiver[index].set(0xFFFF_FFFF);
+
+            // Group 0 when secure, write ignored otherwise.
+            (*GICD).igroupr[index].set(0);
+        }
+
+        for irq in 0..interrupt_count {
+            (*GICD).ipriorityr[irq].set(LOWEST_PRIORITY);
+        }
+
+        // SGIs and PPIs targets are read-only, route all SPIs to CPU0.
+        for irq in SPI_BASE as usize..interrupt_count {
+            (*GICD).itargetsr[irq].set(1);
+        }
+
+        // All SPIs are level-sensitive by default.
+        for index in (SPI_BASE as usize / 16)..(interrupt_count / 16) {
+            (*GICD).icfgr[index].set(0);
+        }
+
+        (*GICD).ctlr.set(1);
+
+        // Accept all priorities, no preemption.
+        (*GICC).pmr.set(0xFF);
+        (*GICC).bpr.set(7);
+        (*GICC).ctlr.set(1);
+    }
+
+    route_irqs_to_current_el();
+}
+
+/// Register a handler for an interrupt ID, returning the previous one.
+pub fn register_handler(irq: u32, handler: Option<IrqHandler>) -> Option<IrqHandler> {
+    assert!((irq as usize) < MAX_INTERRUPTS, "Invalid interrupt {}", irq);
+
+    unsafe {
+        let previous_handler = IRQ_HANDLERS[irq as usize];
+        IRQ_HANDLERS[irq as usize] = handler;
+
+        previous_handler
+    }
+}
+
+pub fn enable(irq: u32) {
+    let (index, bit) = split_bit(irq);
+
+    unsafe { (*GICD).isenabler[index].set(bit) };
+}
+
+pub fn disable(irq: u32) {
+    let (index, bit) = split_bit(irq);
+
+    unsafe { (*GICD).icenabler[index].set(bit) };
+}
+
+pub fn is_enabled(irq: u32) -> bool {
+    let (index, bit) = split_bit(irq);
+
+    unsafe { (*GICD).isenabler[index].get() & bit != 0 }
+}
+
+pub fn is_pending(irq: u32) -> bool {
+    let (index, bit) = split_bit(irq);
+
+    unsafe { (*GICD).ispendr[index].get() & bit != 0 }
+}
+
+pub fn clear_pending(irq: u32) {
+    let (index, bit) = split_bit(irq);
+
+    unsafe { (*GICD).icpendr[index].set(bit) };
+}
+
+/// Set the priority of an interrupt, lower values are more urgent.
+pub fn set_priority(irq: u32, priority: u8) {
+    unsafe { (*GICD).ipriorityr[irq as usize].set(priority) };
+}
+
+pub fn get_priority(irq: u32) -> u8 {
+    unsafe { (*GICD).ipriorityr[irq as usize].get() }
+}
+
+/// Select the trigger mode of a PPI or SPI (SGIs are always edge-triggered).
+pub fn set_trigger(irq: u32, trigger: Trigger) {
+    let index = (irq / 16) as usize;
+    let shift = (irq % 16) * 2 + 1;
+
+    unsafe {
+        let mut value = (*GICD).icfgr[index].get();
+
+        match trigger {
+            Trigger::Level => value &= !(1 << shift),
+            Trigger::Edge => value |= 1 << shift,
+        }
+
+        (*GICD).icfgr[index].set(value);
+    }
+}
+
+/// Send a software generated interrupt to the current CPU.
+pub fn send_sgi_to_self(sgi: u32) {
+    // TargetListFilter = 0b10: the CPU that requested the interrupt
+    unsafe { (*GICD).sgir.set((0b10 << 24) | (sgi & 0xf)) };
+}
+
+/// Signal group 0 interrupts as FIQs instead of IRQs (GICC_CTLR.FIQEn).
+///
+/// Only the secure state sees group 0 interrupts, the write is ignored otherwise.
+pub fn set_group0_as_fiq(enabled: bool) {
+    unsafe {
+        let mut ctlr = (*GICC).ctlr.get();
+
+        if enabled {
+            ctlr |= 1 << 3;
+        } else {
+            ctlr &= !(1 << 3);
+        }
+
+        (*GICC).ctlr.set(ctlr);
+    }
+}
+
+/// Unmask IRQs at the CPU level.
+pub fn enable_cpu_irqs() {
+    unsafe { asm!("msr daifclr, #2", options(nostack)) };
+}
+
+/// Mask IRQs at the CPU level.
+pub fn disable_cpu_irqs() {
+    unsafe { asm!("msr daifset, #2", options(nostack)) };
+}
+
+/// Unmask FIQs at the CPU level.
+pub fn enable_cpu_fiqs() {
+    unsafe { asm!("msr daifclr, #1", options(nostack)) };
+}
+
+/// Mask FIQs at the CPU level.
+pub fn disable_cpu_fiqs() {
+    unsafe { asm!("msr daifset, #1", options(nostack)) };
+}
+
+/// Acknowledge and dispatch all pending interrupts.
+pub fn handle_irq() {
+    dispatch_pending_interrupts();
+}
+
+/// Acknowledge and dispatch the interrupts signalled as FIQs.
+///
+/// They are acknowledged through the same CPU interface registers as IRQs, and go to the
+/// same handlers: only how the CPU is interrupted differs.
+pub fn handle_fiq() {
+    dispatch_pending_interrupts();
+}
+
+fn dispatch_pending_interrupts() {
+    loop {
+        let iar = unsafe { (*GICC).iar.get() };
+        let irq = iar & 0x3ff;
+
+        if irq >= SPECIAL_INTERRUPT_BASE {
+            break;
+        }
+
+        let handler = if (irq as usize) < MAX_INTERRUPTS {
+            unsafe { IRQ_HANDLERS[irq as usize] }
+        } else {
+            None
+        };
+
+        match handler {
+            Some(handler) => handler(irq),
+            None => {
+                warn!("Unhandled IRQ {}, disabling it", irq);
+                disable(irq);
+            }
+        }
+
+        unsafe { (*GICC).eoir.set(iar) };
+    }
+}
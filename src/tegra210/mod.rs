@@ -1 +1,6 @@
 pub mod board;
+pub mod gic;
+pub mod sdmmc;
+pub mod timer;
+pub mod uart;
+pub mod usb;
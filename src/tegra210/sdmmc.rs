@@ -0,0 +1,171 @@
+//! SDMMC controllers of the Tegra X1, for the eMMC on SDMMC4 and SD cards on SDMMC1.
+//!
+//! The command state machine lives in [`crate::sdmmc`], this module gives it the registers,
+//! clocks and DMA of the controllers. The SD slot has to be powered by the earlier boot
+//! stages.
+
+use core::cmp;
+use core::ptr;
+
+use crate::mmu;
+use crate::sdmmc::MIN_HOST_CLOCK_KHZ;
+pub use crate::sdmmc::{CardInfo, CardType, DmaMemory, Error, Sdmmc, SdmmcRegisters};
+use crate::tegra210::timer;
+
+const CAR_BASE: u64 = 0x6000_6000;
+const CAR_RST_DEV_L_SET: u64 = 0x300;
+const CAR_RST_DEV_L_CLR: u64 = 0x304;
+const CAR_CLK_ENB_L_SET: u64 = 0x320;
+const PLLP_KHZ: u32 = 408_000;
+
+/// The SDMMC controllers wired to storage on the board.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Controller {
+    /// The SD card slot.
+    Sdmmc1,
+    /// The eMMC of the P2180 module.
+    Sdmmc4,
+}
+
+impl Controller {
+    fn get_base(self) -> u64 {
+        match self {
+            Controller::Sdmmc1 => 0x700B_0000,
+            Controller::Sdmmc4 => 0x700B_0600,
+        }
+    }
+
+    /// Bit of the controller in the CAR L registers.
+    fn get_car_bit(self) -> u32 {
+        match self {
+            Controller::Sdmmc1 => 14,
+            Controller::Sdmmc4 => 15,
+        }
+    }
+
+    /// Offset of CLK_SOURCE_SDMMCx in the CAR.
+    fn get_clock_source(self) -> u64 {
+        match self {
+            Controller::Sdmmc1 => 0x150,
+            Controller::Sdmmc4 => 0x164,
+        }
+    }
+
+    pub fn get_card_type(self) -> CardType {
+        match self {
+            Controller::Sdmmc1 => CardType::Sd,
+            Controller::Sdmmc4 => CardType::Mmc,
+        }
+    }
+}
+
+fn write_car(offset: u64, value: u32) {
+    unsafe { ptr::write_volatile((CAR_BASE + offset) as *mut u32, value) };
+}
+
+fn wait_microseconds(duration: u32) {
+    let start = timer::get_microseconds();
+
+    while timer::get_elapsed_microseconds(start) < duration {}
+}
+
+/// The registers of a controller, with clocks from the CAR and DMA to DRAM.
+pub struct MmioRegisters {
+    controller: Controller,
+}
+
+impl MmioRegisters {
+    /// Take `controller` out of reset with its clock running.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else must be using the controller.
+    pub unsafe fn new(controller: Controller) -> Self {
+        let mut registers = MmioRegisters { controller };
+        let bit = 1 << controller.get_car_bit();
+
+        write_car(CAR_RST_DEV_L_SET, bit);
+        write_car(CAR_CLK_ENB_L_SET, bit);
+        registers.set_host_clock(MIN_HOST_CLOCK_KHZ);
+        wait_microseconds(2);
+        write_car(CAR_RST_DEV_L_CLR, bit);
+
+        registers
+    }
+}
+
+impl SdmmcRegisters for MmioRegisters {
+    fn read(&self, offset: usize) -> u32 {
+        let address = self.controller.get_base() + offset as u64;
+
+        unsafe { ptr::read_volatile(address as *const u32) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        let address = self.controller.get_base() + offset as u64;
+
+        unsafe { ptr::write_volatile(address as *mut u32, value) };
+    }
+
+    fn set_host_clock(&mut self, rate_khz: u32) -> u32 {
+        // PLLP divided by 1 + N / 2, PLLP being source 0.
+        let divisor = ((2 * PLLP_KHZ + rate_khz - 1) / rate_khz).saturating_sub(2);
+        let divisor = cmp::min(divisor, 0xFF);
+
+        write_car(self.controller.get_clock_source(), divisor);
+        wait_microseconds(2);
+
+        2 * PLLP_KHZ / (divisor + 2)
+    }
+
+    fn get_microseconds(&self) -> u32 {
+        timer::get_microseconds()
+    }
+
+    fn map_dma(&mut self, address: u64, size: usize) -> Option<u32> {
+        // Physical and virtual addresses are the same, 32 bits ADMA2 only reaches 4GB.
+        if address + size as u64 > 1 << 32 {
+            return None;
+        }
+
+        mmu::clean_invalidate_dcache_range(address, size as u64);
+
+        Some(address as u32)
+    }
+
+    fn unmap_dma(&mut self, address: u64, size: usize) {
+        mmu::clean_invalidate_dcache_range(address, size as u64);
+    }
+}
+
+static mut SDMMC1_DMA: DmaMemory = DmaMemory::new();
+static mut SDMMC4_DMA: DmaMemory = DmaMemory::new();
+
+static mut DEVICES: [Option<Sdmmc<'static, MmioRegisters>>; 2] = [None, None];
+
+/// The card on `controller`, initialized on first use.
+///
+/// # Safety
+///
+/// The returned device must not be used after another call for the same controller.
+pub unsafe fn open(
+    controller: Controller,
+) -> Result<&'static mut Sdmmc<'static, MmioRegisters>, Error> {
+    let (slot, dma) = match controller {
+        Controller::Sdmmc1 => (&mut DEVICES[0], &mut SDMMC1_DMA),
+        Controller::Sdmmc4 => (&mut DEVICES[1], &mut SDMMC4_DMA),
+    };
+
+    if slot.is_none() {
+        let mut device = Sdmmc::new(
+            MmioRegisters::new(controller),
+            dma,
+            controller.get_card_type(),
+        );
+        device.init()?;
+
+        *slot = Some(device);
+    }
+
+    Ok(slot.as_mut().unwrap())
+}
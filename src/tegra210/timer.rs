@@ -0,0 +1,16 @@
+//! Microsecond counter (TIMERUS).
+
+use core::ptr;
+
+/// TIMERUS_CNTR_1US, microseconds elapsed since reset.
+const TIMERUS_CNTR_1US: *const u32 = 0x6000_5010 as *const u32;
+
+/// Microseconds elapsed since reset, wraps after ~71 minutes.
+pub fn get_microseconds() -> u32 {
+    unsafe { ptr::read_volatile(TIMERUS_CNTR_1US) }
+}
+
+/// Microseconds elapsed since `start`, a previous value of [`get_microseconds`].
+pub fn get_elapsed_microseconds(start: u32) -> u32 {
+    get_microseconds().wrapping_sub(start)
+}
@@ -0,0 +1,107 @@
+//! Input helpers on top of the libtegra UART driver.
+
+use core::ptr;
+use core::str;
+
+use libtegra::uart::Uart;
+
+use crate::tegra210::timer;
+use crate::transport::Transport;
+
+/// Offset of the line status register.
+const UART_LSR: u64 = 0x14;
+
+/// LSR receive data ready bit.
+const UART_LSR_RDR: u32 = 1 << 0;
+
+/// Base address of the registers of `uart`.
+pub fn get_base(uart: &Uart) -> u64 {
+    match uart {
+        Uart::A => 0x7000_6000,
+        Uart::B => 0x7000_6040,
+        Uart::C => 0x7000_6200,
+        Uart::D => 0x7000_6300,
+        Uart::E => 0x7000_6400,
+    }
+}
+
+/// Get another handle on the same UART.
+pub fn get_handle(uart: &Uart) -> Uart {
+    match uart {
+        Uart::A => Uart::A,
+        Uart::B => Uart::B,
+        Uart::C => Uart::C,
+        Uart::D => Uart::D,
+        Uart::E => Uart::E,
+    }
+}
+
+/// Check if a byte can be read without blocking.
+pub fn has_input(uart: &Uart) -> bool {
+    let lsr = unsafe { ptr::read_volatile((get_base(uart) + UART_LSR) as *const u32) };
+
+    lsr & UART_LSR_RDR != 0
+}
+
+/// Wait up to `timeout_us` microseconds for input, returning whether some is available.
+pub fn wait_for_input(uart: &Uart, timeout_us: u32) -> bool {
+    let start = timer::get_microseconds();
+
+    while timer::get_elapsed_microseconds(start) < timeout_us {
+        if has_input(uart) {
+            return true;
+        }
+    }
+
+    has_input(uart)
+}
+
+/// A UART used by the file transfer and debugging protocols.
+pub struct UartTransport(pub Uart);
+
+impl Transport for UartTransport {
+    fn read_byte(&mut self, timeout_us: u32) -> Option<u8> {
+        if wait_for_input(&self.0, timeout_us) {
+            Some(self.0.read_byte())
+        } else {
+            None
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.0.write_byte(byte)
+    }
+}
+
+/// Read a line of printable ASCII characters with echo and backspace support.
+pub fn read_line<'a>(uart: &Uart, buffer: &'a mut [u8]) -> &'a str {
+    let mut length = 0;
+
+    loop {
+        match uart.read_byte() {
+            b'\r' | b'\n' => break,
+            0x08 | 0x7f => {
+                if length > 0 {
+                    length -= 1;
+
+                    for byte in b"\x08 \x08" {
+                        uart.write_byte(*byte);
+                    }
+                }
+            }
+            byte if (0x20..0x7f).contains(&byte) && length < buffer.len() => {
+                buffer[length] = byte;
+                length += 1;
+
+                uart.write_byte(byte);
+            }
+            _ => {}
+        }
+    }
+
+    uart.write_byte(b'\r');
+    uart.write_byte(b'\n');
+
+    // Only ASCII characters were accepted.
+    str::from_utf8(&buffer[..length]).unwrap()
+}
@@ -0,0 +1,1085 @@
+//! USB serial port (CDC-ACM) on the USB2 device controller (USB1).
+//!
+//! The controller is ChipIdea based. rboot is loaded over RCM, so the boot ROM left PLLU and the
+//! UTMI pads configured: only the controller itself is reset, which makes the host see the RCM
+//! device leave and the serial port appear.
+//!
+//! Control requests are handled from the USB interrupt, so the host can enumerate and open the
+//! port while rboot is busy elsewhere. The interrupt never waits for the host: each stage of a
+//! control transfer is started once the previous one completed.
+//!
+//! Data transfers are synchronous and only happen once the host opened the port (DTR set),
+//! data is dropped otherwise. When the host stops reading, the last transfer is left pending and
+//! output is dropped until the host reads it.
+
+use core::cmp;
+use core::fmt::{self, Write};
+use core::mem;
+use core::ptr;
+
+use crate::mmu;
+use crate::tegra210::gic;
+use crate::tegra210::timer;
+
+const USB_BASE: u64 = 0x7D00_0000;
+
+/// Interrupt of the USB2 device controller.
+pub const USB_IRQ: u32 = gic::SPI_BASE + 20;
+
+const USB2D_USBCMD: u64 = 0x130;
+const USB2D_USBSTS: u64 = 0x134;
+const USB2D_USBINTR: u64 = 0x138;
+const USB2D_DEVICEADDR: u64 = 0x144;
+const USB2D_ENDPTLISTADDR: u64 = 0x148;
+const USB2D_HOSTPC1_DEVLC: u64 = 0x1B4;
+const USB2D_USBMODE: u64 = 0x1F8;
+const USB2D_ENDPTSETUPSTAT: u64 = 0x208;
+const USB2D_ENDPTPRIME: u64 = 0x20C;
+const USB2D_ENDPTFLUSH: u64 = 0x210;
+const USB2D_ENDPTCOMPLETE: u64 = 0x218;
+const USB2D_ENDPTCTRL0: u64 = 0x21C;
+const USB_SUSP_CTRL: u64 = 0x400;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_RESET: u32 = 1 << 1;
+const USBCMD_SETUP_TRIPWIRE: u32 = 1 << 13;
+/// Interrupt threshold control, in micro-frames.
+const USBCMD_ITC_MASK: u32 = 0xFF << 16;
+
+const USBSTS_PORT_CHANGE: u32 = 1 << 2;
+const USBSTS_RESET: u32 = 1 << 6;
+
+/// Transfer completions and setup packets, port changes and bus resets.
+const USBINTR_EVENTS: u32 = (1 << 0) | (1 << 2) | (1 << 6);
+
+/// The address is only applied after the status stage of SET_ADDRESS.
+const DEVICEADDR_ADVANCE: u32 = 1 << 24;
+const DEVICEADDR_SHIFT: u32 = 25;
+
+const DEVLC_SPEED_SHIFT: u32 = 25;
+const DEVLC_SPEED_MASK: u32 = 3;
+const DEVLC_SPEED_HIGH: u32 = 2;
+
+const USBMODE_DEVICE: u32 = 2;
+const USBMODE_SETUP_LOCKOUT_OFF: u32 = 1 << 3;
+
+const USB_PHY_CLK_VALID: u32 = 1 << 7;
+
+/// ENDPTCTRL bits of the OUT half, the IN half is the same 16 bits higher.
+const ENDPTCTRL_STALL: u32 = 1 << 0;
+const ENDPTCTRL_TYPE_SHIFT: u32 = 2;
+const ENDPTCTRL_TOGGLE_RESET: u32 = 1 << 6;
+const ENDPTCTRL_ENABLE: u32 = 1 << 7;
+const ENDPTCTRL_IN_SHIFT: u32 = 16;
+
+const ENDPOINT_TYPE_BULK: u32 = 2;
+const ENDPOINT_TYPE_INTERRUPT: u32 = 3;
+
+const QUEUE_HEAD_INTERRUPT_ON_SETUP: u32 = 1 << 15;
+const QUEUE_HEAD_MAX_PACKET_SIZE_SHIFT: u32 = 16;
+/// Disable the automatic zero length packets, they are sent explicitly when needed.
+const QUEUE_HEAD_ZERO_LENGTH_DISABLE: u32 = 1 << 29;
+
+const TRANSFER_TERMINATE: u32 = 1;
+const TRANSFER_ACTIVE: u32 = 1 << 7;
+const TRANSFER_HALTED: u32 = 1 << 6;
+const TRANSFER_BUFFER_ERROR: u32 = 1 << 5;
+const TRANSFER_TRANSACTION_ERROR: u32 = 1 << 3;
+const TRANSFER_INTERRUPT_ON_COMPLETE: u32 = 1 << 15;
+const TRANSFER_LENGTH_SHIFT: u32 = 16;
+const TRANSFER_LENGTH_MASK: u32 = 0x7FFF;
+
+/// Queue head indexes, an OUT then an IN queue head for every endpoint.
+const EP0_OUT: usize = 0;
+const EP0_IN: usize = 1;
+const DATA_OUT: usize = 2;
+const DATA_IN: usize = 3;
+const NOTIFICATION_IN: usize = 5;
+const QUEUE_HEAD_COUNT: usize = 6;
+
+const DATA_ENDPOINT: u64 = 1;
+const NOTIFICATION_ENDPOINT: u64 = 2;
+
+const CONTROL_MAX_PACKET_SIZE: u16 = 64;
+const NOTIFICATION_MAX_PACKET_SIZE: u16 = 16;
+const FULL_SPEED_BULK_MAX_PACKET_SIZE: u16 = 64;
+const HIGH_SPEED_BULK_MAX_PACKET_SIZE: u16 = 512;
+
+const BUFFER_SIZE: usize = 512;
+
+const RESET_TIMEOUT_US: u32 = 100_000;
+/// Flushing and priming endpoints only takes the controller a few microseconds.
+const FLUSH_TIMEOUT_US: u32 = 1_000;
+/// How long a log write waits for the host, it is dropped after that.
+const DATA_TIMEOUT_US: u32 = 50_000;
+
+/// pid.codes test VID/PID, the serial port doesn't need a dedicated driver.
+const VENDOR_ID: u16 = 0x1209;
+const PRODUCT_ID: u16 = 0x0001;
+
+const REQUEST_TYPE_MASK: u8 = 0x60;
+const REQUEST_TYPE_STANDARD: u8 = 0x00;
+const REQUEST_TYPE_CLASS: u8 = 0x20;
+const REQUEST_RECIPIENT_MASK: u8 = 0x1F;
+const REQUEST_RECIPIENT_ENDPOINT: u8 = 2;
+
+const REQUEST_GET_STATUS: u8 = 0;
+const REQUEST_CLEAR_FEATURE: u8 = 1;
+const REQUEST_SET_FEATURE: u8 = 3;
+const REQUEST_SET_ADDRESS: u8 = 5;
+const REQUEST_GET_DESCRIPTOR: u8 = 6;
+const REQUEST_GET_CONFIGURATION: u8 = 8;
+const REQUEST_SET_CONFIGURATION: u8 = 9;
+const REQUEST_GET_INTERFACE: u8 = 10;
+const REQUEST_SET_INTERFACE: u8 = 11;
+
+const CDC_SET_LINE_CODING: u8 = 0x20;
+const CDC_GET_LINE_CODING: u8 = 0x21;
+const CDC_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const CDC_SEND_BREAK: u8 = 0x23;
+const CDC_CONTROL_LINE_DTR: u16 = 1 << 0;
+
+const DESCRIPTOR_DEVICE: u8 = 1;
+const DESCRIPTOR_CONFIGURATION: u8 = 2;
+const DESCRIPTOR_STRING: u8 = 3;
+const DESCRIPTOR_DEVICE_QUALIFIER: u8 = 6;
+
+const STRING_MANUFACTURER: u8 = 1;
+const STRING_PRODUCT: u8 = 2;
+
+const CONFIGURATION_VALUE: u8 = 1;
+const CONFIGURATION_DESCRIPTOR_SIZE: usize = 67;
+
+/// 115200 bauds, 1 stop bit, no parity, 8 data bits.
+const DEFAULT_LINE_CODING: [u8; 7] = [0x00, 0xC2, 0x01, 0x00, 0, 0, 8];
+
+const CAR_BASE: u64 = 0x6000_6000;
+const CAR_CLK_ENB_L_SET: u64 = 0x320;
+const CAR_USBD_BIT: u32 = 22;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The controller didn't complete an operation in time.
+    Timeout,
+    /// The PHY isn't clocked, the boot ROM didn't leave USB running.
+    PhyNotReady,
+    /// A transfer failed, with the status bits of its descriptor.
+    Transfer(u32),
+    /// The host sent a request the serial port doesn't handle.
+    UnsupportedRequest,
+}
+
+/// Device queue head, as read by the controller.
+#[repr(C, align(64))]
+#[derive(Copy, Clone)]
+struct QueueHead {
+    capabilities: u32,
+    current: u32,
+    next: u32,
+    token: u32,
+    buffers: [u32; 5],
+    reserved: u32,
+    setup: [u8; 8],
+    padding: [u32; 4],
+}
+
+impl QueueHead {
+    const EMPTY: Self = QueueHead {
+        capabilities: 0,
+        current: 0,
+        next: TRANSFER_TERMINATE,
+        token: 0,
+        buffers: [0; 5],
+        reserved: 0,
+        setup: [0; 8],
+        padding: [0; 4],
+    };
+}
+
+/// Device transfer descriptor, kept in its own cache line.
+#[repr(C, align(64))]
+#[derive(Copy, Clone)]
+struct TransferDescriptor {
+    next: u32,
+    token: u32,
+    buffers: [u32; 5],
+    reserved: u32,
+}
+
+impl TransferDescriptor {
+    const EMPTY: Self = TransferDescriptor {
+        next: TRANSFER_TERMINATE,
+        token: 0,
+        buffers: [0; 5],
+        reserved: 0,
+    };
+}
+
+#[repr(C, align(64))]
+struct Buffer([u8; BUFFER_SIZE]);
+
+/// Memory the controller accesses, the queue heads list must be aligned on 2KB.
+#[repr(C, align(2048))]
+pub struct DmaMemory {
+    queue_heads: [QueueHead; QUEUE_HEAD_COUNT],
+    transfers: [TransferDescriptor; QUEUE_HEAD_COUNT],
+    control_buffer: Buffer,
+    data_buffer: Buffer,
+}
+
+impl DmaMemory {
+    pub const fn new() -> Self {
+        DmaMemory {
+            queue_heads: [QueueHead::EMPTY; QUEUE_HEAD_COUNT],
+            transfers: [TransferDescriptor::EMPTY; QUEUE_HEAD_COUNT],
+            control_buffer: Buffer([0; BUFFER_SIZE]),
+            data_buffer: Buffer([0; BUFFER_SIZE]),
+        }
+    }
+}
+
+impl Default for DmaMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stage of the control transfer in progress, each one starting when the previous completed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ControlStage {
+    Idle,
+    /// Sending `length` bytes, then a zero length packet if needed to end the answer.
+    DataIn {
+        length: usize,
+        zero_length_packet: bool,
+    },
+    /// Receiving `length` bytes of line coding.
+    LineCoding {
+        length: usize,
+    },
+    StatusIn,
+    StatusOut,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct SetupPacket {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+}
+
+impl SetupPacket {
+    fn parse(bytes: [u8; 8]) -> Self {
+        SetupPacket {
+            request_type: bytes[0],
+            request: bytes[1],
+            value: u16::from_le_bytes([bytes[2], bytes[3]]),
+            index: u16::from_le_bytes([bytes[4], bytes[5]]),
+            length: u16::from_le_bytes([bytes[6], bytes[7]]),
+        }
+    }
+}
+
+fn read(offset: u64) -> u32 {
+    unsafe { ptr::read_volatile((USB_BASE + offset) as *const u32) }
+}
+
+fn write(offset: u64, value: u32) {
+    unsafe { ptr::write_volatile((USB_BASE + offset) as *mut u32, value) };
+}
+
+fn write_car(offset: u64, value: u32) {
+    unsafe { ptr::write_volatile((CAR_BASE + offset) as *mut u32, value) };
+}
+
+/// Wait up to `timeout_us` microseconds for `condition` to hold.
+fn wait_for<F: Fn() -> bool>(condition: F, timeout_us: u32) -> Result<(), Error> {
+    let start = timer::get_microseconds();
+
+    while !condition() {
+        if timer::get_elapsed_microseconds(start) > timeout_us {
+            return Err(Error::Timeout);
+        }
+    }
+
+    Ok(())
+}
+
+/// Bit of the queue head `index` in the ENDPTPRIME, ENDPTFLUSH and ENDPTCOMPLETE registers.
+fn get_endpoint_bit(index: usize) -> u32 {
+    if index % 2 == 0 {
+        1 << (index / 2)
+    } else {
+        1 << (16 + index / 2)
+    }
+}
+
+fn get_endpoint_control(endpoint: u64) -> u64 {
+    USB2D_ENDPTCTRL0 + endpoint * 4
+}
+
+fn is_high_speed() -> bool {
+    (read(USB2D_HOSTPC1_DEVLC) >> DEVLC_SPEED_SHIFT) & DEVLC_SPEED_MASK == DEVLC_SPEED_HIGH
+}
+
+fn get_bulk_max_packet_size() -> u16 {
+    if is_high_speed() {
+        HIGH_SPEED_BULK_MAX_PACKET_SIZE
+    } else {
+        FULL_SPEED_BULK_MAX_PACKET_SIZE
+    }
+}
+
+fn get_device_descriptor() -> [u8; 18] {
+    let [vendor_low, vendor_high] = VENDOR_ID.to_le_bytes();
+    let [product_low, product_high] = PRODUCT_ID.to_le_bytes();
+
+    [
+        18,
+        DESCRIPTOR_DEVICE,
+        0x00,
+        0x02,
+        // Communications device class, the interfaces tell the rest.
+        0x02,
+        0x00,
+        0x00,
+        CONTROL_MAX_PACKET_SIZE as u8,
+        vendor_low,
+        vendor_high,
+        product_low,
+        product_high,
+        0x00,
+        0x01,
+        STRING_MANUFACTURER,
+        STRING_PRODUCT,
+        0,
+        1,
+    ]
+}
+
+fn get_device_qualifier_descriptor() -> [u8; 10] {
+    [
+        10,
+        DESCRIPTOR_DEVICE_QUALIFIER,
+        0x00,
+        0x02,
+        0x02,
+        0x00,
+        0x00,
+        CONTROL_MAX_PACKET_SIZE as u8,
+        1,
+        0,
+    ]
+}
+
+/// The CDC-ACM configuration: a communication interface with its notification endpoint,
+/// and a data interface with a bulk endpoint in each direction.
+fn get_configuration_descriptor(bulk_max_packet_size: u16) -> [u8; CONFIGURATION_DESCRIPTOR_SIZE] {
+    let [bulk_low, bulk_high] = bulk_max_packet_size.to_le_bytes();
+    let [total_low, total_high] = (CONFIGURATION_DESCRIPTOR_SIZE as u16).to_le_bytes();
+
+    [
+        // Configuration, bus powered, 100mA.
+        9,
+        DESCRIPTOR_CONFIGURATION,
+        total_low,
+        total_high,
+        2,
+        CONFIGURATION_VALUE,
+        0,
+        0x80,
+        50,
+        // Communication interface, abstract control model without AT commands.
+        9,
+        4,
+        0,
+        0,
+        1,
+        0x02,
+        0x02,
+        0x00,
+        0,
+        // Header functional descriptor, CDC 1.10.
+        5,
+        0x24,
+        0x00,
+        0x10,
+        0x01,
+        // Call management functional descriptor, no call management.
+        5,
+        0x24,
+        0x01,
+        0x00,
+        1,
+        // Abstract control management functional descriptor, line coding and state.
+        4,
+        0x24,
+        0x02,
+        0x02,
+        // Union functional descriptor.
+        5,
+        0x24,
+        0x06,
+        0,
+        1,
+        // Notification endpoint.
+        7,
+        5,
+        0x80 | NOTIFICATION_ENDPOINT as u8,
+        0x03,
+        NOTIFICATION_MAX_PACKET_SIZE as u8,
+        0,
+        9,
+        // Data interface.
+        9,
+        4,
+        1,
+        0,
+        2,
+        0x0A,
+        0x00,
+        0x00,
+        0,
+        // Bulk OUT endpoint.
+        7,
+        5,
+        DATA_ENDPOINT as u8,
+        0x02,
+        bulk_low,
+        bulk_high,
+        0,
+        // Bulk IN endpoint.
+        7,
+        5,
+        0x80 | DATA_ENDPOINT as u8,
+        0x02,
+        bulk_low,
+        bulk_high,
+        0,
+    ]
+}
+
+/// Write the UTF-16 string descriptor of `string` to `buffer`, returning its size.
+fn write_string_descriptor(string: &str, buffer: &mut [u8]) -> usize {
+    let mut length = 2;
+
+    for unit in string.encode_utf16() {
+        if length + 2 > cmp::min(buffer.len(), 0xFF) {
+            break;
+        }
+
+        buffer[length..length + 2].copy_from_slice(&unit.to_le_bytes());
+        length += 2;
+    }
+
+    buffer[0] = length as u8;
+    buffer[1] = DESCRIPTOR_STRING;
+
+    length
+}
+
+pub struct UsbSerial<'a> {
+    dma: &'a mut DmaMemory,
+    configuration: u8,
+    line_coding: [u8; 7],
+    /// Whether the host has the port open.
+    data_terminal_ready: bool,
+    control_stage: ControlStage,
+    /// A data transfer timed out and is still waiting for the host.
+    data_in_pending: bool,
+}
+
+impl<'a> UsbSerial<'a> {
+    pub fn new(dma: &'a mut DmaMemory) -> Self {
+        UsbSerial {
+            dma,
+            configuration: 0,
+            line_coding: DEFAULT_LINE_CODING,
+            data_terminal_ready: false,
+            control_stage: ControlStage::Idle,
+            data_in_pending: false,
+        }
+    }
+
+    /// Reset the controller and attach to the host as a serial port.
+    pub fn init(&mut self) -> Result<(), Error> {
+        write_car(CAR_CLK_ENB_L_SET, 1 << CAR_USBD_BIT);
+
+        if read(USB_SUSP_CTRL) & USB_PHY_CLK_VALID == 0 {
+            return Err(Error::PhyNotReady);
+        }
+
+        write(USB2D_USBCMD, read(USB2D_USBCMD) & !USBCMD_RUN);
+        write(USB2D_USBCMD, USBCMD_RESET);
+        wait_for(|| read(USB2D_USBCMD) & USBCMD_RESET == 0, RESET_TIMEOUT_US)?;
+
+        write(USB2D_USBMODE, USBMODE_DEVICE | USBMODE_SETUP_LOCKOUT_OFF);
+
+        self.dma.queue_heads = [QueueHead::EMPTY; QUEUE_HEAD_COUNT];
+        self.dma.transfers = [TransferDescriptor::EMPTY; QUEUE_HEAD_COUNT];
+        self.dma.queue_heads[EP0_OUT].capabilities = QUEUE_HEAD_INTERRUPT_ON_SETUP
+            | u32::from(CONTROL_MAX_PACKET_SIZE) << QUEUE_HEAD_MAX_PACKET_SIZE_SHIFT;
+        self.dma.queue_heads[EP0_IN].capabilities = QUEUE_HEAD_ZERO_LENGTH_DISABLE
+            | u32::from(CONTROL_MAX_PACKET_SIZE) << QUEUE_HEAD_MAX_PACKET_SIZE_SHIFT;
+
+        let queue_heads = self.dma.queue_heads.as_ptr() as u64;
+        mmu::clean_invalidate_dcache_range(queue_heads, mem::size_of::<DmaMemory>() as u64);
+
+        // Physical and virtual addresses are the same, rboot is below 4GB.
+        write(USB2D_ENDPTLISTADDR, queue_heads as u32);
+
+        write(USB2D_USBINTR, USBINTR_EVENTS);
+        write(USB2D_USBSTS, read(USB2D_USBSTS));
+        write(USB2D_DEVICEADDR, 0);
+
+        // Start with an immediate interrupt threshold, the pull-up makes the host enumerate us.
+        write(
+            USB2D_USBCMD,
+            (read(USB2D_USBCMD) & !USBCMD_ITC_MASK) | USBCMD_RUN,
+        );
+
+        Ok(())
+    }
+
+    /// Detach from the host, the controller doesn't access memory afterwards.
+    pub fn shutdown(&mut self) {
+        write(USB2D_USBINTR, 0);
+        write(USB2D_USBCMD, read(USB2D_USBCMD) & !USBCMD_RUN);
+
+        self.disable_endpoints();
+    }
+
+    /// Whether the host configured the device and opened the port.
+    pub fn is_connected(&self) -> bool {
+        self.configuration != 0 && self.data_terminal_ready
+    }
+
+    /// Handle bus events and control requests.
+    pub fn poll(&mut self) {
+        let status = read(USB2D_USBSTS);
+        write(USB2D_USBSTS, status);
+
+        if status & USBSTS_RESET != 0 {
+            self.handle_bus_reset();
+        }
+
+        if status & USBSTS_PORT_CHANGE != 0 && self.configuration == 0 {
+            self.data_terminal_ready = false;
+        }
+
+        let control_index = match self.control_stage {
+            ControlStage::Idle => None,
+            ControlStage::DataIn { .. } | ControlStage::StatusIn => Some(EP0_IN),
+            ControlStage::LineCoding { .. } | ControlStage::StatusOut => Some(EP0_OUT),
+        };
+
+        if let Some(index) = control_index {
+            if read(USB2D_ENDPTCOMPLETE) & get_endpoint_bit(index) != 0
+                && self.advance_control_stage(index).is_err()
+            {
+                self.stall_control_endpoint();
+            }
+        }
+
+        if read(USB2D_ENDPTSETUPSTAT) & get_endpoint_bit(EP0_OUT) != 0 {
+            let setup = self.read_setup_packet();
+
+            match self.handle_setup_packet(setup) {
+                Ok(stage) => self.start_control_stage(stage),
+                Err(_) => self.stall_control_endpoint(),
+            }
+        }
+    }
+
+    /// Send `data` to the host, dropping it when the port isn't open or the host doesn't read.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.poll();
+
+        let bit = get_endpoint_bit(DATA_IN);
+        let address = self.dma.data_buffer.0.as_ptr() as u64;
+
+        if self.data_in_pending {
+            if read(USB2D_ENDPTCOMPLETE) & bit == 0 {
+                return Ok(());
+            }
+
+            self.data_in_pending = false;
+            self.complete_transfer(DATA_IN, address, 0)?;
+        }
+
+        for chunk in data.chunks(BUFFER_SIZE) {
+            if !self.is_connected() {
+                return Ok(());
+            }
+
+            self.dma.data_buffer.0[..chunk.len()].copy_from_slice(chunk);
+            self.start_transfer(DATA_IN, address, chunk.len());
+
+            // The host gets the chunk whenever it reads again, the next ones are dropped.
+            if let Err(error) = wait_for(|| read(USB2D_ENDPTCOMPLETE) & bit != 0, DATA_TIMEOUT_US) {
+                self.data_in_pending = true;
+                return Err(error);
+            }
+
+            self.complete_transfer(DATA_IN, address, chunk.len())?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_bus_reset(&mut self) {
+        write(USB2D_ENDPTSETUPSTAT, read(USB2D_ENDPTSETUPSTAT));
+        write(USB2D_ENDPTCOMPLETE, read(USB2D_ENDPTCOMPLETE));
+
+        wait_for(|| read(USB2D_ENDPTPRIME) == 0, FLUSH_TIMEOUT_US).ok();
+        write(USB2D_ENDPTFLUSH, 0xFFFF_FFFF);
+        wait_for(|| read(USB2D_ENDPTFLUSH) == 0, FLUSH_TIMEOUT_US).ok();
+
+        write(USB2D_DEVICEADDR, 0);
+        self.control_stage = ControlStage::Idle;
+        self.disable_endpoints();
+    }
+
+    /// Copy the setup packet, the tripwire is cleared when another one overwrites it meanwhile.
+    fn read_setup_packet(&mut self) -> SetupPacket {
+        write(USB2D_ENDPTSETUPSTAT, get_endpoint_bit(EP0_OUT));
+
+        let queue_head = &self.dma.queue_heads[EP0_OUT];
+        let setup = loop {
+            write(USB2D_USBCMD, read(USB2D_USBCMD) | USBCMD_SETUP_TRIPWIRE);
+
+            mmu::clean_invalidate_dcache_range(
+                queue_head as *const _ as u64,
+                mem::size_of::<QueueHead>() as u64,
+            );
+            let setup = unsafe { ptr::read_volatile(&queue_head.setup) };
+
+            if read(USB2D_USBCMD) & USBCMD_SETUP_TRIPWIRE != 0 {
+                break setup;
+            }
+        };
+
+        write(USB2D_USBCMD, read(USB2D_USBCMD) & !USBCMD_SETUP_TRIPWIRE);
+
+        // Drop what was left of the previous control transfer.
+        let control_bits = get_endpoint_bit(EP0_OUT) | get_endpoint_bit(EP0_IN);
+        write(USB2D_ENDPTFLUSH, control_bits);
+        wait_for(
+            || read(USB2D_ENDPTFLUSH) & control_bits == 0,
+            FLUSH_TIMEOUT_US,
+        )
+        .ok();
+        write(USB2D_ENDPTCOMPLETE, control_bits);
+        self.control_stage = ControlStage::Idle;
+
+        SetupPacket::parse(setup)
+    }
+
+    /// Handle `setup`, returning the stage the control transfer continues with.
+    fn handle_setup_packet(&mut self, setup: SetupPacket) -> Result<ControlStage, Error> {
+        match (setup.request_type & REQUEST_TYPE_MASK, setup.request) {
+            (REQUEST_TYPE_STANDARD, REQUEST_GET_STATUS) => Ok(self.control_send(&[0, 0], setup)),
+            (REQUEST_TYPE_STANDARD, REQUEST_CLEAR_FEATURE) => {
+                // Only ENDPOINT_HALT exists for endpoints.
+                if setup.request_type & REQUEST_RECIPIENT_MASK == REQUEST_RECIPIENT_ENDPOINT {
+                    self.clear_endpoint_halt(setup.index as u8);
+                }
+
+                Ok(ControlStage::StatusIn)
+            }
+            (REQUEST_TYPE_STANDARD, REQUEST_SET_FEATURE) => Ok(ControlStage::StatusIn),
+            (REQUEST_TYPE_STANDARD, REQUEST_SET_ADDRESS) => {
+                write(
+                    USB2D_DEVICEADDR,
+                    u32::from(setup.value & 0x7F) << DEVICEADDR_SHIFT | DEVICEADDR_ADVANCE,
+                );
+
+                Ok(ControlStage::StatusIn)
+            }
+            (REQUEST_TYPE_STANDARD, REQUEST_GET_DESCRIPTOR) => self.send_descriptor(setup),
+            (REQUEST_TYPE_STANDARD, REQUEST_GET_CONFIGURATION) => {
+                let configuration = self.configuration;
+                Ok(self.control_send(&[configuration], setup))
+            }
+            (REQUEST_TYPE_STANDARD, REQUEST_SET_CONFIGURATION) => {
+                match setup.value as u8 {
+                    0 => self.disable_endpoints(),
+                    CONFIGURATION_VALUE => self.enable_endpoints(),
+                    _ => return Err(Error::UnsupportedRequest),
+                }
+
+                Ok(ControlStage::StatusIn)
+            }
+            (REQUEST_TYPE_STANDARD, REQUEST_GET_INTERFACE) => Ok(self.control_send(&[0], setup)),
+            (REQUEST_TYPE_STANDARD, REQUEST_SET_INTERFACE) if setup.value == 0 => {
+                Ok(ControlStage::StatusIn)
+            }
+            (REQUEST_TYPE_CLASS, CDC_SET_LINE_CODING) => {
+                let length = cmp::min(self.line_coding.len(), usize::from(setup.length));
+                Ok(ControlStage::LineCoding { length })
+            }
+            (REQUEST_TYPE_CLASS, CDC_GET_LINE_CODING) => {
+                let line_coding = self.line_coding;
+                Ok(self.control_send(&line_coding, setup))
+            }
+            (REQUEST_TYPE_CLASS, CDC_SET_CONTROL_LINE_STATE) => {
+                self.data_terminal_ready = setup.value & CDC_CONTROL_LINE_DTR != 0;
+                Ok(ControlStage::StatusIn)
+            }
+            (REQUEST_TYPE_CLASS, CDC_SEND_BREAK) => Ok(ControlStage::StatusIn),
+            _ => Err(Error::UnsupportedRequest),
+        }
+    }
+
+    fn send_descriptor(&mut self, setup: SetupPacket) -> Result<ControlStage, Error> {
+        let [index, descriptor_type] = setup.value.to_le_bytes();
+
+        match (descriptor_type, index) {
+            (DESCRIPTOR_DEVICE, _) => Ok(self.control_send(&get_device_descriptor(), setup)),
+            (DESCRIPTOR_CONFIGURATION, 0) => Ok(self.control_send(
+                &get_configuration_descriptor(get_bulk_max_packet_size()),
+                setup,
+            )),
+            (DESCRIPTOR_DEVICE_QUALIFIER, _) => {
+                Ok(self.control_send(&get_device_qualifier_descriptor(), setup))
+            }
+            (DESCRIPTOR_STRING, _) => {
+                let mut descriptor = [0; 64];
+                let length = match index {
+                    // English (United States) only.
+                    0 => {
+                        descriptor[..4].copy_from_slice(&[4, DESCRIPTOR_STRING, 0x09, 0x04]);
+                        4
+                    }
+                    STRING_MANUFACTURER => write_string_descriptor("rboot", &mut descriptor),
+                    STRING_PRODUCT => {
+                        write_string_descriptor("rboot serial console", &mut descriptor)
+                    }
+                    _ => return Err(Error::UnsupportedRequest),
+                };
+
+                Ok(self.control_send(&descriptor[..length], setup))
+            }
+            _ => Err(Error::UnsupportedRequest),
+        }
+    }
+
+    fn enable_endpoints(&mut self) {
+        let bulk_capabilities = QUEUE_HEAD_ZERO_LENGTH_DISABLE
+            | u32::from(get_bulk_max_packet_size()) << QUEUE_HEAD_MAX_PACKET_SIZE_SHIFT;
+
+        let queue_heads = &mut self.dma.queue_heads;
+        queue_heads[DATA_OUT] = QueueHead::EMPTY;
+        queue_heads[DATA_OUT].capabilities = bulk_capabilities;
+        queue_heads[DATA_IN] = QueueHead::EMPTY;
+        queue_heads[DATA_IN].capabilities = bulk_capabilities;
+        queue_heads[NOTIFICATION_IN] = QueueHead::EMPTY;
+        queue_heads[NOTIFICATION_IN].capabilities = QUEUE_HEAD_ZERO_LENGTH_DISABLE
+            | u32::from(NOTIFICATION_MAX_PACKET_SIZE) << QUEUE_HEAD_MAX_PACKET_SIZE_SHIFT;
+
+        mmu::clean_invalidate_dcache_range(
+            queue_heads.as_ptr() as u64,
+            mem::size_of_val(queue_heads) as u64,
+        );
+
+        let bulk =
+            ENDPTCTRL_ENABLE | ENDPTCTRL_TOGGLE_RESET | ENDPOINT_TYPE_BULK << ENDPTCTRL_TYPE_SHIFT;
+        write(
+            get_endpoint_control(DATA_ENDPOINT),
+            bulk | bulk << ENDPTCTRL_IN_SHIFT,
+        );
+
+        // The unused OUT half still needs a type other than control.
+        let notification = ENDPTCTRL_ENABLE
+            | ENDPTCTRL_TOGGLE_RESET
+            | ENDPOINT_TYPE_INTERRUPT << ENDPTCTRL_TYPE_SHIFT;
+        write(
+            get_endpoint_control(NOTIFICATION_ENDPOINT),
+            ENDPOINT_TYPE_BULK << ENDPTCTRL_TYPE_SHIFT | notification << ENDPTCTRL_IN_SHIFT,
+        );
+
+        self.configuration = CONFIGURATION_VALUE;
+    }
+
+    fn disable_endpoints(&mut self) {
+        write(get_endpoint_control(DATA_ENDPOINT), 0);
+        write(get_endpoint_control(NOTIFICATION_ENDPOINT), 0);
+
+        self.configuration = 0;
+        self.data_terminal_ready = false;
+        self.data_in_pending = false;
+    }
+
+    fn clear_endpoint_halt(&mut self, endpoint_address: u8) {
+        let endpoint = u64::from(endpoint_address & 0xF);
+        if endpoint == 0 || endpoint > NOTIFICATION_ENDPOINT {
+            return;
+        }
+
+        let shift = if endpoint_address & 0x80 != 0 {
+            ENDPTCTRL_IN_SHIFT
+        } else {
+            0
+        };
+
+        let control = read(get_endpoint_control(endpoint));
+        write(
+            get_endpoint_control(endpoint),
+            (control & !(ENDPTCTRL_STALL << shift)) | ENDPTCTRL_TOGGLE_RESET << shift,
+        );
+    }
+
+    fn stall_control_endpoint(&mut self) {
+        // Both halves, the controller clears them on the next setup packet.
+        let control = read(USB2D_ENDPTCTRL0);
+        write(
+            USB2D_ENDPTCTRL0,
+            control | ENDPTCTRL_STALL | ENDPTCTRL_STALL << ENDPTCTRL_IN_SHIFT,
+        );
+    }
+
+    /// Copy the answer to a control read, returning its data stage.
+    fn control_send(&mut self, data: &[u8], setup: SetupPacket) -> ControlStage {
+        let length = cmp::min(data.len(), usize::from(setup.length));
+        self.dma.control_buffer.0[..length].copy_from_slice(&data[..length]);
+
+        // A short answer ending on a full packet needs a zero length packet to be seen as such.
+        let max_packet_size = usize::from(CONTROL_MAX_PACKET_SIZE);
+        let zero_length_packet =
+            length < usize::from(setup.length) && length % max_packet_size == 0;
+
+        ControlStage::DataIn {
+            length,
+            zero_length_packet,
+        }
+    }
+
+    fn start_control_stage(&mut self, stage: ControlStage) {
+        let address = self.dma.control_buffer.0.as_ptr() as u64;
+
+        match stage {
+            ControlStage::Idle => {}
+            ControlStage::DataIn { length, .. } => self.start_transfer(EP0_IN, address, length),
+            ControlStage::LineCoding { length } => self.start_transfer(EP0_OUT, address, length),
+            ControlStage::StatusIn => self.start_transfer(EP0_IN, address, 0),
+            ControlStage::StatusOut => self.start_transfer(EP0_OUT, address, 0),
+        }
+
+        self.control_stage = stage;
+    }
+
+    /// Finish the control stage that completed on the queue head `index` and start the next.
+    fn advance_control_stage(&mut self, index: usize) -> Result<(), Error> {
+        let address = self.dma.control_buffer.0.as_ptr() as u64;
+        let stage = mem::replace(&mut self.control_stage, ControlStage::Idle);
+
+        let next_stage = match stage {
+            ControlStage::DataIn {
+                length,
+                zero_length_packet,
+            } => {
+                self.complete_transfer(index, address, length)?;
+
+                if zero_length_packet {
+                    ControlStage::DataIn {
+                        length: 0,
+                        zero_length_packet: false,
+                    }
+                } else {
+                    ControlStage::StatusOut
+                }
+            }
+            ControlStage::LineCoding { length } => {
+                let received = self.complete_transfer(index, address, length)?;
+                self.line_coding[..received]
+                    .copy_from_slice(&self.dma.control_buffer.0[..received]);
+
+                ControlStage::StatusIn
+            }
+            ControlStage::Idle | ControlStage::StatusIn | ControlStage::StatusOut => {
+                self.complete_transfer(index, address, 0)?;
+
+                ControlStage::Idle
+            }
+        };
+
+        self.start_control_stage(next_stage);
+
+        Ok(())
+    }
+
+    /// Start transferring `length` bytes at `address` on the queue head `index`.
+    fn start_transfer(&mut self, index: usize, address: u64, length: usize) {
+        // The buffer can cross pages, the other pointers are the following 4KB pages.
+        let page = address & !0xFFF;
+        let transfer = TransferDescriptor {
+            next: TRANSFER_TERMINATE,
+            token: (length as u32) << TRANSFER_LENGTH_SHIFT
+                | TRANSFER_INTERRUPT_ON_COMPLETE
+                | TRANSFER_ACTIVE,
+            buffers: [
+                address as u32,
+                (page + 0x1000) as u32,
+                (page + 0x2000) as u32,
+                (page + 0x3000) as u32,
+                (page + 0x4000) as u32,
+            ],
+            reserved: 0,
+        };
+
+        let transfer_address = &self.dma.transfers[index] as *const _ as u64;
+        let queue_head_address = &self.dma.queue_heads[index] as *const _ as u64;
+
+        unsafe {
+            ptr::write_volatile(&mut self.dma.transfers[index], transfer);
+            ptr::write_volatile(
+                &mut self.dma.queue_heads[index].next,
+                transfer_address as u32,
+            );
+            ptr::write_volatile(&mut self.dma.queue_heads[index].token, 0);
+        }
+
+        mmu::clean_invalidate_dcache_range(
+            transfer_address,
+            mem::size_of::<TransferDescriptor>() as u64,
+        );
+        mmu::clean_invalidate_dcache_range(queue_head_address, mem::size_of::<QueueHead>() as u64);
+        if length != 0 {
+            // Received data must not be overwritten by dirty lines, sent data must be in DRAM.
+            mmu::clean_invalidate_dcache_range(address, length as u64);
+        }
+
+        write(USB2D_ENDPTPRIME, get_endpoint_bit(index));
+    }
+
+    /// Acknowledge the completed transfer of `length` bytes at `address` on the queue head
+    /// `index`, returning the count actually transferred.
+    fn complete_transfer(
+        &mut self,
+        index: usize,
+        address: u64,
+        length: usize,
+    ) -> Result<usize, Error> {
+        let is_in = index % 2 == 1;
+        let transfer_address = &self.dma.transfers[index] as *const _ as u64;
+
+        write(USB2D_ENDPTCOMPLETE, get_endpoint_bit(index));
+
+        mmu::clean_invalidate_dcache_range(
+            transfer_address,
+            mem::size_of::<TransferDescriptor>() as u64,
+        );
+        if !is_in && length != 0 {
+            mmu::clean_invalidate_dcache_range(address, length as u64);
+        }
+
+        let token = unsafe { ptr::read_volatile(&self.dma.transfers[index].token) };
+        let errors =
+            TRANSFER_ACTIVE | TRANSFER_HALTED | TRANSFER_BUFFER_ERROR | TRANSFER_TRANSACTION_ERROR;
+        if token & errors != 0 {
+            return Err(Error::Transfer(token & errors));
+        }
+
+        let remaining = ((token >> TRANSFER_LENGTH_SHIFT) & TRANSFER_LENGTH_MASK) as usize;
+        Ok(length - cmp::min(remaining, length))
+    }
+}
+
+static mut DMA: DmaMemory = DmaMemory::new();
+
+static mut DEVICE: Option<UsbSerial<'static>> = None;
+
+/// Handle on the USB serial port, writes go nowhere until [`init`] succeeded.
+///
+/// Output is buffered until [`SerialPort::flush`], so whole log records go in one transfer.
+pub struct SerialPort {
+    buffer: [u8; BUFFER_SIZE],
+    length: usize,
+}
+
+impl SerialPort {
+    const fn new() -> Self {
+        SerialPort {
+            buffer: [0; BUFFER_SIZE],
+            length: 0,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        unsafe { DEVICE.as_ref() }.map_or(false, |device| device.is_connected())
+    }
+
+    /// Send the buffered output.
+    pub fn flush(&mut self) {
+        // The interrupt handler must not run in the middle of a transfer.
+        gic::disable(USB_IRQ);
+
+        // Logs can't wait for a host that stopped reading, they are dropped instead.
+        if let Some(device) = unsafe { DEVICE.as_mut() } {
+            device.write(&self.buffer[..self.length]).ok();
+        }
+
+        if unsafe { DEVICE.is_some() } {
+            gic::enable(USB_IRQ);
+        }
+
+        self.length = 0;
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+
+        while !bytes.is_empty() {
+            if self.length == BUFFER_SIZE {
+                self.flush();
+            }
+
+            let count = cmp::min(BUFFER_SIZE - self.length, bytes.len());
+            self.buffer[self.length..self.length + count].copy_from_slice(&bytes[..count]);
+            self.length += count;
+            bytes = &bytes[count..];
+        }
+
+        Ok(())
+    }
+}
+
+fn handle_irq(_irq: u32) {
+    if let Some(device) = unsafe { DEVICE.as_mut() } {
+        device.poll();
+    }
+}
+
+/// Bring the USB serial port up, with control requests handled from [`USB_IRQ`].
+///
+/// # Safety
+///
+/// Must not be called while the port is used.
+pub unsafe fn init() -> Result<SerialPort, Error> {
+    let mut device = UsbSerial::new(&mut DMA);
+    device.init()?;
+
+    DEVICE = Some(device);
+
+    gic::register_handler(USB_IRQ, Some(handle_irq));
+    gic::enable(USB_IRQ);
+
+    Ok(SerialPort::new())
+}
+
+/// Detach the USB serial port, before handing the machine over to a payload.
+///
+/// # Safety
+///
+/// Must not be called while the port is used.
+pub unsafe fn shutdown() {
+    gic::disable(USB_IRQ);
+
+    if let Some(mut device) = DEVICE.take() {
+        device.shutdown();
+    }
+}
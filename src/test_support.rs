@@ -0,0 +1,80 @@
+//! Helpers shared by the unit tests.
+//!
+//! The disk images in `testdata` are made by real tools, see `testdata/make-images.sh`, and
+//! checked in gzipped.
+
+use std::fs;
+
+use crate::block::{self, BlockDevice, BLOCK_SIZE};
+
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+
+/// A device backed by memory, for the tests of what is built on block devices.
+pub struct MemoryDevice(pub Vec<u8>);
+
+impl BlockDevice for MemoryDevice {
+    fn block_count(&self) -> u64 {
+        (self.0.len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        block::check_range(self.block_count(), lba, buffer.len())?;
+
+        let offset = lba as usize * BLOCK_SIZE;
+        buffer.copy_from_slice(&self.0[offset..offset + buffer.len()]);
+
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        block::check_range(self.block_count(), lba, buffer.len())?;
+
+        let offset = lba as usize * BLOCK_SIZE;
+        self.0[offset..offset + buffer.len()].copy_from_slice(buffer);
+
+        Ok(())
+    }
+}
+
+/// File contents changing from one block to the next, for misplaced reads to show.
+///
+/// The files of the disk images are written with the same function.
+pub fn pattern(size: usize, seed: usize) -> Vec<u8> {
+    (0..size)
+        .map(|index| (index * 7 + index / 512 + seed) as u8)
+        .collect()
+}
+
+/// The name of each of `items`, as given by `name`.
+pub fn names<T, F>(items: impl IntoIterator<Item = T>, name: F) -> Vec<String>
+where
+    F: Fn(&T) -> String,
+{
+    items.into_iter().map(|item| name(&item)).collect()
+}
+
+/// The disk image `testdata/<name>.img.gz`.
+pub fn image(name: &str) -> Vec<u8> {
+    let path = format!("{}/testdata/{}.img.gz", env!("CARGO_MANIFEST_DIR"), name);
+    let gzip = fs::read(&path).unwrap_or_else(|error| panic!("{}: {}", path, error));
+
+    // make-images.sh runs gzip -n: deflate, and no name, comment nor extra field.
+    assert!(
+        gzip.len() >= GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE,
+        "{}",
+        path
+    );
+    assert_eq!(&gzip[..4], &[0x1F, 0x8B, 8, 0], "{}", path);
+
+    let (deflate, trailer) =
+        gzip[GZIP_HEADER_SIZE..].split_at(gzip.len() - GZIP_HEADER_SIZE - GZIP_TRAILER_SIZE);
+    let data = miniz_oxide::inflate::decompress_to_vec(deflate)
+        .unwrap_or_else(|error| panic!("{}: {:?}", path, error));
+
+    // The CRC that comes first would take longer to check than the tests of the image.
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    assert_eq!(data.len() as u32, size, "{}", path);
+
+    data
+}
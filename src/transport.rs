@@ -0,0 +1,19 @@
+//! Byte streams used by the file transfer and debugging protocols.
+//!
+//! Only `core` is used, the module can be built and tested on the host.
+
+pub trait Transport {
+    /// Read a byte, waiting at most `timeout_us` microseconds.
+    fn read_byte(&mut self, timeout_us: u32) -> Option<u8>;
+
+    fn write_byte(&mut self, byte: u8);
+
+    /// Read a byte, waiting as long as needed.
+    fn read_byte_blocking(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.read_byte(u32::MAX) {
+                return byte;
+            }
+        }
+    }
+}
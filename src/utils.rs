@@ -9,6 +9,7 @@ pub fn align_down<T: Num + Not<Output = T> + BitAnd<Output = T> + Copy>(addr: T,
     addr & !(align - T::one())
 }
 
+#[cfg(target_arch = "aarch64")]
 pub fn get_current_el() -> u32 {
     let current_el: u32;
     unsafe {
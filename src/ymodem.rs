@@ -0,0 +1,629 @@
+//! YMODEM receiver, falling back to XMODEM-1K when the sender doesn't send a file header.
+//!
+//! Only `core` is used, the module can be built and tested on the host.
+
+use core::cmp;
+use core::str;
+
+use crate::crc;
+use crate::transport::Transport;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// XMODEM padding byte.
+const SUB: u8 = 0x1A;
+/// Sent by the receiver to request CRC-16 checked packets.
+const CRC_REQUEST: u8 = b'C';
+
+const PACKET_TIMEOUT_US: u32 = 1_000_000;
+const BYTE_TIMEOUT_US: u32 = 100_000;
+
+/// Time given to the user to start the transfer, in packet timeouts.
+const MAX_START_RETRIES: usize = 60;
+const MAX_ERRORS: usize = 10;
+
+pub const MAX_PACKET_SIZE: usize = 1024;
+pub const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    Timeout,
+    /// The sender aborted the transfer.
+    Cancelled,
+    TooManyErrors,
+    /// The file doesn't fit in the destination.
+    TooLarge,
+    OutOfSequence,
+    /// The sender ended the batch without sending any file.
+    NoFile,
+}
+
+/// A received file.
+pub struct FileInfo {
+    pub size: usize,
+    name: [u8; MAX_NAME_LENGTH],
+    name_length: usize,
+}
+
+impl FileInfo {
+    fn new() -> Self {
+        FileInfo {
+            size: 0,
+            name: [0; MAX_NAME_LENGTH],
+            name_length: 0,
+        }
+    }
+
+    /// Name sent by the sender, empty with XMODEM.
+    pub fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_length]).unwrap_or("")
+    }
+}
+
+enum Packet {
+    Data { number: u8, length: usize },
+    EndOfTransmission,
+    Cancel,
+}
+
+enum PacketError {
+    Timeout,
+    Corrupted,
+}
+
+fn read_byte<T: Transport>(transport: &mut T) -> Result<u8, PacketError> {
+    transport
+        .read_byte(BYTE_TIMEOUT_US)
+        .ok_or(PacketError::Corrupted)
+}
+
+fn read_packet<T: Transport>(
+    transport: &mut T,
+    buffer: &mut [u8; MAX_PACKET_SIZE],
+) -> Result<Packet, PacketError> {
+    let length = match transport.read_byte(PACKET_TIMEOUT_US) {
+        None => return Err(PacketError::Timeout),
+        Some(SOH) => 128,
+        Some(STX) => 1024,
+        Some(EOT) => return Ok(Packet::EndOfTransmission),
+        Some(CAN) if read_byte(transport)? == CAN => return Ok(Packet::Cancel),
+        Some(_) => return Err(PacketError::Corrupted),
+    };
+
+    let number = read_byte(transport)?;
+    let number_complement = read_byte(transport)?;
+
+    for byte in buffer[..length].iter_mut() {
+        *byte = read_byte(transport)?;
+    }
+
+    let crc = u16::from_be_bytes([read_byte(transport)?, read_byte(transport)?]);
+
+    if number != !number_complement || crc != crc::crc16_xmodem(&buffer[..length]) {
+        return Err(PacketError::Corrupted);
+    }
+
+    Ok(Packet::Data { number, length })
+}
+
+/// Drop any pending input, used to resynchronize after an error.
+fn purge<T: Transport>(transport: &mut T) {
+    while transport.read_byte(BYTE_TIMEOUT_US).is_some() {}
+}
+
+fn cancel<T: Transport>(transport: &mut T, error: Error) -> Error {
+    for _ in 0..3 {
+        transport.write_byte(CAN);
+    }
+
+    error
+}
+
+/// Parse the YMODEM block 0: the file name and its decimal size, separated by a NUL byte.
+///
+/// The name goes to `info`. The size is optional, None is returned when it is absent or
+/// doesn't fit.
+fn parse_header(data: &[u8], info: &mut FileInfo) -> Option<usize> {
+    let name_length = data
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or_else(|| data.len());
+
+    info.name_length = cmp::min(name_length, MAX_NAME_LENGTH);
+    info.name[..info.name_length].copy_from_slice(&data[..info.name_length]);
+
+    let digits = data
+        .get(name_length + 1..)?
+        .iter()
+        .take_while(|byte| byte.is_ascii_digit());
+
+    let mut size: Option<usize> = None;
+    for digit in digits {
+        let value = size
+            .unwrap_or(0)
+            .checked_mul(10)?
+            .checked_add(usize::from(digit - b'0'))?;
+
+        size = Some(value);
+    }
+
+    size
+}
+
+/// Receive a single file in `destination`, `buffer` holding the packets being received.
+pub fn receive<T: Transport>(
+    transport: &mut T,
+    buffer: &mut [u8; MAX_PACKET_SIZE],
+    destination: &mut [u8],
+) -> Result<FileInfo, Error> {
+    let mut info = FileInfo::new();
+    let mut expected_size = None;
+    let mut received = 0;
+
+    let mut started = false;
+    let mut is_ymodem = true;
+    let mut expected_block: u8 = 0;
+    let mut errors = 0;
+    let mut start_retries = 0;
+    let mut end_of_transmission_count = 0;
+
+    transport.write_byte(CRC_REQUEST);
+
+    loop {
+        match read_packet(transport, buffer) {
+            Err(PacketError::Timeout) if !started => {
+                start_retries += 1;
+                if start_retries == MAX_START_RETRIES {
+                    return Err(cancel(transport, Error::Timeout));
+                }
+
+                transport.write_byte(CRC_REQUEST);
+            }
+            Err(_) => {
+                errors += 1;
+                if errors == MAX_ERRORS {
+                    return Err(cancel(transport, Error::TooManyErrors));
+                }
+
+                purge(transport);
+                transport.write_byte(NAK);
+            }
+            Ok(Packet::Cancel) => return Err(Error::Cancelled),
+            Ok(Packet::EndOfTransmission) => {
+                if !started {
+                    continue;
+                }
+
+                // Make sure this isn't noise by asking for it again.
+                end_of_transmission_count += 1;
+                if end_of_transmission_count == 1 {
+                    transport.write_byte(NAK);
+                    continue;
+                }
+
+                transport.write_byte(ACK);
+                break;
+            }
+            Ok(Packet::Data { number, length }) => {
+                errors = 0;
+
+                if !started {
+                    started = true;
+
+                    // XMODEM directly starts with the first data block.
+                    if number == 1 {
+                        is_ymodem = false;
+                        expected_block = 1;
+                    }
+                }
+
+                if is_ymodem && number == 0 && received == 0 && expected_block == 1 {
+                    // The header was received but our acknowledgement was lost.
+                    transport.write_byte(ACK);
+                    transport.write_byte(CRC_REQUEST);
+                    continue;
+                }
+
+                if number == expected_block.wrapping_sub(1) && received != 0 {
+                    // Retransmission of the previous block.
+                    transport.write_byte(ACK);
+                    continue;
+                }
+
+                if number != expected_block {
+                    return Err(cancel(transport, Error::OutOfSequence));
+                }
+
+                if is_ymodem && expected_block == 0 && received == 0 {
+                    // An empty file name ends the batch.
+                    if buffer[0] == 0 {
+                        transport.write_byte(ACK);
+                        return Err(Error::NoFile);
+                    }
+
+                    expected_size = parse_header(&buffer[..length], &mut info);
+
+                    if expected_size.map_or(false, |size| size > destination.len()) {
+                        return Err(cancel(transport, Error::TooLarge));
+                    }
+
+                    expected_block = 1;
+
+                    transport.write_byte(ACK);
+                    transport.write_byte(CRC_REQUEST);
+                    continue;
+                }
+
+                // The last block is padded up to the block size.
+                let count = match expected_size {
+                    Some(size) => cmp::min(length, size - received),
+                    None => length,
+                };
+
+                if received + count > destination.len() {
+                    return Err(cancel(transport, Error::TooLarge));
+                }
+
+                destination[received..received + count].copy_from_slice(&buffer[..count]);
+                received += count;
+                expected_block = expected_block.wrapping_add(1);
+
+                transport.write_byte(ACK);
+            }
+        }
+    }
+
+    if is_ymodem {
+        // Ask for the next file and end the batch as only one file is supported.
+        transport.write_byte(CRC_REQUEST);
+
+        match read_packet(transport, buffer) {
+            Ok(Packet::Data { number: 0, .. }) if buffer[0] == 0 => transport.write_byte(ACK),
+            _ => {
+                cancel(transport, Error::Cancelled);
+            }
+        }
+    }
+
+    if expected_size.is_none() {
+        // XMODEM, or YMODEM without the optional size, drop the padding.
+        while received > 0 && destination[received - 1] == SUB {
+            received -= 1;
+        }
+    }
+
+    info.size = received;
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Replays the bytes of a sender, `None` being a timeout, and records the answers.
+    struct ScriptedTransport {
+        input: VecDeque<Option<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Transport for ScriptedTransport {
+        fn read_byte(&mut self, _timeout_us: u32) -> Option<u8> {
+            self.input.pop_front().flatten()
+        }
+
+        fn write_byte(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    fn packet(number: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![if data.len() == 128 { SOH } else { STX }, number, !number];
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(&crc::crc16_xmodem(data).to_be_bytes());
+        packet
+    }
+
+    fn header(name: &str, size: Option<usize>) -> Vec<u8> {
+        let mut data = [0; 128];
+        let mut header = format!("{}\0", name);
+        if let Some(size) = size {
+            header += &format!("{} 13750273327 100644", size);
+        }
+        data[..header.len()].copy_from_slice(header.as_bytes());
+
+        packet(0, &data)
+    }
+
+    fn end_of_batch() -> Vec<u8> {
+        packet(0, &[0; 128])
+    }
+
+    /// `size` bytes of data followed by padding, up to `length`.
+    fn block(seed: u8, size: usize, length: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..size as u8).map(|byte| byte ^ seed).collect();
+        data.resize(length, SUB);
+        data
+    }
+
+    /// What was written to the sender, the name and size of the file and its data.
+    type Transfer = (Vec<u8>, Result<(String, usize), Error>, Vec<u8>);
+
+    /// Run a transfer to a `capacity` bytes destination.
+    fn transfer(script: &[&[u8]], capacity: usize) -> Transfer {
+        let mut input = VecDeque::new();
+        for part in script {
+            // An empty part is a timeout.
+            if part.is_empty() {
+                input.push_back(None);
+            }
+
+            input.extend(part.iter().map(|byte| Some(*byte)));
+        }
+
+        let mut transport = ScriptedTransport {
+            input,
+            output: Vec::new(),
+        };
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let mut destination = vec![0; capacity];
+
+        let result = receive(&mut transport, &mut buffer, &mut destination)
+            .map(|info| (info.name().to_string(), info.size));
+        let size = result.as_ref().map_or(0, |(_, size)| *size);
+
+        (transport.output, result, destination[..size].to_vec())
+    }
+
+    #[test]
+    fn xmodem_fallback() {
+        // A first EOT is only confirmed with a NAK, before the start it is ignored.
+        let data = block(0x55, 100, 128);
+        let (output, result, received) =
+            transfer(&[&[EOT], &packet(1, &data), &[EOT, EOT]], 0x1000);
+
+        assert_eq!(output, [CRC_REQUEST, ACK, NAK, ACK]);
+        assert_eq!(result, Ok((String::new(), 100)));
+        assert_eq!(received, &data[..100]);
+
+        // Noise EOT: the sender goes on after the NAK.
+        let first = block(0x11, 1024, 1024);
+        let second = block(0x22, 10, 128);
+        let (output, result, received) = transfer(
+            &[&packet(1, &first), &[EOT], &packet(2, &second), &[EOT]],
+            0x1000,
+        );
+
+        assert_eq!(output, [CRC_REQUEST, ACK, NAK, ACK, ACK]);
+        assert_eq!(result, Ok((String::new(), 1034)));
+        assert_eq!(received, [&first[..], &second[..10]].concat());
+    }
+
+    #[test]
+    fn ymodem_with_size() {
+        // The size keeps SUB bytes ending the file.
+        let mut data = block(0x33, 200, 256);
+        data[199] = SUB;
+        let (output, result, received) = transfer(
+            &[
+                &header("Image", Some(200)),
+                &packet(1, &data[..128]),
+                &packet(2, &data[128..]),
+                &[EOT, EOT],
+                &end_of_batch(),
+            ],
+            200,
+        );
+
+        assert_eq!(
+            output,
+            [
+                CRC_REQUEST,
+                ACK,
+                CRC_REQUEST,
+                ACK,
+                ACK,
+                NAK,
+                ACK,
+                CRC_REQUEST,
+                ACK
+            ]
+        );
+        assert_eq!(result, Ok(("Image".to_string(), 200)));
+        assert_eq!(received, &data[..200]);
+
+        // Without a size, the padding is dropped.
+        let (output, result, received) = transfer(
+            &[
+                &header("initrd", None),
+                &packet(1, &data[..128]),
+                &packet(2, &data[128..]),
+                &[EOT, EOT],
+                &end_of_batch(),
+            ],
+            256,
+        );
+
+        assert_eq!(
+            output,
+            [
+                CRC_REQUEST,
+                ACK,
+                CRC_REQUEST,
+                ACK,
+                ACK,
+                NAK,
+                ACK,
+                CRC_REQUEST,
+                ACK
+            ]
+        );
+        assert_eq!(result, Ok(("initrd".to_string(), 199)));
+        assert_eq!(received, &data[..199]);
+
+        // A sender not ending the batch is cancelled, the file is still received.
+        let (output, result, _) = transfer(
+            &[
+                &header("Image", Some(100)),
+                &packet(1, &data[..128]),
+                &[EOT, EOT],
+            ],
+            200,
+        );
+
+        assert_eq!(
+            output,
+            [
+                CRC_REQUEST,
+                ACK,
+                CRC_REQUEST,
+                ACK,
+                NAK,
+                ACK,
+                CRC_REQUEST,
+                CAN,
+                CAN,
+                CAN
+            ]
+        );
+        assert_eq!(result, Ok(("Image".to_string(), 100)));
+    }
+
+    #[test]
+    fn lost_acknowledgements() {
+        let data = block(0x44, 128, 128);
+        let (output, result, received) = transfer(
+            &[
+                &header("Image", Some(128)),
+                &header("Image", Some(128)),
+                &packet(1, &data),
+                &packet(1, &data),
+                &[EOT, EOT],
+                &end_of_batch(),
+            ],
+            128,
+        );
+
+        assert_eq!(
+            output,
+            [
+                CRC_REQUEST,
+                ACK,
+                CRC_REQUEST,
+                ACK,
+                CRC_REQUEST,
+                ACK,
+                ACK,
+                NAK,
+                ACK,
+                CRC_REQUEST,
+                ACK
+            ]
+        );
+        assert_eq!(result, Ok(("Image".to_string(), 128)));
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn retransmissions() {
+        let data = block(0x66, 128, 128);
+        let mut corrupted = packet(1, &data);
+        corrupted[10] ^= 1;
+        let mut bad_number = packet(1, &data);
+        bad_number[2] = 0;
+
+        // Each bad packet is followed by a timeout ending the purge.
+        let (output, result, received) = transfer(
+            &[
+                &corrupted,
+                &[],
+                &bad_number,
+                &[],
+                // Cut short, the second timeout ends the purge.
+                &packet(1, &data)[..100],
+                &[],
+                &[],
+                &packet(1, &data),
+                &[EOT, EOT],
+            ],
+            0x1000,
+        );
+
+        assert_eq!(output, [CRC_REQUEST, NAK, NAK, NAK, ACK, NAK, ACK]);
+        assert_eq!(result, Ok((String::new(), 128)));
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn failures() {
+        let data = block(0x77, 128, 128);
+        let cancelled_by = |error| Err::<(String, usize), _>(error);
+
+        // Nobody starts sending.
+        let (output, result, _) = transfer(&[], 0x1000);
+        let mut expected = vec![CRC_REQUEST; MAX_START_RETRIES];
+        expected.extend_from_slice(&[CAN, CAN, CAN]);
+        assert_eq!(output, expected);
+        assert_eq!(result, cancelled_by(Error::Timeout));
+
+        // Only garbage.
+        let mut script: Vec<&[u8]> = Vec::new();
+        for _ in 0..MAX_ERRORS {
+            script.extend_from_slice(&[b"?", &[]]);
+        }
+        let (output, result, _) = transfer(&script, 0x1000);
+        let mut expected = vec![CRC_REQUEST];
+        expected.extend_from_slice(&[NAK; MAX_ERRORS - 1]);
+        expected.extend_from_slice(&[CAN, CAN, CAN]);
+        assert_eq!(output, expected);
+        assert_eq!(result, cancelled_by(Error::TooManyErrors));
+
+        let (output, result, _) = transfer(&[&packet(1, &data), &[CAN, CAN]], 0x1000);
+        assert_eq!(output, [CRC_REQUEST, ACK]);
+        assert_eq!(result, cancelled_by(Error::Cancelled));
+
+        let (output, result, _) = transfer(&[&packet(1, &data), &packet(3, &data)], 0x1000);
+        assert_eq!(output, [CRC_REQUEST, ACK, CAN, CAN, CAN]);
+        assert_eq!(result, cancelled_by(Error::OutOfSequence));
+
+        let (output, result, _) = transfer(&[&end_of_batch()], 0x1000);
+        assert_eq!(output, [CRC_REQUEST, ACK]);
+        assert_eq!(result, cancelled_by(Error::NoFile));
+
+        // Too large, from the header or from the data.
+        let (output, result, _) = transfer(&[&header("Image", Some(129))], 128);
+        assert_eq!(output, [CRC_REQUEST, CAN, CAN, CAN]);
+        assert_eq!(result, cancelled_by(Error::TooLarge));
+
+        let (output, result, _) = transfer(&[&packet(1, &data), &packet(2, &data)], 200);
+        assert_eq!(output, [CRC_REQUEST, ACK, CAN, CAN, CAN]);
+        assert_eq!(result, cancelled_by(Error::TooLarge));
+    }
+
+    #[test]
+    fn headers() {
+        let mut info = FileInfo::new();
+        assert_eq!(
+            parse_header(b"Image\x0012345 0\x00", &mut info),
+            Some(12345)
+        );
+        assert_eq!(info.name(), "Image");
+
+        assert_eq!(parse_header(b"Image\x00", &mut info), None);
+        assert_eq!(parse_header(b"Image", &mut info), None);
+        assert_eq!(parse_header(b"Image\x00 12", &mut info), None);
+        assert_eq!(
+            parse_header(b"Image\x0099999999999999999999999", &mut info),
+            None
+        );
+
+        let long_name = [b'a'; MAX_NAME_LENGTH + 10];
+        assert_eq!(parse_header(&long_name, &mut info), None);
+        assert_eq!(info.name().len(), MAX_NAME_LENGTH);
+    }
+}
@@ -0,0 +1,89 @@
+#!/bin/sh
+# Makes the disk images used by the GPT, FAT and ext4 tests.
+#
+# The images are checked in gzipped, run this again after changing it. Needs sfdisk (or python3
+# and libfdisk), cargo, and mke2fs, debugfs and e2fsck from e2fsprogs 1.47.
+
+set -eu
+
+cd "$(dirname "$0")"
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+# Same as pattern() in the tests.
+pattern() {
+    python3 -c "
+import sys
+size, seed = int(sys.argv[1]), int(sys.argv[2])
+sys.stdout.buffer.write(bytes((i * 7 + i // 512 + seed) & 0xFF for i in range(size)))
+" "$1" "$2"
+}
+
+# A 256 blocks disk with an ESP, an unused entry and a Linux partition starting at block 100.
+truncate -s $((256 * 512)) "$work/gpt.img"
+if command -v sfdisk > /dev/null; then
+    sfdisk --quiet --no-reread --no-tell-kernel "$work/gpt.img" < gpt.sfdisk
+else
+    python3 sfdisk.py "$work/gpt.img" gpt.sfdisk
+fi
+# Data to find through the partitions.
+printf '\102' | dd of="$work/gpt.img" bs=512 seek=100 conv=notrunc status=none
+
+# mkfat formats the FAT images and writes their files with rust-fatfs. The nightly rboot is
+# pinned to is too old for its dependencies.
+cargo +stable build --quiet --release --manifest-path mkfat/Cargo.toml
+for fat in 12 16 32; do
+    mkfat/target/release/mkfat $fat "$work/fat$fat.img"
+done
+
+# ext4 with 1KB blocks in 4 groups, and a kernel fragmented enough for an extent tree of
+# depth 1. The files are written by debugfs, which dates them with the fake time when mke2fs -d
+# would copy their ctime. With the UUIDs and hash seed fixed, the image is reproducible.
+export E2FSPROGS_FAKE_TIME=1600000000 E2FSCK_TIME=1600000000
+printf 'DEFAULT primary\nLABEL primary\n  LINUX /boot/Image\n' > "$work/extlinux.conf"
+pattern 3000 3 > "$work/dtb"
+pattern 1024 10 > "$work/filler"
+pattern $((12 * 1024 - 100)) 1 > "$work/kernel"
+# Holes at blocks 2 to 9 and 12 to 39.
+pattern 1500 4 > "$work/sparse"
+pattern 2000 5 | dd of="$work/sparse" bs=1024 seek=10 conv=notrunc status=none
+pattern 100 6 | dd of="$work/sparse" bs=1 seek=$((40 * 1024 + 100)) conv=notrunc status=none
+
+{
+    echo "set_current_time @1600000000"
+    echo "mkdir boot"
+    echo "mkdir boot/extlinux"
+    echo "write $work/extlinux.conf boot/extlinux/extlinux.conf"
+    echo "write $work/dtb boot/tegra210-p2371-2180.dtb"
+    echo "write $work/sparse sparse"
+    echo "symlink vmlinuz boot/Image"
+    echo "symlink loop loop"
+    echo "symlink boot/current ../boot"
+    echo "symlink dtb /boot/current/./current/current/../boot/./current/tegra210-p2371-2180.dtb"
+    # Longer than the paths rboot can follow.
+    echo "symlink long $(printf '%250s' | tr ' ' a)"
+    # Enough names for e2fsck to index the directory.
+    for index in $(seq -w 0 59); do
+        echo "write /dev/null boot/file$index"
+    done
+    # The kernel goes in the blocks freed between the remaining fillers.
+    for index in $(seq 0 9); do
+        echo "write $work/filler boot/filler$index"
+    done
+    for index in 1 3 5 7 9; do
+        echo "rm boot/filler$index"
+    done
+    echo "write $work/kernel boot/Image"
+} > "$work/ext4.debugfs"
+
+mke2fs -q -F -t ext4 -b 1024 -g 1024 -N 256 -L rootfs \
+    -U 01234567-89ab-cdef-0123-456789abcdef \
+    -E hash_seed=00112233-4455-6677-8899-aabbccddeeff,root_owner=0:0 \
+    "$work/ext4.img" 4M > /dev/null
+debugfs -w -f "$work/ext4.debugfs" "$work/ext4.img" > /dev/null 2>&1
+e2fsck -fyD "$work/ext4.img" > /dev/null 2>&1 || [ $? -eq 1 ]
+
+for image in gpt fat12 fat16 fat32 ext4; do
+    gzip -9 -n -c "$work/$image.img" > "$image.img.gz"
+done
@@ -0,0 +1,80 @@
+//! Formats a FAT image and fills it with the tree the FAT tests expect.
+//!
+//! rust-fatfs allocates clusters as files are written, the kernel is written along with a file
+//! deleted afterwards to leave it fragmented.
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+
+/// Same as `pattern()` in the rboot tests.
+fn pattern(size: usize, seed: usize) -> Vec<u8> {
+    (0..size)
+        .map(|index| (index * 7 + index / 512 + seed) as u8)
+        .collect()
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <12|16|32> <image>", args[0]);
+        std::process::exit(1);
+    }
+
+    // Cluster counts well inside the range of each type, with 512 bytes clusters.
+    let (fat_type, total_sectors) = match args[1].as_str() {
+        "12" => (FatType::Fat12, 2048),
+        "16" => (FatType::Fat16, 5120),
+        "32" => (FatType::Fat32, 67584),
+        _ => panic!("unknown FAT type {}", args[1]),
+    };
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&args[2])?;
+    file.set_len(total_sectors as u64 * 512)?;
+
+    let options = FormatVolumeOptions::new()
+        .fat_type(fat_type)
+        .bytes_per_cluster(512)
+        .total_sectors(total_sectors)
+        .volume_id(0x1234_5678)
+        .volume_label(*b"RBOOT      ");
+    fatfs::format_volume(&mut file, options)?;
+
+    let file_system = FileSystem::new(&mut file, FsOptions::new())?;
+    let root = file_system.root_dir();
+
+    let boot = root.create_dir("boot")?;
+    let kernel = pattern(5 * 512 + 100, 1);
+    let mut image = boot.create_file("Image-5.10.0")?;
+    let mut filler = boot.create_file("filler")?;
+    for chunk in kernel.chunks(512) {
+        image.write_all(chunk)?;
+        filler.write_all(&[0xAA; 512])?;
+    }
+    image.flush()?;
+    filler.flush()?;
+    boot.remove("filler")?;
+
+    boot.create_file("extlinux.conf")?
+        .write_all(b"DEFAULT primary\nLABEL primary\n  LINUX /boot/Image-5.10.0\n")?;
+
+    // Enough entries for the directory to take several clusters.
+    let many = root.create_dir("many")?;
+    for index in 0..40 {
+        many.create_file(&format!("file{:02}.bin", index))?
+            .write_all(&pattern(index * 100, index))?;
+    }
+
+    root.create_file("EMPTY.TXT")?;
+    root.create_file("Twenty-six characters.txt")?;
+    root.create_file("A long name over three entries, \u{fc}nicode.tar.gz")?;
+
+    Ok(())
+}
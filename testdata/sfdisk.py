@@ -0,0 +1,43 @@
+#!/usr/bin/env python3
+"""Apply an sfdisk script to an image through libfdisk, for hosts without sfdisk.
+
+Usage: sfdisk.py <image> <script>
+"""
+
+import ctypes
+import sys
+
+fdisk = ctypes.CDLL("libfdisk.so.1")
+libc = ctypes.CDLL(None)
+
+fdisk.fdisk_new_context.restype = ctypes.c_void_p
+fdisk.fdisk_assign_device.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_int]
+fdisk.fdisk_new_script.restype = ctypes.c_void_p
+fdisk.fdisk_new_script.argtypes = [ctypes.c_void_p]
+fdisk.fdisk_script_read_file.argtypes = [ctypes.c_void_p, ctypes.c_void_p]
+fdisk.fdisk_apply_script.argtypes = [ctypes.c_void_p, ctypes.c_void_p]
+fdisk.fdisk_write_disklabel.argtypes = [ctypes.c_void_p]
+fdisk.fdisk_deassign_device.argtypes = [ctypes.c_void_p, ctypes.c_int]
+libc.fopen.restype = ctypes.c_void_p
+libc.fopen.argtypes = [ctypes.c_char_p, ctypes.c_char_p]
+
+
+def check(result, what):
+    if result != 0:
+        sys.exit("{} failed: {}".format(what, result))
+
+
+image, script = sys.argv[1], sys.argv[2]
+
+context = fdisk.fdisk_new_context()
+check(fdisk.fdisk_assign_device(context, image.encode(), 0), "assigning " + image)
+
+dump = fdisk.fdisk_new_script(context)
+file = libc.fopen(script.encode(), b"r")
+if not file:
+    sys.exit("can't open " + script)
+check(fdisk.fdisk_script_read_file(dump, file), "reading " + script)
+
+check(fdisk.fdisk_apply_script(context, dump), "applying the script")
+check(fdisk.fdisk_write_disklabel(context), "writing the table")
+check(fdisk.fdisk_deassign_device(context, 0), "closing " + image)